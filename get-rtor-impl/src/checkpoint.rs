@@ -0,0 +1,315 @@
+use std::{
+  fmt::Display,
+  io::{self, BufRead, Write},
+  iter::Peekable,
+};
+
+use irlf_db::{
+  ir::{Id2Sym, Program},
+  unconvert::unconvert,
+};
+use lf_types::{DebugOnlyId, Tag, Value};
+
+use crate::{record::fnv1a, Db};
+
+/// A value on a delayed connection between the children of an srtor that has been sent but not yet
+/// delivered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DelayedValue {
+  pub connection: DebugOnlyId,
+  /// The index of the channel of the connection that the value was sent on.
+  pub channel: u64,
+  /// The tag at which the value is due at the right end of the connection.
+  pub due: Tag,
+  pub value: Value,
+}
+
+/// The state of an rtor that must be saved in order to resume a simulation.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RtorState {
+  /// The tag that the rtor has reached, which also gives its nesting level. It is `None` for rtors
+  /// that do not keep time.
+  pub tag: Option<Tag>,
+  /// The values in flight on the delayed connections of the rtor, in the order in which each
+  /// connection is to deliver them.
+  pub delayed: Vec<DelayedValue>,
+  /// The states of the children of the rtor, in the order in which it realized them.
+  pub children: Vec<RtorState>,
+}
+
+/// The saved states of the rtors of a scheduler, along with the hash of the program that they were
+/// realized from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+  pub program_hash: u64,
+  pub rtors: Vec<RtorState>,
+}
+
+/// The reasons why a checkpoint cannot be restored.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RestoreError {
+  /// The checkpoint was saved from a different program.
+  ProgramChanged { saved: u64, current: u64 },
+  /// The checkpoint has a different number of rtors than the scheduler that it is restored into.
+  RtorCount { saved: usize, current: usize },
+  /// The checkpoint has a different number of children for an srtor than the srtor has.
+  ChildCount { saved: usize, current: usize },
+  /// The checkpoint has no tag for an rtor that keeps time.
+  MissingTag,
+  /// The checkpoint has state for an rtor that keeps none.
+  UnexpectedState,
+  /// The checkpoint has values in flight on a delayed connection that the srtor does not have.
+  UnknownConnection(DebugOnlyId),
+  /// The checkpoint has values in flight on a channel that a delayed connection does not have.
+  UnknownChannel {
+    connection: DebugOnlyId,
+    channel: u64,
+  },
+}
+
+impl Display for RestoreError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      RestoreError::ProgramChanged { saved, current } => write!(
+        f,
+        "the checkpoint was saved from program {saved:016x}, not {current:016x}"
+      ),
+      RestoreError::RtorCount { saved, current } => write!(
+        f,
+        "the checkpoint has {saved} rtors but the scheduler has {current}"
+      ),
+      RestoreError::ChildCount { saved, current } => write!(
+        f,
+        "the checkpoint has {saved} children for an srtor that has {current}"
+      ),
+      RestoreError::MissingTag => write!(f, "the checkpoint has no tag for an rtor that keeps time"),
+      RestoreError::UnexpectedState => {
+        write!(f, "the checkpoint has state for an rtor that keeps none")
+      }
+      RestoreError::UnknownConnection(connection) => write!(
+        f,
+        "the checkpoint has values in flight on connection {connection}, which the srtor does not have"
+      ),
+      RestoreError::UnknownChannel {
+        connection,
+        channel,
+      } => write!(
+        f,
+        "the checkpoint has values in flight on channel {channel} of connection {connection}, which has fewer channels"
+      ),
+    }
+  }
+}
+
+/// A hash of `program` that is the same across builds, and that changes whenever the program
+/// changes in a way that could invalidate saved states, including renumbering of its ids.
+pub fn program_hash(db: &dyn Db, program: Program, id2sym: Id2Sym) -> u64 {
+  fnv1a(unconvert(db, program, id2sym).to_string().as_bytes())
+}
+
+impl Checkpoint {
+  /// Writes `self` in a line-based format in which the children of a state are indented below it.
+  pub fn write_to(&self, mut out: impl Write) -> io::Result<()> {
+    writeln!(out, "program {:016x}", self.program_hash)?;
+    for state in &self.rtors {
+      write_state(&mut out, state, 0)?;
+    }
+    Ok(())
+  }
+
+  /// Reads a checkpoint in the format produced by `write_to`.
+  pub fn read_from(input: impl BufRead) -> io::Result<Self> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    let mut lines = Vec::new();
+    for line in input.lines() {
+      let line = line?;
+      if !line.trim().is_empty() {
+        lines.push(line);
+      }
+    }
+    let mut lines = lines.iter().map(String::as_str).peekable();
+    let program_hash = lines
+      .next()
+      .and_then(|line| line.strip_prefix("program "))
+      .and_then(|hash| u64::from_str_radix(hash, 16).ok())
+      .ok_or_else(|| invalid("expected the hash of a program".to_string()))?;
+    let mut rtors = vec![];
+    while lines.peek().is_some() {
+      rtors.push(read_state(&mut lines, 0).map_err(invalid)?);
+    }
+    Ok(Checkpoint {
+      program_hash,
+      rtors,
+    })
+  }
+
+  /// Checks that `self` was saved from the program with the given hash.
+  ///
+  /// # Errors
+  /// Returns an error if the program has changed since `self` was saved.
+  pub fn check(&self, program_hash: u64) -> Result<(), RestoreError> {
+    if self.program_hash == program_hash {
+      Ok(())
+    } else {
+      Err(RestoreError::ProgramChanged {
+        saved: self.program_hash,
+        current: program_hash,
+      })
+    }
+  }
+}
+
+fn write_state(out: &mut impl Write, state: &RtorState, depth: usize) -> io::Result<()> {
+  let indent = "  ".repeat(depth);
+  match &state.tag {
+    Some(tag) => writeln!(out, "{indent}rtor {tag}")?,
+    None => writeln!(out, "{indent}rtor -")?,
+  }
+  for delayed in &state.delayed {
+    writeln!(
+      out,
+      "{indent}  delayed {} {} {} {}",
+      delayed.connection, delayed.channel, delayed.due, delayed.value
+    )?;
+  }
+  for child in &state.children {
+    write_state(out, child, depth + 1)?;
+  }
+  Ok(())
+}
+
+fn read_state<'a>(
+  lines: &mut Peekable<impl Iterator<Item = &'a str>>,
+  depth: usize,
+) -> Result<RtorState, String> {
+  let indent = "  ".repeat(depth);
+  let line = lines.next().unwrap();
+  let tag = match line
+    .strip_prefix(&indent)
+    .and_then(|line| line.strip_prefix("rtor "))
+  {
+    Some("-") => None,
+    Some(tag) => Some(tag.parse()?),
+    None => return Err(format!("expected an rtor at \"{line}\"")),
+  };
+  let mut ret = RtorState {
+    tag,
+    ..RtorState::default()
+  };
+  let nested = format!("{indent}  ");
+  while let Some(line) = lines
+    .peek()
+    .copied()
+    .and_then(|line| line.strip_prefix(&nested))
+  {
+    if let Some(delayed) = line.strip_prefix("delayed ") {
+      ret.delayed.push(read_delayed(delayed)?);
+      lines.next();
+    } else {
+      ret.children.push(read_state(lines, depth + 1)?);
+    }
+  }
+  Ok(ret)
+}
+
+fn read_delayed(s: &str) -> Result<DelayedValue, String> {
+  let words: Vec<&str> = s.split_whitespace().collect();
+  let [connection, channel, due, value] = words[..] else {
+    return Err(format!(
+      "expected a connection, channel, tag and value at \"{s}\""
+    ));
+  };
+  Ok(DelayedValue {
+    connection: connection
+      .parse()
+      .map(DebugOnlyId)
+      .map_err(|_| format!("expected a connection at \"{connection}\""))?,
+    channel: channel
+      .parse()
+      .map_err(|_| format!("expected a channel at \"{channel}\""))?,
+    due: due.parse()?,
+    value: value.parse()?,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use irlf_db::from_text;
+
+  use crate::{testing::PAIR, GriTestDatabase};
+
+  use super::*;
+
+  #[test]
+  fn test_round_trip() {
+    let checkpoint = Checkpoint {
+      program_hash: 0xabc,
+      rtors: vec![
+        RtorState {
+          tag: Some(Tag(vec![3, 0, 2])),
+          delayed: vec![
+            DelayedValue {
+              connection: DebugOnlyId(200),
+              channel: 0,
+              due: Tag(vec![5]),
+              value: Value::Tuple(vec![Value::Float(0.5), Value::Bool(false)]),
+            },
+            DelayedValue {
+              connection: DebugOnlyId(201),
+              channel: 1,
+              due: Tag(vec![5]),
+              value: Value::Int(2),
+            },
+          ],
+          children: vec![
+            RtorState::default(),
+            RtorState {
+              tag: Some(Tag(vec![3])),
+              delayed: vec![],
+              children: vec![],
+            },
+          ],
+        },
+        RtorState::default(),
+      ],
+    };
+    let mut text = vec![];
+    checkpoint.write_to(&mut text).unwrap();
+    assert_eq!(
+      String::from_utf8(text.clone()).unwrap(),
+      "program 0000000000000abc
+rtor 3.0.2
+  delayed 200 0 5 (0.5,false)
+  delayed 201 1 5 2
+  rtor -
+  rtor 3
+rtor -
+"
+    );
+    assert_eq!(Checkpoint::read_from(&text[..]).unwrap(), checkpoint);
+  }
+
+  #[test]
+  fn test_program_hash() {
+    let hash = |text: &str| {
+      let db = GriTestDatabase::default();
+      let (program, id2sym) = from_text(text, &db);
+      program_hash(&db, program, id2sym)
+    };
+    assert_eq!(hash(PAIR), hash(PAIR));
+    let renumbered = hash(&PAIR.replace("102", "103"));
+    assert_ne!(renumbered, hash(PAIR));
+    let checkpoint = Checkpoint {
+      program_hash: hash(PAIR),
+      rtors: vec![],
+    };
+    assert_eq!(checkpoint.check(hash(PAIR)), Ok(()));
+    assert_eq!(
+      checkpoint.check(renumbered),
+      Err(RestoreError::ProgramChanged {
+        saved: hash(PAIR),
+        current: renumbered,
+      })
+    );
+  }
+}
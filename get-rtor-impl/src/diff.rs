@@ -0,0 +1,103 @@
+use connectioniterator::nesting::Nesting;
+use irlf_db::ir::{Program, SourceProgram};
+use irlf_ser::diff::ProgramDiff;
+use lf_types::{Comm, Level, Side};
+
+use crate::{rtorimpl::iface_of, Db};
+
+/// The levels at which `main` is visible from outside of the program.
+#[derive(Debug, PartialEq, Eq)]
+pub struct LevelStructure {
+  pub left: Vec<Comm<Level>>,
+  pub right: Vec<Comm<Level>>,
+}
+
+/// The differences between two versions of a program, including differences in behavior that are
+/// visible from outside of the program.
+#[derive(Debug)]
+pub struct Diff {
+  pub program: ProgramDiff,
+  pub level_structure_changed: bool,
+}
+
+pub fn level_structure(db: &dyn Db, program: Program) -> LevelStructure {
+  let iface = iface_of(db, program.main(db));
+  let side = |side| -> Vec<Comm<Level>> {
+    iface
+      .immut_provide(db, &[], side, Level(0), Nesting::default())
+      .collect()
+  };
+  LevelStructure {
+    left: side(Side::Left),
+    right: side(Side::Right),
+  }
+}
+
+/// Computes the differences between `old` and `new`.
+pub fn diff(db: &dyn Db, old: &irlf_ser::ir::Program, new: &irlf_ser::ir::Program) -> Diff {
+  let levels = |program: &irlf_ser::ir::Program| {
+    let source = SourceProgram::new(db, program.clone());
+    level_structure(db, irlf_db::convert::convert(db, source).0)
+  };
+  Diff {
+    program: irlf_ser::diff::diff(old, new),
+    level_structure_changed: levels(old) != levels(new),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use irlf_ser::unpretty::unpretty;
+
+  use crate::GriTestDatabase;
+
+  use super::*;
+
+  const ADD1: &str = "add1 0 add1
+---
+---
+rtor0 1
+  madd1 100 = 0
+  ---
+  L 100 R 100
+  ---
+---
+1
+";
+
+  #[test]
+  fn test_levels_unchanged() {
+    let db = GriTestDatabase::default();
+    let old = unpretty(ADD1).unwrap();
+    let new = unpretty(&ADD1.replace("madd1", "inc")).unwrap();
+    let diff = diff(&db, &old, &new);
+    assert!(!diff.program.is_empty());
+    assert!(!diff.level_structure_changed);
+  }
+
+  #[test]
+  fn test_levels_changed() {
+    let db = GriTestDatabase::default();
+    let old = unpretty(ADD1).unwrap();
+    let new = unpretty(
+      "add1 0 add1
+sum 2 sum
+---
+---
+rtor0 1
+  madd1 100 = 0
+  msum 101 = 2
+  ---
+  L 100
+  R 101
+  ---
+  200 100 101
+---
+1
+",
+    )
+    .unwrap();
+    let diff = diff(&db, &old, &new);
+    assert!(diff.level_structure_changed);
+  }
+}
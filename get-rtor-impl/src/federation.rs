@@ -0,0 +1,698 @@
+use std::{
+  collections::{BTreeSet, HashMap},
+  fmt::Display,
+  io::{self, BufRead, BufReader, Write},
+  os::unix::net::{UnixListener, UnixStream},
+  thread,
+  time::{Duration, Instant},
+};
+
+use irlf_db::ir::{Ctor, Inst, Program};
+use lf_types::{DebugOnlyId, InstId, Level, Tag, Value};
+
+use crate::{
+  rtorimpl::srtorimpl::{realize_partition, Srtor},
+  scheduler::Scheduler,
+  CausalityLoop, Db,
+};
+
+/// The reasons why a program cannot be federated.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum FederationError {
+  /// A federation needs at least one federate.
+  NoFederates,
+  /// Only the instances of a structlike main ctor can be partitioned.
+  NotStructlike,
+  CausalityLoop(CausalityLoop),
+}
+
+impl Display for FederationError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      FederationError::NoFederates => write!(f, "a federation needs at least one federate"),
+      FederationError::NotStructlike => write!(f, "only structlike ctors can be federated"),
+      FederationError::CausalityLoop(causality_loop) => causality_loop.fmt(f),
+    }
+  }
+}
+
+/// Splits the instances of the main ctor of `program` into `n` groups of consecutive ids, one for
+/// each federate.
+pub fn partition(
+  db: &dyn Db,
+  program: Program,
+  n: usize,
+) -> Result<Vec<Vec<InstId>>, FederationError> {
+  if n == 0 {
+    return Err(FederationError::NoFederates);
+  }
+  let Ctor::StructlikeCtor(sctor) = program.main(db) else {
+    return Err(FederationError::NotStructlike);
+  };
+  let mut ids: Vec<InstId> = sctor.insts(db).iter().map(|inst| inst.id(db)).collect();
+  ids.sort();
+  let size = ids.len().div_ceil(n).max(1);
+  let mut ret: Vec<Vec<InstId>> = ids.chunks(size).map(<[InstId]>::to_vec).collect();
+  ret.resize(n, vec![]);
+  Ok(ret)
+}
+
+/// The part of a program that one federate runs: the instances of main that were assigned to it,
+/// realized as a single srtor.
+pub struct Federate<'db> {
+  scheduler: Scheduler<Srtor<'db>>,
+}
+
+impl<'db> Federate<'db> {
+  /// Realizes the instances `insts` of the main ctor of `program`.
+  pub fn new(db: &'db dyn Db, program: Program, insts: &[InstId]) -> Result<Self, FederationError> {
+    let Ctor::StructlikeCtor(sctor) = program.main(db) else {
+      return Err(FederationError::NotStructlike);
+    };
+    let local = |inst: Inst| insts.contains(&inst.id(db));
+    let srtor = realize_partition(db, *sctor, local).map_err(FederationError::CausalityLoop)?;
+    Ok(Federate {
+      scheduler: Scheduler::new([(Box::new(srtor), Level(0))]),
+    })
+  }
+
+  fn srtor(&mut self) -> &mut Srtor<'db> {
+    self.scheduler.rtor_mut(0)
+  }
+
+  /// The tag and level of the earliest event of the federate. Its instances step at level 0, and
+  /// the values that it receives without a delay are delivered at the levels of their receivers.
+  fn next_event(&mut self) -> Option<(Tag, Level)> {
+    let undelayed = self.srtor().next_undelayed();
+    self
+      .scheduler
+      .next_event()
+      .into_iter()
+      .chain(undelayed)
+      .min()
+  }
+
+  /// Processes the events of the federate at `tag` and `level`.
+  fn step_level(&mut self, tag: &Tag, level: Level) {
+    self.scheduler.step_level(tag, level);
+    self.scheduler.reach(0, tag, level).deliver_undelayed(level);
+    self.scheduler.reschedule(0);
+  }
+
+  /// Sends the values that the federate has sent to other federates since this was last called.
+  fn send_outgoing(&mut self, link: &mut Link) -> io::Result<()> {
+    for (connection, channel, due, value) in self.srtor().take_outgoing() {
+      link.send(&Message::Port(connection, channel, due, value))?;
+    }
+    Ok(())
+  }
+}
+
+/// A message between the coordinator and a federate. Each message is sent as one line of text.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Message {
+  /// Identifies a federate to the coordinator when it connects, together with the connections on
+  /// which it receives values from other federates.
+  Hello {
+    index: usize,
+    incoming: Vec<DebugOnlyId>,
+  },
+  /// Asks a federate for its earliest pending event.
+  Next,
+  /// The tag and level of the earliest pending event of a federate, if it has one.
+  Event(Option<(Tag, Level)>),
+  /// Allows a federate to process its events at the given tag and level.
+  Grant(Tag, Level),
+  /// Reports that a federate has processed the events that it was granted.
+  Done,
+  /// A value on the given channel of a connection between federates, which is due at the given tag,
+  /// or at the tag at which it was sent if the connection has no delay. A federate sends these
+  /// before its reply to `Next` or `Grant`, and the coordinator forwards them to the federate that
+  /// receives on the connection.
+  Port(DebugOnlyId, u64, Tag, Value),
+  /// Ends execution.
+  Stop,
+}
+
+impl Display for Message {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Message::Hello { index, incoming } => {
+        write!(f, "hello {index}")?;
+        for connection in incoming {
+          write!(f, " {connection}")?;
+        }
+        Ok(())
+      }
+      Message::Next => write!(f, "next"),
+      Message::Event(None) => write!(f, "idle"),
+      Message::Event(Some((tag, level))) => write!(f, "event {tag} {}", level.0),
+      Message::Grant(tag, level) => write!(f, "grant {tag} {}", level.0),
+      Message::Done => write!(f, "done"),
+      Message::Port(connection, channel, due, value) => {
+        write!(f, "port {connection} {channel} {due} {value}")
+      }
+      Message::Stop => write!(f, "stop"),
+    }
+  }
+}
+
+impl std::str::FromStr for Message {
+  type Err = String;
+
+  /// Parses a message in the format produced by its `Display` impl.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let words: Vec<&str> = s.split_whitespace().collect();
+    let number = |word: &str| {
+      word
+        .parse::<u32>()
+        .map_err(|_| format!("expected a number in message at \"{word}\""))
+    };
+    let connection = |word: &str| {
+      word
+        .parse()
+        .map(DebugOnlyId)
+        .map_err(|_| format!("expected a connection in message at \"{word}\""))
+    };
+    match words[..] {
+      ["hello", index, ref incoming @ ..] => Ok(Message::Hello {
+        index: number(index)? as usize,
+        incoming: incoming
+          .iter()
+          .map(|word| connection(word))
+          .collect::<Result<_, _>>()?,
+      }),
+      ["next"] => Ok(Message::Next),
+      ["idle"] => Ok(Message::Event(None)),
+      ["event", tag, level] => Ok(Message::Event(Some((tag.parse()?, Level(number(level)?))))),
+      ["grant", tag, level] => Ok(Message::Grant(tag.parse()?, Level(number(level)?))),
+      ["done"] => Ok(Message::Done),
+      ["port", id, channel, due, value] => Ok(Message::Port(
+        connection(id)?,
+        u64::from(number(channel)?),
+        due.parse()?,
+        value.parse()?,
+      )),
+      ["stop"] => Ok(Message::Stop),
+      _ => Err(format!("unexpected message \"{s}\"")),
+    }
+  }
+}
+
+/// Values sent between federates, each with its connection, its channel and the tag at which it is
+/// due.
+type Ports = Vec<(DebugOnlyId, u64, Tag, Value)>;
+
+/// One end of a connection between the coordinator and a federate.
+struct Link {
+  reader: BufReader<UnixStream>,
+  writer: UnixStream,
+}
+
+impl Link {
+  fn new(stream: UnixStream) -> io::Result<Self> {
+    Ok(Link {
+      reader: BufReader::new(stream.try_clone()?),
+      writer: stream,
+    })
+  }
+
+  fn send(&mut self, message: &Message) -> io::Result<()> {
+    writeln!(self.writer, "{message}")
+  }
+
+  fn recv(&mut self) -> io::Result<Message> {
+    let mut line = String::new();
+    if self.reader.read_line(&mut line)? == 0 {
+      return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    line
+      .trim()
+      .parse()
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+  }
+
+  /// Receives the reply to a request, together with the port values that were sent before it.
+  fn recv_reply(&mut self) -> io::Result<(Message, Ports)> {
+    let mut ports = vec![];
+    loop {
+      match self.recv()? {
+        Message::Port(connection, channel, due, value) => {
+          ports.push((connection, channel, due, value))
+        }
+        reply => return Ok((reply, ports)),
+      }
+    }
+  }
+}
+
+/// Orders the events of several federates as a single scheduler would order them, and forwards the
+/// values that they send to each other.
+pub struct Coordinator {
+  federates: Vec<Link>,
+  /// The federate that receives on each connection between federates.
+  receivers: HashMap<DebugOnlyId, usize>,
+}
+
+impl Coordinator {
+  /// Waits for `n` federates to connect to `listener` and say hello, giving up with
+  /// `io::ErrorKind::TimedOut` once `timeout` has passed. Federates are ordered by the index that
+  /// they identify themselves with, regardless of the order in which they connect.
+  pub fn accept(listener: &UnixListener, n: usize, timeout: Duration) -> io::Result<Self> {
+    let deadline = Instant::now() + timeout;
+    let timed_out = || {
+      io::Error::new(
+        io::ErrorKind::TimedOut,
+        "federates took too long to connect",
+      )
+    };
+    listener.set_nonblocking(true)?;
+    let mut federates: Vec<Option<Link>> = (0..n).map(|_| None).collect();
+    let mut receivers = HashMap::new();
+    for _ in 0..n {
+      let stream = loop {
+        match listener.accept() {
+          Ok((stream, _)) => break stream,
+          Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            if Instant::now() >= deadline {
+              return Err(timed_out());
+            }
+            thread::sleep(Duration::from_millis(10));
+          }
+          Err(e) => return Err(e),
+        }
+      };
+      stream.set_nonblocking(false)?;
+      let remaining = deadline
+        .checked_duration_since(Instant::now())
+        .filter(|remaining| !remaining.is_zero())
+        .ok_or_else(timed_out)?;
+      stream.set_read_timeout(Some(remaining))?;
+      let mut link = Link::new(stream)?;
+      let hello = link.recv().map_err(|e| match e.kind() {
+        // Depending on the platform, a read that times out fails with either kind.
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => timed_out(),
+        _ => e,
+      })?;
+      match hello {
+        Message::Hello { index, incoming } if index < n && federates[index].is_none() => {
+          link.writer.set_read_timeout(None)?;
+          receivers.extend(incoming.into_iter().map(|connection| (connection, index)));
+          federates[index] = Some(link);
+        }
+        message => {
+          return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected a new federate to say hello but got \"{message}\""),
+          ))
+        }
+      }
+    }
+    Ok(Coordinator {
+      federates: federates.into_iter().map(Option::unwrap).collect(),
+      receivers,
+    })
+  }
+
+  /// Grants events in order of tag and then of level until the next one would be after `end`, and
+  /// then stops the federates. Federates with events at the same tag and level process them
+  /// concurrently. Returns the tags and levels that were granted, in order.
+  pub fn run_until(&mut self, end: &Tag) -> io::Result<Vec<(Tag, Level)>> {
+    let mut next = vec![];
+    let mut ports = vec![];
+    for i in 0..self.federates.len() {
+      let (event, sent) = self.next_event(i)?;
+      next.push(event);
+      ports.extend(sent);
+    }
+    self.forward(ports, &mut next)?;
+    let mut granted = vec![];
+    while let Some(earliest) = next
+      .iter()
+      .flatten()
+      .min()
+      .filter(|(tag, _)| tag <= end)
+      .cloned()
+    {
+      let due: Vec<usize> = (0..next.len())
+        .filter(|&i| next[i].as_ref() == Some(&earliest))
+        .collect();
+      for &i in &due {
+        self.federates[i].send(&Message::Grant(earliest.0.clone(), earliest.1))?;
+      }
+      let mut ports = vec![];
+      for &i in &due {
+        match self.federates[i].recv_reply()? {
+          (Message::Done, sent) => ports.extend(sent),
+          (message, _) => return Err(unexpected(&message)),
+        }
+        let (event, sent) = self.next_event(i)?;
+        next[i] = event;
+        ports.extend(sent);
+      }
+      self.forward(ports, &mut next)?;
+      granted.push(earliest);
+    }
+    for link in &mut self.federates {
+      link.send(&Message::Stop)?;
+    }
+    Ok(granted)
+  }
+
+  /// Delivers `ports` to the federates that receive them and updates the next events of those
+  /// federates in `next`.
+  fn forward(&mut self, mut ports: Ports, next: &mut [Option<(Tag, Level)>]) -> io::Result<()> {
+    while !ports.is_empty() {
+      let mut receivers = BTreeSet::new();
+      for (connection, channel, due, value) in std::mem::take(&mut ports) {
+        let Some(&i) = self.receivers.get(&connection) else {
+          return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no federate receives on connection {connection}"),
+          ));
+        };
+        self.federates[i].send(&Message::Port(connection, channel, due, value))?;
+        receivers.insert(i);
+      }
+      for i in receivers {
+        let (event, sent) = self.next_event(i)?;
+        next[i] = event;
+        ports.extend(sent);
+      }
+    }
+    Ok(())
+  }
+
+  fn next_event(&mut self, i: usize) -> io::Result<(Option<(Tag, Level)>, Ports)> {
+    let link = &mut self.federates[i];
+    link.send(&Message::Next)?;
+    match link.recv_reply()? {
+      (Message::Event(event), sent) => Ok((event, sent)),
+      (message, _) => Err(unexpected(&message)),
+    }
+  }
+}
+
+/// Runs `federate` as the `index`th federate of the coordinator at the other end of `stream`, until
+/// the coordinator stops it.
+pub fn run_federate(federate: &mut Federate, stream: UnixStream, index: usize) -> io::Result<()> {
+  let mut link = Link::new(stream)?;
+  link.send(&Message::Hello {
+    index,
+    incoming: federate.srtor().incoming().collect(),
+  })?;
+  loop {
+    match link.recv()? {
+      Message::Next => {
+        federate.send_outgoing(&mut link)?;
+        link.send(&Message::Event(federate.next_event()))?;
+      }
+      Message::Grant(tag, level) => {
+        federate.step_level(&tag, level);
+        federate.send_outgoing(&mut link)?;
+        link.send(&Message::Done)?;
+      }
+      Message::Port(connection, channel, due, value) => {
+        if !federate.srtor().receive(connection, channel, due, value) {
+          return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+              "federate {index} does not receive on channel {channel} of connection {connection}"
+            ),
+          ));
+        }
+        federate.scheduler.reschedule(0);
+      }
+      Message::Stop => return Ok(()),
+      message => return Err(unexpected(&message)),
+    }
+  }
+}
+
+fn unexpected(message: &Message) -> io::Error {
+  io::Error::new(
+    io::ErrorKind::InvalidData,
+    format!("unexpected message \"{message}\""),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{env, process::Command, rc::Rc};
+
+  use irlf_db::from_text;
+  use lf_types::Side;
+
+  use crate::{record::Recorder, rtor::Rtor, GriTestDatabase};
+
+  use super::*;
+
+  const SOCKET_VAR: &str = "IRLF_FEDERATE_SOCKET";
+  const INDEX_VAR: &str = "IRLF_FEDERATE_INDEX";
+  /// Set to run `UNDELAYED` instead of `PING_PONG`.
+  const UNDELAYED_VAR: &str = "IRLF_FEDERATE_UNDELAYED";
+  const N_FEDERATES: usize = 2;
+
+  /// Two instances that pass a counter back and forth over delayed connections, each adding 1 to it.
+  /// Main exposes the left side of `x` (101).
+  const PING_PONG: &str = "add1 0x0 add1
+---
+---
+rtor0 0x1
+  a 100 = 0x0
+  ---
+  L 100 R 100
+  ---
+rtor1 0x2
+  x 101 = 0x1
+  y 102 = 0x1
+  ---
+  L 101
+  ---
+  200 101 102 after 2
+  201 102 101 after 3
+---
+0x2
+";
+
+  /// `PING_PONG` with the connection from `x` to `y` made undelayed.
+  fn undelayed() -> String {
+    PING_PONG.replace("200 101 102 after 2", "200 101 102")
+  }
+
+  fn end() -> Tag {
+    Tag(vec![20])
+  }
+
+  /// Realizes the instances of the ping pong program `text` in `insts`, recording the values that
+  /// they receive, and starts the counter by writing 0 to `x`, which does nothing unless `x` is
+  /// among `insts`.
+  fn ping_pong<'db>(
+    db: &'db GriTestDatabase,
+    text: &str,
+    insts: &[InstId],
+  ) -> (Federate<'db>, Rc<Recorder>) {
+    let (program, _) = from_text(text, db);
+    let mut federate = Federate::new(db, program, insts).unwrap();
+    let recorder = Rc::new(Recorder::default());
+    federate.scheduler.record(&recorder);
+    federate.srtor().provide(&[], Side::Left).next().unwrap()(&0u64);
+    federate.scheduler.reschedule(0);
+    (federate, recorder)
+  }
+
+  /// The values that were delivered to the instances of a federate, in order.
+  fn log(recorder: &Recorder) -> Vec<Value> {
+    let writes = recorder.recording().writes;
+    writes.into_iter().map(|write| write.value).collect()
+  }
+
+  fn counter(values: impl Iterator<Item = u64>) -> Vec<Value> {
+    values.map(Value::Int).collect()
+  }
+
+  /// Runs one federate, which checks the values that it receives. This is only meant to be run in
+  /// a process spawned by `federation`.
+  #[test]
+  #[ignore = "run by test_federated_ping_pong and test_federated_undelayed"]
+  fn federate() {
+    let (Ok(socket), Ok(index)) = (env::var(SOCKET_VAR), env::var(INDEX_VAR)) else {
+      return;
+    };
+    let index: usize = index.parse().unwrap();
+    let is_undelayed = env::var(UNDELAYED_VAR).is_ok();
+    let text = if is_undelayed {
+      undelayed()
+    } else {
+      PING_PONG.to_string()
+    };
+    let db = GriTestDatabase::default();
+    let (program, _) = from_text(&text, &db);
+    let insts = &partition(&db, program, N_FEDERATES).unwrap()[index];
+    let (mut federate, recorder) = ping_pong(&db, &text, insts);
+    run_federate(&mut federate, UnixStream::connect(socket).unwrap(), index).unwrap();
+    // 102 receives the odd values on 200 and 101 the even ones on 201.
+    let last = if is_undelayed { 13 } else { 8 };
+    let expected = counter((1..=last).filter(|x| (x % 2 == 0) == (index == 0)));
+    assert_eq!(log(&recorder), expected);
+  }
+
+  /// Runs the federates of the ping pong program, or of `undelayed()` if `is_undelayed`, in
+  /// processes of their own until `end()`, and returns the tags and levels that were granted.
+  fn federation(is_undelayed: bool) -> Vec<(Tag, Level)> {
+    let name = format!("irlf-federation-{is_undelayed}-{}.sock", std::process::id());
+    let socket = env::temp_dir().join(name);
+    let _ = std::fs::remove_file(&socket);
+    let listener = UnixListener::bind(&socket).unwrap();
+    let children: Vec<_> = (0..N_FEDERATES)
+      .map(|index| {
+        let mut command = Command::new(env::current_exe().unwrap());
+        command
+          .args(["--exact", "federation::tests::federate", "--ignored"])
+          .env(SOCKET_VAR, &socket)
+          .env(INDEX_VAR, index.to_string());
+        if is_undelayed {
+          command.env(UNDELAYED_VAR, "1");
+        }
+        command.spawn().unwrap()
+      })
+      .collect();
+    let mut coordinator =
+      Coordinator::accept(&listener, N_FEDERATES, Duration::from_secs(60)).unwrap();
+    let granted = coordinator.run_until(&end()).unwrap();
+    for mut child in children {
+      assert!(child.wait().unwrap().success());
+    }
+    std::fs::remove_file(&socket).unwrap();
+    granted
+  }
+
+  #[test]
+  fn test_federated_ping_pong() {
+    let granted = federation(false);
+    let db = GriTestDatabase::default();
+    let (mut single, recorder) = ping_pong(&db, PING_PONG, &[InstId(101), InstId(102)]);
+    let mut expected = vec![];
+    while let Some((tag, level)) = single
+      .scheduler
+      .next_event()
+      .filter(|(tag, _)| *tag <= end())
+    {
+      single.scheduler.step_level(&tag, level);
+      expected.push((tag, level));
+    }
+    // The counter crosses at 2, 5, 7, 10, 12, 15, 17 and 20.
+    assert_eq!(log(&recorder), counter(1..=8));
+    assert_eq!(granted, expected);
+  }
+
+  #[test]
+  fn test_accept_times_out() {
+    let socket = env::temp_dir().join(format!("irlf-timeout-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&socket);
+    let listener = UnixListener::bind(&socket).unwrap();
+    let _silent = UnixStream::connect(&socket).unwrap();
+    let err = Coordinator::accept(&listener, 2, Duration::from_millis(50))
+      .err()
+      .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    std::fs::remove_file(&socket).unwrap();
+  }
+
+  #[test]
+  fn test_message_round_trip() {
+    for message in [
+      Message::Hello {
+        index: 2,
+        incoming: vec![],
+      },
+      Message::Hello {
+        index: 0,
+        incoming: vec![DebugOnlyId(200), DebugOnlyId(201)],
+      },
+      Message::Next,
+      Message::Event(None),
+      Message::Event(Some((Tag(vec![3, 0, 1]), Level(2)))),
+      Message::Grant(Tag(vec![7]), Level(0)),
+      Message::Done,
+      Message::Port(
+        DebugOnlyId(200),
+        1,
+        Tag(vec![5]),
+        Value::Tuple(vec![Value::Float(0.5), Value::Int(3)]),
+      ),
+      Message::Stop,
+    ] {
+      assert_eq!(message.to_string().parse(), Ok(message));
+    }
+  }
+
+  #[test]
+  fn test_partition() {
+    let db = GriTestDatabase::default();
+    let (program, _) = from_text(
+      "add1 0x0 add1
+---
+---
+rtor1 0x1
+  x 103 = 0x0
+  y 101 = 0x0
+  z 102 = 0x0
+  ---
+  ---
+---
+0x1
+",
+      &db,
+    );
+    let ids = |ids: &[u64]| ids.iter().copied().map(InstId).collect::<Vec<_>>();
+    assert_eq!(
+      partition(&db, program, 2),
+      Ok(vec![ids(&[101, 102]), ids(&[103])])
+    );
+    assert_eq!(
+      partition(&db, program, 4),
+      Ok(vec![ids(&[101]), ids(&[102]), ids(&[103]), ids(&[])])
+    );
+    assert_eq!(
+      partition(&db, program, 0),
+      Err(FederationError::NoFederates)
+    );
+  }
+
+  #[test]
+  fn test_undelayed_boundary() {
+    let db = GriTestDatabase::default();
+    let text = undelayed();
+    let (mut x, _) = ping_pong(&db, &text, &[InstId(101)]);
+    let (mut y, recorder) = ping_pong(&db, &text, &[InstId(102)]);
+    // `x` sends 1 as soon as it is given 0, and `y` receives it at the same tag after `x` steps.
+    let sent = x.srtor().take_outgoing();
+    assert_eq!(sent, [(DebugOnlyId(200), 0, Tag::default(), Value::Int(1))]);
+    for (connection, channel, due, value) in sent {
+      assert!(y.srtor().receive(connection, channel, due, value));
+    }
+    assert_eq!(x.next_event(), None);
+    assert_eq!(y.next_event(), Some((Tag::default(), Level(1))));
+    y.step_level(&Tag::default(), Level(1));
+    assert_eq!(log(&recorder), counter(1..=1));
+    assert_eq!(y.next_event(), None);
+    // `y` answers on the delayed connection, which is due 3 later.
+    assert_eq!(
+      y.srtor().take_outgoing(),
+      [(DebugOnlyId(201), 0, Tag(vec![3]), Value::Int(2))]
+    );
+  }
+
+  #[test]
+  fn test_federated_undelayed() {
+    let granted = federation(true);
+    // The counter crosses to `y` at level 1 of the tag at which `x` sends it, and back to `x` 3
+    // later.
+    let mut expected = vec![(Tag(vec![0]), Level(1))];
+    for t in (3..=18).step_by(3) {
+      expected.extend([(Tag(vec![t]), Level(0)), (Tag(vec![t]), Level(1))]);
+    }
+    assert_eq!(granted, expected);
+  }
+}
@@ -3,15 +3,45 @@
 #![feature(fn_traits)]
 #![feature(trait_alias)]
 
+pub mod checkpoint;
+pub mod diff;
+#[cfg(unix)]
+pub mod federation;
+pub mod record;
 pub mod rtor;
 mod rtorimpl;
+pub mod scheduler;
+#[cfg(test)]
+mod testing;
+pub mod trace;
+pub mod vcd;
+
+use std::collections::HashSet;
+
+use irlf_db::{
+  ir::{Ctor, LibCtor, Program},
+  typecheck::{LibTypes, TypeError},
+};
+use lf_types::{Level, Side, Type};
+pub use rtorimpl::{
+  iface_of,
+  srtorimpl::CausalityLoop,
+  wasmrtorimpl::{read_wasm_path, WasmModules, WASM_PATH_VAR},
+};
 
 #[salsa::jar(db=Db)]
 pub struct Jar(
   crate::rtorimpl::srtorimpl::SrtorIface,
   // crate::rtorimpl::librtorimpl::FunRtorIface,
   rtorimpl::lctor_of,
+  rtorimpl::wasmrtorimpl::WasmModules,
   rtorimpl::srtorimpl::srtor_of,
+  rtorimpl::srtorimpl::SideKey,
+  rtorimpl::srtorimpl::srtor_side,
+  rtorimpl::srtorimpl::srtor_n_levels,
+  rtorimpl::srtorimpl::srtor_provide_unique,
+  rtorimpl::srtorimpl::child_levels,
+  rtorimpl::srtorimpl::sctor_feeds_through,
 );
 
 #[derive(Default)]
@@ -22,3 +52,79 @@ pub(crate) struct GriTestDatabase {
 impl salsa::Database for GriTestDatabase {}
 pub trait Db: salsa::DbWithJar<Jar> + irlf_db::Db {}
 impl<DB> Db for DB where DB: ?Sized + salsa::DbWithJar<Jar> + salsa::DbWithJar<irlf_db::Jar> {}
+
+/// The reasons why a program cannot be realized.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RealizeError {
+  Type(Vec<TypeError>),
+  CausalityLoop(CausalityLoop),
+}
+
+/// The types that the implementations of lib ctors expect.
+struct LctorTypes<'db>(&'db dyn Db);
+
+impl LibTypes for LctorTypes<'_> {
+  fn params(&self, _db: &dyn irlf_db::Db, lctor: LibCtor) -> Option<Vec<Type>> {
+    Some(rtorimpl::lctor_of(self.0, lctor).ok()?.param_types())
+  }
+  fn ports(&self, _db: &dyn irlf_db::Db, lctor: LibCtor, side: Side) -> Option<Vec<Type>> {
+    rtorimpl::lctor_of(self.0, lctor).ok()?.port_types(side)
+  }
+}
+
+/// Checks that the connections of `program` are well-typed, taking the types of the ports of lib
+/// ctors from their implementations, that they connect as many channels on each end, and that the
+/// instances of lib ctors have implementations and are passed args that those implementations
+/// accept.
+pub fn typecheck(db: &dyn Db, program: Program) -> Vec<TypeError> {
+  let mut errors = irlf_db::typecheck::check_with(db, program, &LctorTypes(db));
+  for ctor in program.ctors(db) {
+    if let Ctor::StructlikeCtor(sctor) = ctor {
+      for inst in sctor.insts(db) {
+        if let Ctor::LibCtor(lctor) = inst.ctor(db) {
+          if let Err(e) = rtorimpl::lctor_of(db, *lctor) {
+            errors.push(TypeError::Lctor {
+              ctor: sctor.id(db),
+              inst: inst.id(db),
+              reason: e.to_string(),
+            });
+          }
+        }
+      }
+      errors.extend(rtorimpl::srtorimpl::width_errors(db, *sctor));
+    }
+  }
+  errors
+}
+
+/// Realizes the main ctor of `program` after checking that it is well-typed.
+///
+/// # Errors
+/// Returns the type errors of `program` if it has any, or else the first causality loop found while
+/// assigning levels.
+pub fn realize<'db>(
+  db: &'db dyn Db,
+  program: Program,
+) -> Result<Box<dyn rtor::Rtor<'db> + 'db>, RealizeError> {
+  let errors = typecheck(db, program);
+  if !errors.is_empty() {
+    return Err(RealizeError::Type(errors));
+  }
+  iface_of(db, program.main(db))
+    .realize(db, vec![])
+    .map_err(RealizeError::CausalityLoop)
+}
+
+/// Returns the levels at which an instance of `ctor` is to be notified of level advancement.
+///
+/// # Errors
+/// Returns the causality loop that prevents the levels of the children of `ctor` from being
+/// assigned, if there is one.
+pub fn levels(db: &dyn Db, ctor: &Ctor) -> Result<HashSet<Level>, CausalityLoop> {
+  if let Ctor::StructlikeCtor(sctor) = ctor {
+    if let Err(causality_loop) = rtorimpl::srtorimpl::child_levels(db, *sctor) {
+      return Err(causality_loop.clone());
+    }
+  }
+  Ok(iface_of(db, ctor).levels(db))
+}
@@ -0,0 +1,552 @@
+use std::{
+  any::Any,
+  cell::{Cell, RefCell},
+  collections::BTreeMap,
+  fmt::Display,
+  io::{self, BufRead, Write},
+  rc::Rc,
+};
+
+use irlf_db::ir::Inst;
+use lf_types::{InstId, Level, Side, Tag, Value};
+
+use crate::{
+  checkpoint::{RestoreError, RtorState},
+  rtor::{Inputs, Rtor, SetPort},
+  rtorimpl::value::{from_value, to_value},
+  scheduler::Scheduler,
+  trace::{parse_path, show_path, MemberPath},
+};
+
+/// A value written to a port during a simulation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortWrite {
+  pub tag: Tag,
+  pub level: Level,
+  /// The path of bank members from `main` to the instance that owns the port. It is empty for the
+  /// ports of `main` itself.
+  pub path: MemberPath,
+  pub side: Side,
+  /// The index of the port among the channels of its side.
+  pub channel: u64,
+  pub value: Value,
+}
+
+impl Display for PortWrite {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let path = if self.path.is_empty() {
+      "-".to_string()
+    } else {
+      show_path(&self.path)
+    };
+    write!(
+      f,
+      "{} {} {path} {} {} {}",
+      self.tag, self.level.0, self.side, self.channel, self.value
+    )
+  }
+}
+
+impl std::str::FromStr for PortWrite {
+  type Err = String;
+
+  /// Parses a port write in the format produced by its `Display` impl.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let number = |word: &str| {
+      word
+        .parse::<u64>()
+        .map_err(|_| format!("expected a number in port write at \"{word}\""))
+    };
+    let words: Vec<&str> = s.split_whitespace().collect();
+    let [tag, level, path, side, channel, value] = words[..] else {
+      return Err(format!("expected six fields in port write \"{s}\""));
+    };
+    let path = match path {
+      "-" => vec![],
+      path => parse_path(path)?,
+    };
+    Ok(PortWrite {
+      tag: tag.parse()?,
+      level: Level(
+        u32::try_from(number(level)?).map_err(|_| format!("level {level} is too large"))?,
+      ),
+      path,
+      side: side.parse()?,
+      channel: number(channel)?,
+      value: value.parse()?,
+    })
+  }
+}
+
+/// The port writes of a simulation, in the order in which they were made.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Recording {
+  pub writes: Vec<PortWrite>,
+}
+
+impl Recording {
+  /// Writes `self` as one port write per line.
+  pub fn write_to(&self, mut out: impl Write) -> io::Result<()> {
+    for write in &self.writes {
+      writeln!(out, "{write}")?;
+    }
+    Ok(())
+  }
+
+  /// Reads a recording in the format produced by `write_to`.
+  pub fn read_from(input: impl BufRead) -> io::Result<Self> {
+    let mut writes = vec![];
+    for line in input.lines() {
+      let line = line?;
+      if line.trim().is_empty() {
+        continue;
+      }
+      writes.push(
+        line
+          .parse()
+          .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+      );
+    }
+    Ok(Recording { writes })
+  }
+
+  /// A hash of the text of `self` that is stable across runs and builds, so that two runs can be
+  /// compared without keeping both of their recordings.
+  pub fn hash(&self) -> u64 {
+    let mut text = vec![];
+    self.write_to(&mut text).unwrap();
+    fnv1a(&text)
+  }
+
+  /// Checks that another run of the same simulation made the same writes as `self`.
+  ///
+  /// # Errors
+  /// Returns the first write at which the two recordings differ.
+  pub fn compare(&self, rerun: &Recording) -> Result<(), Box<Divergence>> {
+    let n = self.writes.len().max(rerun.writes.len());
+    match (0..n).find(|&i| self.writes.get(i) != rerun.writes.get(i)) {
+      None => Ok(()),
+      Some(index) => Err(Box::new(Divergence {
+        index,
+        expected: self.writes.get(index).cloned(),
+        actual: rerun.writes.get(index).cloned(),
+      })),
+    }
+  }
+}
+
+/// The first difference between the recordings of two runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+  pub index: usize,
+  pub expected: Option<PortWrite>,
+  pub actual: Option<PortWrite>,
+}
+
+impl Display for Divergence {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let show = |write: &Option<PortWrite>| {
+      write
+        .as_ref()
+        .map_or("nothing".to_string(), ToString::to_string)
+    };
+    write!(
+      f,
+      "the runs diverged at write {}: expected {} but got {}",
+      self.index,
+      show(&self.expected),
+      show(&self.actual)
+    )
+  }
+}
+
+/// Records the values written to ports, attributing them to the tag and level being processed.
+///
+/// A `Scheduler` keeps the tag and level up to date once it is given the recorder with
+/// `Scheduler::record`, and realized rtors record the values delivered between their descendants
+/// once they are given it with `Rtor::record`.
+pub struct Recorder {
+  now: RefCell<(Tag, Level)>,
+  recording: RefCell<Recording>,
+  unrecorded: Cell<usize>,
+}
+
+impl Default for Recorder {
+  fn default() -> Self {
+    Recorder {
+      now: RefCell::new((Tag::default(), Level(0))),
+      recording: RefCell::new(Recording::default()),
+      unrecorded: Cell::new(0),
+    }
+  }
+}
+
+impl Recorder {
+  /// Attributes the writes that follow to `tag` and `level`.
+  pub fn set_now(&self, tag: Tag, level: Level) {
+    *self.now.borrow_mut() = (tag, level);
+  }
+
+  /// Records that `value` was written to the given port.
+  pub(crate) fn push(&self, path: MemberPath, side: Side, channel: u64, value: Value) {
+    let (tag, level) = self.now.borrow().clone();
+    self.recording.borrow_mut().writes.push(PortWrite {
+      tag,
+      level,
+      path,
+      side,
+      channel,
+      value,
+    });
+  }
+
+  /// Records that the port value `value` was written to the given port, or counts it as unrecorded
+  /// if it is not of any port type.
+  pub(crate) fn push_any(&self, path: MemberPath, side: Side, channel: u64, value: &dyn Any) {
+    match to_value(value) {
+      Some(recorded) => self.push(path, side, channel, recorded),
+      None => self.unrecorded.set(self.unrecorded.get() + 1),
+    }
+  }
+
+  /// Wraps `port` so that the values written to it are recorded as writes to the given port.
+  pub fn record<'db>(
+    self: &Rc<Self>,
+    port: SetPort<'db>,
+    path: MemberPath,
+    side: Side,
+    channel: u64,
+  ) -> SetPort<'db> {
+    let recorder = Rc::clone(self);
+    Box::new(move |value: &dyn Any| {
+      recorder.push_any(path.clone(), side, channel, value);
+      port(value);
+    })
+  }
+
+  /// Returns the writes recorded so far.
+  pub fn recording(&self) -> Recording {
+    self.recording.borrow().clone()
+  }
+
+  /// The number of writes that were passed on without being recorded because their values were not
+  /// of any port type. A recording is only complete if this is zero.
+  pub fn unrecorded(&self) -> usize {
+    self.unrecorded.get()
+  }
+}
+
+/// Why a recording could not be replayed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayError {
+  /// The recording has an input of `main` that `main` has no port for or whose value it cannot
+  /// take.
+  UnknownInput(PortWrite),
+  /// The replay made different writes than the recording.
+  Diverged(Divergence),
+}
+
+impl Display for ReplayError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ReplayError::UnknownInput(write) => write!(f, "main has no port for the input \"{write}\""),
+      ReplayError::Diverged(divergence) => divergence.fmt(f),
+    }
+  }
+}
+
+/// Stands for `main` while feeding it the values that a recording says were written to its inputs,
+/// each once `main` has reached the tag at which it was recorded, so that a run can be repeated
+/// without whatever produced its inputs.
+pub struct Replay<'db> {
+  main: Box<dyn Rtor<'db> + 'db>,
+  /// The ports of the left side of `main`.
+  ports: Vec<SetPort<'db>>,
+  /// The inputs that have not been fed yet, each with its channel, by the tag at which they were
+  /// recorded.
+  inputs: BTreeMap<Tag, Vec<(u64, Value)>>,
+  tag: Tag,
+  recorder: Rc<Recorder>,
+}
+
+impl<'db> Replay<'db> {
+  /// Feeds `main` the inputs of `recording` that were written before it was first stepped, and
+  /// records them with `recorder` as the original run recorded them.
+  ///
+  /// # Errors
+  /// Returns `ReplayError::UnknownInput` for the first input in `recording` that `main` has no port
+  /// for.
+  pub fn new(
+    main: Box<dyn Rtor<'db> + 'db>,
+    recording: &Recording,
+    recorder: &Rc<Recorder>,
+  ) -> Result<Self, Box<ReplayError>> {
+    let ports: Vec<_> = main.provide(&[], Side::Left).collect();
+    let mut inputs: BTreeMap<Tag, Vec<(u64, Value)>> = BTreeMap::new();
+    for write in &recording.writes {
+      if !write.path.is_empty() || write.side != Side::Left {
+        continue;
+      }
+      if write.channel >= ports.len() as u64 || from_value(&write.value).is_none() {
+        return Err(Box::new(ReplayError::UnknownInput(write.clone())));
+      }
+      inputs
+        .entry(write.tag.clone())
+        .or_default()
+        .push((write.channel, write.value.clone()));
+    }
+    let mut ret = Replay {
+      main,
+      ports,
+      inputs,
+      tag: Tag::default(),
+      recorder: Rc::clone(recorder),
+    };
+    ret.feed();
+    Ok(ret)
+  }
+
+  /// Feeds `main` the inputs that were recorded by the tag that it has reached.
+  fn feed(&mut self) {
+    let later = self.inputs.split_off(&self.tag.step_down());
+    let due = std::mem::replace(&mut self.inputs, later);
+    for (channel, value) in due.into_values().flatten() {
+      let port_value = from_value(&value).expect("inputs are only kept if they can be restored");
+      self.recorder.push(vec![], Side::Left, channel, value);
+      self.ports[channel as usize](&*port_value);
+    }
+  }
+}
+
+impl<'db> Rtor<'db> for Replay<'db> {
+  fn accept(&mut self, part: &[Inst], side: Side, inputs: &mut Inputs<'db>) {
+    self.main.accept(part, side, inputs);
+  }
+
+  fn provide(&self, part: &[Inst], side: Side) -> Inputs<'db> {
+    self.main.provide(part, side)
+  }
+
+  fn step_forward(&mut self, distance: u64) -> Option<Tag> {
+    self.main.step_forward(distance);
+    self.tag = self.tag.step_forward(distance);
+    self.feed();
+    Some(self.tag.clone())
+  }
+
+  fn step_down(&mut self) {
+    self.main.step_down();
+    self.tag = self.tag.step_down();
+    self.feed();
+  }
+
+  fn step_up(&mut self) -> Option<Tag> {
+    self.main.step_up();
+    self.tag = self.tag.step_up().unwrap_or_else(|| self.tag.clone());
+    self.feed();
+    Some(self.tag.clone())
+  }
+
+  fn next_event(&self) -> Option<Tag> {
+    let input = self.inputs.keys().next().cloned();
+    self.main.next_event().into_iter().chain(input).min()
+  }
+
+  fn save(&self) -> RtorState {
+    RtorState {
+      tag: Some(self.tag.clone()),
+      delayed: vec![],
+      children: vec![self.main.save()],
+    }
+  }
+
+  fn restore(&mut self, state: &RtorState) -> Result<(), RestoreError> {
+    let [main] = &state.children[..] else {
+      return Err(RestoreError::ChildCount {
+        saved: state.children.len(),
+        current: 1,
+      });
+    };
+    let tag = state.tag.clone().ok_or(RestoreError::MissingTag)?;
+    self.main.restore(main)?;
+    // The inputs up to `tag` were fed before the state was saved.
+    self.inputs = self.inputs.split_off(&tag.step_down());
+    self.tag = tag;
+    Ok(())
+  }
+
+  fn record(&mut self, recorder: &Rc<Recorder>, path: &[(InstId, u64)]) {
+    self.recorder = Rc::clone(recorder);
+    self.main.record(recorder, path);
+  }
+}
+
+/// Reruns `main` until `end` with the inputs of `recording`, which was recorded from a run of the
+/// same program until `end`, and checks that it makes the same writes.
+///
+/// # Errors
+/// Returns the first input of `recording` that `main` has no port for, or the first write at which
+/// the rerun differs from `recording`.
+pub fn replay<'db>(
+  main: Box<dyn Rtor<'db> + 'db>,
+  recording: &Recording,
+  end: &Tag,
+) -> Result<(), Box<ReplayError>> {
+  let recorder = Rc::new(Recorder::default());
+  let replay: Box<dyn Rtor<'db> + 'db> = Box::new(Replay::new(main, recording, &recorder)?);
+  let mut scheduler = Scheduler::new([(replay, Level(0))]);
+  scheduler.record(&recorder);
+  scheduler.run_until(end);
+  recording
+    .compare(&recorder.recording())
+    .map_err(|divergence| Box::new(ReplayError::Diverged(*divergence)))
+}
+
+/// The 64-bit FNV-1a hash of `bytes`, which unlike the hashers of the standard library is the same
+/// across builds.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+    (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use irlf_db::from_text;
+
+  use crate::GriTestDatabase;
+
+  use super::*;
+
+  fn recorded(values: &[u64]) -> Recording {
+    let recorder = Rc::new(Recorder::default());
+    let sink = Rc::new(Cell::new(0));
+    let port = {
+      let sink = Rc::clone(&sink);
+      recorder.record(
+        Box::new(move |value: &dyn Any| sink.set(*value.downcast_ref::<u64>().unwrap())),
+        vec![(InstId(101), 0), (InstId(100), 2)],
+        Side::Right,
+        1,
+      )
+    };
+    let input = recorder.record(Box::new(|_: &dyn Any| {}), vec![], Side::Left, 0);
+    input(&true);
+    input(&(0.5f64, 3u64));
+    // Not a port value, so it cannot be recorded.
+    input(&'x');
+    assert_eq!(recorder.unrecorded(), 1);
+    for (step, value) in values.iter().enumerate() {
+      recorder.set_now(Tag(vec![step as u64, 1]), Level(2));
+      port(value);
+    }
+    assert_eq!(sink.get(), *values.last().unwrap());
+    recorder.recording()
+  }
+
+  #[test]
+  fn test_record() {
+    let recording = recorded(&[4, 5]);
+    let mut text = vec![];
+    recording.write_to(&mut text).unwrap();
+    assert_eq!(
+      String::from_utf8(text.clone()).unwrap(),
+      "0 0 - L 0 true
+0 0 - L 0 (0.5,3)
+0.1 2 101[0].100[2] R 1 4
+1.1 2 101[0].100[2] R 1 5
+"
+    );
+    assert_eq!(Recording::read_from(&text[..]).unwrap(), recording);
+  }
+
+  #[test]
+  fn test_compare() {
+    let recording = recorded(&[4, 5]);
+    assert_eq!(recording.compare(&recorded(&[4, 5])), Ok(()));
+    assert_eq!(recording.hash(), recorded(&[4, 5]).hash());
+    assert_ne!(recording.hash(), recorded(&[4, 6]).hash());
+    let divergence = recording.compare(&recorded(&[4])).unwrap_err();
+    assert_eq!(
+      divergence.to_string(),
+      "the runs diverged at write 3: expected 1.1 2 101[0].100[2] R 1 5 but got nothing"
+    );
+  }
+
+  /// `x` (101) sends to the second member of the bank `y` (102) with a delay of 2.
+  const BANKED: &str = "add1 0x0 add1
+---
+---
+rtor0 0x1
+  a 100 = 0x0
+  ---
+  L 100 R 100
+  ---
+rtor1 0x2
+  x 101 = 0x1
+  y 102 = 0x1 [2]
+  ---
+  L 101 R 102
+  ---
+  200 101 102 [1:2] after 2
+---
+0x2
+";
+
+  /// Runs `main` until 6, writing 5 to its input at the start and 9 once it has reached 2, and
+  /// returns what was recorded.
+  fn original<'db>(mut main: Box<dyn Rtor<'db> + 'db>) -> Recording {
+    let recorder = Rc::new(Recorder::default());
+    let mut outputs: Inputs = Box::new((0..2).map(|_| -> SetPort { Box::new(|_| {}) }));
+    main.accept(&[], Side::Right, &mut outputs);
+    let input = recorder.record(
+      main.provide(&[], Side::Left).next().unwrap(),
+      vec![],
+      Side::Left,
+      0,
+    );
+    input(&5u64);
+    let mut scheduler = Scheduler::new([(main, Level(0))]);
+    scheduler.record(&recorder);
+    scheduler.run_until(&Tag(vec![2]));
+    input(&9u64);
+    scheduler.reschedule(0);
+    scheduler.run_until(&Tag(vec![6]));
+    recorder.recording()
+  }
+
+  #[test]
+  fn test_replay() {
+    let db = GriTestDatabase::default();
+    let (program, _) = from_text(BANKED, &db);
+    let recording = original(crate::realize(&db, program).unwrap());
+    let mut text = vec![];
+    recording.write_to(&mut text).unwrap();
+    assert_eq!(
+      String::from_utf8(text).unwrap(),
+      "0 0 - L 0 5
+2 0 102[1] L 0 6
+2 0 - L 0 9
+4 0 102[1] L 0 10
+"
+    );
+    let end = Tag(vec![6]);
+    assert_eq!(
+      replay(crate::realize(&db, program).unwrap(), &recording, &end),
+      Ok(())
+    );
+
+    let mut tampered = recording.clone();
+    tampered.writes[3].value = Value::Int(11);
+    let err = replay(crate::realize(&db, program).unwrap(), &tampered, &end).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "the runs diverged at write 3: expected 4 0 102[1] L 0 11 but got 4 0 102[1] L 0 10"
+    );
+
+    let mut unknown = recording.clone();
+    unknown.writes[2].channel = 1;
+    let err = replay(crate::realize(&db, program).unwrap(), &unknown, &end).unwrap_err();
+    assert_eq!(*err, ReplayError::UnknownInput(unknown.writes[2].clone()));
+  }
+}
@@ -1,19 +1,42 @@
-use connectioniterator::{
-  nesting::{Nesting, NestingStack},
-  ConnectionIterator, ProvidingConnectionIterator,
-};
+use connectioniterator::{nesting::Nesting, ConnectionIterator, ProvidingConnectionIterator};
 use dyn_clone::DynClone;
 use irlf_db::ir::Inst;
-use lf_types::{Comm, FlowDirection, Level, Net, Side, SideMatch};
-use std::{any::Any, collections::HashSet, rc::Rc};
+use lf_types::{Comm, FlowDirection, InstId, Level, Side, SideMatch, Tag, Type};
+use std::{any::Any, cell::Cell, collections::HashSet, rc::Rc};
 
 pub type RtorN = Box<dyn RtorIface>;
 
-use crate::{rtorimpl::FixpointingStatus, Db};
+use crate::{
+  checkpoint::{RestoreError, RtorState},
+  record::Recorder,
+  rtorimpl::{srtorimpl::CausalityLoop, FixpointingStatus},
+  trace::Tracer,
+  Db,
+};
 pub type SetPort<'db> = Box<dyn Fn(&dyn Any) + 'db>;
-pub type Inputs<'a> = Box<dyn ConnectionIterator<'a, Item = SetPort<'a>, N = RtorN> + 'a>;
+/// The ports of a side of an rtor, one for each of its data channels in order.
+pub type Inputs<'a> = Box<dyn Iterator<Item = SetPort<'a>> + 'a>;
+
+/// Raises the level of an entry of an iface to at least the given level.
+pub type Leveller = Rc<dyn Fn(Comm<Level>) -> FixpointingStatus>;
+
+/// An entry of an inputs iface, which levels the entry of the providing iface that it stands for.
+#[derive(Clone)]
+pub enum ComptimeInput {
+  Notify(Leveller),
+  Data(Leveller),
+}
+
+impl ComptimeInput {
+  /// Replaces the leveller of `self`, keeping whether it is a notify or a data entry.
+  pub fn map(&self, f: impl Fn(&Leveller) -> Leveller) -> Self {
+    match self {
+      ComptimeInput::Notify(leveller) => ComptimeInput::Notify(f(leveller)),
+      ComptimeInput::Data(leveller) => ComptimeInput::Data(f(leveller)),
+    }
+  }
+}
 
-pub type ComptimeInput = Comm<Rc<dyn Fn(Comm<Level>) -> FixpointingStatus>>;
 pub type InputsIface<'a> = Box<dyn ConnectionIterator<'a, Item = ComptimeInput, N = RtorN> + 'a>;
 pub type ProvidingInputsIface<'a> =
   Box<dyn ProvidingConnectionIterator<'a, Item = ComptimeInput, N = RtorN> + 'a>;
@@ -22,25 +45,79 @@ pub type LevelIterator<'a> =
   Box<dyn ProvidingConnectionIterator<'a, Item = Comm<Level>, N = RtorN> + 'a>;
 
 pub type FuzzySideIterator<'a> =
-  Box<dyn Iterator<Item = (Level, SideMatch, Comm<Box<dyn RtorIface + 'a>>)> + 'a>;
-pub type ExactSideIterator<'a> =
-  Box<dyn Iterator<Item = (Level, Comm<Box<dyn RtorIface + 'a>>)> + 'a>;
+  Box<dyn Iterator<Item = (Level, SideMatch, Comm<Box<dyn RtorIface>>)> + 'a>;
+pub type ExactSideIterator<'a> = Box<dyn Iterator<Item = (Level, Comm<Box<dyn RtorIface>>)> + 'a>;
+
+/// The notify entries of an inputs iface that were passed over while looking for a data entry. A
+/// notify waits on the data entries that precede it, so it stays deferred until those have been
+/// levelled, and is then given the highest of their levels.
+pub struct DeferredNotifys {
+  /// The deferred notifys, each with the number of data entries returned by `next_data` before it.
+  pub(crate) notifys: Vec<(Leveller, usize)>,
+  /// The levels given to the data entries returned by `next_data` since notifys were last resolved.
+  pub(crate) data: Vec<Rc<Cell<Level>>>,
+  /// The highest level given to a data entry before notifys were last resolved.
+  pub(crate) floor: Level,
+  /// The intrinsic level that the data entries returned by `next_data` are offset by before they
+  /// reach the frame in which the notifys are levelled.
+  pub(crate) offset: Level,
+}
+
+impl Default for DeferredNotifys {
+  fn default() -> Self {
+    DeferredNotifys {
+      notifys: vec![],
+      data: vec![],
+      floor: Level(0),
+      offset: Level(0),
+    }
+  }
+}
+
+impl DeferredNotifys {
+  pub fn new() -> Self {
+    Self::default()
+  }
 
-pub type DeferredNotifys = HashSet<NestingStack<RtorN>>;
+  pub fn len(&self) -> usize {
+    self.notifys.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.notifys.is_empty()
+  }
+}
 
 /// A runtime reactor instance.
 pub trait Rtor<'db> {
-  /// Accepts the input of a downstream rtor. Returns true if the current rtor can now provide a
-  /// different input. It must be safe to ignore the return value of this method.
-  fn accept(&mut self, side: Side, inputs: Inputs<'db>) -> bool;
-  /// Provides the inputs of this rtor.
-  fn provide(&'db self, side: Side, nesting: Nesting<RtorN>) -> Inputs<'db>;
-  /// Steps this rtor forward by `distance` timesteps within the current nesting level.
-  fn step_forward(&mut self, distance: u64) -> Option<Net>;
-  /// Decrements the nesting level of this rtor's time.
+  /// Accepts the ports of the downstream rtors that the given part of this rtor writes to on the
+  /// given side, taking one port from `inputs` for each of the channels of that part.
+  fn accept(&mut self, part: &[Inst], side: Side, inputs: &mut Inputs<'db>);
+  /// Provides the ports of the given part of this rtor on the given side, which upstream rtors
+  /// write to.
+  fn provide(&self, part: &[Inst], side: Side) -> Inputs<'db>;
+  /// Steps this rtor forward by `distance` timesteps within the current nesting level. Returns the
+  /// tag that it reached, or `None` if it does not keep time.
+  fn step_forward(&mut self, distance: u64) -> Option<Tag>;
+  /// Enters the time nested within the current tag of this rtor (see [`Tag::step_down`]).
   fn step_down(&mut self);
-  /// Increments the nesting level of this rtor's time.
-  fn step_up(&mut self) -> Option<Net>;
+  /// Leaves the current nesting level of this rtor's time (see [`Tag::step_up`]). Returns the tag
+  /// that it reached, or `None` if it does not keep time.
+  fn step_up(&mut self) -> Option<Tag>;
+  /// Returns the tag of the earliest event that this rtor has pending, or `None` if it is idle.
+  fn next_event(&self) -> Option<Tag>;
+  /// Returns the state of this rtor that must be saved in order to resume it later.
+  fn save(&self) -> RtorState;
+  /// Resumes from a state that was saved by an rtor realized from the same ctor.
+  ///
+  /// # Errors
+  /// Returns an error if `state` could not have been saved by such an rtor, in which case this rtor
+  /// may have been partly restored.
+  fn restore(&mut self, state: &RtorState) -> Result<(), RestoreError>;
+  /// Records with `recorder` the values that are delivered on the connections between the
+  /// descendants of this rtor, whose instance is the bank member at the end of `path` from `main`.
+  /// Rtors without descendants have nothing to record.
+  fn record(&mut self, _recorder: &Rc<Recorder>, _path: &[(InstId, u64)]) {}
 }
 
 /// A potentially mutable compile-time model of a runtime `Rtor`.
@@ -52,8 +129,8 @@ pub trait RtorComptime<'db> {
   /// correctness.
   fn iterate_levels(&mut self) -> FixpointingStatus;
   /// Requires that the levels of the given part of `self` be lower-bounded by `lower_bound`.
-  /// Whether this bound is strict may depend on `last_direction`, the last flow direction on the
-  /// given side.
+  /// The bound is strict unless `last_direction`, the last flow direction on the given side, is
+  /// `In`.
   fn lower_bound(
     &mut self,
     part: &[Inst],
@@ -61,6 +138,9 @@ pub trait RtorComptime<'db> {
     lower_bound: Level,
     last_direction: FlowDirection,
   );
+  /// Records every subsequent raise of the levels of this in `tracer`. Rtors that do not have
+  /// levels of their own can ignore this.
+  fn trace(&mut self, _tracer: Tracer) {}
   /// Returns the levels of the ambient program at which this reactor's local level is to be
   /// incremented.
   fn levels(&self) -> HashSet<Level>;
@@ -142,11 +222,15 @@ pub trait RtorIface: DynClone + std::fmt::Debug {
   /// Produces an instance of the RtorComptime associated with this.
   fn comptime_realize<'db>(&self, db: &'db dyn Db) -> Box<dyn RtorComptime<'db> + 'db>;
   /// Constructs an implementation given compile time and instantiation time args.
+  ///
+  /// # Errors
+  /// Returns the causality loop that prevents the levels of some nested rtor from being assigned,
+  /// if there is one.
   fn realize<'db>(
     &self,
     db: &'db dyn Db,
     _inst_time_args: Vec<&'db dyn std::any::Any>,
-  ) -> Box<dyn Rtor<'db> + 'db>;
+  ) -> Result<Box<dyn Rtor<'db> + 'db>, CausalityLoop>;
   /// Returns the rtorifaces exposed on the given side by the given part of this.
   fn side<'db>(&self, db: &'db dyn Db, side: SideMatch, part: &[Inst]) -> FuzzySideIterator<'db>;
   /// Returns the rtorifaces exposed on the given side by the given part of this.
@@ -157,6 +241,15 @@ pub trait RtorIface: DynClone + std::fmt::Debug {
         .map(|(a, _, c)| (a, c)),
     )
   }
+  /// The types of the instantiation-time arguments that `realize` expects.
+  fn param_types(&self) -> Vec<Type> {
+    vec![]
+  }
+  /// The types of the data ports on the given side of any instance of this rtor, or `None` if they
+  /// are not known.
+  fn port_types(&self, _side: Side) -> Option<Vec<Type>> {
+    None
+  }
   fn iface_id(&self) -> u128;
 }
 
@@ -1,39 +1,225 @@
 use std::{
   any::TypeId,
+  cell::{Cell, RefCell},
+  cmp,
   collections::{hash_map::DefaultHasher, HashSet},
   fmt::Debug,
   hash::{Hash, Hasher},
   rc::Rc,
 };
 
-use connectioniterator::{iterator_new, nesting::Nesting};
+use connectioniterator::{emptyiterator::EmptyIterator, iterator_new, nesting::Nesting};
 use irlf_db::ir::Inst;
-use lf_types::{Comm, Level, Side, SideMatch};
+use lf_types::{Comm, FlowDirection, Level, Side, SideMatch, Tag, Type, Value};
 
-use crate::rtor::{
-  DeferredNotifys, InputsIface, LevelIterator, Rtor, RtorComptime, RtorIface, RtorN,
+use crate::{
+  checkpoint::{RestoreError, RtorState},
+  rtor::{
+    ComptimeInput, DeferredNotifys, Inputs, InputsIface, LevelIterator, ProvidingInputsIface, Rtor,
+    RtorComptime, RtorIface, RtorN, SetPort,
+  },
 };
 
-use super::{util::require_empty, FixpointingStatus};
+use super::{
+  srtorimpl::CausalityLoop,
+  util::{level_downstream, next_data, raise, require_empty},
+  value::{erase_bi, from_value, to_value, ErasedBiFn, PortValue},
+  FixpointingStatus,
+};
 
 #[derive(Clone)]
 pub struct BiFunRtorIface {
-  f: Rc<dyn Fn(u64, u64) -> u64>,
+  /// Builds the function computed by an instance, once for each instance.
+  make_f: Rc<dyn Fn() -> ErasedBiFn>,
+  inputs: (Type, Type),
+  output: Type,
   id: u128,
 }
 impl BiFunRtorIface {
-  pub fn new<T: Fn(u64, u64) -> u64 + 'static>(f: T) -> Self {
+  pub fn new<A: PortValue, B: PortValue, C: PortValue, T: Fn(A, B) -> C + 'static>(f: T) -> Self {
     let mut hasher = DefaultHasher::new();
     TypeId::of::<T>().hash(&mut hasher);
     let id = (hasher.finish() as u128) ^ 0xab28b6284f0552f30c158ea3265ea718;
-    BiFunRtorIface { f: Rc::new(f), id }
+    let f = erase_bi(Rc::new(f));
+    Self::erased(
+      Rc::new(move || Rc::clone(&f)),
+      (A::ty(), B::ty()),
+      C::ty(),
+      id,
+    )
+  }
+  /// Creates a reactor from a function whose types are only known at runtime. `id` must differ
+  /// between reactors that compute different functions.
+  pub(crate) fn erased(
+    make_f: Rc<dyn Fn() -> ErasedBiFn>,
+    inputs: (Type, Type),
+    output: Type,
+    id: u128,
+  ) -> Self {
+    BiFunRtorIface {
+      make_f,
+      inputs,
+      output,
+      id,
+    }
+  }
+}
+
+struct BiFunRtorComptime<'a> {
+  iface: BiFunRtorIface,
+  downstream: Rc<RefCell<Option<InputsIface<'a>>>>,
+  /// The level of the inputs, after which the output comes.
+  level: Rc<Cell<Level>>,
+}
+
+struct BiFunRtor<'db> {
+  f: ErasedBiFn,
+  /// The values written to each input at the current tag. The function reacts once both have been
+  /// written.
+  inputs: Rc<RefCell<[Option<Value>; 2]>>,
+  /// The ports that the result is written to.
+  downstream: Rc<RefCell<Vec<SetPort<'db>>>>,
+}
+
+impl BiFunRtor<'_> {
+  /// Forgets the inputs of the previous tag.
+  fn reset(&mut self) {
+    *self.inputs.borrow_mut() = [None, None];
+  }
+}
+
+impl<'db> Rtor<'db> for BiFunRtor<'db> {
+  fn accept(&mut self, part: &[Inst], side: Side, inputs: &mut Inputs<'db>) {
+    require_empty(part);
+    if let Side::Right = side {
+      self.downstream.borrow_mut().extend(inputs.next());
+    }
+  }
+
+  fn provide(&self, part: &[Inst], side: Side) -> Inputs<'db> {
+    require_empty(part);
+    if let Side::Right = side {
+      return Box::new(std::iter::empty());
+    }
+    let ports: Vec<SetPort<'db>> = (0..2)
+      .map(|i| {
+        let f = Rc::clone(&self.f);
+        let inputs = Rc::clone(&self.inputs);
+        let downstream = Rc::clone(&self.downstream);
+        let port: SetPort<'db> = Box::new(move |x| {
+          // A value that cannot be kept is one that the function cannot react to either.
+          inputs.borrow_mut()[i] = to_value(x);
+          let (a, b) = match &*inputs.borrow() {
+            [Some(a), Some(b)] => (from_value(a).unwrap(), from_value(b).unwrap()),
+            _ => return,
+          };
+          if let Ok(y) = f(&*a, &*b) {
+            for port in downstream.borrow().iter() {
+              port(&*y);
+            }
+          }
+        });
+        port
+      })
+      .collect();
+    Box::new(ports.into_iter())
+  }
+
+  fn step_forward(&mut self, _distance: u64) -> Option<Tag> {
+    self.reset();
+    None
+  }
+
+  fn step_down(&mut self) {
+    self.reset();
+  }
+
+  fn step_up(&mut self) -> Option<Tag> {
+    self.reset();
+    None
+  }
+
+  /// Like function rtors, these react as soon as both inputs are set.
+  fn next_event(&self) -> Option<Tag> {
+    None
+  }
+
+  /// The inputs only last for one tag, and the scheduler only saves states between tags.
+  fn save(&self) -> RtorState {
+    RtorState::default()
+  }
+
+  fn restore(&mut self, state: &RtorState) -> Result<(), RestoreError> {
+    if *state != RtorState::default() {
+      return Err(RestoreError::UnexpectedState);
+    }
+    self.reset();
+    Ok(())
+  }
+}
+
+impl<'a> RtorComptime<'a> for BiFunRtorComptime<'a> {
+  fn iterate_levels(&mut self) -> FixpointingStatus {
+    level_downstream(&self.downstream, self.level.get() + Level(1))
+  }
+  fn levels(&self) -> HashSet<Level> {
+    HashSet::new() // never notify; fn-like rtors react immediately
+  }
+  fn accept(&mut self, part: &[Inst], side: Side, inputs: &mut InputsIface<'a>) {
+    require_empty(part);
+    if let Side::Right = side {
+      RefCell::replace(self.downstream.as_ref(), Some(inputs.clone()));
+      inputs.next();
+    }
+  }
+  fn provide(
+    &self,
+    part: &[Inst],
+    side: Side,
+    nesting: Nesting<RtorN>,
+  ) -> ProvidingInputsIface<'a> {
+    require_empty(part);
+    match side {
+      Side::Right => EmptyIterator::new_dyn(nesting),
+      // The same entries as `immut_provide`, of which the notify needs no level.
+      Side::Left => iterator_new(
+        nesting,
+        Box::new(self.iface.clone()),
+        vec![
+          ComptimeInput::Data(raise(&self.level)),
+          ComptimeInput::Data(raise(&self.level)),
+          ComptimeInput::Notify(Rc::new(|_| FixpointingStatus::Unchanged)),
+        ],
+      ),
+    }
+  }
+
+  fn lower_bound(
+    &mut self,
+    part: &[Inst],
+    side: Side,
+    lower_bound: Level,
+    last_direction: FlowDirection,
+  ) {
+    require_empty(part);
+    if side == Side::Left {
+      let nonstrict = cmp::max(lower_bound, self.level.get());
+      self.level.replace(if last_direction == FlowDirection::In {
+        nonstrict
+      } else {
+        nonstrict + Level(1)
+      });
+    }
   }
 }
 
 impl Debug for BiFunRtorIface {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     f.debug_struct("BiFunRtorIface")
-      .field("f", &"???")
+      .field(
+        "f",
+        &format!("{} {} -> {}", self.inputs.0, self.inputs.1, self.output),
+      )
       .field("id", &self.id)
       .finish()
   }
@@ -71,8 +257,7 @@ impl RtorIface for BiFunRtorIface {
   ) -> FixpointingStatus {
     require_empty(part);
     if side == Side::Right {
-      let first = inputs_iface.next().unwrap();
-      first.unwrap()(Comm::Data(Level(1)))
+      next_data(inputs_iface, deferred_notifys).unwrap()(Comm::Data(Level(1)))
     } else {
       FixpointingStatus::Unchanged
     }
@@ -98,16 +283,24 @@ impl RtorIface for BiFunRtorIface {
     iterator_new(nesting, Box::new(self.clone()), ret)
   }
 
-  fn comptime_realize<'db>(&self, db: &'db dyn crate::Db) -> Box<dyn RtorComptime<'db> + 'db> {
-    todo!()
+  fn comptime_realize<'db>(&self, _db: &'db dyn crate::Db) -> Box<dyn RtorComptime<'db> + 'db> {
+    Box::new(BiFunRtorComptime {
+      iface: self.clone(),
+      downstream: Rc::new(RefCell::new(None)),
+      level: Rc::new(Cell::new(Level(0))),
+    })
   }
 
   fn realize<'db>(
     &self,
-    db: &'db dyn crate::Db,
+    _db: &'db dyn crate::Db,
     _inst_time_args: Vec<&'db dyn std::any::Any>,
-  ) -> Box<dyn Rtor<'db> + 'db> {
-    todo!()
+  ) -> Result<Box<dyn Rtor<'db> + 'db>, CausalityLoop> {
+    Ok(Box::new(BiFunRtor {
+      f: (self.make_f)(),
+      inputs: Rc::new(RefCell::new([None, None])),
+      downstream: Rc::new(RefCell::new(vec![])),
+    }))
   }
 
   fn side<'db>(
@@ -115,14 +308,60 @@ impl RtorIface for BiFunRtorIface {
     _db: &'db dyn crate::Db,
     _side: SideMatch,
     part: &[Inst],
-  ) -> Box<dyn Iterator<Item = (Level, SideMatch, Comm<Box<dyn RtorIface + 'db>>)> + 'db> {
+  ) -> Box<dyn Iterator<Item = (Level, SideMatch, Comm<Box<dyn RtorIface>>)> + 'db> {
     require_empty(part);
     let cself: Box<dyn RtorIface> = Box::new(self.clone());
     Box::new(vec![(Level(0), SideMatch::Both, Comm::Data(cself))].into_iter())
     // FIXME: This assumes that the input width is only 1?
   }
 
+  fn port_types(&self, side: Side) -> Option<Vec<Type>> {
+    Some(match side {
+      Side::Left => vec![self.inputs.0.clone(), self.inputs.1.clone()],
+      Side::Right => vec![self.output.clone()],
+    })
+  }
+
   fn iface_id(&self) -> u128 {
     self.id
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::any::Any;
+
+  use crate::GriTestDatabase;
+
+  use super::*;
+
+  #[test]
+  fn test_react_to_both_inputs() {
+    let db = GriTestDatabase::default();
+    let mut rtor = BiFunRtorIface::new(|x: u64, y: u64| x * 10 + y)
+      .realize(&db, vec![])
+      .unwrap();
+    let outputs = Rc::new(RefCell::new(vec![]));
+    let sink = Rc::clone(&outputs);
+    let port: SetPort =
+      Box::new(move |z: &dyn Any| sink.borrow_mut().push(*z.downcast_ref::<u64>().unwrap()));
+    rtor.accept(
+      &[],
+      Side::Right,
+      &mut (Box::new(std::iter::once(port)) as Inputs),
+    );
+    let inputs: Vec<_> = rtor.provide(&[], Side::Left).collect();
+    inputs[1](&2u64);
+    assert!(outputs.borrow().is_empty());
+    inputs[0](&1u64);
+    assert_eq!(*outputs.borrow(), [12]);
+    // Inputs last for one tag, so one input alone is not enough at the next.
+    rtor.step_forward(1);
+    inputs[0](&3u64);
+    assert_eq!(*outputs.borrow(), [12]);
+    inputs[1](&true);
+    assert_eq!(*outputs.borrow(), [12]);
+    inputs[1](&4u64);
+    assert_eq!(*outputs.borrow(), [12, 34]);
+  }
+}
@@ -1,98 +1,155 @@
+use crate::checkpoint::{RestoreError, RtorState};
 use crate::rtor::{
-  DeferredNotifys, Inputs, InputsIface, LevelIterator, ProvidingInputsIface, Rtor, RtorComptime,
-  RtorIface, RtorN,
+  ComptimeInput, DeferredNotifys, Inputs, InputsIface, LevelIterator, ProvidingInputsIface, Rtor,
+  RtorComptime, RtorIface, RtorN, SetPort,
 };
 use crate::Db;
 use connectioniterator::emptyiterator::EmptyIterator;
 use connectioniterator::iterator_new;
 use connectioniterator::nesting::Nesting;
 use irlf_db::ir::Inst;
-use lf_types::{Comm, FlowDirection, Level, Net, Side, SideMatch};
-use std::any::TypeId;
+use lf_types::{Comm, FlowDirection, Level, Side, SideMatch, Tag, Type};
+use std::any::{Any, TypeId};
 use std::cell::Cell;
 use std::cmp;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
-use std::{cell::RefCell, marker::PhantomData, rc::Rc};
+use std::{cell::RefCell, rc::Rc};
 
-use super::util::require_empty;
+use super::srtorimpl::CausalityLoop;
+use super::util::{level_downstream, next_data, raise, require_empty};
+use super::value::{erase, ErasedFn, MakeFn, PortValue};
 use super::FixpointingStatus;
 
 #[derive(Clone)]
 pub struct FunRtorIface {
-  f: Rc<dyn Fn(u64) -> u64>,
+  /// Builds the function computed by an instance from its instantiation-time arguments.
+  make_f: MakeFn,
+  /// The types of the instantiation-time arguments that `make_f` expects.
+  params: Vec<Type>,
+  input: Type,
+  output: Type,
   id: u128,
 }
 
 impl std::fmt::Debug for FunRtorIface {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    f.debug_struct("FunRtorIface").field("f", &"??").finish()
+    f.debug_struct("FunRtorIface")
+      .field("f", &format!("{} -> {}", self.input, self.output))
+      .finish()
   }
 }
 
 struct FunRtorComptime<'a> {
+  iface: FunRtorIface,
   downstream: Rc<RefCell<Option<InputsIface<'a>>>>,
   level: Rc<Cell<Level>>,
 }
 struct FunRtor<'db> {
-  downstream: Option<Inputs<'db>>,
-  f: Rc<dyn Fn(u64) -> u64>,
-  phantom: PhantomData<&'db u64>,
+  f: ErasedFn,
+  /// The ports that the result is written to. They are shared with the ports that `provide` gives,
+  /// which may be given before the ports are accepted.
+  downstream: Rc<RefCell<Vec<SetPort<'db>>>>,
 }
 
 impl FunRtorIface {
-  pub fn new<T: Fn(u64) -> u64 + 'static>(f: T) -> Self {
+  pub fn new<A: PortValue, B: PortValue, T: Fn(A) -> B + 'static>(f: T) -> Self {
+    let f = erase(Rc::new(f));
+    Self::with_id::<T, A, B>(vec![], Rc::new(move |_| Rc::clone(&f)))
+  }
+  /// Creates a reactor whose function depends on the instantiation-time arguments of each
+  /// instance. The arguments passed to `make_f` have the types listed in `params`.
+  pub fn parameterized<
+    A: PortValue,
+    B: PortValue,
+    T: Fn(&[&dyn Any]) -> Rc<dyn Fn(A) -> B> + 'static,
+  >(
+    params: Vec<Type>,
+    make_f: T,
+  ) -> Self {
+    Self::with_id::<T, A, B>(params, Rc::new(move |args| erase(make_f(args))))
+  }
+  fn with_id<T: 'static, A: PortValue, B: PortValue>(params: Vec<Type>, make_f: MakeFn) -> Self {
     let mut hasher = DefaultHasher::new();
     TypeId::of::<T>().hash(&mut hasher);
     let id = (hasher.finish() as u128) ^ 0x60F0D1407CF238F917600842BACE12A3;
-    FunRtorIface { f: Rc::new(f), id }
+    Self {
+      params,
+      ..Self::erased(make_f, A::ty(), B::ty(), id)
+    }
+  }
+  /// Creates a reactor from a function whose types are only known at runtime. `id` must differ
+  /// between reactors that compute different functions.
+  pub(crate) fn erased(make_f: MakeFn, input: Type, output: Type, id: u128) -> Self {
+    FunRtorIface {
+      make_f,
+      params: vec![],
+      input,
+      output,
+      id,
+    }
   }
 }
 
 impl<'db> Rtor<'db> for FunRtor<'db> {
-  fn accept(&mut self, side: Side, inputs: Inputs<'db>) -> bool {
+  fn accept(&mut self, part: &[Inst], side: Side, inputs: &mut Inputs<'db>) {
+    require_empty(part);
     if let Side::Right = side {
-      self.downstream = Some(inputs);
-      false
-    } else {
-      false
+      // Wider ports are made of banks of fun rtors, which have width 1.
+      self.downstream.borrow_mut().extend(inputs.next());
+    }
+  }
+
+  fn provide(&self, part: &[Inst], side: Side) -> Inputs<'db> {
+    require_empty(part);
+    if let Side::Right = side {
+      return Box::new(std::iter::empty());
     }
+    let f = Rc::clone(&self.f);
+    let downstream = Rc::clone(&self.downstream);
+    let input: SetPort<'db> = Box::new(move |x| {
+      // An input that the function cannot react to leaves the output absent.
+      if let Ok(y) = f(x) {
+        for port in downstream.borrow().iter() {
+          port(&*y);
+        }
+      }
+    });
+    Box::new(std::iter::once(input))
   }
 
-  fn provide(&'db self, side: Side, nesting: Nesting<RtorN>) -> Inputs<'db> {
-    // if let Side::Right = side {
-    //   return EmptyIterator::new(nesting);
-    // }
-    // let fclone = self.f.clone();
-    todo!()
-    // map(*self.downstream.as_ref().unwrap(), move |it| {
-    //   let fcloneclone = fclone.clone();
-    //   let mapped_it = move |sth: &dyn Any| {
-    //     let sth = sth.downcast_ref::<u64>().unwrap();
-    //     #[allow(clippy::redundant_closure_call)]
-    //     let mapped = fcloneclone(*sth);
-    //     (*it)(&mapped)
-    //   };
-    //   let b: SetPort<'db> = Box::new(mapped_it);
-    //   b
-    // })
-  }
-
-  fn step_forward(&mut self, _distance: u64) -> Option<Net> {
+  fn step_forward(&mut self, _distance: u64) -> Option<Tag> {
     None
   }
 
   fn step_down(&mut self) {}
 
-  fn step_up(&mut self) -> Option<Net> {
+  fn step_up(&mut self) -> Option<Tag> {
+    None
+  }
+
+  /// Function rtors react as soon as their input is set, so they never have events of their own.
+  fn next_event(&self) -> Option<Tag> {
     None
   }
+
+  fn save(&self) -> RtorState {
+    RtorState::default()
+  }
+
+  fn restore(&mut self, state: &RtorState) -> Result<(), RestoreError> {
+    if *state == RtorState::default() {
+      Ok(())
+    } else {
+      Err(RestoreError::UnexpectedState)
+    }
+  }
 }
 
 impl<'a> RtorComptime<'a> for FunRtorComptime<'a> {
   fn iterate_levels(&mut self) -> FixpointingStatus {
-    FixpointingStatus::Unchanged
+    level_downstream(&self.downstream, self.level.get())
   }
   fn levels(&self) -> HashSet<Level> {
     HashSet::new() // never notify; fn-like rtors react immediately
@@ -101,7 +158,7 @@ impl<'a> RtorComptime<'a> for FunRtorComptime<'a> {
     require_empty(part);
     if let Side::Right = side {
       RefCell::replace(self.downstream.as_ref(), Some(inputs.clone()));
-      inputs.next(); // ! This assumes that the width of self is 1 !
+      inputs.next(); // Wider ports are made of banks of fun rtors, which have width 1.
     }
   }
   fn provide(
@@ -111,23 +168,13 @@ impl<'a> RtorComptime<'a> for FunRtorComptime<'a> {
     nesting: Nesting<RtorN>,
   ) -> ProvidingInputsIface<'a> {
     require_empty(part);
-    // trivial_inputs_iface_giver()
-    if let Side::Right = side {
-      EmptyIterator::new_dyn(nesting)
-    } else {
-      // let self_level = Rc::clone(&self.level);
-      // map(
-      //   Box::new(LazyIterClone::new(Rc::clone(&self.downstream))),
-      //   Rc::new(move |it: lf_types::Comm<Rc<dyn Fn(lf_types::Comm<Level>) -> FixpointingStatus>>| {
-      //     let self_level = Rc::clone(&self_level);
-      //     let c: dyn Fn(Level) -> IIEltE = move |x| {
-      //       self_level.replace(x);
-      //       it(x)
-      //     };
-      //     Rc::new(c)
-      //   }),
-      // )
-      todo!()
+    match side {
+      Side::Right => EmptyIterator::new_dyn(nesting),
+      Side::Left => iterator_new(
+        nesting,
+        Box::new(self.iface.clone()),
+        vec![ComptimeInput::Data(raise(&self.level))],
+      ),
     }
   }
 
@@ -142,7 +189,7 @@ impl<'a> RtorComptime<'a> for FunRtorComptime<'a> {
     if side == Side::Left {
       let nonstrict = cmp::max(lower_bound, self.level.get());
       let strict = nonstrict + Level(1);
-      self.level.replace(if last_direction == FlowDirection::Out {
+      self.level.replace(if last_direction == FlowDirection::In {
         nonstrict
       } else {
         strict
@@ -162,10 +209,7 @@ impl RtorIface for FunRtorIface {
   ) -> FixpointingStatus {
     require_empty(part);
     if side == Side::Right {
-      let first = inputs_iface.next().unwrap();
-      // FIXME: The right thing to do here is probably to find the first element of the iface that
-      // is not a Notify
-      first.unwrap()(Comm::Data(Level(0)))
+      next_data(inputs_iface, deferred_notifys).unwrap()(Comm::Data(Level(0)))
     } else {
       FixpointingStatus::Unchanged
     }
@@ -191,6 +235,7 @@ impl RtorIface for FunRtorIface {
 
   fn comptime_realize<'db>(&self, _db: &'db dyn Db) -> Box<dyn RtorComptime<'db> + 'db> {
     Box::new(FunRtorComptime {
+      iface: self.clone(),
       downstream: Rc::new(RefCell::new(None)),
       level: Rc::new(Cell::new(Level(0))), // TODO: check?
     })
@@ -198,13 +243,12 @@ impl RtorIface for FunRtorIface {
   fn realize<'db>(
     &self,
     _db: &'db dyn Db,
-    _inst_time_args: Vec<&'db dyn std::any::Any>,
-  ) -> Box<dyn Rtor<'db> + 'db> {
-    Box::new(FunRtor {
-      downstream: None,
-      phantom: PhantomData,
-      f: Rc::clone(&self.f),
-    })
+    inst_time_args: Vec<&'db dyn Any>,
+  ) -> Result<Box<dyn Rtor<'db> + 'db>, CausalityLoop> {
+    Ok(Box::new(FunRtor {
+      f: (self.make_f)(&inst_time_args),
+      downstream: Rc::new(RefCell::new(vec![])),
+    }))
   }
 
   fn immut_provide_unique(
@@ -222,14 +266,103 @@ impl RtorIface for FunRtorIface {
     _db: &'db dyn Db,
     _side: SideMatch,
     part: &[Inst],
-  ) -> Box<dyn Iterator<Item = (Level, SideMatch, Comm<Box<dyn RtorIface + 'db>>)> + 'db> {
+  ) -> Box<dyn Iterator<Item = (Level, SideMatch, Comm<Box<dyn RtorIface>>)> + 'db> {
     require_empty(part);
     let cself: Box<dyn RtorIface> = Box::new(self.clone());
+    // A fun rtor has one port on each side. Multiports are banks of fun rtors, whose members are
+    // repeated by the iface of the enclosing ctor.
     Box::new(vec![(Level(0), SideMatch::Both, Comm::Data(cself))].into_iter())
-    // FIXME: This assumes that the input width is only 1?
+  }
+
+  fn param_types(&self) -> Vec<Type> {
+    self.params.clone()
+  }
+
+  fn port_types(&self, side: Side) -> Option<Vec<Type>> {
+    Some(vec![match side {
+      Side::Left => self.input.clone(),
+      Side::Right => self.output.clone(),
+    }])
   }
 
   fn iface_id(&self) -> u128 {
     self.id
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::GriTestDatabase;
+
+  /// Writes `x` to the input of an instance of `iface` realized with `args`, and returns what the
+  /// instance writes to its output, if anything.
+  fn react<B: PortValue>(
+    iface: &dyn RtorIface,
+    args: Vec<&'static dyn Any>,
+    x: &dyn Any,
+  ) -> Option<B> {
+    let db = GriTestDatabase::default();
+    let mut rtor = iface.realize(&db, args).unwrap();
+    let output = Rc::new(RefCell::new(None));
+    let sink = Rc::clone(&output);
+    let port: SetPort = Box::new(move |y| *sink.borrow_mut() = y.downcast_ref::<B>().cloned());
+    rtor.accept(
+      &[],
+      Side::Right,
+      &mut (Box::new(std::iter::once(port)) as Inputs),
+    );
+    rtor.provide(&[], Side::Left).next().unwrap()(x);
+    output.take()
+  }
+
+  #[test]
+  fn test_parameterized() {
+    let iface = FunRtorIface::parameterized(vec![Type::Int], |args| {
+      let k = *args[0].downcast_ref::<u64>().unwrap();
+      Rc::new(move |x: u64| x * k)
+    });
+    assert_eq!(react::<u64>(&iface, vec![&3u64], &7u64), Some(21));
+    assert_eq!(react::<u64>(&iface, vec![&5u64], &7u64), Some(35));
+  }
+
+  #[test]
+  fn test_types() {
+    let add1 = FunRtorIface::new(|x: u64| x + 1);
+    assert_eq!(react::<u64>(&add1, vec![], &7u64), Some(8));
+    let halve = FunRtorIface::new(|x: f64| (x / 2.0, x < 0.0));
+    assert_eq!(halve.input, Type::Float);
+    assert_eq!(halve.output, Type::Tuple(vec![Type::Float, Type::Bool]));
+    assert_eq!(
+      react::<(f64, bool)>(&halve, vec![], &-3.0),
+      Some((-1.5, true))
+    );
+    // The function cannot react to a value of the wrong type, so there is no output.
+    assert_eq!(react::<u64>(&add1, vec![], &true), None);
+    assert_ne!(add1.id, halve.id);
+    assert_eq!(add1.clone().id, add1.id);
+  }
+
+  #[test]
+  fn test_fan_out() {
+    let db = GriTestDatabase::default();
+    let mut rtor = FunRtorIface::new(|x: u64| x + 1)
+      .realize(&db, vec![])
+      .unwrap();
+    // The input may be provided before the output is accepted.
+    let input = rtor.provide(&[], Side::Left).next().unwrap();
+    let outputs = Rc::new(RefCell::new(vec![]));
+    for _ in 0..2 {
+      let sink = Rc::clone(&outputs);
+      let port: SetPort =
+        Box::new(move |y| sink.borrow_mut().push(*y.downcast_ref::<u64>().unwrap()));
+      rtor.accept(
+        &[],
+        Side::Right,
+        &mut (Box::new(std::iter::once(port)) as Inputs),
+      );
+    }
+    input(&1u64);
+    assert_eq!(*outputs.borrow(), [2, 2]);
+  }
+}
@@ -2,15 +2,22 @@ pub mod bifunrtorimpl;
 pub mod funrtorimpl;
 pub mod srtorimpl;
 mod util;
+pub mod value;
+pub mod wasmrtorimpl;
 
-use std::ops::BitOrAssign;
+use std::{fmt::Display, ops::BitOrAssign, rc::Rc};
 
 use crate::{
   rtor::RtorIface,
-  rtorimpl::{bifunrtorimpl::BiFunRtorIface, funrtorimpl::FunRtorIface},
+  rtorimpl::{
+    bifunrtorimpl::BiFunRtorIface,
+    funrtorimpl::FunRtorIface,
+    wasmrtorimpl::{WasmLctor, WasmModules},
+  },
   Db,
 };
 use irlf_db::ir::{Ctor, LibCtor};
+use lf_types::Type;
 
 #[derive(PartialEq, Eq)]
 pub enum FixpointingStatus {
@@ -26,35 +33,78 @@ impl BitOrAssign for FixpointingStatus {
   }
 }
 
-pub fn iface_of<'db>(db: &'db dyn Db, ctor: &Ctor) -> Box<dyn RtorIface + 'db> {
+/// Returns the iface of `ctor`, through which its instances are realized.
+///
+/// # Panics
+/// Panics if `ctor` is a lib ctor that has no implementation, which `typecheck` reports.
+pub fn iface_of(db: &dyn Db, ctor: &Ctor) -> Box<dyn RtorIface> {
   match ctor {
     Ctor::StructlikeCtor(sctor) => crate::rtorimpl::srtorimpl::srtor_of(db, *sctor),
     Ctor::BinaryCtor(_) => todo!(),
-    Ctor::LibCtor(lctor) => lctor_of(db, *lctor),
+    Ctor::LibCtor(lctor) => lctor_of(db, *lctor).unwrap_or_else(|e| panic!("{e}")),
   }
 }
 
-#[salsa::tracked]
-pub fn lctor_of(db: &dyn crate::Db, lctor: LibCtor) -> Box<dyn RtorIface> {
-  match lctor.name(db).as_str() {
-    "add1" => Box::new(FunRtorIface::new(|x| x + 1)),
-    "mul2" => Box::new(FunRtorIface::new(|x| x * 2)),
-    "sum" => Box::new(BiFunRtorIface::new(|x, y| x + y)),
-    "prod" => Box::new(BiFunRtorIface::new(|x, y| x * y)),
-    s => panic!("\"{s}\" is not an lctor name"),
+/// The reasons why a lib ctor has no implementation.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum LctorError {
+  /// No lib ctor of this name is built in or given by `WasmModules`.
+  Unknown(String),
+  /// The module that `WasmModules` gives for this name does not implement a lib ctor.
+  Load { name: String, reason: String },
+}
+
+impl Display for LctorError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      LctorError::Unknown(name) => write!(f, "\"{name}\" is not an lctor name"),
+      LctorError::Load { name, reason } => write!(f, "cannot load lctor \"{name}\": {reason}"),
+    }
   }
 }
 
+#[salsa::tracked]
+pub fn lctor_of(db: &dyn crate::Db, lctor: LibCtor) -> Result<Box<dyn RtorIface>, LctorError> {
+  Ok(match lctor.name(db).as_str() {
+    "add1" => Box::new(FunRtorIface::new(|x: u64| x + 1)),
+    "mul2" => Box::new(FunRtorIface::new(|x: u64| x * 2)),
+    "gain" => Box::new(FunRtorIface::parameterized(vec![Type::Int], |args| {
+      let k = *args[0].downcast_ref::<u64>().expect("gain takes a u64");
+      Rc::new(move |x: u64| x * k)
+    })),
+    "not" => Box::new(FunRtorIface::new(|x: bool| !x)),
+    "sum" => Box::new(BiFunRtorIface::new(|x: u64, y: u64| x + y)),
+    "prod" => Box::new(BiFunRtorIface::new(|x: u64, y: u64| x * y)),
+    "fsum" => Box::new(BiFunRtorIface::new(|x: f64, y: f64| x + y)),
+    "fprod" => Box::new(BiFunRtorIface::new(|x: f64, y: f64| x * y)),
+    name => {
+      let wasm = WasmModules::try_get(db)
+        .and_then(|modules| modules.modules(db).get(name))
+        .ok_or_else(|| LctorError::Unknown(name.to_string()))?;
+      WasmLctor::from_bytes(wasm)
+        .map_err(|e| LctorError::Load {
+          name: name.to_string(),
+          reason: e.to_string(),
+        })?
+        .iface()
+    }
+  })
+}
+
 #[cfg(test)]
 mod tests {
-  use std::collections::HashSet;
+  use std::{cell::RefCell, collections::HashSet};
 
-  use connectioniterator::nesting::Nesting;
+  use connectioniterator::{iterator_new, nesting::Nesting};
   use expect_test::{expect, Expect};
   use irlf_db::from_text;
-  use lf_types::{Level, Side};
+  use lf_types::{Comm, InstId, Level, Side};
 
-  use crate::GriTestDatabase;
+  use crate::{
+    rtor::{ComptimeInput, DeferredNotifys, InputsIface, Leveller, RtorN},
+    testing::main_sctor,
+    GriTestDatabase,
+  };
 
   use super::*;
 
@@ -131,6 +181,165 @@ rtor1 3
 3
 ";
 
+  /// Returns inputs made of the given data (`true`) and notify (`false`) entries, along with the
+  /// entries in the order in which they will be levelled and the levels that they will be given.
+  fn recording_inputs(entries: &[bool]) -> (InputsIface<'static>, Rc<RefCell<Vec<String>>>) {
+    let recorded = Rc::new(RefCell::new(vec![]));
+    let entries = entries
+      .iter()
+      .map(|&is_data| {
+        let recorded = Rc::clone(&recorded);
+        let f: Leveller = Rc::new(move |level| {
+          if let Comm::Data(level) = level {
+            let kind = if is_data { "data" } else { "notify" };
+            recorded.borrow_mut().push(format!("{kind} {}", level.0));
+          }
+          FixpointingStatus::Unchanged
+        });
+        if is_data {
+          ComptimeInput::Data(f)
+        } else {
+          ComptimeInput::Notify(f)
+        }
+      })
+      .collect();
+    let consumer: RtorN = Box::new(FunRtorIface::new(|x: u64| x));
+    let inputs: InputsIface<'static> = iterator_new(Nesting::default(), consumer, entries);
+    (inputs, recorded)
+  }
+
+  fn accept_expect(iface: &dyn RtorIface, db: &dyn Db, entries: &[bool], expect: Expect) {
+    let (mut inputs, recorded) = recording_inputs(entries);
+    let mut deferred = DeferredNotifys::new();
+    iface.immut_accept(db, &[], Side::Right, &mut inputs, &mut deferred);
+    let actual = format!(
+      "levels: {:?}\nremaining: {}\ndeferred: {}",
+      recorded.borrow(),
+      inputs.count(),
+      deferred.len()
+    );
+    expect.assert_eq(&actual);
+  }
+
+  const NESTED_NOTIFYS: &str = "add1 0x0 add1
+---
+---
+rtor0 0x1
+  a 100 = 0x0
+  b 101 = 0x0
+  ---
+  L 100 R 100
+  R -
+  L 101 R 101
+  ---
+rtor1 0x2
+  inner 102 = 0x1
+  c 103 = 0x0
+  ---
+  L 102 R 102
+  R -
+  L 103 R 103
+  ---
+---
+0x2
+";
+
+  #[test]
+  fn test_accept_notifys() {
+    let db = GriTestDatabase::default();
+    let (program, _inst2sym) = from_text(NESTED_NOTIFYS, &db);
+    let iface = iface_of(&db, program.main(&db));
+    // The notify at the head waits on nothing, and each of the others waits on the data entry
+    // before it, so all of them are resolved by the time the data entries have been levelled.
+    accept_expect(
+      &*iface,
+      &db,
+      &[false, true, true, false, true, false, true],
+      expect![[r#"
+          levels: ["data 0", "notify 0", "data 1", "notify 1", "data 2", "notify 2"]
+          remaining: 1
+          deferred: 0"#]],
+    );
+    // A lone function rtor never resolves the notifys that it passes over.
+    accept_expect(
+      &FunRtorIface::new(|x: u64| x + 1),
+      &db,
+      &[false, false, true, false],
+      expect![[r#"
+          levels: ["data 0"]
+          remaining: 1
+          deferred: 2"#]],
+    );
+  }
+
+  const WASM_LCTORS: &str = "add3 0x0 add3
+bad 0x1 bad
+---
+---
+rtor0 0x2
+  a 100 = 0x0
+  b 101 = 0x1
+  ---
+  L 100 R 100
+  ---
+---
+0x2
+";
+
+  #[test]
+  fn test_lctor_errors() {
+    let db = GriTestDatabase::default();
+    let (program, _inst2sym) = from_text(WASM_LCTORS, &db);
+    let reasons = |db: &GriTestDatabase| {
+      sort(
+        crate::typecheck(db, program)
+          .into_iter()
+          .map(|error| error.to_string())
+          .collect(),
+      )
+    };
+    assert_eq!(
+      reasons(&db),
+      [
+        "instance 100 in ctor 0x2 cannot be realized: \"add3\" is not an lctor name",
+        "instance 101 in ctor 0x2 cannot be realized: \"bad\" is not an lctor name",
+      ]
+    );
+
+    let db = GriTestDatabase::default();
+    let (program, _inst2sym) = from_text(WASM_LCTORS, &db);
+    let add3 = wat::parse_str(
+      r#"(module
+  (func (export "n_inputs") (result i32) i32.const 1)
+  (func (export "react_0") (param i64) (result i64) local.get 0 i64.const 3 i64.add))"#,
+    )
+    .unwrap();
+    WasmModules::new(
+      &db,
+      [
+        ("add3".to_string(), add3),
+        ("bad".to_string(), b"\0asm".to_vec()),
+      ]
+      .into(),
+    );
+    let errors = crate::typecheck(&db, program);
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].to_string().starts_with(
+      "instance 101 in ctor 0x2 cannot be realized: cannot load lctor \"bad\": invalid module"
+    ));
+    let sctor = main_sctor(&db, program);
+    let add3 = sctor
+      .insts(&db)
+      .iter()
+      .find(|inst| inst.id(&db) == InstId(100))
+      .unwrap()
+      .ctor(&db);
+    assert_eq!(
+      iface_of(&db, add3).port_types(Side::Right),
+      Some(vec![Type::Int])
+    );
+  }
+
   #[test]
   fn test0() {
     let text = BASIC_NO_MERGING;
@@ -145,9 +354,10 @@ rtor1 3
   #[test]
   fn test1() {
     let text = MERGING;
+    // The third item of the left side is the `L -` entry of the iface of `rtor1`.
     let expect = expect![[r#"
         levels: [Level(0), Level(1)]
-        left: [Data(Level(0)), Data(Level(0))]
+        left: [Data(Level(0)), Data(Level(0)), Notify]
         right: [Data(Level(1))]
         unique_left: [Level(0)]
         unique_right: [Level(1)]"#]];
@@ -1,33 +1,405 @@
 use std::{
   cell::RefCell,
-  collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+  collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+  fmt::Display,
   hash::{Hash, Hasher},
   rc::Rc,
 };
 
 use crate::{
-  rtor::{ComptimeInput, DeferredNotifys, FuzzySideIterator, Inputs, ProvidingInputsIface, RtorN},
+  checkpoint::{DelayedValue, RestoreError, RtorState},
+  record::Recorder,
+  rtor::{
+    ComptimeInput, DeferredNotifys, FuzzySideIterator, Inputs, Leveller, ProvidingInputsIface,
+    RtorN, SetPort,
+  },
+  trace::{Cause, MemberPath, Trace, Tracer},
   Db,
 };
 use connectioniterator::{
   chainclone::ChainClone,
+  iterator_new,
   map::{map, pmap},
   nesting::Nesting,
+  ConnectionIterator, ProvidingConnectionIterator,
+};
+use irlf_db::{
+  ir::{Connection, Ctor, Inst, InstRef, StructlikeCtor},
+  typecheck::TypeError,
+};
+use lf_types::{
+  Comm, CtorId, DebugOnlyId, DeltaT, FlowDirection, InstId, Level, Literal, Side, SideMatch, Slice,
+  Tag, Value,
 };
-use irlf_db::ir::{Inst, InstRef, StructlikeCtor};
-use lf_types::{Comm, FlowDirection, Level, Side, SideMatch};
 
 use crate::rtor::{InputsIface, LevelIterator, Rtor, RtorComptime, RtorIface};
 
 use closure::closure;
 
-use super::{iface_of, FixpointingStatus};
+use super::{
+  iface_of,
+  util::resolve_notifys,
+  value::{from_value, to_value},
+  FixpointingStatus,
+};
 
 // dyn_clone::clone_trait_object!(ChainClone<Level, dyn LevelIterator<Item = Level>>);
 // impl ConnectionIterator<Level> for ChainClone<Level, Box<dyn ConnectionIterator<Level>>> {}
 
 pub struct Srtor<'db> {
-  downstream: Option<Inputs<'db>>,
+  db: &'db dyn Db,
+  sctor: StructlikeCtor,
+  children: Vec<(Member, Box<dyn Rtor<'db> + 'db>)>,
+  /// The ids of the instances of `children`, in the same order.
+  child_ids: Vec<InstId>,
+  /// The tag that this has reached.
+  tag: Tag,
+  delay_lines: Vec<DelayLine<'db>>,
+}
+
+/// A delayed connection between two children of an srtor, which holds the values sent by its left
+/// end until the srtor reaches the tag at which they are due at its right end, or a connection
+/// without a delay between children of different federates.
+struct DelayLine<'db> {
+  connection: DebugOnlyId,
+  /// Shared with the ports to which the left end sends.
+  in_flight: Rc<RefCell<InFlight>>,
+  /// The ports of the right end, one for each channel of the connection.
+  targets: Vec<SetPort<'db>>,
+  boundary: Boundary,
+  /// For a connection without a delay, the level within a tag at which the federate of its right end
+  /// is given the values sent at that tag.
+  undelayed: Option<Level>,
+  /// The port of the right end that each channel of the connection is delivered to, as the path from
+  /// the srtor to the member that owns it and its channel on the left side of that member, as which
+  /// deliveries are recorded.
+  ports: Vec<(MemberPath, u64)>,
+  /// The recorder of deliveries, with the path from `main` to the srtor.
+  recorder: Option<(Rc<Recorder>, MemberPath)>,
+}
+
+/// Which ends of a delay line belong to an srtor that realizes only some of the children of its
+/// sctor, as one federate of a federation does.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum Boundary {
+  /// Both ends are children of the srtor.
+  Local,
+  /// Only the left end is a child of the srtor, so values are sent to another federate instead of
+  /// being delivered.
+  Outgoing,
+  /// Only the right end is a child of the srtor, so values are received from another federate.
+  Incoming,
+}
+
+/// The values on a delayed connection that have been sent but not yet delivered.
+struct InFlight {
+  delay: DeltaT,
+  /// The tag of the srtor that owns the connection, which is when values are sent.
+  now: Tag,
+  /// The values in the order in which they were sent, each with its channel, by the tag at which
+  /// they are due.
+  by_due: BTreeMap<Tag, Vec<(u64, Value)>>,
+}
+
+impl<'db> DelayLine<'db> {
+  fn new(
+    connection: DebugOnlyId,
+    delay: DeltaT,
+    boundary: Boundary,
+    undelayed: Option<Level>,
+    ports: Vec<(MemberPath, u64)>,
+    targets: Vec<SetPort<'db>>,
+  ) -> Self {
+    DelayLine {
+      connection,
+      in_flight: Rc::new(RefCell::new(InFlight {
+        delay,
+        now: Tag::default(),
+        by_due: BTreeMap::new(),
+      })),
+      targets,
+      boundary,
+      undelayed,
+      ports,
+      recorder: None,
+    }
+  }
+
+  /// Returns the port to which the left end sends on the given channel. A value sent at some tag is
+  /// due `delay` after it, with the outermost component of `delay` counted at the outermost nesting
+  /// depth.
+  fn input(&self, channel: u64) -> SetPort<'db> {
+    let in_flight = Rc::clone(&self.in_flight);
+    Box::new(move |x| {
+      let value = to_value(x).expect("values on delayed connections should be port values");
+      let mut in_flight = in_flight.borrow_mut();
+      let due = in_flight.now.add_at(0, &in_flight.delay);
+      in_flight
+        .by_due
+        .entry(due)
+        .or_default()
+        .push((channel, value));
+    })
+  }
+
+  /// The values in flight, in the order in which they are to be delivered.
+  fn save(&self) -> Vec<DelayedValue> {
+    let in_flight = self.in_flight.borrow();
+    let mut ret = vec![];
+    for (due, values) in &in_flight.by_due {
+      ret.extend(values.iter().map(|(channel, value)| DelayedValue {
+        connection: self.connection,
+        channel: *channel,
+        due: due.clone(),
+        value: value.clone(),
+      }));
+    }
+    ret
+  }
+
+  /// The tag at which the next value is due at the right end, if the right end is a child of the
+  /// srtor.
+  fn next_due(&self) -> Option<Tag> {
+    if self.boundary == Boundary::Outgoing {
+      return None;
+    }
+    self.in_flight.borrow().by_due.keys().next().cloned()
+  }
+
+  /// Delivers to the right end the values of a delayed connection that are due by the tag that the
+  /// srtor has reached.
+  fn deliver(&self) {
+    if self.boundary == Boundary::Outgoing || self.undelayed.is_some() {
+      // The values are waiting for `take_outgoing` or for `deliver_undelayed`.
+      return;
+    }
+    self.deliver_due();
+  }
+
+  /// Delivers to the right end the values that are due by the tag that the srtor has reached.
+  fn deliver_due(&self) {
+    let due = {
+      let mut in_flight = self.in_flight.borrow_mut();
+      let after = in_flight.now.step_down();
+      let later = in_flight.by_due.split_off(&after);
+      std::mem::replace(&mut in_flight.by_due, later)
+    };
+    for (channel, value) in due.into_values().flatten() {
+      let port_value = from_value(&value).expect("values are only kept if they can be restored");
+      if let Some((recorder, path)) = &self.recorder {
+        let (member, member_channel) = &self.ports[channel as usize];
+        // The right end provides the ports on its left side.
+        recorder.push(
+          [&path[..], &member[..]].concat(),
+          Side::Left,
+          *member_channel,
+          value,
+        );
+      }
+      self.targets[channel as usize](&*port_value);
+    }
+  }
+}
+
+impl<'db> Srtor<'db> {
+  /// The connections on which values are received from other federates.
+  pub(crate) fn incoming(&self) -> impl Iterator<Item = DebugOnlyId> + '_ {
+    self
+      .delay_lines
+      .iter()
+      .filter(|line| line.boundary == Boundary::Incoming)
+      .map(|line| line.connection)
+  }
+
+  /// Removes the values that have been sent to other federates, each with its connection, its
+  /// channel and the tag at which it is due.
+  pub(crate) fn take_outgoing(&mut self) -> Vec<(DebugOnlyId, u64, Tag, Value)> {
+    let mut ret = vec![];
+    for line in &self.delay_lines {
+      if line.boundary == Boundary::Outgoing {
+        let by_due = std::mem::take(&mut line.in_flight.borrow_mut().by_due);
+        for (due, values) in by_due {
+          ret.extend(
+            values
+              .into_iter()
+              .map(|(channel, value)| (line.connection, channel, due.clone(), value)),
+          );
+        }
+      }
+    }
+    ret
+  }
+
+  /// Queues a value received from another federate on the given channel, which is due at `due`.
+  /// Returns false if there is no such incoming connection or channel.
+  pub(crate) fn receive(
+    &mut self,
+    connection: DebugOnlyId,
+    channel: u64,
+    due: Tag,
+    value: Value,
+  ) -> bool {
+    let Some(line) = self.delay_lines.iter().find(|line| {
+      line.connection == connection
+        && line.boundary == Boundary::Incoming
+        && channel < line.targets.len() as u64
+    }) else {
+      return false;
+    };
+    line
+      .in_flight
+      .borrow_mut()
+      .by_due
+      .entry(due)
+      .or_default()
+      .push((channel, value));
+    true
+  }
+
+  /// The earliest tag and level at which values received from other federates on connections without
+  /// a delay are to be delivered, if there are any.
+  pub(crate) fn next_undelayed(&self) -> Option<(Tag, Level)> {
+    self
+      .delay_lines
+      .iter()
+      .filter(|line| line.boundary == Boundary::Incoming)
+      .filter_map(|line| Some((line.next_due()?, line.undelayed?)))
+      .min()
+  }
+
+  /// Delivers the values received from other federates on connections without a delay whose right
+  /// ends are at `level`, which are due by the tag that the srtor has reached.
+  pub(crate) fn deliver_undelayed(&self, level: Level) {
+    for line in &self.delay_lines {
+      if line.boundary == Boundary::Incoming && line.undelayed == Some(level) {
+        line.deliver_due();
+      }
+    }
+  }
+
+  /// Moves to `tag`, moving each child with `step`, and then delivers the values that are due by
+  /// then. The delay lines move before the children, so that the values that the children send as
+  /// they move are sent at `tag`, and deliver after them, so that the values delivered are not lost
+  /// as the children move.
+  fn reach(&mut self, tag: Tag, step: impl Fn(&mut (dyn Rtor<'db> + 'db))) {
+    for line in &self.delay_lines {
+      line.in_flight.borrow_mut().now = tag.clone();
+    }
+    self.tag = tag;
+    for (_, child) in &mut self.children {
+      step(child.as_mut());
+    }
+    for line in &self.delay_lines {
+      line.deliver();
+    }
+  }
+
+  fn child(&self, member: Member) -> Option<&(dyn Rtor<'db> + 'db)> {
+    self
+      .children
+      .iter()
+      .find(|(m, _)| *m == member)
+      .map(|(_, child)| child.as_ref())
+  }
+
+  fn is_local(&self, inst: Inst) -> bool {
+    self.children.iter().any(|((i, _), _)| *i == inst)
+  }
+
+  /// Provides the ports of the given part of `member`, which discard the values written to them if
+  /// `member` belongs to another federate.
+  fn provide_member(&self, member: Member, part: &[Inst], side: Side) -> Inputs<'db> {
+    match self.child(member) {
+      Some(child) => child.provide(part, side),
+      None => Box::new(discarding(member_width(self.db, member.0, part, side))),
+    }
+  }
+
+  /// Makes the given part of `member` accept its ports from `inputs`, which are skipped if `member`
+  /// belongs to another federate.
+  fn accept_member(&mut self, member: Member, part: &[Inst], side: Side, inputs: &mut Inputs<'db>) {
+    match self.children.iter_mut().find(|(m, _)| *m == member) {
+      Some((_, child)) => child.accept(part, side, inputs),
+      None => {
+        for _ in 0..member_width(self.db, member.0, part, side) {
+          inputs.next();
+        }
+      }
+    }
+  }
+
+  /// Wires the ports of the left end of `connection` to those of its right end, through a delay line
+  /// if it is delayed or joins different federates.
+  fn connect(&mut self, connection: Connection) {
+    let db = self.db;
+    let lref = connection.left(db).iref(db);
+    let rref = connection.right(db).iref(db);
+    let (Ok(upstream), Ok(downstream)) = (
+      End::new(db, &lref, connection.left_slice(db), Side::Right),
+      End::new(db, &rref, connection.right_slice(db), Side::Left),
+    ) else {
+      unreachable!("typecheck rejects slices that are out of range")
+    };
+    let boundary = match (self.is_local(upstream.inst), self.is_local(downstream.inst)) {
+      (true, true) => Boundary::Local,
+      (true, false) => Boundary::Outgoing,
+      (false, true) => Boundary::Incoming,
+      (false, false) => return,
+    };
+    // The left end writes to the ports that the right end provides on its left side.
+    let targets: Vec<SetPort<'db>> = downstream
+      .members()
+      .flat_map(|d| self.provide_member(d, downstream.rest, Side::Left))
+      .skip(downstream.skip as usize)
+      .take(downstream.width as usize)
+      .collect();
+    let mut sources: Inputs<'db> = if connection.is_delayed(db) || boundary != Boundary::Local {
+      let ports = (0..downstream.width)
+        .map(|channel| downstream.port_of(db, channel))
+        .collect();
+      // A value sent without a delay is due at the tag at which it is sent.
+      let (delay, undelayed) = match connection.delay(db) {
+        Some(delay) => (delay.clone(), None),
+        None => (
+          vec![0],
+          Some(undelayed_depth(db, self.sctor, downstream.inst)),
+        ),
+      };
+      let line = DelayLine::new(
+        connection.id(db),
+        delay,
+        boundary,
+        undelayed,
+        ports,
+        targets,
+      );
+      let sources: Vec<_> = (0..downstream.width)
+        .map(|channel| line.input(channel))
+        .collect();
+      self.delay_lines.push(line);
+      Box::new(sources.into_iter())
+    } else {
+      Box::new(targets.into_iter())
+    };
+    // The members of the left end take the channels in turn, and the channels of a member outside
+    // of the selection accept nothing.
+    let mut channel = 0;
+    for (i, u) in upstream.members().enumerate() {
+      let before = if i == 0 { upstream.skip } else { 0 };
+      let take = (upstream.member_width - before).min(upstream.width - channel);
+      let after = upstream.member_width - before - take;
+      let selected: Vec<_> = sources.by_ref().take(take as usize).collect();
+      let mut inputs: Inputs<'db> =
+        Box::new(discarding(before).chain(selected).chain(discarding(after)));
+      self.accept_member(u, upstream.rest, Side::Right, &mut inputs);
+      channel += take;
+    }
+  }
+}
+
+/// Ports that discard the values written to them.
+fn discarding<'db>(n: u64) -> impl Iterator<Item = SetPort<'db>> {
+  (0..n).map(|_| -> SetPort<'db> { Box::new(|_| {}) })
 }
 
 pub struct SrtorComptime<'a> {
@@ -35,6 +407,7 @@ pub struct SrtorComptime<'a> {
   db: &'a dyn Db,
   levels_internal2external: Rc<RefCell<BTreeMap<Level, Level>>>,
   external_connections: Vec<(Vec<Inst>, Side, InputsIface<'a>)>,
+  tracer: Option<Tracer>,
 }
 
 #[salsa::tracked]
@@ -80,83 +453,854 @@ impl<'a> SrtorComptime<'a> {
       iface,
       db,
       // Conservatively start with a "ghost" entry at level zero because this instance _could_ have
-      // inputs right before it in an interface with the same level as its starting level.
-      levels_internal2external: Rc::new(RefCell::new(BTreeMap::from([(Level(0), Level(0))]))),
+      // inputs right before it in an interface with the same level as its starting level. Every
+      // other intrinsic level starts out mapped to itself.
+      levels_internal2external: Rc::new(RefCell::new(
+        iface
+          .levels(db)
+          .into_iter()
+          .chain([Level(0)])
+          .map(|level| (level, level))
+          .collect(),
+      )),
       external_connections: vec![],
+      tracer: None,
     }
   }
 }
 
-fn iface(sctor: StructlikeCtor, db: &dyn Db, side: SideMatch) -> SideIterator {
-  SideIterator::new(db, sctor, side)
+/// Iterates over the elements of the given side of the iface of `sctor`. An element that refers
+/// to a whole bank is repeated once per member of the bank.
+fn iface(
+  sctor: StructlikeCtor,
+  db: &dyn Db,
+  side: SideMatch,
+) -> impl Iterator<Item = Comm<InstRef>> + '_ {
+  SideIterator::new(db, sctor, side).flat_map(move |elt| {
+    let n = match &elt {
+      Comm::Data(iref) if iref.iref(db).len() == 1 => iref.iref(db)[0].width(db),
+      _ => 1,
+    };
+    std::iter::repeat_n(elt, n as usize)
+  })
+}
+
+/// Iterates over the data elements of the given side of the iface of `sctor`, each as the member
+/// that it exposes and the part of that member.
+fn exposed(
+  db: &dyn Db,
+  sctor: StructlikeCtor,
+  side: Side,
+) -> impl Iterator<Item = (Member, Vec<Inst>)> + '_ {
+  let mut next_member = HashMap::new();
+  iface(sctor, db, SideMatch::One(side)).filter_map(move |elt| match elt {
+    Comm::Data(iref) => Some(exposed_member(&mut next_member, &iref.iref(db))),
+    Comm::Notify => None,
+  })
+}
+
+/// The member that an element of an iface refers to, and the part of it, given the number of times
+/// that the preceding elements have referred to each whole bank.
+fn exposed_member(next_member: &mut HashMap<Inst, u64>, iref: &[Inst]) -> (Member, Vec<Inst>) {
+  let [inst, rest @ ..] = iref else {
+    unreachable!("refs should never be empty if the ast passed parsing/validation")
+  };
+  // An element that refers to a whole bank is repeated once per member of the bank.
+  let member = if rest.is_empty() {
+    let next = next_member.entry(*inst).or_default();
+    *next += 1;
+    *next - 1
+  } else {
+    0
+  };
+  ((*inst, member), rest.to_vec())
+}
+
+/// The number of data channels on the given side of the given part of an instance of `inst`.
+fn member_width(db: &dyn Db, inst: Inst, part: &[Inst], side: Side) -> u64 {
+  iface_of(db, inst.ctor(db))
+    .immut_provide(db, part, side, Level(0), Nesting::default())
+    .filter(|it| matches!(it, Comm::Data(_)))
+    .count() as u64
+}
+
+pub(crate) fn bank_members(
+  db: &dyn Db,
+  sctor: StructlikeCtor,
+) -> impl Iterator<Item = Member> + '_ {
+  sctor
+    .insts(db)
+    .iter()
+    .flat_map(move |inst| (0..inst.width(db)).map(move |i| (*inst, i)))
 }
 
 impl<'db> Rtor<'db> for Srtor<'db> {
-  fn accept(&mut self, side: lf_types::Side, inputs: crate::rtor::Inputs<'db>) -> bool {
-    todo!()
+  fn accept(&mut self, part: &[Inst], side: Side, inputs: &mut Inputs<'db>) {
+    if let [inst, rest @ ..] = part {
+      return self.accept_member((*inst, 0), rest, side, inputs);
+    }
+    for (member, rest) in exposed(self.db, self.sctor, side) {
+      self.accept_member(member, &rest, side, inputs);
+    }
   }
 
-  fn provide(&'db self, side: lf_types::Side, nesting: Nesting<RtorN>) -> crate::rtor::Inputs<'db> {
-    todo!()
+  fn provide(&self, part: &[Inst], side: Side) -> Inputs<'db> {
+    if let [inst, rest @ ..] = part {
+      return self.provide_member((*inst, 0), rest, side);
+    }
+    let ports: Vec<_> = exposed(self.db, self.sctor, side)
+      .flat_map(|(member, rest)| self.provide_member(member, &rest, side))
+      .collect();
+    Box::new(ports.into_iter())
   }
 
-  fn step_forward(&mut self, distance: u64) -> Option<lf_types::Net> {
-    todo!()
+  fn step_forward(&mut self, distance: u64) -> Option<lf_types::Tag> {
+    self.reach(self.tag.step_forward(distance), |child| {
+      child.step_forward(distance);
+    });
+    Some(self.tag.clone())
   }
 
   fn step_down(&mut self) {
-    todo!()
+    self.reach(self.tag.step_down(), |child| child.step_down());
   }
 
-  fn step_up(&mut self) -> Option<lf_types::Net> {
-    todo!()
+  fn step_up(&mut self) -> Option<lf_types::Tag> {
+    let tag = self.tag.step_up().unwrap_or_else(|| self.tag.clone());
+    self.reach(tag, |child| {
+      child.step_up();
+    });
+    Some(self.tag.clone())
+  }
+
+  fn next_event(&self) -> Option<lf_types::Tag> {
+    let children = self
+      .children
+      .iter()
+      .filter_map(|(_, child)| child.next_event());
+    // The values on connections without a delay are delivered by `deliver_undelayed`.
+    let delay_lines = self
+      .delay_lines
+      .iter()
+      .filter(|line| line.undelayed.is_none())
+      .filter_map(DelayLine::next_due);
+    children.chain(delay_lines).min()
   }
+
+  fn save(&self) -> RtorState {
+    RtorState {
+      tag: Some(self.tag.clone()),
+      delayed: self.delay_lines.iter().flat_map(DelayLine::save).collect(),
+      children: self
+        .children
+        .iter()
+        .map(|(_, child)| child.save())
+        .collect(),
+    }
+  }
+
+  fn restore(&mut self, state: &RtorState) -> Result<(), RestoreError> {
+    if state.children.len() != self.children.len() {
+      return Err(RestoreError::ChildCount {
+        saved: state.children.len(),
+        current: self.children.len(),
+      });
+    }
+    let tag = state.tag.clone().ok_or(RestoreError::MissingTag)?;
+    for delayed in &state.delayed {
+      match self
+        .delay_lines
+        .iter()
+        .find(|line| line.connection == delayed.connection)
+      {
+        None => return Err(RestoreError::UnknownConnection(delayed.connection)),
+        Some(line) if delayed.channel >= line.targets.len() as u64 => {
+          return Err(RestoreError::UnknownChannel {
+            connection: delayed.connection,
+            channel: delayed.channel,
+          })
+        }
+        Some(_) => {}
+      }
+    }
+    for ((_, child), state) in self.children.iter_mut().zip(&state.children) {
+      child.restore(state)?;
+    }
+    self.tag = tag;
+    for line in &self.delay_lines {
+      let mut in_flight = line.in_flight.borrow_mut();
+      in_flight.now = self.tag.clone();
+      in_flight.by_due.clear();
+      for delayed in state
+        .delayed
+        .iter()
+        .filter(|delayed| delayed.connection == line.connection)
+      {
+        in_flight
+          .by_due
+          .entry(delayed.due.clone())
+          .or_default()
+          .push((delayed.channel, delayed.value.clone()));
+      }
+    }
+    Ok(())
+  }
+
+  fn record(&mut self, recorder: &Rc<Recorder>, path: &[(InstId, u64)]) {
+    for (((_, i), child), id) in self.children.iter_mut().zip(&self.child_ids) {
+      child.record(recorder, &[path, &[(*id, *i)]].concat());
+    }
+    for line in &mut self.delay_lines {
+      line.recorder = Some((Rc::clone(recorder), path.to_vec()));
+    }
+  }
+}
+
+/// Realizes an instance of `sctor` and its children.
+fn realize_srtor(db: &dyn Db, sctor: StructlikeCtor) -> Result<Srtor<'_>, CausalityLoop> {
+  realize_partition(db, sctor, |_| true)
+}
+
+/// Realizes an instance of `sctor` with only the children for which `local` holds, as one federate
+/// of a federation. Delayed connections between these children and the others send to and receive
+/// from the other federates.
+pub(crate) fn realize_partition(
+  db: &dyn Db,
+  sctor: StructlikeCtor,
+  local: impl Fn(Inst) -> bool,
+) -> Result<Srtor<'_>, CausalityLoop> {
+  if let Err(causality_loop) = child_levels(db, sctor) {
+    return Err(causality_loop.clone());
+  }
+  let (child_ids, children) = bank_members(db, sctor)
+    .filter(|(inst, _)| local(*inst))
+    .map(|member| {
+      let args = member.0.args(db).iter().map(Literal::as_any).collect();
+      let rtor = iface_of(db, member.0.ctor(db)).realize(db, args)?;
+      Ok((member.0.id(db), (member, rtor)))
+    })
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .unzip();
+  let mut srtor = Srtor {
+    db,
+    sctor,
+    children,
+    child_ids,
+    tag: Tag::default(),
+    delay_lines: vec![],
+  };
+  for connection in sctor.connections(db) {
+    srtor.connect(*connection);
+  }
+  Ok(srtor)
+}
+
+/// A member of a bank of instances, identified by its index in the bank.
+type Member = (Inst, u64);
+
+/// An end of a connection, elaborated into the bank members that it selects.
+struct End<'a> {
+  inst: Inst,
+  rest: &'a [Inst],
+  first: u64,
+  n_members: u64,
+  /// The number of channels of the first selected member that precede the selection.
+  skip: u64,
+  /// The number of channels of each member.
+  member_width: u64,
+  /// The number of selected channels.
+  width: u64,
+}
+
+impl<'a> End<'a> {
+  /// Elaborates an end of a connection, or returns the width of the referenced port if `slice`
+  /// exceeds it.
+  fn new(db: &dyn Db, iref: &'a [Inst], slice: Option<Slice>, side: Side) -> Result<Self, u64> {
+    let [inst, rest @ ..] = iref else {
+      unreachable!("refs should never be empty if the ast passed parsing/validation")
+    };
+    let member_width = member_width(db, *inst, rest, side);
+    // Only a reference to a whole instance selects more than one member of a bank.
+    let bank = if rest.is_empty() { inst.width(db) } else { 1 };
+    let total = member_width * bank;
+    let slice = slice.unwrap_or(Slice {
+      start: 0,
+      end: total,
+    });
+    if slice.end > total {
+      return Err(total);
+    }
+    let (first, end) = match slice.start.checked_div(member_width) {
+      Some(first) => (first, slice.end.div_ceil(member_width)),
+      None => (0, bank),
+    };
+    Ok(End {
+      inst: *inst,
+      rest,
+      first,
+      n_members: end - first,
+      skip: slice.start - first * member_width,
+      member_width,
+      width: slice.width(),
+    })
+  }
+
+  fn members(&self) -> impl Iterator<Item = Member> {
+    let inst = self.inst;
+    (self.first..self.first + self.n_members).map(move |i| (inst, i))
+  }
+
+  /// Whether the selection consists of whole members.
+  fn is_aligned(&self) -> bool {
+    self.skip == 0 && self.width == self.n_members * self.member_width
+  }
+
+  /// The member that the `channel`th selected channel belongs to.
+  fn member_of(&self, channel: u64) -> Member {
+    (
+      self.inst,
+      self.first + (self.skip + channel) / self.member_width.max(1),
+    )
+  }
+
+  /// The port that the `channel`th selected channel stands for, as the path from the srtor to the
+  /// member that owns it and its channel within that member.
+  fn port_of(&self, db: &dyn Db, channel: u64) -> (MemberPath, u64) {
+    let (inst, i) = self.member_of(channel);
+    // A part of an instance is never a bank.
+    let path = std::iter::once((inst.id(db), i))
+      .chain(self.rest.iter().map(|inst| (inst.id(db), 0)))
+      .collect();
+    (path, (self.skip + channel) % self.member_width.max(1))
+  }
+}
+
+/// Reports the connections of `sctor` whose slices are out of range or whose ends select different
+/// numbers of channels.
+pub(crate) fn width_errors(db: &dyn Db, sctor: StructlikeCtor) -> Vec<TypeError> {
+  let mut ret = vec![];
+  for connection in sctor.connections(db) {
+    let lref = connection.left(db).iref(db);
+    let rref = connection.right(db).iref(db);
+    let ends = [
+      (&lref, connection.left_slice(db), Side::Right),
+      (&rref, connection.right_slice(db), Side::Left),
+    ]
+    .map(|(iref, slice, side)| {
+      End::new(db, iref, slice, side).map_err(|width| TypeError::SliceOutOfRange {
+        ctor: sctor.id(db),
+        connection: connection.id(db),
+        slice: slice.unwrap(),
+        width,
+      })
+    });
+    match ends {
+      [Ok(upstream), Ok(downstream)] if upstream.width != downstream.width => {
+        ret.push(TypeError::Width {
+          ctor: sctor.id(db),
+          connection: connection.id(db),
+          left: upstream.width,
+          right: downstream.width,
+        })
+      }
+      ends => ret.extend(ends.into_iter().filter_map(Result::err)),
+    }
+  }
+  ret
 }
 
+/// The inputs that a member of the upstream bank of a connection accepts when the channels that it
+/// selects need not line up with the members of the downstream bank. The selection may start or end
+/// in the middle of the ports of the member, and the channels outside of it accept nothing.
+#[derive(Clone)]
+struct Window<'a> {
+  /// The inputs of the downstream members that the remaining selected channels come from, in
+  /// order.
+  sources: VecDeque<InputsIface<'a>>,
+  /// The number of unselected channels before the selection.
+  before: u64,
+  /// The number of selected channels that remain.
+  take: u64,
+  /// The number of unselected channels after the selection.
+  after: u64,
+  nesting: Nesting<RtorN>,
+}
+
+impl<'a> Window<'a> {
+  fn new(sources: VecDeque<InputsIface<'a>>, width: u64) -> Self {
+    Window {
+      sources,
+      before: 0,
+      take: width,
+      after: 0,
+      nesting: Nesting::default(),
+    }
+  }
+
+  fn unselected() -> ComptimeInput {
+    ComptimeInput::Data(Rc::new(|_| FixpointingStatus::Unchanged))
+  }
+}
+
+impl Iterator for Window<'_> {
+  type Item = ComptimeInput;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.before > 0 {
+      self.before -= 1;
+      return Some(Self::unselected());
+    }
+    while self.take > 0 {
+      match self.sources.front_mut()?.next() {
+        Some(item) => {
+          if let ComptimeInput::Data(_) = item {
+            self.take -= 1;
+          }
+          return Some(item);
+        }
+        None => {
+          self.sources.pop_front();
+        }
+      }
+    }
+    if self.after > 0 {
+      self.after -= 1;
+      return Some(Self::unselected());
+    }
+    None
+  }
+}
+
+impl<'a> ConnectionIterator<'a> for Window<'a> {
+  type N = RtorN;
+
+  fn current_nesting(&self) -> &Nesting<RtorN> {
+    self
+      .sources
+      .front()
+      .map_or(&self.nesting, |source| source.current_nesting())
+  }
+}
+
+/// The members that each member accepts inputs from, and whose levels it may therefore raise.
+type Downstreams = HashMap<Member, Vec<Member>>;
+
 fn connect<'db>(
   db: &dyn Db,
-  children: &mut HashMap<Inst, Box<dyn RtorComptime<'db> + 'db>>,
+  children: &mut HashMap<Member, Box<dyn RtorComptime<'db> + 'db>>,
   sctor: StructlikeCtor,
-) {
-  let mut connect =
-    |ref_accept: Vec<Inst>, ref_provide: Vec<Inst>, side_accept: Side, side_provide: Side| {
-      let mut provide1: InputsIface =
-        children[&ref_provide[0]].provide(&ref_provide[1..], side_accept, Nesting::default());
-      children.get_mut(&ref_accept[0]).unwrap().accept(
-        &ref_accept[1..],
-        side_provide,
-        &mut provide1,
-      )
+  trace: Option<&Rc<Trace>>,
+) -> Downstreams {
+  let mut downstreams = Downstreams::new();
+  let provide = |children: &HashMap<Member, Box<dyn RtorComptime<'db> + 'db>>,
+                 end: &End,
+                 member: Member,
+                 skip: u64,
+                 id| {
+    let inputs = children[&member].provide(end.rest, Side::Left, Nesting::default());
+    let mut inputs: InputsIface = match trace {
+      Some(trace) => pmap(inputs, trace.attribute(id)),
+      None => inputs,
     };
+    for _ in 0..skip {
+      // Only data items are channels.
+      while let Some(ComptimeInput::Notify(_)) = inputs.next() {}
+    }
+    inputs
+  };
   for connection in sctor.connections(db) {
+    // The left end accepts on its right side the inputs that the right end provides on its left
+    // side.
     let lref = connection.left(db).iref(db);
     let rref = connection.right(db).iref(db);
-    connect(lref, rref, Side::Left, Side::Right);
+    let (Ok(upstream), Ok(downstream)) = (
+      End::new(db, &lref, connection.left_slice(db), Side::Right),
+      End::new(db, &rref, connection.right_slice(db), Side::Left),
+    ) else {
+      unreachable!("typecheck rejects slices that are out of range")
+    };
+    if connection.is_delayed(db) {
+      // Values that arrive at a later tag do not constrain the order of execution within a tag.
+      continue;
+    }
+    if !upstream.is_aligned() {
+      connect_unaligned(
+        children,
+        &upstream,
+        &downstream,
+        &mut downstreams,
+        |children, d, skip| provide(children, &downstream, d, skip, connection.id(db)),
+      );
+    } else if upstream.n_members == downstream.n_members && downstream.is_aligned() {
+      for (u, d) in upstream.members().zip(downstream.members()) {
+        let mut inputs = provide(children, &downstream, d, downstream.skip, connection.id(db));
+        children
+          .get_mut(&u)
+          .unwrap()
+          .accept(upstream.rest, Side::Right, &mut inputs);
+        downstreams.entry(u).or_default().push(d);
+      }
+    } else if downstream.n_members == 1 {
+      // The members of the upstream bank take turns accepting from the same downstream multiport.
+      let d = (downstream.inst, downstream.first);
+      let mut inputs = provide(children, &downstream, d, downstream.skip, connection.id(db));
+      for u in upstream.members() {
+        children
+          .get_mut(&u)
+          .unwrap()
+          .accept(upstream.rest, Side::Right, &mut inputs);
+        downstreams.entry(u).or_default().push(d);
+      }
+    } else {
+      connect_unaligned(
+        children,
+        &upstream,
+        &downstream,
+        &mut downstreams,
+        |children, d, skip| provide(children, &downstream, d, skip, connection.id(db)),
+      );
+    }
   }
+  downstreams
 }
 
-fn fixpoint<'db>(children: &mut HashMap<Inst, Box<dyn RtorComptime + 'db>>) {
-  let mut changed = FixpointingStatus::Unchanged;
-  for (_, child) in children.iter_mut() {
-    changed |= child.iterate_levels();
+/// Connects the members of `upstream` to the channels of `downstream` in order, one channel at a
+/// time, for selections whose members do not line up. `provide` gives the inputs of a downstream
+/// member after skipping the given number of its channels.
+fn connect_unaligned<'db>(
+  children: &mut HashMap<Member, Box<dyn RtorComptime<'db> + 'db>>,
+  upstream: &End,
+  downstream: &End,
+  downstreams: &mut Downstreams,
+  provide: impl Fn(&HashMap<Member, Box<dyn RtorComptime<'db> + 'db>>, Member, u64) -> InputsIface<'db>,
+) {
+  let sources = downstream
+    .members()
+    .enumerate()
+    .map(|(i, d)| provide(children, d, if i == 0 { downstream.skip } else { 0 }))
+    .collect();
+  let mut cursor = Window::new(sources, downstream.width);
+  let mut channel = 0;
+  for (i, u) in upstream.members().enumerate() {
+    let before = if i == 0 { upstream.skip } else { 0 };
+    let take = (upstream.member_width - before).min(upstream.width - channel);
+    let mut inputs: InputsIface = Box::new(Window {
+      before,
+      take,
+      after: upstream.member_width - before - take,
+      ..cursor.clone()
+    });
+    children
+      .get_mut(&u)
+      .unwrap()
+      .accept(upstream.rest, Side::Right, &mut inputs);
+    let ds = downstreams.entry(u).or_default();
+    for c in channel..channel + take {
+      let d = downstream.member_of(c);
+      if !ds.contains(&d) {
+        ds.push(d);
+      }
+    }
+    for _ in 0..take {
+      // Only data items are channels.
+      while let Some(ComptimeInput::Notify(_)) = cursor.next() {}
+    }
+    channel += take;
+  }
+}
+
+/// A cycle of connections along which levels must keep increasing, so that no level assignment is
+/// compatible with the order in which the connected instances are to execute.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CausalityLoop {
+  pub ctor: CtorId,
+  pub insts: Vec<InstId>,
+  pub connections: Vec<DebugOnlyId>,
+}
+
+impl Display for CausalityLoop {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let join = |ids: Vec<String>| ids.join(", ");
+    write!(
+      f,
+      "causality loop in ctor {} through instances {} and connections {}",
+      self.ctor,
+      join(self.insts.iter().map(ToString::to_string).collect()),
+      join(self.connections.iter().map(ToString::to_string).collect())
+    )
   }
-  // todo: what if the children arrive at a level assignment that is incompatible with the ambient
-  // TPO? You must add more constraints
-  if changed == FixpointingStatus::Changed {
-    fixpoint(children);
+}
+
+/// Assigns the levels of the children of `sctor`, recording why they were raised if `tracer`, which
+/// traces the instance whose ctor is `sctor`, is given.
+pub(crate) fn assign_levels<'db>(
+  db: &'db dyn Db,
+  sctor: StructlikeCtor,
+  tracer: Option<&Tracer>,
+) -> Result<HashMap<Member, Box<dyn RtorComptime<'db> + 'db>>, CausalityLoop> {
+  structural_loop(db, sctor)?;
+  let mut children: HashMap<Member, Box<dyn RtorComptime<'db> + 'db>> = bank_members(db, sctor)
+    .map(|member| (member, iface_of(db, member.0.ctor(db)).comptime_realize(db)))
+    .collect();
+  if let Some(tracer) = tracer {
+    for (member, child) in children.iter_mut() {
+      child.trace(tracer.child(member.0.id(db), member.1));
+    }
+  }
+  let downstreams = connect(db, &mut children, sctor, tracer.map(|tracer| &tracer.trace));
+  bound_by_iface(db, sctor, &mut children);
+  fixpoint(db, sctor, &mut children, &downstreams)?;
+  Ok(children)
+}
+
+/// Lower-bounds the levels of each child by the level at which the iface of `sctor` exposes it,
+/// which is after the levels of the siblings that precede it on the same side.
+fn bound_by_iface<'db>(
+  db: &dyn Db,
+  sctor: StructlikeCtor,
+  children: &mut HashMap<Member, Box<dyn RtorComptime<'db> + 'db>>,
+) {
+  for side in [Side::Left, Side::Right] {
+    let elements = StartingIntrinsicLevelProvider::new(
+      iface(sctor, db, SideMatch::One(side)).map(|elt| {
+        elt.map(|iref| {
+          let iref = iref.iref(db).clone();
+          (iface_of(db, iref[0].ctor(db)), iref)
+        })
+      }),
+      db,
+      SideMatch::One(side),
+    );
+    let mut next_member = HashMap::new();
+    for (level, elt) in elements {
+      let Comm::Data((_, iref)) = elt else {
+        continue;
+      };
+      let (member, rest) = exposed_member(&mut next_member, &iref);
+      children
+        .get_mut(&member)
+        .unwrap()
+        .lower_bound(&rest, side, level, FlowDirection::In);
+    }
+  }
+}
+
+/// Iterates the levels of `children` until they stop changing.
+fn fixpoint<'db>(
+  db: &dyn Db,
+  sctor: StructlikeCtor,
+  children: &mut HashMap<Member, Box<dyn RtorComptime<'db> + 'db>>,
+  downstreams: &Downstreams,
+) -> Result<(), CausalityLoop> {
+  worklist(children, downstreams).map_err(|looping| {
+    let looping = looping.into_iter().map(|member| member.0).collect();
+    causality_loop(db, sctor, &looping, &undelayed_edges(db, sctor, |_| true))
+  })
+}
+
+/// An undelayed connection from the instance of its left end to that of its right end.
+type Edge = (Inst, Inst, DebugOnlyId);
+
+/// The undelayed connections of `sctor` whose left ends are instances for which `from` holds.
+fn undelayed_edges(db: &dyn Db, sctor: StructlikeCtor, from: impl Fn(Inst) -> bool) -> Vec<Edge> {
+  sctor
+    .connections(db)
+    .iter()
+    .filter(|c| !c.is_delayed(db))
+    .map(|c| (c.left(db).iref(db)[0], c.right(db).iref(db)[0], c.id(db)))
+    .filter(|(left, _, _)| from(*left))
+    .collect()
+}
+
+/// Whether an instance of `ctor` may write to its right side at the same tag as it reads from its
+/// left side. Lib rtors react to their inputs as soon as they are set.
+fn feeds_through(db: &dyn Db, ctor: &Ctor) -> bool {
+  match ctor {
+    Ctor::StructlikeCtor(sctor) => sctor_feeds_through(db, *sctor),
+    Ctor::BinaryCtor(_) | Ctor::LibCtor(_) => true,
+  }
+}
+
+/// Whether some instance that `sctor` exposes on its left side reaches one that it exposes on its
+/// right side along undelayed connections and through instances that feed through.
+#[salsa::tracked]
+pub fn sctor_feeds_through(db: &dyn crate::Db, sctor: StructlikeCtor) -> bool {
+  let exposed_insts = |side| -> HashSet<Inst> {
+    exposed(db, sctor, side)
+      .map(|((inst, _), _)| inst)
+      .collect()
+  };
+  let outputs = exposed_insts(Side::Right);
+  let edges = undelayed_edges(db, sctor, |inst| feeds_through(db, inst.ctor(db)));
+  let mut seen = exposed_insts(Side::Left);
+  let mut stack: Vec<Inst> = seen.iter().copied().collect();
+  while let Some(inst) = stack.pop() {
+    if outputs.contains(&inst) && feeds_through(db, inst.ctor(db)) {
+      return true;
+    }
+    for &(left, right, _) in &edges {
+      if left == inst && seen.insert(right) {
+        stack.push(right);
+      }
+    }
+  }
+  false
+}
+
+/// The level within a tag at which `inst`, a child of `sctor`, receives the values sent to it
+/// without a delay from another federate: the number of undelayed connections on the longest path of
+/// them that reaches it, where a path only continues through instances that feed through. The
+/// instances of a federate step at level 0, so every instance receives after those that send to it.
+pub(crate) fn undelayed_depth(db: &dyn Db, sctor: StructlikeCtor, inst: Inst) -> Level {
+  let edges = undelayed_edges(db, sctor, |_| true);
+  let mut depth: HashMap<Inst, u32> = HashMap::new();
+  // The graph of the edges from instances that feed through has no cycles, so the depths settle
+  // within one round per instance.
+  for _ in 0..sctor.insts(db).len() {
+    for &(left, right, _) in &edges {
+      let reached = if feeds_through(db, left.ctor(db)) {
+        depth.get(&left).copied().unwrap_or(0) + 1
+      } else {
+        1
+      };
+      let entry = depth.entry(right).or_default();
+      *entry = (*entry).max(reached);
+    }
+  }
+  Level(depth.get(&inst).copied().unwrap_or(0).max(1))
+}
+
+/// Finds a cycle of undelayed connections in `sctor` along which every instance feeds through, so
+/// that each instance would have to react after itself within a tag.
+fn structural_loop(db: &dyn Db, sctor: StructlikeCtor) -> Result<(), CausalityLoop> {
+  let edges = undelayed_edges(db, sctor, |inst| feeds_through(db, inst.ctor(db)));
+  // Kahn's algorithm removes every instance that is not on or after such a cycle.
+  let mut in_degree: HashMap<Inst, usize> = sctor.insts(db).iter().map(|inst| (*inst, 0)).collect();
+  for (_, right, _) in &edges {
+    *in_degree.get_mut(right).unwrap() += 1;
+  }
+  let mut ready: Vec<Inst> = in_degree
+    .iter()
+    .filter(|(_, degree)| **degree == 0)
+    .map(|(inst, _)| *inst)
+    .collect();
+  while let Some(inst) = ready.pop() {
+    in_degree.remove(&inst);
+    for (_, right, _) in edges.iter().filter(|(left, _, _)| *left == inst) {
+      let degree = in_degree.get_mut(right).unwrap();
+      *degree -= 1;
+      if *degree == 0 {
+        ready.push(*right);
+      }
+    }
+  }
+  if in_degree.is_empty() {
+    Ok(())
+  } else {
+    let looping = in_degree.into_keys().collect();
+    Err(causality_loop(db, sctor, &looping, &edges))
+  }
+}
+
+/// Iterates the levels of `children` until they stop changing, re-evaluating a child only after a
+/// child that may have raised its levels has changed.
+///
+/// Unless there is a causality loop, no child needs to be evaluated more times than there are
+/// levels in total (as in Bellman-Ford with a FIFO queue). Otherwise, the children that were
+/// evaluated more than once are returned.
+fn worklist<'db, K: Copy + Eq + Hash>(
+  children: &mut HashMap<K, Box<dyn RtorComptime<'db> + 'db>>,
+  downstreams: &HashMap<K, Vec<K>>,
+) -> Result<(), HashSet<K>> {
+  let n_levels: usize = children
+    .values()
+    .map(|child| child.levels().len() + 1)
+    .sum();
+  let mut queue: VecDeque<K> = children.keys().copied().collect();
+  let mut queued: HashSet<K> = queue.iter().copied().collect();
+  let mut evaluations: HashMap<K, usize> = HashMap::new();
+  while let Some(k) = queue.pop_front() {
+    queued.remove(&k);
+    let count = evaluations.entry(k).or_default();
+    *count += 1;
+    if *count > n_levels + 1 {
+      return Err(
+        evaluations
+          .into_iter()
+          .filter(|(_, count)| *count > 1)
+          .map(|(k, _)| k)
+          .collect(),
+      );
+    }
+    if children.get_mut(&k).unwrap().iterate_levels() == FixpointingStatus::Changed {
+      for d in downstreams.get(&k).into_iter().flatten() {
+        if queued.insert(*d) {
+          queue.push_back(*d);
+        }
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Finds the cycles of `edges` in `sctor` that pass through any of the instances in `changed`.
+fn causality_loop(
+  db: &dyn Db,
+  sctor: StructlikeCtor,
+  changed: &HashSet<Inst>,
+  edges: &[Edge],
+) -> CausalityLoop {
+  let reach = |from: Inst| {
+    let mut seen = HashSet::new();
+    let mut stack = vec![from];
+    while let Some(inst) = stack.pop() {
+      for &(left, right, _) in edges {
+        if left == inst && seen.insert(right) {
+          stack.push(right);
+        }
+      }
+    }
+    seen
+  };
+  let reaches: HashMap<Inst, HashSet<Inst>> = sctor
+    .insts(db)
+    .iter()
+    .map(|inst| (*inst, reach(*inst)))
+    .collect();
+  let same_cycle = |a: &Inst, b: &Inst| reaches[a].contains(b) && reaches[b].contains(a);
+  let mut insts: Vec<_> = sctor
+    .insts(db)
+    .iter()
+    .copied()
+    .filter(|inst| changed.iter().any(|c| same_cycle(inst, c)))
+    .map(|inst| inst.id(db))
+    .collect();
+  insts.sort();
+  let mut connections: Vec<_> = edges
+    .iter()
+    .filter(|(left, right, _)| {
+      same_cycle(left, right) && changed.iter().any(|c| same_cycle(left, c))
+    })
+    .map(|(_, _, id)| *id)
+    .collect();
+  connections.sort();
+  CausalityLoop {
+    ctor: sctor.id(db),
+    insts,
+    connections,
   }
 }
 
 /// An iterator over the ifaces of selected parts of a side.
-struct StartingIntrinsicLevelProvider<'a, T, I: Iterator<Item = Comm<(Box<dyn RtorIface + 'a>, T)>>>
-{
+struct StartingIntrinsicLevelProvider<'a, T, I: Iterator<Item = Comm<(Box<dyn RtorIface>, T)>>> {
   it: I,
   db: &'a dyn Db,
   side: SideMatch,
   current_level: Level,
 }
 
-impl<'a, T, I: Iterator<Item = Comm<(Box<dyn RtorIface + 'a>, T)>>>
+impl<'a, T, I: Iterator<Item = Comm<(Box<dyn RtorIface>, T)>>>
   StartingIntrinsicLevelProvider<'a, T, I>
 {
   fn new(it: I, db: &'a dyn Db, side: SideMatch) -> Self {
@@ -173,22 +1317,19 @@ impl<'a, T, I: Iterator<Item = Comm<(Box<dyn RtorIface + 'a>, T)>>>
   }
 }
 
-impl<'a, T, I: Iterator<Item = Comm<(Box<dyn RtorIface + 'a>, T)>>> Iterator
+impl<'a, T, I: Iterator<Item = Comm<(Box<dyn RtorIface>, T)>>> Iterator
   for StartingIntrinsicLevelProvider<'a, T, I>
 {
-  type Item = (Level, Box<dyn RtorIface + 'a>, T);
+  type Item = (Level, Comm<(Box<dyn RtorIface>, T)>);
 
   fn next(&mut self) -> Option<Self::Item> {
     let ret = self.it.next()?;
-    if let Comm::Data((ret, propagate)) = ret {
-      let current_level = self.current_level;
-      let delta = ret.n_levels(self.db, self.side);
-      self.current_level += delta;
-      Some((current_level, ret, propagate))
-    } else {
-      self.current_level += Level(1);
-      self.next()
-    }
+    let current_level = self.current_level;
+    self.current_level += match &ret {
+      Comm::Data((iface, _)) => iface.n_levels(self.db, self.side),
+      Comm::Notify => Level(1),
+    };
+    Some((current_level, ret))
   }
 }
 
@@ -201,15 +1342,19 @@ fn sequence_max<'a>(a: &'a [Inst], b: &'a [Inst]) -> Option<&'a [Inst]> {
   }
 }
 
+/// Raises the levels to which `map` maps `intrinsic_level` and the levels after it to at least
+/// `lower_bound`, passing each raised intrinsic level and its old and new levels to `on_bump`.
 fn adjust(
   map: &mut BTreeMap<Level, Level>,
-  mut lower_bound: Level,
+  lower_bound: Level,
   intrinsic_level: Level,
+  mut on_bump: impl FnMut(Level, Level, Level),
 ) -> FixpointingStatus {
   let mut changed = FixpointingStatus::Unchanged;
-  for (_, l) in map.range_mut(intrinsic_level..) {
+  // The map is monotonic, so raising one level raises the ones after it too.
+  for (intrinsic_level, l) in map.range_mut(intrinsic_level..) {
     if *l < lower_bound {
-      lower_bound = *l + Level(1);
+      on_bump(*intrinsic_level, *l, lower_bound);
       *l = lower_bound;
       changed = FixpointingStatus::Changed;
     } else {
@@ -227,6 +1372,72 @@ fn rest_or_empty<T>(slice: &[T]) -> &[T] {
   }
 }
 
+/// The inputs that an srtor provides, which level the entries of its iface at the intrinsic levels
+/// that the iface provides.
+#[derive(Clone)]
+struct Levelled<'a> {
+  levels: LevelIterator<'a>,
+  map: Rc<RefCell<BTreeMap<Level, Level>>>,
+  tracer: Option<Tracer>,
+  /// The first intrinsic level after the last data entry provided so far.
+  after_data: Level,
+}
+
+impl Levelled<'_> {
+  fn leveller(&self, intrinsic_level: Level) -> Leveller {
+    let map = Rc::clone(&self.map);
+    let tracer = self.tracer.clone();
+    Rc::new(move |lower_bound: Comm<Level>| match lower_bound {
+      Comm::Data(lower_bound) => {
+        let mut record = tracer
+          .as_ref()
+          .map(|tracer| tracer.record(tracer.input_cause(), FlowDirection::In, lower_bound));
+        adjust(
+          &mut map.borrow_mut(),
+          lower_bound,
+          intrinsic_level,
+          |level, from, to| {
+            if let Some(record) = &mut record {
+              record(level, from, to);
+            }
+          },
+        )
+      }
+      Comm::Notify => FixpointingStatus::Unchanged,
+    })
+  }
+}
+
+impl Iterator for Levelled<'_> {
+  type Item = ComptimeInput;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    Some(match self.levels.next()? {
+      // A notify comes after the data entries before it, so raising it raises the entries after
+      // those data entries.
+      Comm::Notify => ComptimeInput::Notify(self.leveller(self.after_data)),
+      Comm::Data(intrinsic_level) => {
+        self.after_data = intrinsic_level + Level(1);
+        ComptimeInput::Data(self.leveller(intrinsic_level))
+      }
+    })
+  }
+}
+
+impl<'a> ConnectionIterator<'a> for Levelled<'a> {
+  type N = RtorN;
+
+  fn current_nesting(&self) -> &Nesting<RtorN> {
+    self.levels.current_nesting()
+  }
+}
+
+impl<'a> ProvidingConnectionIterator<'a> for Levelled<'a> {
+  fn finish(self: Box<Self>) -> Nesting<RtorN> {
+    self.levels.finish()
+  }
+}
+
 impl<'a> RtorComptime<'a> for SrtorComptime<'a> {
   fn iterate_levels(&mut self) -> FixpointingStatus {
     // Do the accept. If fixpointing is just the same as doing the accept, then maybe we should not
@@ -242,23 +1453,21 @@ impl<'a> RtorComptime<'a> for SrtorComptime<'a> {
         *side,
         &mut map(
           &mut cloned_inputs,
-          Rc::new(
-            closure!(clone levels_map, |f: Comm<Rc<dyn Fn(Comm<Level>) -> FixpointingStatus>>| {
-              f.map(|f: &Rc<dyn Fn(Comm<Level>) -> FixpointingStatus>| {
-                let f = Rc::clone(f);
-                // Note the extraction of `ret` into a local variable with a type annotation when it
-                // could equivalently be returned immediately. This is either a bug or a weird
-                // limitation of Rust's type inference system.
-                let ret: Rc<dyn Fn(Comm<Level>) -> FixpointingStatus> = Rc::new(
-                  closure!(clone levels_map, |intrinsic_lower_bound: Comm<Level>| {
-                    (*f)(intrinsic_lower_bound.map(
-                      |intrinsic_lower_bound| (*levels_map.borrow())[intrinsic_lower_bound]
-                    ))
-                  }));
-                ret
-              })
-            }),
-          ),
+          Rc::new(closure!(clone levels_map, |f: ComptimeInput| {
+            f.map(|f: &Rc<dyn Fn(Comm<Level>) -> FixpointingStatus>| {
+              let f = Rc::clone(f);
+              // Note the extraction of `ret` into a local variable with a type annotation when it
+              // could equivalently be returned immediately. This is either a bug or a weird
+              // limitation of Rust's type inference system.
+              let ret: Rc<dyn Fn(Comm<Level>) -> FixpointingStatus> = Rc::new(
+                closure!(clone levels_map, |intrinsic_lower_bound: Comm<Level>| {
+                  (*f)(intrinsic_lower_bound.map(
+                    |intrinsic_lower_bound| (*levels_map.borrow())[intrinsic_lower_bound]
+                  ))
+                }));
+              ret
+            })
+          })),
         ),
         &mut deferred,
       );
@@ -287,24 +1496,14 @@ impl<'a> RtorComptime<'a> for SrtorComptime<'a> {
     side: lf_types::Side,
     nesting: Nesting<RtorN>,
   ) -> ProvidingInputsIface<'a> {
-    let mymap = Rc::clone(&self.levels_internal2external);
-    let f = Rc::new(move |intrinsic_level: Comm<Level>| {
-      let intrinsic_level = intrinsic_level.clone();
-      let mymap = Rc::clone(&mymap);
-      let f = move |lower_bound: Comm<Level>| match (lower_bound, intrinsic_level.clone()) {
-        (Comm::Data(lower_bound), Comm::Data(intrinsic_level)) => {
-          adjust(&mut mymap.borrow_mut(), lower_bound, intrinsic_level)
-        }
-        _ => FixpointingStatus::Unchanged,
-      };
-      ComptimeInput::Data(Rc::new(f)) as ComptimeInput
-    });
-    pmap(
-      self
+    Box::new(Levelled {
+      levels: self
         .iface
         .immut_provide(self.db, part, side, Level(0), nesting),
-      f,
-    )
+      map: Rc::clone(&self.levels_internal2external),
+      tracer: self.tracer.clone(),
+      after_data: Level(0),
+    })
   }
 
   fn lower_bound(
@@ -315,18 +1514,36 @@ impl<'a> RtorComptime<'a> for SrtorComptime<'a> {
     last_direction: FlowDirection,
   ) {
     // TODO: is side_exact the right one?
-    if let Some((level, _iface)) = self.iface.side_exact(self.db, side, part).next() {
+    if let Some((level, _iface)) = self
+      .iface
+      .side_exact(self.db, side, part)
+      .find(|(_, iface)| matches!(iface, Comm::Data(_)))
+    {
+      let lower_bound = if last_direction == FlowDirection::In {
+        lower_bound
+      } else {
+        lower_bound + Level(1)
+      };
+      let mut record = self
+        .tracer
+        .as_ref()
+        .map(|tracer| tracer.record(Cause::LowerBound, last_direction, lower_bound));
       adjust(
         &mut self.levels_internal2external.borrow_mut(),
-        if last_direction == FlowDirection::In {
-          lower_bound
-        } else {
-          lower_bound + Level(1)
-        },
+        lower_bound,
         level,
+        |level, from, to| {
+          if let Some(record) = &mut record {
+            record(level, from, to);
+          }
+        },
       );
     }
   }
+
+  fn trace(&mut self, tracer: Tracer) {
+    self.tracer = Some(tracer);
+  }
 }
 
 impl RtorIface for SrtorIface {
@@ -341,32 +1558,33 @@ impl RtorIface for SrtorIface {
     let mut changed = FixpointingStatus::Unchanged;
     for (starting_intrinsic_level, iface) in self.side_exact(db, side, part) {
       if let Comm::Data(iface) = iface {
+        // Notifys are left in the frame of `deferred_notifys`, which records the levels of the data
+        // entries relative to that frame.
+        let outer_offset = deferred_notifys.offset;
+        deferred_notifys.offset = outer_offset + starting_intrinsic_level;
         changed |= iface.immut_accept(
           db,
           rest_or_empty(part),
           side,
           &mut map(
             inputs_iface,
-            Rc::new(move |it| {
-              it.map(|it| {
-                // another case where the variable had to be factored out in order for it to type-check. Why?
-                let it: Rc<dyn Fn(Comm<Level>) -> FixpointingStatus> = it.clone();
-                let c: Rc<dyn Fn(Comm<Level>) -> FixpointingStatus> =
-                  Rc::new(move |level: Comm<Level>| {
-                    let ret: FixpointingStatus =
-                      it(level.map(|level| *level + starting_intrinsic_level));
-                    ret
-                  });
-                c
-              })
+            Rc::new(move |it| match it {
+              ComptimeInput::Notify(_) => it.clone(),
+              ComptimeInput::Data(it) => {
+                let it = it.clone();
+                ComptimeInput::Data(Rc::new(move |level: Comm<Level>| {
+                  it(level.map(|level| *level + starting_intrinsic_level))
+                }))
+              }
             }),
           ),
           deferred_notifys,
         );
-
-        // TODO: consume all level increments which are now at the head of the sequence and use them
-        // to consume all the deferred notifys
+        deferred_notifys.offset = outer_offset;
       }
+      // Everything consumed so far has been levelled, either by the child above or by an earlier
+      // one, so no notify needs to wait any longer.
+      changed |= resolve_notifys(inputs_iface, deferred_notifys);
     }
     changed
   }
@@ -383,7 +1601,12 @@ impl RtorIface for SrtorIface {
     let rc_part = Rc::new(part.to_vec());
     for (starting_intrinsic_level, iface) in self.side_exact(db, side, part) {
       match iface {
-        Comm::Notify => todo!(),
+        Comm::Notify => {
+          let this = *self;
+          sub_iterators.push(Rc::new(move |nesting| {
+            iterator_new(nesting, Box::new(this), vec![Comm::Notify])
+          }));
+        }
         Comm::Data(iface) => {
           let rc_part = rc_part.clone();
           sub_iterators.push(Rc::new(move |nesting| {
@@ -405,16 +1628,8 @@ impl RtorIface for SrtorIface {
     &self,
     db: &'db dyn Db,
     _inst_time_args: Vec<&'db dyn std::any::Any>,
-  ) -> Box<dyn Rtor<'db> + 'db> {
-    let mut children = self
-      .sctor(db)
-      .insts(db)
-      .iter()
-      .map(|inst| (*inst, iface_of(db, inst.ctor(db)).comptime_realize(db)))
-      .collect();
-    connect(db, &mut children, self.sctor(db));
-    fixpoint(&mut children);
-    todo!("this can be implemented later after the level assignment algorithm passes unit tests")
+  ) -> Result<Box<dyn Rtor<'db> + 'db>, CausalityLoop> {
+    Ok(Box::new(realize_srtor(db, self.sctor(db))?))
   }
 
   fn comptime_realize<'db>(&self, db: &'db dyn Db) -> Box<dyn RtorComptime<'db> + 'db> {
@@ -428,62 +1643,25 @@ impl RtorIface for SrtorIface {
     side: Side,
     starting_level: Level,
   ) -> HashSet<Level> {
-    let mut ret = HashSet::new();
-    for (starting_intrinsic_level, iface) in self.side_exact(db, side, part) {
-      match iface {
-        Comm::Notify => {
-          ret.insert(starting_intrinsic_level);
-        }
-        Comm::Data(iface) => {
-          ret.extend(iface.immut_provide_unique(
-            db,
-            rest_or_empty(part),
-            side,
-            starting_level + starting_intrinsic_level,
-          ));
-        }
-      }
-    }
-    ret
+    let key = SideKey::new(db, *self, SideMatch::One(side), part.to_vec());
+    srtor_provide_unique(db, key)
+      .iter()
+      .map(|level| starting_level + *level)
+      .collect()
   }
+
   fn side<'db>(
     &self,
     db: &'db dyn Db,
     side: SideMatch,
     part: &[Inst],
-  ) -> Box<dyn Iterator<Item = (Level, SideMatch, Comm<Box<dyn RtorIface + 'db>>)> + 'db> {
-    let part = part.to_vec();
-    let ifaces = StartingIntrinsicLevelProvider::new(
-      iface(self.sctor(db), db, side).map(|child| {
-        child.map(|child| {
-          let [child, tail @ ..] = &child.iref(db)[..] else {
-            unreachable!("refs should never be empty if the ast passed parsing/validation")
-          };
-          let child = iface_of(db, child.ctor(db));
-          (child, tail.to_vec())
-        })
-      }),
-      db,
-      side,
-    )
-    .filter_map(closure!(clone side, clone part, |(level, child, tail)| {
-      let longer = sequence_max(&tail, rest_or_empty(&part))?;
-      let ret: FuzzySideIterator<'db> = child.side(db, side, longer);
-      Some(ret.map(move |(level_unadjusted, sm, iface)| (level_unadjusted + level, sm, iface)))
-    }))
-    .flatten();
-    Box::new(ifaces)
+  ) -> Box<dyn Iterator<Item = (Level, SideMatch, Comm<Box<dyn RtorIface>>)> + 'db> {
+    let key = SideKey::new(db, *self, side, part.to_vec());
+    Box::new(srtor_side(db, key).iter().cloned())
   }
 
   fn n_levels(&self, db: &dyn Db, side: SideMatch) -> Level {
-    // FIXME: there is some duplication here with side
-    StartingIntrinsicLevelProvider::new(
-      iface(self.sctor(db), db, side)
-        .map(|it| it.map(|it| (iface_of(db, it.iref(db)[0].ctor(db)), ()))),
-      db,
-      side,
-    )
-    .n_levels()
+    srtor_n_levels(db, SideKey::new(db, *self, side, vec![]))
   }
 
   fn iface_id(&self) -> u128 {
@@ -493,8 +1671,778 @@ impl RtorIface for SrtorIface {
   }
 }
 
+/// Selects the given side of the given part of an srtor.
+#[salsa::interned]
+pub struct SideKey {
+  iface: SrtorIface,
+  side: SideMatch,
+  part: Vec<Inst>,
+}
+
+/// The levels at which the selected part of an srtor is to receive a TAGL, relative to the starting
+/// level of the srtor.
+#[salsa::tracked(return_ref)]
+pub fn srtor_provide_unique(db: &dyn crate::Db, key: SideKey) -> HashSet<Level> {
+  let SideMatch::One(side) = key.side(db) else {
+    unreachable!("levels are provided on one side at a time")
+  };
+  let part = key.part(db);
+  let mut ret = HashSet::new();
+  for (starting_intrinsic_level, iface) in key.iface(db).side_exact(db, side, &part) {
+    match iface {
+      Comm::Notify => {
+        ret.insert(starting_intrinsic_level);
+      }
+      Comm::Data(iface) => {
+        ret.extend(iface.immut_provide_unique(
+          db,
+          rest_or_empty(&part),
+          side,
+          starting_intrinsic_level,
+        ));
+      }
+    }
+  }
+  ret
+}
+
+/// An rtoriface exposed by an srtor, with the level at which it starts and the sides it matches.
+pub type SideElement = (Level, SideMatch, Comm<Box<dyn RtorIface>>);
+
+/// The rtorifaces exposed by the selected part of an srtor.
+#[salsa::tracked(return_ref)]
+pub fn srtor_side(db: &dyn crate::Db, key: SideKey) -> Vec<SideElement> {
+  let side = key.side(db);
+  let part = key.part(db);
+  StartingIntrinsicLevelProvider::new(
+    iface(key.iface(db).sctor(db), db, side).map(|child| {
+      child.map(|child| {
+        let [child, tail @ ..] = &child.iref(db)[..] else {
+          unreachable!("refs should never be empty if the ast passed parsing/validation")
+        };
+        let child = iface_of(db, child.ctor(db));
+        (child, tail.to_vec())
+      })
+    }),
+    db,
+    side,
+  )
+  .filter_map(|(level, child)| {
+    let ret: FuzzySideIterator = match child {
+      // A notify belongs to the whole side, not to any part of it.
+      Comm::Notify if part.is_empty() => Box::new(std::iter::once((Level(0), side, Comm::Notify))),
+      Comm::Notify => return None,
+      Comm::Data((child, tail)) => {
+        let longer = sequence_max(&tail, rest_or_empty(&part))?;
+        child.side(db, side, longer)
+      }
+    };
+    Some(ret.map(move |(level_unadjusted, sm, iface)| (level_unadjusted + level, sm, iface)))
+  })
+  .flatten()
+  .collect()
+}
+
+/// The number of levels of the selected side of an srtor.
+#[salsa::tracked]
+pub fn srtor_n_levels(db: &dyn crate::Db, key: SideKey) -> Level {
+  StartingIntrinsicLevelProvider::new(
+    iface(key.iface(db).sctor(db), db, key.side(db))
+      .map(|it| it.map(|it| (iface_of(db, it.iref(db)[0].ctor(db)), ()))),
+    db,
+    key.side(db),
+  )
+  .n_levels()
+}
+
+/// The levels of each member of each bank of instances in `sctor` once they have been fixpointed.
+#[salsa::tracked(return_ref)]
+pub fn child_levels(
+  db: &dyn crate::Db,
+  sctor: StructlikeCtor,
+) -> Result<BTreeMap<(InstId, u64), BTreeSet<Level>>, CausalityLoop> {
+  Ok(
+    assign_levels(db, sctor, None)?
+      .into_iter()
+      .map(|(member, child)| {
+        (
+          (member.0.id(db), member.1),
+          child.levels().into_iter().collect(),
+        )
+      })
+      .collect(),
+  )
+}
+
 #[salsa::tracked]
 #[allow(clippy::borrowed_box)]
 pub fn srtor_of(db: &dyn crate::Db, sctor: StructlikeCtor) -> Box<dyn RtorIface> {
   Box::new(SrtorIface::new(db, sctor))
 }
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    cell::Cell,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+  };
+
+  use irlf_db::from_text;
+
+  use crate::{
+    checkpoint::Checkpoint,
+    record::{PortWrite, Recording, Replay},
+    scheduler::{ParallelScheduler, Scheduler},
+    testing::main_sctor,
+    GriTestDatabase,
+  };
+
+  use super::*;
+
+  /// The fixpointing algorithm that `worklist` replaced, which evaluates every child once per round.
+  fn rounds<'db, K>(children: &mut HashMap<K, Box<dyn RtorComptime<'db> + 'db>>) {
+    let mut changed = FixpointingStatus::Unchanged;
+    for child in children.values_mut() {
+      changed |= child.iterate_levels();
+    }
+    if changed == FixpointingStatus::Changed {
+      rounds(children);
+    }
+  }
+
+  /// A child with a single level that lower-bounds the levels of its downstream children, plus a
+  /// delay, in the way that the levels of upstream rtors lower-bound those of downstream ones.
+  struct Node {
+    level: Rc<Cell<u32>>,
+    downstream: Vec<(Rc<Cell<u32>>, u32)>,
+  }
+
+  impl<'db> RtorComptime<'db> for Node {
+    fn iterate_levels(&mut self) -> FixpointingStatus {
+      let mut changed = FixpointingStatus::Unchanged;
+      for (level, delay) in &self.downstream {
+        if level.get() < self.level.get() + delay {
+          level.set(self.level.get() + delay);
+          changed = FixpointingStatus::Changed;
+        }
+      }
+      changed
+    }
+    fn lower_bound(&mut self, _: &[Inst], _: Side, _: Level, _: FlowDirection) {}
+    fn levels(&self) -> HashSet<Level> {
+      HashSet::from([Level(self.level.get())])
+    }
+    fn accept(&mut self, _: &[Inst], _: Side, _: &mut InputsIface<'db>) {}
+    fn provide(&self, _: &[Inst], _: Side, _: Nesting<RtorN>) -> ProvidingInputsIface<'db> {
+      unimplemented!()
+    }
+  }
+
+  /// Builds an acyclic graph of `n` nodes whose edges are chosen by `seed`, and returns its nodes,
+  /// its dependencies, and the levels of its nodes.
+  #[allow(clippy::type_complexity)]
+  fn graph<'db>(
+    n: usize,
+    seed: usize,
+  ) -> (
+    HashMap<usize, Box<dyn RtorComptime<'db> + 'db>>,
+    HashMap<usize, Vec<usize>>,
+    Vec<Rc<Cell<u32>>>,
+  ) {
+    let levels: Vec<_> = (0..n).map(|_| Rc::new(Cell::new(0))).collect();
+    let mut downstreams: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut children: HashMap<usize, Box<dyn RtorComptime<'db> + 'db>> = HashMap::new();
+    for (i, level) in levels.iter().enumerate() {
+      let mut downstream = vec![];
+      for (j, other) in levels.iter().enumerate().skip(i + 1) {
+        if (i * 7 + j * 13 + seed).is_multiple_of(5) {
+          downstream.push((Rc::clone(other), ((i + j + seed) % 3) as u32));
+          downstreams.entry(i).or_default().push(j);
+        }
+      }
+      let level = Rc::clone(level);
+      children.insert(i, Box::new(Node { level, downstream }));
+    }
+    (children, downstreams, levels)
+  }
+
+  const CONTEXT: &str = "add1 0x0 add1
+---
+---
+rtor0 0x1
+  a 100 = 0x0
+  ---
+  L 100 R 100
+  ---
+rtor1 0x2
+  x 101 = 0x1
+  y 102 = 0x1
+  ---
+  L 101
+  L -
+  L -
+  L 102
+  ---
+---
+0x2
+";
+
+  #[test]
+  fn test_bound_by_iface() {
+    let db = GriTestDatabase::default();
+    let (program, _) = from_text(CONTEXT, &db);
+    let sctor = main_sctor(&db, program);
+    let mut children: HashMap<Member, Box<dyn RtorComptime>> = bank_members(&db, sctor)
+      .map(|member| {
+        (
+          member,
+          iface_of(&db, member.0.ctor(&db)).comptime_realize(&db),
+        )
+      })
+      .collect();
+    bound_by_iface(&db, sctor, &mut children);
+    let levels = |id| {
+      let (_, child) = children
+        .iter()
+        .find(|(member, _)| member.0.id(&db) == InstId(id))
+        .unwrap();
+      let mut levels: Vec<_> = child.levels().into_iter().collect();
+      levels.sort();
+      levels
+    };
+    // Both children are the same ctor, but `y` comes after two notifys of its parent.
+    assert_eq!(levels(101), vec![Level(0)]);
+    assert_eq!(levels(102), vec![Level(2)]);
+  }
+
+  #[test]
+  fn test_child_levels() {
+    let db = GriTestDatabase::default();
+    let (program, _) = from_text(CONTEXT, &db);
+    let sctor = main_sctor(&db, program);
+    let levels = child_levels(&db, sctor);
+    assert_eq!(
+      levels,
+      &Ok(BTreeMap::from([
+        ((InstId(101), 0), BTreeSet::from([Level(0)])),
+        ((InstId(102), 0), BTreeSet::from([Level(2)])),
+      ]))
+    );
+    // The levels are computed once and then shared.
+    assert!(std::ptr::eq(levels, child_levels(&db, sctor)));
+  }
+
+  const ISOMORPHIC: &str = "add1 0x0 add1
+---
+---
+rtor0 0x1
+  a 100 = 0x0
+  ---
+  L 100 R 100
+  ---
+rtor1 0x3
+  b 103 = 0x0
+  ---
+  L 103 R 103
+  ---
+rtor2 0x2
+  x 101 = 0x1
+  y 102 = 0x3
+  ---
+  L 101 R 102
+  ---
+  200 101 102
+---
+0x2
+";
+
+  #[test]
+  fn test_hash_consed_levels() {
+    let levels_of = |consed: bool| {
+      let db = GriTestDatabase::default();
+      let (program, _) = if consed {
+        irlf_db::from_text_hash_consed(ISOMORPHIC, &db)
+      } else {
+        from_text(ISOMORPHIC, &db)
+      };
+      let sctor = main_sctor(&db, program);
+      let [x, y] = [0, 1].map(|i| match sctor.insts(&db)[i].ctor(&db) {
+        irlf_db::ir::Ctor::StructlikeCtor(sctor) => *sctor,
+        _ => unreachable!(),
+      });
+      std::ptr::eq(child_levels(&db, x), child_levels(&db, y))
+    };
+    // Once `rtor1` is merged into `rtor0`, the levels of both wrappers are computed once.
+    assert!(!levels_of(false));
+    assert!(levels_of(true));
+  }
+
+  #[test]
+  fn test_worklist_matches_rounds() {
+    for seed in 0..10 {
+      let (mut expected_children, _, expected) = graph(40, seed);
+      rounds(&mut expected_children);
+      let (mut children, downstreams, actual) = graph(40, seed);
+      assert_eq!(worklist(&mut children, &downstreams), Ok(()));
+      let get = |levels: Vec<Rc<Cell<u32>>>| levels.iter().map(|l| l.get()).collect::<Vec<_>>();
+      assert_eq!(get(actual), get(expected), "seed {seed}");
+    }
+  }
+
+  const CYCLE: &str = "add1 0x0 add1
+---
+---
+rtor0 0x1
+  a 100 = 0x0
+  ---
+  L 100 R 100
+  ---
+rtor1 0x2
+  x 101 = 0x1
+  y 102 = 0x1
+  z 103 = 0x1
+  ---
+  L 103 R 103
+  ---
+  200 101 102
+  201 102 101
+  202 102 103
+---
+0x2
+";
+
+  #[test]
+  fn test_causality_loop() {
+    let db = GriTestDatabase::default();
+    let (program, _) = from_text(CYCLE, &db);
+    let error = crate::realize(&db, program).err().unwrap();
+    let crate::RealizeError::CausalityLoop(error) = error else {
+      panic!("expected a causality loop but got {error:?}")
+    };
+    // `z` comes after the loop but is not on it.
+    assert_eq!(
+      error,
+      CausalityLoop {
+        ctor: CtorId(2),
+        insts: vec![InstId(101), InstId(102)],
+        connections: vec![DebugOnlyId(200), DebugOnlyId(201)],
+      }
+    );
+    assert_eq!(
+      error.to_string(),
+      "causality loop in ctor 0x2 through instances 101, 102 and connections 200, 201"
+    );
+  }
+
+  #[test]
+  fn test_delay_breaks_loop() {
+    let db = GriTestDatabase::default();
+    let delayed = CYCLE.replace("201 102 101", "201 102 101 after 1");
+    let (program, _) = from_text(&delayed, &db);
+    assert!(crate::realize(&db, program).is_ok());
+    // A wrapper whose left side reaches its right side only after a delay does not feed through, so
+    // a cycle of such wrappers does not loop either.
+    let db = GriTestDatabase::default();
+    let wrapped = CYCLE.replace(
+      "  a 100 = 0x0
+  ---
+  L 100 R 100
+  ---",
+      "  a 100 = 0x0
+  b 104 = 0x0
+  ---
+  L 100 R 104
+  ---
+  203 100 104 after 1",
+    );
+    let (program, _) = from_text(&wrapped, &db);
+    let Ctor::StructlikeCtor(wrapper) = main_sctor(&db, program).insts(&db)[0].ctor(&db) else {
+      unreachable!()
+    };
+    assert!(!sctor_feeds_through(&db, *wrapper));
+    assert!(crate::realize(&db, program).is_ok());
+  }
+
+  /// An instance `x` (101) that sends to an instance `y` (102) after 2, where main exposes the left
+  /// side of `x` and the right side of `y`, and both add 1.
+  const DELAYED: &str = "add1 0x0 add1
+---
+---
+rtor0 0x1
+  a 100 = 0x0
+  ---
+  L 100 R 100
+  ---
+rtor1 0x2
+  x 101 = 0x1
+  y 102 = 0x1
+  ---
+  L 101 R 102
+  ---
+  200 101 102 after 2
+---
+0x2
+";
+
+  /// Returns a port that collects the values written to it in `values`.
+  fn sink<'db>(values: &Rc<RefCell<Vec<u64>>>) -> Inputs<'db> {
+    let values = Rc::clone(values);
+    let port: SetPort = Box::new(move |x| values.borrow_mut().push(*x.downcast_ref().unwrap()));
+    Box::new(std::iter::once(port))
+  }
+
+  #[test]
+  fn test_delay_line() {
+    let db = GriTestDatabase::default();
+    let (program, _) = from_text(&DELAYED.replace("after 2", "after 0.2"), &db);
+    let sctor = main_sctor(&db, program);
+    let mut srtor = realize_srtor(&db, sctor).unwrap();
+    let delivered = Rc::new(RefCell::new(vec![]));
+    srtor.accept(&[], Side::Right, &mut sink(&delivered));
+    let input = srtor.provide(&[], Side::Left).next().unwrap();
+    assert_eq!(srtor.next_event(), None);
+    input(&5u64);
+    input(&6u64);
+    assert_eq!(srtor.next_event(), Some(Tag(vec![0, 2])));
+    srtor.step_down();
+    srtor.step_forward(1);
+    assert!(delivered.borrow().is_empty());
+    srtor.step_forward(1);
+    assert_eq!(*delivered.borrow(), vec![7, 8]);
+    assert_eq!(srtor.next_event(), None);
+  }
+
+  #[test]
+  fn test_delayed_end_to_end() {
+    let db = GriTestDatabase::default();
+    let (program, _) = from_text(&DELAYED.replace("after 2", "after 3"), &db);
+    let mut main = crate::realize(&db, program).unwrap();
+    let delivered = Rc::new(RefCell::new(vec![]));
+    main.accept(&[], Side::Right, &mut sink(&delivered));
+    main.provide(&[], Side::Left).next().unwrap()(&5u64);
+    let mut scheduler = Scheduler::new([(main, Level(0))]);
+    scheduler.run_until(&Tag(vec![2]));
+    assert!(delivered.borrow().is_empty());
+    // `x` sends 6 at 0, which `y` receives exactly 3 later.
+    assert_eq!(scheduler.step(), Some(Tag(vec![3])));
+    assert_eq!(*delivered.borrow(), vec![7]);
+    assert_eq!(scheduler.step(), None);
+  }
+
+  #[test]
+  fn test_checkpoint_delay_line() {
+    let db = GriTestDatabase::default();
+    let (program, _) = from_text(DELAYED, &db);
+    let sctor = main_sctor(&db, program);
+    let mut srtor = realize_srtor(&db, sctor).unwrap();
+    srtor.provide(&[], Side::Left).next().unwrap()(&5u64);
+    srtor.step_forward(1);
+    srtor.provide(&[], Side::Left).next().unwrap()(&6u64);
+    let checkpoint = Checkpoint {
+      program_hash: 0,
+      rtors: vec![srtor.save()],
+    };
+    let mut text = vec![];
+    checkpoint.write_to(&mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.contains("  delayed 200 0 2 6\n  delayed 200 0 3 7\n"));
+    let checkpoint = Checkpoint::read_from(text.as_bytes()).unwrap();
+
+    let mut restored = realize_srtor(&db, sctor).unwrap();
+    let delivered = Rc::new(RefCell::new(vec![]));
+    restored.accept(&[], Side::Right, &mut sink(&delivered));
+    let mut unknown = checkpoint.rtors[0].clone();
+    unknown.delayed[0].connection = DebugOnlyId(201);
+    assert_eq!(
+      restored.restore(&unknown),
+      Err(RestoreError::UnknownConnection(DebugOnlyId(201)))
+    );
+    let mut unknown = checkpoint.rtors[0].clone();
+    unknown.delayed[0].channel = 1;
+    assert_eq!(
+      restored.restore(&unknown),
+      Err(RestoreError::UnknownChannel {
+        connection: DebugOnlyId(200),
+        channel: 1
+      })
+    );
+    let mut childless = checkpoint.rtors[0].clone();
+    childless.children.pop();
+    assert_eq!(
+      restored.restore(&childless),
+      Err(RestoreError::ChildCount {
+        saved: 1,
+        current: 2
+      })
+    );
+    restored.restore(&checkpoint.rtors[0]).unwrap();
+    assert_eq!(restored.save(), checkpoint.rtors[0]);
+    assert_eq!(restored.next_event(), Some(Tag(vec![2])));
+    restored.step_forward(1);
+    assert_eq!(*delivered.borrow(), vec![7]);
+    restored.step_forward(1);
+    assert_eq!(*delivered.borrow(), vec![7, 8]);
+    assert_eq!(restored.next_event(), None);
+  }
+
+  #[test]
+  fn test_parallel_realized() {
+    let delivered = Arc::new(Mutex::new(vec![]));
+    let mut scheduler = {
+      let delivered = Arc::clone(&delivered);
+      ParallelScheduler::new(NonZeroUsize::new(3).unwrap(), &[], move |i, serve| {
+        let db = GriTestDatabase::default();
+        let (program, _) = from_text(&DELAYED.replace("after 2", "after 1"), &db);
+        let sctor = main_sctor(&db, program);
+        let mut srtor = realize_srtor(&db, sctor).unwrap();
+        let target = Arc::clone(&delivered);
+        let port: SetPort = Box::new(move |x| {
+          target
+            .lock()
+            .unwrap()
+            .push(*x.downcast_ref::<u64>().unwrap())
+        });
+        srtor.accept(
+          &[],
+          Side::Right,
+          &mut (Box::new(std::iter::once(port)) as Inputs),
+        );
+        srtor.provide(&[], Side::Left).next().unwrap()(&(i as u64));
+        serve(vec![(Box::new(srtor), Level(0))]);
+      })
+    };
+    assert_eq!(scheduler.next_tag(), Some(Tag(vec![1])));
+    scheduler.run_until(&Tag(vec![10]));
+    assert_eq!(scheduler.next_tag(), None);
+    let mut delivered = delivered.lock().unwrap().clone();
+    delivered.sort();
+    assert_eq!(delivered, [2, 3, 4]);
+  }
+
+  /// The `k`th stage of a chain that feeds 5, 6 and 7 at 0, 1 and 2 to `DELAYED`, which adds 2 to
+  /// each of them 2 later, and then passes them through `add1`, `mul2` and `add1`. The last stage
+  /// writes to `sink`.
+  fn stage<'db>(
+    db: &'db GriTestDatabase,
+    k: usize,
+    sink: &Arc<Mutex<Vec<u64>>>,
+  ) -> Box<dyn Rtor<'db> + 'db> {
+    let mut rtor = if k == 0 {
+      let (program, _) = from_text(DELAYED, db);
+      let inputs = Recording {
+        writes: (0..3)
+          .map(|t| PortWrite {
+            tag: Tag(vec![t]),
+            level: Level(0),
+            path: vec![],
+            side: Side::Left,
+            channel: 0,
+            value: Value::Int(5 + t),
+          })
+          .collect(),
+      };
+      let main = crate::realize(db, program).unwrap();
+      Box::new(Replay::new(main, &inputs, &Rc::new(Recorder::default())).unwrap())
+    } else {
+      let lib = ["add1", "mul2", "add1"][k - 1];
+      let (program, _) = from_text(&format!("{lib} 0x0 {lib}\n---\n---\n---\n0x0\n"), db);
+      crate::realize(db, program).unwrap()
+    };
+    if k == 3 {
+      let sink = Arc::clone(sink);
+      let port: SetPort =
+        Box::new(move |x| sink.lock().unwrap().push(*x.downcast_ref::<u64>().unwrap()));
+      rtor.accept(
+        &[],
+        Side::Right,
+        &mut (Box::new(std::iter::once(port)) as Inputs),
+      );
+    }
+    rtor
+  }
+
+  #[test]
+  fn test_parallel_links() {
+    let end = Tag(vec![10]);
+    let sequential = Arc::new(Mutex::new(vec![]));
+    {
+      let db = GriTestDatabase::default();
+      let mut stages: Vec<_> = (0..4).map(|k| stage(&db, k, &sequential)).collect();
+      for k in 0..3 {
+        let mut inputs = stages[k + 1].provide(&[], Side::Left);
+        stages[k].accept(&[], Side::Right, &mut inputs);
+      }
+      let levels = (0..4).map(Level);
+      let mut scheduler = Scheduler::new(stages.into_iter().zip(levels));
+      scheduler.run_until(&end);
+    }
+    assert_eq!(*sequential.lock().unwrap(), [17, 19, 21]);
+    for threads in [1, 3] {
+      // The stages are spread over the workers, so that each link crosses between workers when
+      // there are several.
+      let owned = move |i: usize| (0..4).filter(move |k| k % threads == i);
+      let order: Vec<usize> = (0..threads).flat_map(owned).collect();
+      let global = |k| order.iter().position(|&o| o == k).unwrap();
+      let links: Vec<_> = (0..3)
+        .map(|k| ((global(k), 0), (global(k + 1), 0)))
+        .collect();
+      let parallel = Arc::new(Mutex::new(vec![]));
+      let mut scheduler = {
+        let parallel = Arc::clone(&parallel);
+        ParallelScheduler::new(
+          NonZeroUsize::new(threads).unwrap(),
+          &links,
+          move |i, serve| {
+            let db = GriTestDatabase::default();
+            let stages = owned(i)
+              .map(|k| (stage(&db, k, &parallel), Level(k as u32)))
+              .collect();
+            serve(stages);
+          },
+        )
+      };
+      scheduler.run_until(&end);
+      assert_eq!(scheduler.next_tag(), None);
+      drop(scheduler);
+      assert_eq!(*parallel.lock().unwrap(), *sequential.lock().unwrap());
+    }
+  }
+
+  const NESTED_DELAY: &str = "add1 0x0 add1
+---
+---
+rtor0 0x1
+  a 100 = 0x0
+  ---
+  L 100 R 100
+  ---
+rtor1 0x2
+  x 101 = 0x1
+  y 102 = 0x1
+  ---
+  L 101 R 102
+  ---
+  200 101 102 after 3
+rtor2 0x3
+  inner 103 = 0x2
+  ---
+  L 103 R 103
+  ---
+---
+0x3
+";
+
+  #[test]
+  fn test_schedule_nested_events() {
+    let db = GriTestDatabase::default();
+    let (program, _) = from_text(NESTED_DELAY, &db);
+    let mut outer = realize_srtor(&db, main_sctor(&db, program)).unwrap();
+    let delivered = Rc::new(RefCell::new(vec![]));
+    outer.accept(&[], Side::Right, &mut sink(&delivered));
+    // The left side of `outer` is that of `x` within `inner`.
+    outer.provide(&[], Side::Left).next().unwrap()(&7u64);
+    let outer: Box<dyn Rtor> = Box::new(outer);
+    let mut scheduler = Scheduler::new([(outer, Level(0))]);
+    let recorder = Rc::new(Recorder::default());
+    scheduler.record(&recorder);
+    assert_eq!(scheduler.step(), Some(Tag(vec![3])));
+    assert_eq!(*delivered.borrow(), vec![9]);
+    assert_eq!(scheduler.step(), None);
+    assert_eq!(
+      recorder.recording().writes,
+      [PortWrite {
+        tag: Tag(vec![3]),
+        level: Level(0),
+        path: vec![(InstId(103), 0), (InstId(102), 0)],
+        side: Side::Left,
+        channel: 0,
+        value: Value::Int(8),
+      }]
+    );
+  }
+
+  const UNALIGNED: &str = "add1 0x0 add1
+---
+---
+rtor0 0x1
+  a 100 = 0x0
+  b 101 = 0x0
+  ---
+  L 100 R 100 L 101 R 101
+  ---
+rtor1 0x3
+  c 108 = 0x0
+  ---
+  L 108 R 108
+  ---
+rtor2 0x2
+  p 102 = 0x1 [2]
+  q 103 = 0x3 [3]
+  r 104 = 0x3
+  ---
+  ---
+  105 102 [1:4] 103
+  106 102 [0:1] 104
+---
+0x2
+";
+
+  #[test]
+  fn test_unaligned() {
+    let db = GriTestDatabase::default();
+    let (program, _) = from_text(UNALIGNED, &db);
+    assert_eq!(crate::typecheck(&db, program), vec![]);
+    let sctor = main_sctor(&db, program);
+    assert!(child_levels(&db, sctor).is_ok());
+    let mut children: HashMap<Member, Box<dyn RtorComptime>> = bank_members(&db, sctor)
+      .map(|member| {
+        (
+          member,
+          iface_of(&db, member.0.ctor(&db)).comptime_realize(&db),
+        )
+      })
+      .collect();
+    let ids = |members: &[Member]| -> Vec<(InstId, u64)> {
+      members.iter().map(|(inst, i)| (inst.id(&db), *i)).collect()
+    };
+    let downstreams: BTreeMap<_, _> = connect(&db, &mut children, sctor, None)
+      .into_iter()
+      .map(|(member, ds)| (ids(&[member])[0], ids(&ds)))
+      .collect();
+    // The second channel of `p` is its first member's last, and the remaining two channels are the
+    // second member's, which span the last two members of `q`.
+    assert_eq!(
+      downstreams,
+      BTreeMap::from([
+        ((InstId(102), 0), vec![(InstId(103), 0), (InstId(104), 0)]),
+        ((InstId(102), 1), vec![(InstId(103), 1), (InstId(103), 2)]),
+      ])
+    );
+  }
+
+  #[test]
+  fn test_width_errors() {
+    let db = GriTestDatabase::default();
+    let text = UNALIGNED.replace("105 102 [1:4] 103", "105 102 [1:5] 103\n  107 104 102");
+    let (program, _) = from_text(&text, &db);
+    let sctor = main_sctor(&db, program);
+    assert_eq!(
+      width_errors(&db, sctor),
+      vec![
+        TypeError::SliceOutOfRange {
+          ctor: CtorId(2),
+          connection: DebugOnlyId(105),
+          slice: Slice { start: 1, end: 5 },
+          width: 4,
+        },
+        TypeError::Width {
+          ctor: CtorId(2),
+          connection: DebugOnlyId(107),
+          left: 1,
+          right: 4,
+        },
+      ]
+    );
+  }
+}
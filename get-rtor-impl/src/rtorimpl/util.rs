@@ -1,7 +1,102 @@
+use std::{
+  cell::{Cell, RefCell},
+  cmp,
+  rc::Rc,
+};
+
 use irlf_db::ir::Inst;
+use lf_types::{Comm, Level};
+
+use crate::rtor::{ComptimeInput, DeferredNotifys, InputsIface, Leveller};
+
+use super::FixpointingStatus;
+
+/// Returns a leveller that raises `level` to the levels that it is given.
+pub fn raise(level: &Rc<Cell<Level>>) -> Leveller {
+  let level = Rc::clone(level);
+  Rc::new(move |lower_bound| match lower_bound {
+    Comm::Data(lower_bound) if lower_bound > level.get() => {
+      level.set(lower_bound);
+      FixpointingStatus::Changed
+    }
+    _ => FixpointingStatus::Unchanged,
+  })
+}
+
+/// Levels the first data entry of `downstream`, once it has been accepted, at `level`.
+pub fn level_downstream(
+  downstream: &RefCell<Option<InputsIface>>,
+  level: Level,
+) -> FixpointingStatus {
+  let Some(mut inputs) = downstream.borrow().clone() else {
+    return FixpointingStatus::Unchanged;
+  };
+  inputs
+    .find_map(|input| match input {
+      ComptimeInput::Data(f) => Some(f(Comm::Data(level))),
+      ComptimeInput::Notify(_) => None,
+    })
+    .unwrap_or(FixpointingStatus::Unchanged)
+}
 
 pub fn require_empty(part: &[Inst]) {
   if !part.is_empty() {
     panic!()
   }
 }
+
+/// Returns the next data entry of `inputs_iface`, deferring the notify entries that precede it. The
+/// returned leveller records its level in `deferred_notifys` for the notifys that come after it.
+pub fn next_data(
+  inputs_iface: &mut InputsIface,
+  deferred_notifys: &mut DeferredNotifys,
+) -> Option<Leveller> {
+  loop {
+    match inputs_iface.next()? {
+      ComptimeInput::Notify(f) => {
+        let preceding = deferred_notifys.data.len();
+        deferred_notifys.notifys.push((f, preceding));
+      }
+      ComptimeInput::Data(f) => {
+        let level = Rc::new(Cell::new(Level(0)));
+        deferred_notifys.data.push(Rc::clone(&level));
+        let offset = deferred_notifys.offset;
+        return Some(Rc::new(move |lower_bound| {
+          if let Comm::Data(lower_bound) = lower_bound {
+            level.set(cmp::max(level.get(), lower_bound + offset));
+          }
+          f(lower_bound)
+        }));
+      }
+    }
+  }
+}
+
+/// Levels the deferred notifys along with the notify entries at the head of `inputs_iface`. This
+/// must only be called once every data entry that has been consumed from `inputs_iface` has been
+/// levelled.
+pub fn resolve_notifys(
+  inputs_iface: &mut InputsIface,
+  deferred_notifys: &mut DeferredNotifys,
+) -> FixpointingStatus {
+  let mut lookahead = inputs_iface.clone();
+  while let Some(ComptimeInput::Notify(f)) = lookahead.next() {
+    inputs_iface.next();
+    let preceding = deferred_notifys.data.len();
+    deferred_notifys.notifys.push((f, preceding));
+  }
+  let floor = deferred_notifys.floor;
+  let data = std::mem::take(&mut deferred_notifys.data);
+  let level_before = |preceding: usize| {
+    data[..preceding]
+      .iter()
+      .map(|level| level.get())
+      .fold(floor, cmp::max)
+  };
+  let mut changed = FixpointingStatus::Unchanged;
+  for (f, preceding) in deferred_notifys.notifys.drain(..) {
+    changed |= f(Comm::Data(level_before(preceding)));
+  }
+  deferred_notifys.floor = level_before(data.len());
+  changed
+}
@@ -0,0 +1,241 @@
+use std::{any::Any, fmt::Display, rc::Rc};
+
+use lf_types::{Type, Value};
+
+/// A value that can pass through a port of a lib rtor.
+pub trait PortValue: Any + Clone {
+  /// The type that ports carrying this value declare.
+  fn ty() -> Type;
+  fn to_value(&self) -> Value;
+  /// Returns `None` if `value` is not of type `Self::ty()`.
+  fn from_value(value: &Value) -> Option<Self>;
+}
+
+impl PortValue for u64 {
+  fn ty() -> Type {
+    Type::Int
+  }
+  fn to_value(&self) -> Value {
+    Value::Int(*self)
+  }
+  fn from_value(value: &Value) -> Option<Self> {
+    match value {
+      Value::Int(x) => Some(*x),
+      _ => None,
+    }
+  }
+}
+
+impl PortValue for f64 {
+  fn ty() -> Type {
+    Type::Float
+  }
+  fn to_value(&self) -> Value {
+    Value::Float(*self)
+  }
+  fn from_value(value: &Value) -> Option<Self> {
+    match value {
+      Value::Float(x) => Some(*x),
+      _ => None,
+    }
+  }
+}
+
+impl PortValue for bool {
+  fn ty() -> Type {
+    Type::Bool
+  }
+  fn to_value(&self) -> Value {
+    Value::Bool(*self)
+  }
+  fn from_value(value: &Value) -> Option<Self> {
+    match value {
+      Value::Bool(b) => Some(*b),
+      _ => None,
+    }
+  }
+}
+
+impl PortValue for () {
+  fn ty() -> Type {
+    Type::Tuple(vec![])
+  }
+  fn to_value(&self) -> Value {
+    Value::Tuple(vec![])
+  }
+  fn from_value(value: &Value) -> Option<Self> {
+    match value {
+      Value::Tuple(elts) if elts.is_empty() => Some(()),
+      _ => None,
+    }
+  }
+}
+
+impl<A: PortValue, B: PortValue> PortValue for (A, B) {
+  fn ty() -> Type {
+    Type::Tuple(vec![A::ty(), B::ty()])
+  }
+  fn to_value(&self) -> Value {
+    Value::Tuple(vec![self.0.to_value(), self.1.to_value()])
+  }
+  fn from_value(value: &Value) -> Option<Self> {
+    match value {
+      Value::Tuple(elts) => match &elts[..] {
+        [a, b] => Some((A::from_value(a)?, B::from_value(b)?)),
+        _ => None,
+      },
+      _ => None,
+    }
+  }
+}
+
+impl<A: PortValue, B: PortValue, C: PortValue> PortValue for (A, B, C) {
+  fn ty() -> Type {
+    Type::Tuple(vec![A::ty(), B::ty(), C::ty()])
+  }
+  fn to_value(&self) -> Value {
+    Value::Tuple(vec![
+      self.0.to_value(),
+      self.1.to_value(),
+      self.2.to_value(),
+    ])
+  }
+  fn from_value(value: &Value) -> Option<Self> {
+    match value {
+      Value::Tuple(elts) => match &elts[..] {
+        [a, b, c] => Some((A::from_value(a)?, B::from_value(b)?, C::from_value(c)?)),
+        _ => None,
+      },
+      _ => None,
+    }
+  }
+}
+
+/// Invokes `$f` on each type of value that can be converted to and from a `Value` without knowing
+/// its type statically: the scalar port values, and the tuples of up to three of them.
+macro_rules! for_each_port_value {
+  ($f:ident) => {
+    for_each_port_value!(@with $f [u64, f64, bool, ()]);
+  };
+  (@with $f:ident [$($a:ty),*]) => {
+    $( $f!($a); )*
+    $( for_each_port_value!(@pairs $f $a [u64, f64, bool, ()]); )*
+    $( for_each_port_value!(@triples $f $a [u64, f64, bool, ()]); )*
+  };
+  (@pairs $f:ident $a:ty [$($b:ty),*]) => {
+    $( $f!(($a, $b)); )*
+  };
+  (@triples $f:ident $a:ty [$($b:ty),*]) => {
+    $( for_each_port_value!(@triples_of $f $a, $b [u64, f64, bool, ()]); )*
+  };
+  (@triples_of $f:ident $a:ty, $b:ty [$($c:ty),*]) => {
+    $( $f!(($a, $b, $c)); )*
+  };
+}
+
+/// Keeps a value that was passed to a port, or returns `None` if it is not of one of the types
+/// that `for_each_port_value` lists.
+pub fn to_value(x: &dyn Any) -> Option<Value> {
+  macro_rules! try_type {
+    ($t:ty) => {
+      if let Some(x) = x.downcast_ref::<$t>() {
+        return Some(x.to_value());
+      }
+    };
+  }
+  for_each_port_value!(try_type);
+  None
+}
+
+/// Returns `value` in the form in which it is passed to ports, or `None` if it is not of one of
+/// the types that `for_each_port_value` lists.
+pub fn from_value(value: &Value) -> Option<Box<dyn Any>> {
+  macro_rules! try_type {
+    ($t:ty) => {
+      if let Some(x) = <$t>::from_value(value) {
+        return Some(Box::new(x));
+      }
+    };
+  }
+  for_each_port_value!(try_type);
+  None
+}
+
+/// A value that was passed to a port of a different type.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TypeMismatch {
+  pub expected: Type,
+}
+
+impl Display for TypeMismatch {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "expected a value of type {}", self.expected)
+  }
+}
+
+/// The reasons why a lib rtor cannot compute its output.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ReactError {
+  TypeMismatch(TypeMismatch),
+  /// The implementation of the lib rtor failed, as when a WebAssembly module traps or runs out of
+  /// fuel.
+  Failed(String),
+}
+
+impl Display for ReactError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ReactError::TypeMismatch(e) => e.fmt(f),
+      ReactError::Failed(e) => f.write_str(e),
+    }
+  }
+}
+
+impl From<TypeMismatch> for ReactError {
+  fn from(e: TypeMismatch) -> Self {
+    ReactError::TypeMismatch(e)
+  }
+}
+
+/// A function whose argument and result types are only known at runtime, in the form in which
+/// values are passed between ports.
+pub type ErasedFn = Rc<dyn Fn(&dyn Any) -> Result<Box<dyn Any>, ReactError>>;
+pub type ErasedBiFn = Rc<dyn Fn(&dyn Any, &dyn Any) -> Result<Box<dyn Any>, ReactError>>;
+/// Builds the function computed by an instance of a lib rtor from its instantiation-time arguments.
+pub type MakeFn = Rc<dyn Fn(&[&dyn Any]) -> ErasedFn>;
+
+pub fn downcast<A: PortValue>(x: &dyn Any) -> Result<A, TypeMismatch> {
+  x.downcast_ref::<A>()
+    .cloned()
+    .ok_or(TypeMismatch { expected: A::ty() })
+}
+
+pub fn erase<A: PortValue, B: PortValue>(f: Rc<dyn Fn(A) -> B>) -> ErasedFn {
+  Rc::new(move |x| Ok(Box::new(f(downcast(x)?))))
+}
+
+pub fn erase_bi<A: PortValue, B: PortValue, C: PortValue>(f: Rc<dyn Fn(A, B) -> C>) -> ErasedBiFn {
+  Rc::new(move |x, y| Ok(Box::new(f(downcast(x)?, downcast(y)?))))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_value_round_trip() {
+    let value = to_value(&(1u64, 2.5f64, ())).unwrap();
+    assert_eq!(
+      value,
+      Value::Tuple(vec![Value::Int(1), Value::Float(2.5), Value::Tuple(vec![])])
+    );
+    assert_eq!(value.ty(), <(u64, f64, ())>::ty());
+    let restored = from_value(&value).unwrap();
+    assert_eq!(
+      restored.downcast_ref::<(u64, f64, ())>(),
+      Some(&(1, 2.5, ()))
+    );
+    assert_eq!(to_value(&"not a port value"), None);
+    assert!(from_value(&Value::Tuple(vec![Value::Tuple(vec![])])).is_none());
+  }
+}
@@ -0,0 +1,364 @@
+//! Library ctors implemented by WebAssembly modules.
+//!
+//! A module that implements a library ctor must export:
+//! - `n_inputs: () -> i32`, which is 1 or 2. A module with one input has the levels of a function
+//!   rtor, and a module with two inputs has the levels of a binary function rtor, so that its right
+//!   input is one level after its left input.
+//! - `react_<i>: (i64) -> i64` for each input `i`, counting from 0. The react functions are called
+//!   in order of input with the value at that input, and the result of the last one is the output.
+//!
+//! Values are `u64`s, passed as `i64`s with the same bits. Modules run in the wasmi interpreter
+//! without any imports or start function. Each realized rtor runs in its own instance of its
+//! module, whose memories and tables may only grow to `MAX_MEMORY` bytes and `MAX_TABLE_ELEMENTS`
+//! elements, and each call to a react function is limited to `FUEL` units of fuel, so a module
+//! cannot reach the host, share state between rtors, exhaust memory or run forever.
+
+use std::{cell::RefCell, collections::BTreeMap, env, fmt::Display, fs, io, path::PathBuf, rc::Rc};
+
+use lf_types::Type;
+use wasmi::{Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+
+use crate::{record::fnv1a, rtor::RtorIface};
+
+use super::{
+  bifunrtorimpl::BiFunRtorIface,
+  funrtorimpl::FunRtorIface,
+  value::{downcast, ErasedBiFn, ErasedFn, ReactError},
+};
+
+/// The environment variable that lists the directories in which to look for modules, separated as
+/// in `PATH`.
+pub const WASM_PATH_VAR: &str = "IRLF_WASM_PATH";
+
+/// The fuel available to each call of a react function.
+pub const FUEL: u64 = 1_000_000;
+
+/// The size in bytes to which the linear memory of an instance may grow.
+pub const MAX_MEMORY: usize = 16 << 20;
+
+/// The number of elements to which a table of an instance may grow.
+pub const MAX_TABLE_ELEMENTS: u32 = 10_000;
+
+/// The modules that implement the lib ctors that are not built in, by the names of those lib ctors.
+///
+/// Lib ctors are looked up in this input rather than in the file system, so that salsa sees when
+/// they change. Without it, only the built-in lib ctors can be realized.
+#[salsa::input(singleton)]
+pub struct WasmModules {
+  #[return_ref]
+  pub modules: BTreeMap<String, Vec<u8>>,
+}
+
+/// Reads the modules `<name>.wasm` in the directories listed in `WASM_PATH_VAR`, by name. As in
+/// `PATH`, a module in an earlier directory hides the modules of the same name in later ones.
+pub fn read_wasm_path() -> io::Result<BTreeMap<String, Vec<u8>>> {
+  match env::var_os(WASM_PATH_VAR) {
+    Some(dirs) => read_modules(env::split_paths(&dirs)),
+    None => Ok(BTreeMap::new()),
+  }
+}
+
+fn read_modules(dirs: impl IntoIterator<Item = PathBuf>) -> io::Result<BTreeMap<String, Vec<u8>>> {
+  let mut ret = BTreeMap::new();
+  for dir in dirs {
+    let entries = match fs::read_dir(&dir) {
+      Ok(entries) => entries,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+      Err(e) => return Err(e),
+    };
+    for entry in entries {
+      let path = entry?.path();
+      if path.extension() != Some("wasm".as_ref()) || !path.is_file() {
+        continue;
+      }
+      let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+        continue;
+      };
+      if !ret.contains_key(name) {
+        ret.insert(name.to_string(), fs::read(&path)?);
+      }
+    }
+  }
+  Ok(ret)
+}
+
+/// The reasons why a module cannot implement a library ctor.
+#[derive(Debug)]
+pub enum LoadError {
+  /// The module is not valid WebAssembly, or it could not be instantiated.
+  Wasm(wasmi::Error),
+  /// The module imports something, which would give it access to the host.
+  Import(String),
+  /// The module does not follow the ABI of library ctors.
+  Abi(String),
+}
+
+impl Display for LoadError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      LoadError::Wasm(e) => write!(f, "invalid module: {e}"),
+      LoadError::Import(name) => write!(f, "modules may not import anything but it imports {name}"),
+      LoadError::Abi(e) => write!(f, "the module does not implement a library ctor: {e}"),
+    }
+  }
+}
+
+impl From<wasmi::Error> for LoadError {
+  fn from(e: wasmi::Error) -> Self {
+    LoadError::Wasm(e)
+  }
+}
+
+/// An instance of a module together with the store that it runs in.
+struct Sandbox {
+  store: Store<StoreLimits>,
+  react: Vec<TypedFunc<i64, i64>>,
+}
+
+impl Sandbox {
+  fn new(module: &Module) -> Result<Self, LoadError> {
+    let limits = StoreLimitsBuilder::new()
+      .memory_size(MAX_MEMORY)
+      .table_elements(MAX_TABLE_ELEMENTS)
+      .build();
+    let mut store = Store::new(module.engine(), limits);
+    store.limiter(|limits| limits);
+    let instance = Linker::<StoreLimits>::new(module.engine())
+      .instantiate(&mut store, module)?
+      .ensure_no_start(&mut store)
+      .map_err(|e| LoadError::Wasm(e.into()))?;
+    let n_inputs = instance
+      .get_typed_func::<(), i32>(&store, "n_inputs")
+      .map_err(|e| LoadError::Abi(format!("n_inputs: {e}")))?;
+    store.set_fuel(FUEL).unwrap();
+    let n_inputs = match n_inputs.call(&mut store, ())? {
+      n @ (1 | 2) => n as usize,
+      n => return Err(LoadError::Abi(format!("{n} inputs is not 1 or 2"))),
+    };
+    let react = (0..n_inputs)
+      .map(|i| {
+        instance
+          .get_typed_func::<i64, i64>(&store, &format!("react_{i}"))
+          .map_err(|e| LoadError::Abi(format!("react_{i}: {e}")))
+      })
+      .collect::<Result<_, _>>()?;
+    Ok(Sandbox { store, react })
+  }
+
+  /// Calls the react functions of the module with `inputs` and returns the output.
+  ///
+  /// # Errors
+  /// Returns an error if the module traps or runs out of fuel.
+  ///
+  /// # Panics
+  /// Panics if the number of inputs is wrong.
+  fn react(&mut self, inputs: &[u64]) -> Result<u64, ReactError> {
+    assert_eq!(inputs.len(), self.react.len(), "wrong number of inputs");
+    let mut ret = 0;
+    for (i, (react, x)) in self.react.iter().zip(inputs).enumerate() {
+      self.store.set_fuel(FUEL).unwrap();
+      ret = react
+        .call(&mut self.store, *x as i64)
+        .map_err(|e| ReactError::Failed(format!("react_{i} of a wasm lctor failed: {e}")))?
+        as u64;
+    }
+    Ok(ret)
+  }
+}
+
+/// A library ctor implemented by a WebAssembly module.
+#[derive(Clone)]
+pub struct WasmLctor {
+  module: Rc<Module>,
+  n_inputs: usize,
+  id: u128,
+}
+
+impl WasmLctor {
+  /// Loads a module from its binary or text format.
+  pub fn from_bytes(wasm: &[u8]) -> Result<Self, LoadError> {
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config);
+    let module = Module::new(&engine, wasm)?;
+    if let Some(import) = module.imports().next() {
+      return Err(LoadError::Import(format!(
+        "{}.{}",
+        import.module(),
+        import.name()
+      )));
+    }
+    let n_inputs = Sandbox::new(&module)?.react.len();
+    Ok(WasmLctor {
+      module: Rc::new(module),
+      n_inputs,
+      id: (fnv1a(wasm) as u128) ^ 0x3d1c_5e0a_94f7_b862_07ae_41c9_58d3_6f1b,
+    })
+  }
+
+  /// Instantiates the module afresh, so that the instance shares no state with other instances.
+  fn sandbox(&self) -> Sandbox {
+    Sandbox::new(&self.module)
+      .expect("a module that was instantiated once should instantiate again")
+  }
+
+  /// Returns an iface whose realized rtors each run in their own sandbox.
+  pub fn iface(&self) -> Box<dyn RtorIface> {
+    let this = self.clone();
+    if self.n_inputs == 1 {
+      Box::new(FunRtorIface::erased(
+        Rc::new(move |_| -> ErasedFn {
+          let sandbox = RefCell::new(this.sandbox());
+          Rc::new(move |x| Ok(Box::new(sandbox.borrow_mut().react(&[downcast(x)?])?)))
+        }),
+        Type::Int,
+        Type::Int,
+        self.id,
+      ))
+    } else {
+      Box::new(BiFunRtorIface::erased(
+        Rc::new(move || -> ErasedBiFn {
+          let sandbox = RefCell::new(this.sandbox());
+          Rc::new(move |x, y| {
+            let inputs = [downcast(x)?, downcast(y)?];
+            Ok(Box::new(sandbox.borrow_mut().react(&inputs)?))
+          })
+        }),
+        (Type::Int, Type::Int),
+        Type::Int,
+        self.id,
+      ))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const ADD3: &str = r#"(module
+  (func (export "n_inputs") (result i32) i32.const 1)
+  (func (export "react_0") (param i64) (result i64)
+    local.get 0
+    i64.const 3
+    i64.add))"#;
+
+  const DIFF: &str = r#"(module
+  (global $x (mut i64) (i64.const 0))
+  (func (export "n_inputs") (result i32) i32.const 2)
+  (func (export "react_0") (param i64) (result i64)
+    local.get 0
+    global.set $x
+    local.get 0)
+  (func (export "react_1") (param i64) (result i64)
+    global.get $x
+    local.get 0
+    i64.sub))"#;
+
+  fn load(wat: &str) -> Result<WasmLctor, LoadError> {
+    WasmLctor::from_bytes(&wat::parse_str(wat).unwrap())
+  }
+
+  #[test]
+  fn test_react() {
+    let add3 = load(ADD3).unwrap();
+    assert_eq!(add3.n_inputs, 1);
+    assert_eq!(add3.sandbox().react(&[4]), Ok(7));
+    assert_eq!(add3.sandbox().react(&[u64::MAX]), Ok(2));
+    let diff = load(DIFF).unwrap();
+    assert_eq!(diff.n_inputs, 2);
+    assert_eq!(diff.sandbox().react(&[10, 3]), Ok(7));
+    assert_ne!(add3.id, diff.id);
+  }
+
+  #[test]
+  fn test_sandboxes_are_separate() {
+    let count = load(
+      r#"(module
+  (global $n (mut i64) (i64.const 0))
+  (func (export "n_inputs") (result i32) i32.const 1)
+  (func (export "react_0") (param i64) (result i64)
+    global.get $n
+    local.get 0
+    i64.add
+    global.set $n
+    global.get $n))"#,
+    )
+    .unwrap();
+    let (mut a, mut b) = (count.sandbox(), count.sandbox());
+    assert_eq!(a.react(&[1]), Ok(1));
+    assert_eq!(a.react(&[1]), Ok(2));
+    assert_eq!(b.react(&[1]), Ok(1));
+  }
+
+  #[test]
+  fn test_read_modules() {
+    let dir = env::temp_dir().join(format!("irlf-wasm-{}", std::process::id()));
+    let (first, second) = (dir.join("first"), dir.join("second"));
+    fs::create_dir_all(&first).unwrap();
+    fs::create_dir_all(&second).unwrap();
+    fs::write(first.join("add3.wasm"), wat::parse_str(ADD3).unwrap()).unwrap();
+    fs::write(second.join("add3.wasm"), wat::parse_str(DIFF).unwrap()).unwrap();
+    fs::write(second.join("diff.wasm"), wat::parse_str(DIFF).unwrap()).unwrap();
+    fs::write(second.join("notes.txt"), "not a module").unwrap();
+    let modules = read_modules([dir.join("missing"), first, second]).unwrap();
+    assert_eq!(modules.keys().collect::<Vec<_>>(), ["add3", "diff"]);
+    let add3 = WasmLctor::from_bytes(&modules["add3"]).unwrap();
+    assert_eq!(add3.sandbox().react(&[1]), Ok(4));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_sandbox() {
+    let imports = load(
+      r#"(module
+  (import "env" "exit" (func))
+  (func (export "n_inputs") (result i32) i32.const 1))"#,
+    );
+    assert!(matches!(imports, Err(LoadError::Import(name)) if name == "env.exit"));
+    let missing = load(r#"(module (func (export "n_inputs") (result i32) i32.const 2))"#);
+    assert!(matches!(missing, Err(LoadError::Abi(e)) if e.starts_with("react_0")));
+  }
+
+  #[test]
+  fn test_memory_limit() {
+    let huge = load(
+      r#"(module
+  (memory 1024)
+  (func (export "n_inputs") (result i32) i32.const 1)
+  (func (export "react_0") (param i64) (result i64) local.get 0))"#,
+    );
+    assert!(matches!(huge, Err(LoadError::Wasm(_))));
+    let grow = load(
+      r#"(module
+  (memory 1)
+  (func (export "n_inputs") (result i32) i32.const 1)
+  (func (export "react_0") (param i64) (result i64)
+    local.get 0
+    i32.wrap_i64
+    memory.grow
+    i64.extend_i32_s))"#,
+    )
+    .unwrap();
+    let mut sandbox = grow.sandbox();
+    assert_eq!(sandbox.react(&[1]), Ok(1));
+    // Growing past `MAX_MEMORY` fails without trapping.
+    assert_eq!(sandbox.react(&[1024]), Ok(u64::MAX));
+  }
+
+  #[test]
+  fn test_out_of_fuel() {
+    let spin = load(
+      r#"(module
+  (func (export "n_inputs") (result i32) i32.const 1)
+  (func (export "react_0") (param i64) (result i64)
+    (loop $forever (br $forever))
+    local.get 0))"#,
+    )
+    .unwrap();
+    let error = spin.sandbox().react(&[0]).unwrap_err();
+    assert!(
+      matches!(error, ReactError::Failed(e) if e.starts_with("react_0 of a wasm lctor failed"))
+    );
+  }
+}
@@ -0,0 +1,1030 @@
+use std::{
+  cell::RefCell,
+  cmp::Reverse,
+  collections::{BTreeMap, BTreeSet, BinaryHeap},
+  num::NonZeroUsize,
+  rc::Rc,
+  sync::{
+    mpsc::{self, Receiver, Sender},
+    Arc,
+  },
+  thread::{self, JoinHandle},
+  time::{Duration, Instant},
+};
+
+use lf_types::{Level, Side, Tag, TagStep, Value};
+
+use crate::{
+  checkpoint::{Checkpoint, RestoreError},
+  record::Recorder,
+  rtor::{Inputs, Rtor, SetPort},
+  rtorimpl::value::{from_value, to_value},
+};
+
+/// An rtor together with the time that it has reached.
+struct Scheduled<R: ?Sized> {
+  rtor: Box<R>,
+  tag: Tag,
+}
+
+impl<R: ?Sized> Scheduled<R> {
+  /// Moves the rtor directly to `to`, without visiting the tags in between.
+  fn advance<'db>(&mut self, to: &Tag)
+  where
+    R: Rtor<'db>,
+  {
+    for step in self.tag.steps_to(to) {
+      match step {
+        TagStep::Forward(distance) => {
+          self.rtor.step_forward(distance);
+        }
+        TagStep::Down => self.rtor.step_down(),
+        TagStep::Up => {
+          self.rtor.step_up();
+        }
+      }
+    }
+    self.tag = to.clone();
+  }
+}
+
+/// The pending events of some rtors, each of which executes at a fixed level within each tag.
+struct EventQueue {
+  levels: Vec<Level>,
+  /// The tag of the event of each rtor that is in the queue, if there is one.
+  queued: Vec<Option<Tag>>,
+  heap: BinaryHeap<Reverse<(Tag, Level, usize)>>,
+}
+
+impl EventQueue {
+  fn new(levels: Vec<Level>) -> Self {
+    EventQueue {
+      queued: vec![None; levels.len()],
+      levels,
+      heap: BinaryHeap::new(),
+    }
+  }
+
+  /// Replaces the queued event of the `idx`th rtor with `next`.
+  fn reschedule(&mut self, idx: usize, next: Option<Tag>) {
+    if let Some(tag) = &next {
+      self
+        .heap
+        .push(Reverse((tag.clone(), self.levels[idx], idx)));
+    }
+    self.queued[idx] = next;
+  }
+
+  fn clear(&mut self) {
+    self.heap.clear();
+    self.queued.fill(None);
+  }
+
+  fn next_event(&mut self) -> Option<(Tag, Level)> {
+    self.discard_stale();
+    self
+      .heap
+      .peek()
+      .map(|Reverse((tag, level, _))| (tag.clone(), *level))
+  }
+
+  /// Removes the events at `now` from the queue, or only those at `only_level` if it is given, and
+  /// returns the rtors that they belong to grouped by level, in increasing order of level and then
+  /// of index.
+  fn due(&mut self, now: &Tag, only_level: Option<Level>) -> Vec<(Level, Vec<usize>)> {
+    let mut groups: Vec<(Level, Vec<usize>)> = vec![];
+    while self.heap.peek().is_some_and(|Reverse((tag, level, _))| {
+      tag == now && only_level.is_none_or(|only| only == *level)
+    }) {
+      let Reverse((tag, level, idx)) = self.heap.pop().unwrap();
+      if self.queued[idx].as_ref() != Some(&tag) {
+        continue;
+      }
+      // An rtor that was rescheduled twice at the same tag must still be stepped only once.
+      self.queued[idx] = None;
+      match groups.last_mut() {
+        Some((last, group)) if *last == level => group.push(idx),
+        _ => groups.push((level, vec![idx])),
+      }
+    }
+    groups
+  }
+
+  /// Drops the queued events that have been superseded by a later call to `reschedule`.
+  fn discard_stale(&mut self) {
+    while self
+      .heap
+      .peek()
+      .is_some_and(|Reverse((tag, _, idx))| self.queued[*idx].as_ref() != Some(tag))
+    {
+      self.heap.pop();
+    }
+  }
+}
+
+/// Steps rtors only at the tags at which they have events, in order of tag and then of level.
+///
+/// `R` is usually `dyn Rtor<'db> + 'db`.
+pub struct Scheduler<R: ?Sized> {
+  rtors: Vec<Scheduled<R>>,
+  events: EventQueue,
+  recorder: Option<Rc<Recorder>>,
+}
+
+impl<'db, R: ?Sized + Rtor<'db>> Scheduler<R> {
+  /// Schedules the given rtors, each of which starts at the default tag and executes at the given
+  /// level within each tag.
+  pub fn new(rtors: impl IntoIterator<Item = (Box<R>, Level)>) -> Self {
+    let (rtors, levels): (Vec<_>, Vec<_>) = rtors
+      .into_iter()
+      .map(|(rtor, level)| {
+        let scheduled = Scheduled {
+          rtor,
+          tag: Tag::default(),
+        };
+        (scheduled, level)
+      })
+      .unzip();
+    let mut ret = Scheduler {
+      rtors,
+      events: EventQueue::new(levels),
+      recorder: None,
+    };
+    for idx in 0..ret.rtors.len() {
+      ret.reschedule(idx);
+    }
+    ret
+  }
+
+  /// Returns the scheduled rtors in the order in which they were given.
+  pub fn into_rtors(self) -> Vec<Box<R>> {
+    self.rtors.into_iter().map(|s| s.rtor).collect()
+  }
+
+  /// The `idx`th scheduled rtor. Call `reschedule` after changing when its next event is.
+  pub fn rtor_mut(&mut self, idx: usize) -> &mut R {
+    &mut self.rtors[idx].rtor
+  }
+
+  /// Moves the `idx`th rtor to `now`, before which it must have no events pending, so that values
+  /// can be given to it at `now`, and attributes the values delivered from then on to `now` and
+  /// `level`. Call `reschedule` after giving it values.
+  pub fn reach(&mut self, idx: usize, now: &Tag, level: Level) -> &mut R {
+    if let Some(recorder) = &self.recorder {
+      recorder.set_now(now.clone(), level);
+    }
+    self.rtors[idx].advance(now);
+    &mut self.rtors[idx].rtor
+  }
+
+  /// Records with `recorder` the values delivered within the scheduled rtors, which are taken to be
+  /// the children of `main`, attributing them to the tags and levels at which they are delivered.
+  pub fn record(&mut self, recorder: &Rc<Recorder>) {
+    for scheduled in &mut self.rtors {
+      scheduled.rtor.record(recorder, &[]);
+    }
+    self.recorder = Some(Rc::clone(recorder));
+  }
+
+  /// Saves the states of the scheduled rtors, which were realized from the program with the given
+  /// hash.
+  pub fn checkpoint(&self, program_hash: u64) -> Checkpoint {
+    Checkpoint {
+      program_hash,
+      rtors: self.rtors.iter().map(|s| s.rtor.save()).collect(),
+    }
+  }
+
+  /// Restores the states of the scheduled rtors from `checkpoint`. The rtors must have been
+  /// realized from the program with the given hash, in the same order as the ones that were saved.
+  ///
+  /// # Errors
+  /// Returns an error, and leaves the rtors as they were, if `checkpoint` was saved from a different
+  /// program or a different number of rtors, or if it has a state that one of the rtors could not
+  /// have saved.
+  pub fn restore(
+    &mut self,
+    checkpoint: &Checkpoint,
+    program_hash: u64,
+  ) -> Result<(), RestoreError> {
+    checkpoint.check(program_hash)?;
+    if checkpoint.rtors.len() != self.rtors.len() {
+      return Err(RestoreError::RtorCount {
+        saved: checkpoint.rtors.len(),
+        current: self.rtors.len(),
+      });
+    }
+    let saved: Vec<_> = self
+      .rtors
+      .iter()
+      .map(|scheduled| scheduled.rtor.save())
+      .collect();
+    for (idx, state) in checkpoint.rtors.iter().enumerate() {
+      if let Err(e) = self.rtors[idx].rtor.restore(state) {
+        for (scheduled, state) in self.rtors.iter_mut().zip(&saved).take(idx + 1) {
+          scheduled
+            .rtor
+            .restore(state)
+            .expect("rtors can be restored from states that they saved");
+        }
+        return Err(e);
+      }
+    }
+    for (scheduled, state) in self.rtors.iter_mut().zip(&checkpoint.rtors) {
+      scheduled.tag = state.tag.clone().unwrap_or_default();
+    }
+    self.events.clear();
+    for idx in 0..self.rtors.len() {
+      self.reschedule(idx);
+    }
+    Ok(())
+  }
+
+  /// Queues the next event of the `idx`th rtor. This must be called whenever something other than
+  /// the scheduler, such as an input, may have changed when that event is.
+  pub fn reschedule(&mut self, idx: usize) {
+    let next = self.rtors[idx].rtor.next_event();
+    self.events.reschedule(idx, next);
+  }
+
+  /// The tag of the earliest pending event, if there is one.
+  pub fn next_tag(&mut self) -> Option<Tag> {
+    self.events.next_event().map(|(tag, _)| tag)
+  }
+
+  /// The tag and level of the earliest pending event, if there is one.
+  pub fn next_event(&mut self) -> Option<(Tag, Level)> {
+    self.events.next_event()
+  }
+
+  /// Processes the events at `now` of the rtors at `level`. No earlier events may be pending.
+  pub fn step_level(&mut self, now: &Tag, level: Level) {
+    for group in self.events.due(now, Some(level)) {
+      self.step_group(now, group);
+    }
+  }
+
+  /// Processes all of the events at the earliest tag at which there are any, and returns that tag.
+  pub fn step(&mut self) -> Option<Tag> {
+    let now = self.next_tag()?;
+    for group in self.events.due(&now, None) {
+      self.step_group(&now, group);
+    }
+    Some(now)
+  }
+
+  fn step_group(&mut self, now: &Tag, (level, idxs): (Level, Vec<usize>)) {
+    if let Some(recorder) = &self.recorder {
+      recorder.set_now(now.clone(), level);
+    }
+    for idx in idxs {
+      self.rtors[idx].advance(now);
+      self.reschedule(idx);
+    }
+  }
+
+  /// Processes events until the next one would be after `end`.
+  pub fn run_until(&mut self, end: &Tag) {
+    while self.next_tag().is_some_and(|tag| tag <= *end) {
+      self.step();
+    }
+  }
+
+  /// Processes events until the next one would be after `end`, waiting for the physical time of
+  /// each tag to arrive on `clock` before processing its events. Calls `on_lag` for each tag whose
+  /// physical time had already passed because earlier reactions overran.
+  pub fn run_real_time(
+    &mut self,
+    end: &Tag,
+    pacing: Pacing,
+    clock: &mut impl Clock,
+    mut on_lag: impl FnMut(Lag),
+  ) {
+    while let Some(tag) = self.next_tag().filter(|tag| tag <= end) {
+      let deadline = pacing.physical_time(&tag);
+      let now = clock.elapsed();
+      if now > deadline {
+        on_lag(Lag {
+          tag,
+          by: now - deadline,
+        });
+      } else {
+        clock.sleep(deadline - now);
+      }
+      self.step();
+    }
+  }
+}
+
+/// The rtors of one worker of a `ParallelScheduler`, each with the level at which it executes.
+pub type WorkerRtors<'db> = Vec<(Box<dyn Rtor<'db> + 'db>, Level)>;
+
+/// Runs the rtors of a worker until its `ParallelScheduler` is dropped.
+pub type Serve<'a> = &'a mut dyn for<'db> FnMut(WorkerRtors<'db>);
+
+/// A connection from a channel of the right side of one rtor of a `ParallelScheduler` to a channel
+/// of the left side of another, each given as the index of the rtor and the channel.
+pub type Link = ((usize, u64), (usize, u64));
+
+/// A value written to a channel of a side of an rtor, given by its index among the rtors of its
+/// worker.
+type Sent = (usize, u64, Value);
+
+/// What the scheduler asks of a worker.
+enum Command {
+  /// Sends the values written to the right sides of the given rtors to the scheduler, and keeps the
+  /// ports of the left sides of the other given rtors to deliver values to.
+  Wire {
+    sources: Vec<usize>,
+    targets: Vec<usize>,
+  },
+  /// Moves the given rtors to the given tag.
+  Advance(Tag, Vec<usize>),
+  /// Writes values to the left sides of rtors.
+  Deliver(Vec<Sent>),
+}
+
+/// What a worker reports to the scheduler.
+enum Report {
+  /// The next events of all of the rtors of the worker, each with its level, once it starts.
+  Started(Vec<(Option<Tag>, Level)>),
+  /// The next events of the rtors that a command moved or delivered to, and the values that they
+  /// wrote to the right sides of linked rtors, in the order in which they were written.
+  Done {
+    next_events: Vec<(usize, Option<Tag>)>,
+    sent: Vec<Sent>,
+  },
+}
+
+/// A thread that owns some of the rtors of a `ParallelScheduler`.
+struct Worker {
+  commands: Option<Sender<Command>>,
+  reports: Receiver<Report>,
+  thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+  fn send(&self, command: Command) {
+    self
+      .commands
+      .as_ref()
+      .unwrap()
+      .send(command)
+      .expect("workers should run until the scheduler is dropped");
+  }
+
+  fn done(&self) -> (Vec<(usize, Option<Tag>)>, Vec<Sent>) {
+    match self.reports.recv() {
+      Ok(Report::Done { next_events, sent }) => (next_events, sent),
+      _ => panic!("workers should run until the scheduler is dropped"),
+    }
+  }
+}
+
+/// Steps rtors like `Scheduler`, but on a pool of worker threads that each own some of the rtors,
+/// so that rtors that share a level are stepped concurrently. Rtors at the same level do not depend
+/// on each other within a tag, so the result is the same as that of `Scheduler`.
+///
+/// Rtors are not `Send`, so each worker builds its own rtors on its own thread, for example by
+/// realizing its part of a program with a database of its own. Values cross between workers on
+/// links, which the scheduler forwards once the level that sent them is done.
+pub struct ParallelScheduler {
+  workers: Vec<Worker>,
+  /// The worker of each rtor and the index of the rtor among those of its worker.
+  rtors: Vec<(usize, usize)>,
+  /// The channels of left sides that each channel of a right side is linked to, by rtor and channel.
+  links: BTreeMap<(usize, u64), Vec<(usize, u64)>>,
+  events: EventQueue,
+}
+
+impl ParallelScheduler {
+  /// Starts `threads` workers, the `i`th of which calls `build(i, serve)` and must pass its rtors to
+  /// `serve`. The rtors are numbered in order of worker and then in the order in which each worker
+  /// passed them, and `links` connects them by these numbers.
+  ///
+  /// # Panics
+  /// Panics if a worker panics or returns without serving its rtors, or if a link names an rtor that
+  /// does not exist or does not go to a higher level, since a value must be sent before the level
+  /// that receives it is stepped.
+  pub fn new(
+    threads: NonZeroUsize,
+    links: &[Link],
+    build: impl for<'a> Fn(usize, Serve<'a>) + Send + Sync + 'static,
+  ) -> Self {
+    let build = Arc::new(build);
+    let mut workers = vec![];
+    for i in 0..threads.get() {
+      let (commands, received) = mpsc::channel();
+      let (report, reports) = mpsc::channel();
+      let build = Arc::clone(&build);
+      let thread = thread::spawn(move || {
+        build(i, &mut |rtors| serve(rtors, &received, &report));
+      });
+      workers.push(Worker {
+        commands: Some(commands),
+        reports,
+        thread: Some(thread),
+      });
+    }
+    let mut rtors = vec![];
+    let mut first_events = vec![];
+    for (w, worker) in workers.iter().enumerate() {
+      let Ok(Report::Started(events)) = worker.reports.recv() else {
+        panic!("workers should serve their rtors")
+      };
+      rtors.extend((0..events.len()).map(|idx| (w, idx)));
+      first_events.extend(events);
+    }
+    let mut by_right = BTreeMap::<_, Vec<_>>::new();
+    let mut wiring = vec![(BTreeSet::new(), BTreeSet::new()); workers.len()];
+    for &((from, from_channel), (to, to_channel)) in links {
+      assert!(
+        from < rtors.len() && to < rtors.len(),
+        "link from rtor {from} to rtor {to} names an rtor that does not exist"
+      );
+      let (from_level, to_level) = (first_events[from].1, first_events[to].1);
+      assert!(
+        from_level < to_level,
+        "link from rtor {from} at level {} to rtor {to} at level {} does not go to a higher level",
+        from_level.0,
+        to_level.0
+      );
+      by_right
+        .entry((from, from_channel))
+        .or_default()
+        .push((to, to_channel));
+      wiring[rtors[from].0].0.insert(rtors[from].1);
+      wiring[rtors[to].0].1.insert(rtors[to].1);
+    }
+    for (worker, (sources, targets)) in workers.iter().zip(wiring) {
+      worker.send(Command::Wire {
+        sources: sources.into_iter().collect(),
+        targets: targets.into_iter().collect(),
+      });
+    }
+    let mut ret = ParallelScheduler {
+      workers,
+      rtors,
+      links: by_right,
+      events: EventQueue::new(first_events.iter().map(|(_, level)| *level).collect()),
+    };
+    for (idx, (next, _)) in first_events.into_iter().enumerate() {
+      ret.events.reschedule(idx, next);
+    }
+    ret
+  }
+
+  /// The tag of the earliest pending event, if there is one.
+  pub fn next_tag(&mut self) -> Option<Tag> {
+    self.events.next_event().map(|(tag, _)| tag)
+  }
+
+  /// Processes all of the events at the earliest tag at which there are any, and returns that tag.
+  pub fn step(&mut self) -> Option<Tag> {
+    let now = self.next_tag()?;
+    // The values sent at a level may give events at `now` to rtors at higher levels, so the levels
+    // are processed one at a time.
+    while let Some((_, level)) = self.events.next_event().filter(|(tag, _)| *tag == now) {
+      for (_, group) in self.events.due(&now, Some(level)) {
+        let mut by_worker = vec![vec![]; self.workers.len()];
+        for idx in group {
+          let (w, local) = self.rtors[idx];
+          by_worker[w].push(local);
+        }
+        let sent = self.run(by_worker, |local| Command::Advance(now.clone(), local));
+        self.forward(sent);
+      }
+    }
+    Some(now)
+  }
+
+  /// Gives each worker that has work the command made from its work, before any is waited on so
+  /// that they work concurrently, and then reschedules the rtors that they report on. Returns the
+  /// values that the rtors sent, each with its global rtor, in order of rtor.
+  fn run<T>(
+    &mut self,
+    by_worker: Vec<Vec<T>>,
+    command: impl Fn(Vec<T>) -> Command,
+  ) -> Vec<(usize, u64, Value)> {
+    let busy: Vec<usize> = (0..by_worker.len())
+      .filter(|&w| !by_worker[w].is_empty())
+      .collect();
+    for (worker, work) in self.workers.iter().zip(by_worker) {
+      if !work.is_empty() {
+        worker.send(command(work));
+      }
+    }
+    let mut ret = vec![];
+    for w in busy {
+      let (next_events, sent) = self.workers[w].done();
+      // The rtors are numbered in order of worker.
+      let first = self.rtors.partition_point(|&(rw, _)| rw < w);
+      let global = |local| first + local;
+      for (local, next) in next_events {
+        let idx = global(local);
+        self.events.reschedule(idx, next);
+      }
+      ret.extend(
+        sent
+          .into_iter()
+          .map(|(local, channel, value)| (global(local), channel, value)),
+      );
+    }
+    // Sorting is stable, so the values of each rtor stay in the order in which they were sent.
+    ret.sort_by_key(|(idx, _, _)| *idx);
+    ret
+  }
+
+  /// Delivers the values sent on links, and then the values that the rtors that received them sent
+  /// in turn, until no more are sent.
+  fn forward(&mut self, mut sent: Vec<(usize, u64, Value)>) {
+    while !sent.is_empty() {
+      let mut by_worker = vec![vec![]; self.workers.len()];
+      for (from, from_channel, value) in sent {
+        for &(to, to_channel) in self.links.get(&(from, from_channel)).into_iter().flatten() {
+          let (w, local) = self.rtors[to];
+          by_worker[w].push((local, to_channel, value.clone()));
+        }
+      }
+      sent = self.run(by_worker, Command::Deliver);
+    }
+  }
+
+  /// Processes events until the next one would be after `end`.
+  pub fn run_until(&mut self, end: &Tag) {
+    while self.next_tag().is_some_and(|tag| tag <= *end) {
+      self.step();
+    }
+  }
+}
+
+/// Runs the rtors of a worker, reporting their next events when it starts and after each command,
+/// until the scheduler hangs up.
+fn serve<'db>(rtors: WorkerRtors<'db>, commands: &Receiver<Command>, reports: &Sender<Report>) {
+  let (mut rtors, levels): (Vec<_>, Vec<_>) = rtors
+    .into_iter()
+    .map(|(rtor, level)| {
+      let scheduled = Scheduled {
+        rtor,
+        tag: Tag::default(),
+      };
+      (scheduled, level)
+    })
+    .unzip();
+  let all = (0..rtors.len())
+    .map(|idx| (rtors[idx].rtor.next_event(), levels[idx]))
+    .collect();
+  if reports.send(Report::Started(all)).is_err() {
+    return;
+  }
+  let outbox: Rc<RefCell<Vec<Sent>>> = Rc::default();
+  let mut inputs: Vec<Vec<SetPort<'db>>> = rtors.iter().map(|_| vec![]).collect();
+  while let Ok(command) = commands.recv() {
+    let idxs: Vec<usize> = match command {
+      Command::Wire { sources, targets } => {
+        for idx in sources {
+          let outbox = Rc::clone(&outbox);
+          let mut ports: Inputs<'db> = Box::new((0..).map(move |channel| -> SetPort<'db> {
+            let outbox = Rc::clone(&outbox);
+            Box::new(move |x| {
+              let value = to_value(x).expect("values on links should be port values");
+              outbox.borrow_mut().push((idx, channel, value));
+            })
+          }));
+          rtors[idx].rtor.accept(&[], Side::Right, &mut ports);
+        }
+        for idx in targets {
+          inputs[idx] = rtors[idx].rtor.provide(&[], Side::Left).collect();
+        }
+        continue;
+      }
+      Command::Advance(now, idxs) => {
+        for &idx in &idxs {
+          rtors[idx].advance(&now);
+        }
+        idxs
+      }
+      Command::Deliver(values) => {
+        let mut idxs = vec![];
+        for (idx, channel, value) in values {
+          let port_value = from_value(&value).expect("values on links are port values");
+          inputs[idx][channel as usize](&*port_value);
+          idxs.push(idx);
+        }
+        idxs.sort_unstable();
+        idxs.dedup();
+        idxs
+      }
+    };
+    let next_events = idxs
+      .into_iter()
+      .map(|idx| (idx, rtors[idx].rtor.next_event()))
+      .collect();
+    let sent = std::mem::take(&mut *outbox.borrow_mut());
+    if reports.send(Report::Done { next_events, sent }).is_err() {
+      return;
+    }
+  }
+}
+
+impl Drop for ParallelScheduler {
+  fn drop(&mut self) {
+    for worker in &mut self.workers {
+      // Closing the channel stops the worker.
+      worker.commands.take();
+    }
+    for worker in &mut self.workers {
+      if let Some(thread) = worker.thread.take() {
+        // A worker that panicked has already reported it, and the scheduler may be dropped while
+        // unwinding from the resulting panic here.
+        let _ = thread.join();
+      }
+    }
+  }
+}
+
+/// How tags map to physical time in real-time execution.
+#[derive(Debug, Clone, Copy)]
+pub struct Pacing {
+  /// The physical duration of one step at the outermost nesting depth. Steps at deeper nesting
+  /// depths take no physical time.
+  pub unit: Duration,
+}
+
+impl Pacing {
+  /// The physical time of `tag`, measured from the start of execution. Tags too far in the future
+  /// to be measured in nanoseconds are paced at `Duration::MAX`, which is never reached.
+  pub fn physical_time(&self, tag: &Tag) -> Duration {
+    let steps = tag.0.first().copied().unwrap_or(0);
+    u64::try_from(self.unit.as_nanos())
+      .ok()
+      .and_then(|unit| unit.checked_mul(steps))
+      .map_or(Duration::MAX, Duration::from_nanos)
+  }
+}
+
+/// A tag whose events could not be processed at their physical time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lag {
+  pub tag: Tag,
+  /// How long after its physical time the processing of the events at `tag` started.
+  pub by: Duration,
+}
+
+/// A source of physical time for real-time execution.
+pub trait Clock {
+  /// The physical time elapsed since execution started.
+  fn elapsed(&self) -> Duration;
+  /// Waits for `duration` to pass.
+  fn sleep(&mut self, duration: Duration);
+}
+
+/// The wall clock, started when it is created.
+pub struct WallClock {
+  start: Instant,
+}
+
+impl Default for WallClock {
+  fn default() -> Self {
+    WallClock {
+      start: Instant::now(),
+    }
+  }
+}
+
+impl Clock for WallClock {
+  fn elapsed(&self) -> Duration {
+    self.start.elapsed()
+  }
+  fn sleep(&mut self, duration: Duration) {
+    std::thread::sleep(duration);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    cell::{Cell, RefCell},
+    sync::Mutex,
+  };
+
+  use irlf_db::ir::Inst;
+  use lf_types::Side;
+
+  use crate::{checkpoint::RtorState, rtor::Inputs};
+
+  use super::*;
+
+  /// An rtor that has events at the given tags and logs the steps that it takes.
+  struct Mock {
+    name: &'static str,
+    tag: Tag,
+    events: Vec<Tag>,
+    log: Rc<RefCell<Vec<String>>>,
+    /// The physical time that each step takes, and the clock on which it passes.
+    cost: Option<(Duration, Rc<Cell<Duration>>)>,
+  }
+
+  impl Mock {
+    fn reach(&mut self, tag: Tag, step: String) -> Option<Tag> {
+      self.log.borrow_mut().push(format!("{} {step}", self.name));
+      if let Some((cost, clock)) = &self.cost {
+        clock.set(clock.get() + *cost);
+      }
+      self.events.retain(|event| *event > tag);
+      self.tag = tag;
+      Some(self.tag.clone())
+    }
+  }
+
+  impl<'db> Rtor<'db> for Mock {
+    fn accept(&mut self, _: &[Inst], _: Side, _: &mut Inputs<'db>) {
+      unimplemented!()
+    }
+    fn provide(&self, _: &[Inst], _: Side) -> Inputs<'db> {
+      unimplemented!()
+    }
+    fn step_forward(&mut self, distance: u64) -> Option<Tag> {
+      self.reach(self.tag.step_forward(distance), format!("+{distance}"))
+    }
+    fn step_down(&mut self) {
+      self.reach(self.tag.step_down(), "down".to_string());
+    }
+    fn step_up(&mut self) -> Option<Tag> {
+      self.reach(self.tag.step_up().unwrap(), "up".to_string())
+    }
+    fn next_event(&self) -> Option<Tag> {
+      self.events.first().cloned()
+    }
+    fn save(&self) -> RtorState {
+      RtorState {
+        tag: Some(self.tag.clone()),
+        ..RtorState::default()
+      }
+    }
+    fn restore(&mut self, state: &RtorState) -> Result<(), RestoreError> {
+      let tag = state.tag.clone().ok_or(RestoreError::MissingTag)?;
+      self.events.retain(|event| *event > tag);
+      self.tag = tag;
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_skips_idle_tags() {
+    let log = Rc::new(RefCell::new(vec![]));
+    let mock = |name, events: &[&[u64]], level| {
+      let rtor: Box<dyn Rtor> = Box::new(Mock {
+        name,
+        tag: Tag::default(),
+        events: events.iter().map(|event| Tag(event.to_vec())).collect(),
+        log: Rc::clone(&log),
+        cost: None,
+      });
+      (rtor, Level(level))
+    };
+    let mut scheduler = Scheduler::new([
+      mock("b", &[&[5]], 1),
+      mock("a", &[&[2], &[2, 0, 3], &[5]], 0),
+    ]);
+    scheduler.run_until(&Tag(vec![4]));
+    assert_eq!(*log.borrow(), ["a +2", "a down", "a down", "a +3"]);
+    assert_eq!(scheduler.next_tag(), Some(Tag(vec![5])));
+    log.borrow_mut().clear();
+    // Rtors at lower levels step first.
+    assert_eq!(scheduler.step(), Some(Tag(vec![5])));
+    assert_eq!(*log.borrow(), ["a up", "a up", "a +3", "b +5"]);
+    assert_eq!(scheduler.step(), None);
+  }
+
+  /// A clock that only advances when rtors step or the scheduler sleeps.
+  struct FakeClock(Rc<Cell<Duration>>);
+
+  impl Clock for FakeClock {
+    fn elapsed(&self) -> Duration {
+      self.0.get()
+    }
+    fn sleep(&mut self, duration: Duration) {
+      self.0.set(self.0.get() + duration);
+    }
+  }
+
+  #[test]
+  fn test_real_time() {
+    let time = Rc::new(Cell::new(Duration::ZERO));
+    let ms = Duration::from_millis;
+    let rtor: Box<dyn Rtor> = Box::new(Mock {
+      name: "a",
+      tag: Tag::default(),
+      events: vec![Tag(vec![1]), Tag(vec![2]), Tag(vec![2, 1]), Tag(vec![9])],
+      log: Rc::new(RefCell::new(vec![])),
+      cost: Some((ms(15), Rc::clone(&time))),
+    });
+    let mut scheduler = Scheduler::new([(rtor, Level(0))]);
+    let mut lags = vec![];
+    scheduler.run_real_time(
+      &Tag(vec![10]),
+      Pacing { unit: ms(10) },
+      &mut FakeClock(Rc::clone(&time)),
+      |lag| lags.push(lag),
+    );
+    // Each step takes 15ms. Stepping to 1 ends at 25ms, after the physical time of 2, and stepping
+    // to 2 ends at 40ms, after the physical time of 2.1. Stepping into 2.1 ends at 70ms, which leaves
+    // 20ms to wait for 9.
+    assert_eq!(
+      lags,
+      [
+        Lag {
+          tag: Tag(vec![2]),
+          by: ms(5),
+        },
+        Lag {
+          tag: Tag(vec![2, 1]),
+          by: ms(20),
+        },
+      ]
+    );
+    assert_eq!(time.get(), ms(120));
+  }
+
+  #[test]
+  fn test_pacing() {
+    let pacing = Pacing {
+      unit: Duration::from_millis(10),
+    };
+    assert_eq!(
+      pacing.physical_time(&Tag(vec![3, 7])),
+      Duration::from_millis(30)
+    );
+    assert_eq!(
+      pacing.physical_time(&Tag(vec![u64::from(u32::MAX) + 1])),
+      Duration::from_millis(10 * (u64::from(u32::MAX) + 1))
+    );
+    assert_eq!(pacing.physical_time(&Tag(vec![u64::MAX])), Duration::MAX);
+  }
+
+  /// The seeds of some walkers and the tags that they reached.
+  type Report = Arc<Mutex<Vec<(u64, Vec<Tag>)>>>;
+
+  /// An rtor that records the tags that it reaches, with events that depend on the tags reached
+  /// so far.
+  struct Walker {
+    seed: u64,
+    tag: Tag,
+    reached: Vec<Tag>,
+    /// Where the seed and the reached tags go when the walker is dropped, for walkers that live on
+    /// other threads.
+    report: Option<Report>,
+  }
+
+  /// Walkers with the given seeds, spread over three levels.
+  fn walkers(seeds: impl Iterator<Item = u64>) -> impl Iterator<Item = (Box<Walker>, Level)> {
+    reporting_walkers(seeds, None)
+  }
+
+  fn reporting_walkers(
+    seeds: impl Iterator<Item = u64>,
+    report: Option<Report>,
+  ) -> impl Iterator<Item = (Box<Walker>, Level)> {
+    seeds.map(move |seed| {
+      let walker = Box::new(Walker {
+        seed,
+        tag: Tag::default(),
+        reached: vec![],
+        report: report.clone(),
+      });
+      (walker, Level((seed % 3) as u32))
+    })
+  }
+
+  impl Drop for Walker {
+    fn drop(&mut self) {
+      if let Some(report) = &self.report {
+        let reached = std::mem::take(&mut self.reached);
+        report.lock().unwrap().push((self.seed, reached));
+      }
+    }
+  }
+
+  impl Walker {
+    fn reach(&mut self, tag: Tag) -> Option<Tag> {
+      self.reached.push(tag.clone());
+      self.tag = tag;
+      Some(self.tag.clone())
+    }
+  }
+
+  impl<'db> Rtor<'db> for Walker {
+    fn accept(&mut self, _: &[Inst], _: Side, _: &mut Inputs<'db>) {
+      unimplemented!()
+    }
+    fn provide(&self, _: &[Inst], _: Side) -> Inputs<'db> {
+      unimplemented!()
+    }
+    fn step_forward(&mut self, distance: u64) -> Option<Tag> {
+      self.reach(self.tag.step_forward(distance))
+    }
+    fn step_down(&mut self) {
+      self.reach(self.tag.step_down());
+    }
+    fn step_up(&mut self) -> Option<Tag> {
+      self.reach(self.tag.step_up().unwrap())
+    }
+    fn next_event(&self) -> Option<Tag> {
+      let n = self
+        .seed
+        .wrapping_mul(31)
+        .wrapping_add(self.reached.len() as u64 * 7)
+        % 5;
+      Some(match n {
+        0 => self.tag.step_down(),
+        1 if self.tag.depth() > 0 => self.tag.step_up().unwrap().step_forward(1),
+        _ => self.tag.step_forward(n),
+      })
+    }
+    fn save(&self) -> RtorState {
+      unimplemented!()
+    }
+    fn restore(&mut self, _: &RtorState) -> Result<(), RestoreError> {
+      unimplemented!()
+    }
+  }
+
+  #[test]
+  fn test_parallel_is_deterministic() {
+    const THREADS: u64 = 4;
+    let end = Tag(vec![30]);
+    let mut sequential = Scheduler::new(walkers(0..40));
+    sequential.run_until(&end);
+    let sequential: Vec<_> = sequential
+      .into_rtors()
+      .into_iter()
+      .map(|walker| walker.reached.clone())
+      .collect();
+    assert!(sequential.iter().all(|tags| tags.len() > 5));
+    let report = Arc::new(Mutex::new(vec![]));
+    let mut parallel = {
+      let report = Arc::clone(&report);
+      ParallelScheduler::new(
+        NonZeroUsize::new(THREADS as usize).unwrap(),
+        &[],
+        move |i, serve| {
+          let seeds = (0..40).filter(|seed| seed % THREADS == i as u64);
+          serve(
+            reporting_walkers(seeds, Some(Arc::clone(&report)))
+              .map(|(walker, level)| (walker as Box<dyn Rtor>, level))
+              .collect(),
+          );
+        },
+      )
+    };
+    parallel.run_until(&end);
+    drop(parallel);
+    let mut parallel = report.lock().unwrap().clone();
+    parallel.sort_by_key(|(seed, _)| *seed);
+    let parallel: Vec<_> = parallel.into_iter().map(|(_, reached)| reached).collect();
+    assert_eq!(sequential, parallel);
+  }
+
+  #[test]
+  fn test_restore() {
+    let log = Rc::new(RefCell::new(vec![]));
+    let scheduler = || {
+      let mock = |name, events: &[&[u64]]| {
+        let rtor: Box<dyn Rtor> = Box::new(Mock {
+          name,
+          tag: Tag::default(),
+          events: events.iter().map(|event| Tag(event.to_vec())).collect(),
+          log: Rc::clone(&log),
+          cost: None,
+        });
+        (rtor, Level(0))
+      };
+      Scheduler::new([mock("a", &[&[1], &[3], &[3, 2]]), mock("b", &[&[2], &[4]])])
+    };
+    let mut original = scheduler();
+    original.run_until(&Tag(vec![2]));
+    let checkpoint = original.checkpoint(7);
+    let mut text = vec![];
+    checkpoint.write_to(&mut text).unwrap();
+    let checkpoint = Checkpoint::read_from(&text[..]).unwrap();
+    let mut restored = scheduler();
+    assert_eq!(
+      restored.restore(&checkpoint, 8),
+      Err(RestoreError::ProgramChanged {
+        saved: 7,
+        current: 8
+      })
+    );
+    let mut corrupted = checkpoint.clone();
+    corrupted.rtors[1].tag = None;
+    assert_eq!(
+      restored.restore(&corrupted, 7),
+      Err(RestoreError::MissingTag)
+    );
+    restored.restore(&checkpoint, 7).unwrap();
+    log.borrow_mut().clear();
+    restored.run_until(&Tag(vec![10]));
+    assert_eq!(*log.borrow(), ["a +2", "a down", "a +2", "b +2"]);
+  }
+}
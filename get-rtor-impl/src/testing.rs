@@ -0,0 +1,37 @@
+//! Programs and helpers that the tests of several modules share.
+
+use irlf_db::ir::{Ctor, Program, StructlikeCtor};
+
+/// Two instances, `x` (101) and `y` (102), of an sctor that wraps an `add1` (100), with connection
+/// 200 from `x` to `y`. Main exposes the left side of `y`, a notify and the left side of `x`.
+pub(crate) const PAIR: &str = "add1 0x0 add1
+---
+---
+rtor0 0x1
+  a 100 = 0x0
+  ---
+  L 100 R 100
+  ---
+rtor1 0x2
+  x 101 = 0x1
+  y 102 = 0x1
+  ---
+  L 102
+  L -
+  L 101
+  ---
+  200 101 102
+---
+0x2
+";
+
+/// Returns the main ctor of `program`.
+///
+/// # Panics
+/// Panics if the main ctor of `program` is not an sctor.
+pub(crate) fn main_sctor(db: &dyn irlf_db::Db, program: Program) -> StructlikeCtor {
+  let Ctor::StructlikeCtor(sctor) = program.main(db) else {
+    panic!("the main ctor of a test program should be an sctor")
+  };
+  *sctor
+}
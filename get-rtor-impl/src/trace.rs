@@ -0,0 +1,292 @@
+use std::{
+  cell::{Cell, RefCell},
+  fmt::Write,
+  rc::Rc,
+};
+
+use irlf_db::ir::{Ctor, Program, StructlikeCtor};
+use lf_types::{Comm, DebugOnlyId, FlowDirection, InstId, Level};
+
+use crate::{
+  rtor::ComptimeInput,
+  rtorimpl::{
+    srtorimpl::{assign_levels, bank_members, CausalityLoop},
+    FixpointingStatus,
+  },
+  Db,
+};
+
+/// Why a level was raised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cause {
+  /// The level of the instance at the other end of the given connection.
+  Connection(DebugOnlyId),
+  /// A lower bound on the instance as a whole, such as the one given by its position in the iface
+  /// of its parent.
+  LowerBound,
+}
+
+/// The bank members that lead from a child of the main ctor down to a nested instance, each given by
+/// the id of its bank and its index in the bank.
+pub type MemberPath = Vec<(InstId, u64)>;
+
+/// Shows `path` as the ids of its members with their indices, such as `101[0].100[2]`.
+pub fn show_path(path: &[(InstId, u64)]) -> String {
+  path
+    .iter()
+    .map(|(inst, i)| format!("{inst}[{i}]"))
+    .collect::<Vec<_>>()
+    .join(".")
+}
+
+/// Parses a non-empty path in the format produced by `show_path`.
+pub fn parse_path(s: &str) -> Result<MemberPath, String> {
+  s.split('.')
+    .map(|member| {
+      let invalid = || format!("expected a bank member such as 101[0] at \"{member}\"");
+      let (inst, i) = member
+        .strip_suffix(']')
+        .and_then(|member| member.split_once('['))
+        .ok_or_else(invalid)?;
+      Ok((
+        InstId(inst.parse().map_err(|_| invalid())?),
+        i.parse().map_err(|_| invalid())?,
+      ))
+    })
+    .collect()
+}
+
+/// A raise of the level to which an instance maps one of its intrinsic levels.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bump {
+  pub member: MemberPath,
+  pub intrinsic_level: Level,
+  pub from: Level,
+  pub to: Level,
+  pub lower_bound: Level,
+  pub cause: Cause,
+  pub direction: FlowDirection,
+}
+
+/// The bumps made while assigning the levels of the children of a structlike ctor and of the
+/// children of the structlike ctors nested in it, in the order in which they were made.
+#[derive(Debug, Default)]
+pub struct Trace {
+  connection: Cell<Option<DebugOnlyId>>,
+  bumps: RefCell<Vec<Bump>>,
+}
+
+impl Trace {
+  pub fn bumps(&self) -> Vec<Bump> {
+    self.bumps.borrow().clone()
+  }
+
+  /// Returns the bumps of the level to which `member` maps `intrinsic_level`.
+  pub fn bumps_of(&self, member: &[(InstId, u64)], intrinsic_level: Level) -> Vec<Bump> {
+    self
+      .bumps
+      .borrow()
+      .iter()
+      .filter(|bump| bump.member == member && bump.intrinsic_level == intrinsic_level)
+      .cloned()
+      .collect()
+  }
+
+  /// Explains the final level to which `member` maps `intrinsic_level`.
+  pub fn justify(&self, member: &[(InstId, u64)], intrinsic_level: Level) -> String {
+    let bumps = self.bumps_of(member, intrinsic_level);
+    let last = bumps.last().map_or(intrinsic_level, |bump| bump.to);
+    let path = show_path(member);
+    let mut ret = format!(
+      "instance {path} maps its intrinsic level {} to level {}:",
+      intrinsic_level.0, last.0
+    );
+    if bumps.is_empty() {
+      ret.push_str("\n  never raised");
+    }
+    for bump in bumps {
+      let cause = match bump.cause {
+        Cause::Connection(id) => format!("connection {id}"),
+        Cause::LowerBound => "a lower bound on the whole instance".to_string(),
+      };
+      write!(
+        ret,
+        "\n  raised from {} to {} by {cause} (lower bound {}, last flow {:?})",
+        bump.from.0, bump.to.0, bump.lower_bound.0, bump.direction
+      )
+      .unwrap();
+    }
+    ret
+  }
+
+  /// Attributes the bumps made through the given inputs to `connection`.
+  pub(crate) fn attribute(
+    self: &Rc<Self>,
+    connection: DebugOnlyId,
+  ) -> Rc<dyn Fn(ComptimeInput) -> ComptimeInput> {
+    let trace = Rc::clone(self);
+    Rc::new(move |input: ComptimeInput| {
+      input.map(|f| {
+        let f = Rc::clone(f);
+        let trace = Rc::clone(&trace);
+        let ret: Rc<dyn Fn(Comm<Level>) -> FixpointingStatus> = Rc::new(move |level| {
+          let outer = trace.connection.replace(Some(connection));
+          let ret = f(level);
+          trace.connection.set(outer);
+          ret
+        });
+        ret
+      })
+    })
+  }
+}
+
+/// Where the bumps of the levels of one instance are recorded.
+#[derive(Clone)]
+pub struct Tracer {
+  pub(crate) trace: Rc<Trace>,
+  pub(crate) member: MemberPath,
+}
+
+impl Tracer {
+  /// The tracer of the given bank member of the ctor of the instance traced by `self`.
+  pub(crate) fn child(&self, inst: InstId, i: u64) -> Tracer {
+    let mut member = self.member.clone();
+    member.push((inst, i));
+    Tracer {
+      trace: Rc::clone(&self.trace),
+      member,
+    }
+  }
+
+  /// The cause of a bump made by an input.
+  pub(crate) fn input_cause(&self) -> Cause {
+    Cause::Connection(
+      self
+        .trace
+        .connection
+        .get()
+        .expect("inputs should be attributed to connections while tracing"),
+    )
+  }
+
+  pub(crate) fn record(
+    &self,
+    cause: Cause,
+    direction: FlowDirection,
+    lower_bound: Level,
+  ) -> impl FnMut(Level, Level, Level) + '_ {
+    move |intrinsic_level, from, to| {
+      self.trace.bumps.borrow_mut().push(Bump {
+        member: self.member.clone(),
+        intrinsic_level,
+        from,
+        to,
+        lower_bound,
+        cause,
+        direction,
+      });
+    }
+  }
+}
+
+/// Assigns the levels of the children of the main ctor of `program`, and of the children of every
+/// structlike ctor nested in it, as realizing it would, and records why each level was raised.
+///
+/// # Errors
+/// Returns the causality loop that prevents the levels from being assigned, if there is one.
+pub fn explain_levels(db: &dyn Db, program: Program) -> Result<Rc<Trace>, CausalityLoop> {
+  let trace = Rc::new(Trace::default());
+  if let Ctor::StructlikeCtor(sctor) = program.main(db) {
+    explain_sctor(
+      db,
+      *sctor,
+      &Tracer {
+        trace: Rc::clone(&trace),
+        member: vec![],
+      },
+    )?;
+  }
+  Ok(trace)
+}
+
+/// Assigns the levels of the children of `sctor`, which is the ctor of the instance traced by
+/// `tracer`, and then recurses into those children that are structlike.
+fn explain_sctor(db: &dyn Db, sctor: StructlikeCtor, tracer: &Tracer) -> Result<(), CausalityLoop> {
+  assign_levels(db, sctor, Some(tracer))?;
+  for (inst, i) in bank_members(db, sctor) {
+    if let Ctor::StructlikeCtor(child) = inst.ctor(db) {
+      explain_sctor(db, *child, &tracer.child(inst.id(db), i))?;
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use irlf_db::from_text;
+
+  use crate::{testing::PAIR, GriTestDatabase};
+
+  use super::*;
+
+  #[test]
+  fn test_justify() {
+    let db = GriTestDatabase::default();
+    let (program, _) = from_text(PAIR, &db);
+    let trace = explain_levels(&db, program).unwrap();
+    assert_eq!(
+      trace.justify(&[(InstId(101), 0)], Level(0)),
+      "instance 101[0] maps its intrinsic level 0 to level 1:
+  raised from 0 to 1 by a lower bound on the whole instance (lower bound 1, last flow In)"
+    );
+    assert_eq!(
+      trace.justify(&[(InstId(102), 0)], Level(0)),
+      "instance 102[0] maps its intrinsic level 0 to level 1:
+  raised from 0 to 1 by connection 200 (lower bound 1, last flow In)"
+    );
+    assert_eq!(trace.bumps().len(), 2);
+  }
+
+  const NESTED: &str = "add1 0x0 add1
+---
+---
+rtor0 0x1
+  a 100 = 0x0
+  ---
+  L 100 R 100
+  ---
+rtor1 0x2
+  p 101 = 0x1
+  q 102 = 0x1
+  ---
+  L -
+  L 101
+  R 102
+  ---
+  200 101 102
+rtor2 0x3
+  x 103 = 0x2 [2]
+  ---
+  L 103
+  ---
+---
+0x3
+";
+
+  #[test]
+  fn test_justify_nested() {
+    let db = GriTestDatabase::default();
+    let (program, _) = from_text(NESTED, &db);
+    let trace = explain_levels(&db, program).unwrap();
+    for i in 0..2 {
+      assert_eq!(
+        trace.justify(&[(InstId(103), i), (InstId(102), 0)], Level(0)),
+        format!(
+          "instance 103[{i}].102[0] maps its intrinsic level 0 to level 1:
+  raised from 0 to 1 by connection 200 (lower bound 1, last flow In)"
+        )
+      );
+    }
+  }
+}
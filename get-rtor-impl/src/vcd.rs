@@ -0,0 +1,540 @@
+use std::{
+  collections::HashMap,
+  fmt::Display,
+  io::{self, Write},
+};
+
+use irlf_db::ir::Id2Sym;
+use lf_types::{Side, Tag, Type, Value};
+
+use crate::{
+  record::{PortWrite, Recording},
+  trace::{show_path, MemberPath},
+  Db,
+};
+
+/// A port of an instance, identified by the path of bank members from `main` to its owner.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Port {
+  pub path: MemberPath,
+  pub side: Side,
+  pub channel: u64,
+}
+
+impl Port {
+  fn of(write: &PortWrite) -> Self {
+    Port {
+      path: write.path.clone(),
+      side: write.side,
+      channel: write.channel,
+    }
+  }
+}
+
+/// How tags map to VCD time, which is a single number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimeEncoding {
+  /// Only the outermost component of a tag counts, so the events in nested time share the VCD time
+  /// of the tag that they are nested within.
+  Outermost,
+  /// The components of a tag are digits with the given radices for nesting depths 1, 2, and so on,
+  /// so that `MixedRadix(vec![10])` maps `3.4` to 34 and `3` to 30.
+  MixedRadix(Vec<u64>),
+}
+
+impl TimeEncoding {
+  /// Returns the VCD time of `tag`.
+  ///
+  /// # Errors
+  /// Fails if `tag` is nested more deeply than the encoding allows, if a component of it does not
+  /// fit its radix, or if its time does not fit in a `u64`.
+  pub fn encode(&self, tag: &Tag) -> Result<u64, EncodeError> {
+    let outermost = tag.0.first().copied().unwrap_or(0);
+    match self {
+      TimeEncoding::Outermost => Ok(outermost),
+      TimeEncoding::MixedRadix(radices) => {
+        if tag.depth() > radices.len() {
+          return Err(EncodeError::TooDeep(tag.clone()));
+        }
+        radices
+          .iter()
+          .enumerate()
+          .try_fold(outermost, |time, (i, &radix)| {
+            let digit = tag.0.get(i + 1).copied().unwrap_or(0);
+            if digit >= radix {
+              return Err(EncodeError::DigitTooLarge {
+                tag: tag.clone(),
+                depth: i + 1,
+                radix,
+              });
+            }
+            time
+              .checked_mul(radix)
+              .and_then(|time| time.checked_add(digit))
+              .ok_or_else(|| EncodeError::Overflow(tag.clone()))
+          })
+      }
+    }
+  }
+}
+
+/// The reasons why a tag has no VCD time under a `TimeEncoding`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+  /// The tag is nested more deeply than there are radices.
+  TooDeep(Tag),
+  /// The component of the tag at the given depth is not less than the radix for that depth.
+  DigitTooLarge { tag: Tag, depth: usize, radix: u64 },
+  /// The time of the tag does not fit in a `u64`.
+  Overflow(Tag),
+}
+
+impl Display for EncodeError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      EncodeError::TooDeep(tag) => write!(f, "tag {tag} is nested too deeply to be encoded"),
+      EncodeError::DigitTooLarge { tag, depth, radix } => write!(
+        f,
+        "tag {tag} does not fit the radix {radix} at depth {depth}"
+      ),
+      EncodeError::Overflow(tag) => write!(f, "the time of tag {tag} is too large to be encoded"),
+    }
+  }
+}
+
+/// Writes the values of selected ports as a VCD waveform.
+pub struct VcdWriter<W: Write> {
+  out: W,
+  encoding: TimeEncoding,
+  vars: HashMap<Port, Var>,
+  time: Option<u64>,
+}
+
+/// The VCD variables of a port. A port of a tuple type has a variable for each field, in the order
+/// of the fields, and each field is flattened in the same way.
+#[derive(Debug)]
+struct Var {
+  ty: Type,
+  codes: Vec<String>,
+}
+
+impl<W: Write> VcdWriter<W> {
+  /// Writes the header of a waveform of `ports`, each of the given type, to `out`. The scopes of
+  /// the waveform follow the instance hierarchy, with one scope per bank member named as in
+  /// `id2sym` followed by its index, such as `x[1]`, and the ports of `main` are in the outermost
+  /// scope. Ints and bools are declared as integers and floats as reals.
+  ///
+  /// # Errors
+  /// Fails with `io::ErrorKind::InvalidInput` if the path of a port names an instance that is not in
+  /// `id2sym`, or if the type of a port is or contains a named type, which VCD cannot show.
+  pub fn new(
+    mut out: W,
+    db: &dyn Db,
+    id2sym: Id2Sym,
+    ports: &[(Port, Type)],
+    encoding: TimeEncoding,
+  ) -> io::Result<Self> {
+    let inst2sym = id2sym.inst2sym(db);
+    let mut ports = ports.to_vec();
+    ports.sort_by_key(|(port, _)| (port.path.clone(), port.side == Side::Right, port.channel));
+    ports.dedup_by(|a, b| a.0 == b.0);
+    let mut vars = HashMap::new();
+    let mut next_code = 0;
+    let mut scope: MemberPath = vec![];
+    writeln!(out, "$scope module main $end")?;
+    for (port, ty) in ports {
+      while !port.path.starts_with(&scope) {
+        scope.pop();
+        writeln!(out, "$upscope $end")?;
+      }
+      for &(id, i) in &port.path[scope.len()..] {
+        let Some(name) = inst2sym.get(&id) else {
+          return Err(invalid_input(format!(
+            "instance {id} is not in the program"
+          )));
+        };
+        writeln!(out, "$scope module {name}[{i}] $end")?;
+        scope.push((id, i));
+      }
+      if !shown(&ty) {
+        let owner = if port.path.is_empty() {
+          "main".to_string()
+        } else {
+          show_path(&port.path)
+        };
+        return Err(invalid_input(format!(
+          "port {}{} of {owner} has type {ty}, which VCD cannot show",
+          port.side, port.channel
+        )));
+      }
+      let mut codes = vec![];
+      declare(
+        &mut out,
+        &ty,
+        &format!("{}{}", port.side, port.channel),
+        &mut next_code,
+        &mut codes,
+      )?;
+      vars.insert(port, Var { ty, codes });
+    }
+    for _ in 0..=scope.len() {
+      writeln!(out, "$upscope $end")?;
+    }
+    writeln!(out, "$enddefinitions $end")?;
+    Ok(VcdWriter {
+      out,
+      encoding,
+      vars,
+      time: None,
+    })
+  }
+
+  /// Writes the value change of `write` if its port was selected. Writes must be given in the order
+  /// in which they were made.
+  ///
+  /// # Errors
+  /// Fails with `io::ErrorKind::InvalidInput`, without writing anything, if the value of `write` is
+  /// not of the type of its port, if its tag has no VCD time, or if that time precedes the time of
+  /// an earlier write.
+  pub fn write(&mut self, write: &PortWrite) -> io::Result<()> {
+    let Some(var) = self.vars.get(&Port::of(write)) else {
+      return Ok(());
+    };
+    let mut changes = vec![];
+    if !changes_of(&write.value, &var.ty, &mut changes) {
+      return Err(invalid_input(format!(
+        "the value of \"{write}\" is not of type {}",
+        var.ty
+      )));
+    }
+    let time = self
+      .encoding
+      .encode(&write.tag)
+      .map_err(|e| invalid_input(e.to_string()))?;
+    if self.time != Some(time) {
+      if self.time.is_some_and(|last| last > time) {
+        return Err(invalid_input(format!(
+          "\"{write}\" was given after a write at a later time"
+        )));
+      }
+      writeln!(self.out, "#{time}")?;
+      self.time = Some(time);
+    }
+    for (change, code) in changes.iter().zip(&var.codes) {
+      writeln!(self.out, "{change} {code}")?;
+    }
+    Ok(())
+  }
+
+  /// Writes the value changes of the selected ports in `recording`.
+  pub fn write_recording(&mut self, recording: &Recording) -> io::Result<()> {
+    for write in &recording.writes {
+      self.write(write)?;
+    }
+    Ok(())
+  }
+
+  pub fn into_inner(self) -> W {
+    self.out
+  }
+}
+
+/// Returns whether VCD can show the values of type `ty`, which it cannot if `ty` is or contains a
+/// named type.
+fn shown(ty: &Type) -> bool {
+  match ty {
+    Type::Int | Type::Float | Type::Bool => true,
+    Type::Tuple(fields) => fields.iter().all(shown),
+    Type::Named(_) => false,
+  }
+}
+
+/// Declares the variables of a port of type `ty` named `name`, which `shown` accepts, with the
+/// identifier codes from `next_code` on, and appends those codes to `codes`. The fields of a tuple
+/// are named after their positions.
+fn declare(
+  out: &mut impl Write,
+  ty: &Type,
+  name: &str,
+  next_code: &mut usize,
+  codes: &mut Vec<String>,
+) -> io::Result<()> {
+  let kind = match ty {
+    Type::Int | Type::Bool => "integer",
+    Type::Float => "real",
+    Type::Named(_) => unreachable!("`shown` rejects named types"),
+    Type::Tuple(fields) => {
+      for (i, field) in fields.iter().enumerate() {
+        declare(out, field, &format!("{name}_{i}"), next_code, codes)?;
+      }
+      return Ok(());
+    }
+  };
+  let code = identifier_code(*next_code);
+  *next_code += 1;
+  writeln!(out, "$var {kind} 64 {code} {name} $end")?;
+  codes.push(code);
+  Ok(())
+}
+
+/// Appends the value changes of the variables of a port of type `ty` to `changes`, if `value` is
+/// of that type, and returns whether it is.
+fn changes_of(value: &Value, ty: &Type, changes: &mut Vec<String>) -> bool {
+  match (value, ty) {
+    (Value::Int(x), Type::Int) => changes.push(format!("b{x:b}")),
+    (Value::Bool(b), Type::Bool) => changes.push(format!("b{}", u8::from(*b))),
+    (Value::Float(x), Type::Float) => changes.push(format!("r{x}")),
+    (Value::Tuple(values), Type::Tuple(fields)) if values.len() == fields.len() => {
+      return values
+        .iter()
+        .zip(fields)
+        .all(|(value, field)| changes_of(value, field, changes))
+    }
+    _ => return false,
+  }
+  true
+}
+
+fn invalid_input(message: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// The `i`th identifier code, using the printable ASCII characters that VCD allows.
+fn identifier_code(mut i: usize) -> String {
+  const FIRST: u8 = b'!';
+  const N: usize = (b'~' - b'!' + 1) as usize;
+  let mut ret = String::new();
+  loop {
+    ret.push(char::from(FIRST + (i % N) as u8));
+    i /= N;
+    if i == 0 {
+      return ret;
+    }
+    i -= 1;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use irlf_db::from_text;
+  use lf_types::{InstId, Level};
+
+  use crate::{testing::PAIR, GriTestDatabase};
+
+  use super::*;
+
+  fn port(path: &[(u64, u64)], side: Side, channel: u64) -> Port {
+    Port {
+      path: path.iter().map(|&(id, i)| (InstId(id), i)).collect(),
+      side,
+      channel,
+    }
+  }
+
+  fn write(tag: &[u64], port: &Port, value: Value) -> PortWrite {
+    PortWrite {
+      tag: Tag(tag.to_vec()),
+      level: Level(0),
+      path: port.path.clone(),
+      side: port.side,
+      channel: port.channel,
+      value,
+    }
+  }
+
+  #[test]
+  fn test_vcd() {
+    let db = GriTestDatabase::default();
+    let (_, id2sym) = from_text(PAIR, &db);
+    let input = port(&[], Side::Left, 0);
+    let inner = port(&[(101, 0), (100, 0)], Side::Right, 0);
+    let outer = port(&[(102, 0)], Side::Right, 0);
+    let mut vcd = VcdWriter::new(
+      vec![],
+      &db,
+      id2sym,
+      &[
+        (outer.clone(), Type::Bool),
+        (inner.clone(), Type::Int),
+        (input.clone(), Type::Int),
+      ],
+      TimeEncoding::MixedRadix(vec![10]),
+    )
+    .unwrap();
+    let recording = Recording {
+      writes: vec![
+        write(&[0], &input, Value::Int(5)),
+        write(&[0, 1], &inner, Value::Int(6)),
+        write(&[0, 1], &port(&[(101, 0)], Side::Right, 0), Value::Int(6)),
+        write(&[2], &outer, Value::Bool(true)),
+      ],
+    };
+    vcd.write_recording(&recording).unwrap();
+    assert_eq!(
+      String::from_utf8(vcd.into_inner()).unwrap(),
+      "$scope module main $end
+$var integer 64 ! L0 $end
+$scope module x[0] $end
+$scope module a[0] $end
+$var integer 64 \" R0 $end
+$upscope $end
+$upscope $end
+$scope module y[0] $end
+$var integer 64 # R0 $end
+$upscope $end
+$upscope $end
+$enddefinitions $end
+#0
+b101 !
+#1
+b110 \"
+#20
+b1 #
+"
+    );
+  }
+
+  #[test]
+  fn test_vcd_real_and_tuple() {
+    let db = GriTestDatabase::default();
+    let (_, id2sym) = from_text(PAIR, &db);
+    let input = port(&[], Side::Left, 0);
+    let output = port(&[], Side::Right, 0);
+    let pair = Type::Tuple(vec![Type::Bool, Type::Tuple(vec![Type::Float, Type::Int])]);
+    let mut vcd = VcdWriter::new(
+      vec![],
+      &db,
+      id2sym,
+      &[(input.clone(), Type::Float), (output.clone(), pair)],
+      TimeEncoding::Outermost,
+    )
+    .unwrap();
+    vcd.write(&write(&[1], &input, Value::Float(2.5))).unwrap();
+    let value = Value::Tuple(vec![
+      Value::Bool(true),
+      Value::Tuple(vec![Value::Float(-0.25), Value::Int(3)]),
+    ]);
+    vcd.write(&write(&[1], &output, value)).unwrap();
+    let err = vcd
+      .write(&write(&[2], &output, Value::Tuple(vec![Value::Bool(true)])))
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "the value of \"2 0 - R 0 (true)\" is not of type (bool,(float,int))"
+    );
+    assert_eq!(
+      String::from_utf8(vcd.into_inner()).unwrap(),
+      "$scope module main $end
+$var real 64 ! L0 $end
+$var integer 64 \" R0_0 $end
+$var real 64 # R0_1_0 $end
+$var integer 64 $ R0_1_1 $end
+$upscope $end
+$enddefinitions $end
+#1
+r2.5 !
+b1 \"
+r-0.25 #
+b11 $
+"
+    );
+  }
+
+  #[test]
+  fn test_encoding() {
+    let tag = Tag(vec![3, 4, 5]);
+    assert_eq!(TimeEncoding::Outermost.encode(&tag), Ok(3));
+    assert_eq!(
+      TimeEncoding::MixedRadix(vec![10, 100]).encode(&tag),
+      Ok(3405)
+    );
+    assert_eq!(
+      TimeEncoding::MixedRadix(vec![10, 100]).encode(&Tag(vec![3])),
+      Ok(3000)
+    );
+    assert_eq!(
+      TimeEncoding::MixedRadix(vec![10]).encode(&tag),
+      Err(EncodeError::TooDeep(tag.clone()))
+    );
+    assert_eq!(
+      TimeEncoding::MixedRadix(vec![10, 5]).encode(&tag),
+      Err(EncodeError::DigitTooLarge {
+        tag: tag.clone(),
+        depth: 2,
+        radix: 5
+      })
+    );
+    assert_eq!(
+      TimeEncoding::MixedRadix(vec![u64::MAX]).encode(&Tag(vec![2, 0])),
+      Err(EncodeError::Overflow(Tag(vec![2, 0])))
+    );
+    assert_eq!(identifier_code(0), "!");
+    assert_eq!(identifier_code(94), "!!");
+  }
+
+  #[test]
+  fn test_invalid_writes() {
+    let db = GriTestDatabase::default();
+    let (_, id2sym) = from_text(PAIR, &db);
+    let unknown = port(&[(105, 0)], Side::Left, 0);
+    let err = VcdWriter::new(
+      vec![],
+      &db,
+      id2sym,
+      &[(unknown, Type::Int)],
+      TimeEncoding::Outermost,
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.to_string(), "instance 105 is not in the program");
+    let named = (
+      port(&[(101, 0)], Side::Left, 0),
+      Type::Named("celsius".into()),
+    );
+    let err = VcdWriter::new(vec![], &db, id2sym, &[named], TimeEncoding::Outermost)
+      .err()
+      .unwrap();
+    assert_eq!(
+      err.to_string(),
+      "port L0 of 101[0] has type celsius, which VCD cannot show"
+    );
+    let input = port(&[], Side::Left, 0);
+    let mut vcd = VcdWriter::new(
+      vec![],
+      &db,
+      id2sym,
+      &[(input.clone(), Type::Int)],
+      TimeEncoding::MixedRadix(vec![10]),
+    )
+    .unwrap();
+    vcd.write(&write(&[2], &input, Value::Int(1))).unwrap();
+    for (write, message) in [
+      (
+        write(&[1], &input, Value::Int(1)),
+        "\"1 0 - L 0 1\" was given after a write at a later time",
+      ),
+      (
+        write(&[3, 12], &input, Value::Int(1)),
+        "tag 3.12 does not fit the radix 10 at depth 1",
+      ),
+      (
+        write(&[3], &input, Value::Float(0.5)),
+        "the value of \"3 0 - L 0 0.5\" is not of type int",
+      ),
+    ] {
+      let err = vcd.write(&write).unwrap_err();
+      assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+      assert_eq!(err.to_string(), message);
+    }
+    assert_eq!(
+      String::from_utf8(vcd.into_inner()).unwrap(),
+      "$scope module main $end
+$var integer 64 ! L0 $end
+$upscope $end
+$enddefinitions $end
+#20
+b1 !
+"
+    );
+  }
+}
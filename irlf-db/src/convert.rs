@@ -1,4 +1,4 @@
-use std::{cell::RefCell, collections::HashMap};
+use std::collections::HashMap;
 
 use irlf_ser::visitor::Visitor;
 use lf_types::{Comm, CtorId, Iface, IfaceNode, InstId};
@@ -50,22 +50,6 @@ impl irlf_ser::visitor::Visitor for GetIds {
   }
 }
 
-std::thread_local! {
-  static MEMO_CTORS : RefCell<HashMap<CtorId, crate::ir::Ctor>> = RefCell::new(HashMap::new());
-  static MEMO_INSTS : RefCell<HashMap<InstId, crate::ir::Inst>> = RefCell::new(HashMap::new());
-}
-
-macro_rules! check_cache {
-  ($cache_name: ident, $id: ident) => {
-    if let Some(ret) = $cache_name.with(|map| {
-      let borrowed = map.borrow_mut();
-      borrowed.get(&$id).cloned()
-    }) {
-      return ret;
-    }
-  };
-}
-
 fn convert_ctor(
   db: &dyn Db,
   instid2inst: &HashMap<&InstId, &irlf_ser::ir::CtorCall>,
@@ -73,12 +57,11 @@ fn convert_ctor(
   id: CtorId,
   ctor: &irlf_ser::ir::Ctor,
 ) -> crate::ir::Ctor {
-  check_cache!(MEMO_CTORS, id);
   match ctor {
     irlf_ser::ir::Ctor::StructlikeCtor(sctor) => {
       let insts = convert_insts(db, instid2inst, ctorid2ctor, sctor);
-      let iface = convert_iface(db, instid2inst, ctorid2ctor, &sctor.iface);
-      let connections = convert_connections(db, instid2inst, ctorid2ctor, &sctor.connections);
+      let iface = convert_iface(db, instid2inst, ctorid2ctor, sctor);
+      let connections = convert_connections(db, instid2inst, ctorid2ctor, sctor);
       crate::ir::Ctor::StructlikeCtor(crate::ir::StructlikeCtor::new(
         db,
         id,
@@ -90,9 +73,12 @@ fn convert_ctor(
     irlf_ser::ir::Ctor::BinaryCtor(bctor) => {
       crate::ir::Ctor::BinaryCtor(crate::ir::BinaryCtor::new(db, id, bctor.path.clone()))
     }
-    irlf_ser::ir::Ctor::LibCtor(lctor) => {
-      crate::ir::Ctor::LibCtor(crate::ir::LibCtor::new(db, id, lctor.name.clone()))
-    }
+    irlf_ser::ir::Ctor::LibCtor(lctor) => crate::ir::Ctor::LibCtor(crate::ir::LibCtor::new(
+      db,
+      id,
+      lctor.name.clone(),
+      lctor.params.clone(),
+    )),
   }
 }
 
@@ -100,33 +86,51 @@ fn convert_connections(
   db: &dyn Db,
   instid2inst: &HashMap<&InstId, &irlf_ser::ir::CtorCall>,
   ctorid2ctor: &HashMap<CtorId, irlf_ser::ir::Ctor>,
-  connections: &[irlf_ser::ir::Connection],
+  sctor: &irlf_ser::ir::StructlikeCtor,
 ) -> Vec<crate::ir::Connection> {
-  connections
+  sctor
+    .connections
     .iter()
     .map(|c| {
       crate::ir::Connection::new(
         db,
         c.id,
-        convert_instref(db, ctorid2ctor, instid2inst, &c.left),
-        convert_instref(db, ctorid2ctor, instid2inst, &c.right),
+        convert_instref(db, ctorid2ctor, instid2inst, sctor, &c.left),
+        c.left_slice,
+        convert_instref(db, ctorid2ctor, instid2inst, sctor, &c.right),
+        c.right_slice,
+        c.delay.clone(),
       )
     })
     .collect()
 }
 
+/// Converts a ref that occurs in `sctor`. Each instance of the ref is looked up among the
+/// instances of the ctor that declares it, which is `sctor` for the first and the ctor of the
+/// previous instance for the others, since different ctors may declare instances with the same id.
 fn convert_instref(
   db: &dyn Db,
   ctorid2ctor: &HashMap<CtorId, irlf_ser::ir::Ctor>,
   instid2inst: &HashMap<&InstId, &irlf_ser::ir::CtorCall>,
+  sctor: &irlf_ser::ir::StructlikeCtor,
   iref: &irlf_ser::ir::InstRef,
 ) -> crate::ir::InstRef {
+  let mut scope = Some(sctor);
   crate::ir::InstRef::new(
     db,
     iref
       .0
       .iter()
-      .map(|id| convert_ctorcall(db, instid2inst, ctorid2ctor, *id, instid2inst[id]))
+      .map(|id| {
+        let call = scope
+          .and_then(|sctor| sctor.insts.get(id))
+          .unwrap_or(instid2inst[id]);
+        scope = match &ctorid2ctor[&call.ctor] {
+          irlf_ser::ir::Ctor::StructlikeCtor(sctor) => Some(sctor),
+          _ => None,
+        };
+        convert_ctorcall(db, instid2inst, ctorid2ctor, *id, call)
+      })
       .collect(),
   )
 }
@@ -135,23 +139,26 @@ fn convert_iface_elt_e(
   db: &dyn Db,
   ctorid2ctor: &HashMap<CtorId, irlf_ser::ir::Ctor>,
   instid2inst: &HashMap<&InstId, &irlf_ser::ir::CtorCall>,
+  sctor: &irlf_ser::ir::StructlikeCtor,
   iref: &Comm<irlf_ser::ir::IfaceElt>,
 ) -> Comm<crate::ir::IfaceElt> {
-  iref.map(|elt| convert_instref(db, ctorid2ctor, instid2inst, elt))
+  iref.map(|elt| convert_instref(db, ctorid2ctor, instid2inst, sctor, elt))
 }
 
 fn convert_iface(
   db: &dyn Db,
   instid2inst: &HashMap<&InstId, &irlf_ser::ir::CtorCall>,
   ctorid2ctor: &HashMap<CtorId, irlf_ser::ir::Ctor>,
-  iface: &Iface<irlf_ser::ir::IfaceElt>,
+  sctor: &irlf_ser::ir::StructlikeCtor,
 ) -> Iface<crate::ir::IfaceElt> {
-  iface
+  sctor
+    .iface
     .iter()
     .map(|node| {
       IfaceNode(
         node.0,
-        convert_iface_elt_e(db, ctorid2ctor, instid2inst, &node.1),
+        convert_iface_elt_e(db, ctorid2ctor, instid2inst, sctor, &node.1),
+        node.2.clone(),
       )
     })
     .collect()
@@ -177,7 +184,6 @@ fn convert_ctorcall(
   id: InstId,
   call: &irlf_ser::ir::CtorCall,
 ) -> crate::ir::Inst {
-  check_cache!(MEMO_INSTS, id);
   crate::ir::Inst::new(
     db,
     id,
@@ -188,5 +194,7 @@ fn convert_ctorcall(
       call.ctor,
       &ctorid2ctor[&call.ctor],
     ),
+    call.width,
+    call.args.clone(),
   )
 }
@@ -2,7 +2,7 @@ use std::{collections::HashMap, path::PathBuf};
 
 pub type IfaceElt = InstRef;
 
-use lf_types::{CtorId, DebugOnlyId, Iface, InstId};
+use lf_types::{CtorId, DebugOnlyId, DeltaT, Iface, InstId, Literal, Slice};
 
 #[salsa::tracked]
 pub struct Inst {
@@ -10,6 +10,10 @@ pub struct Inst {
   pub id: InstId,
   #[return_ref]
   pub ctor: Ctor,
+  /// The number of instances in the bank.
+  pub width: u64,
+  #[return_ref]
+  pub args: Vec<Literal>,
 }
 
 #[salsa::tracked]
@@ -24,8 +28,24 @@ pub struct Connection {
   pub id: DebugOnlyId,
   #[return_ref]
   pub left: InstRef,
+  pub left_slice: Option<Slice>,
   #[return_ref]
   pub right: InstRef,
+  pub right_slice: Option<Slice>,
+  /// The delay after which values reach the right end, if they do not arrive at the same tag.
+  #[return_ref]
+  pub delay: Option<DeltaT>,
+}
+
+impl Connection {
+  /// Whether values take time to reach the right end. A delay of zero, which the text format
+  /// rejects but other sources of programs may not, delivers values at the same tag.
+  pub fn is_delayed(self, db: &dyn crate::Db) -> bool {
+    self
+      .delay(db)
+      .as_ref()
+      .is_some_and(|delay| delay.iter().any(|&d| d != 0))
+  }
 }
 
 #[salsa::tracked]
@@ -53,6 +73,8 @@ pub struct LibCtor {
   pub id: CtorId,
   #[return_ref]
   pub name: String,
+  #[return_ref]
+  pub params: Vec<irlf_ser::ir::Sym>,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
@@ -2,6 +2,7 @@ use ir::{Id2Sym, Program};
 
 pub mod convert;
 pub mod ir;
+pub mod typecheck;
 pub mod unconvert;
 
 #[salsa::jar(db = Db)]
@@ -16,6 +17,7 @@ pub struct Jar(
   crate::convert::convert,
   crate::ir::InstRef,
   crate::ir::Id2Sym,
+  crate::typecheck::check,
 );
 
 pub trait Db: salsa::DbWithJar<Jar> {}
@@ -27,3 +29,12 @@ pub fn from_text(text: &str, db: &dyn Db) -> (Program, Id2Sym) {
   let source = crate::ir::SourceProgram::new(db, source);
   crate::convert::convert(db, source)
 }
+
+/// Like `from_text`, but merges isomorphic ctors so that work done per ctor is shared across
+/// duplicates.
+pub fn from_text_hash_consed(text: &str, db: &dyn Db) -> (Program, Id2Sym) {
+  let mut source = irlf_ser::unpretty::unpretty(text).unwrap();
+  irlf_ser::canon::hash_cons(&mut source);
+  let source = crate::ir::SourceProgram::new(db, source);
+  crate::convert::convert(db, source)
+}
@@ -0,0 +1,634 @@
+use std::fmt::Display;
+
+use lf_types::{Comm, CtorId, DebugOnlyId, InstId, Literal, Side, Slice, Type};
+
+use crate::ir::{Ctor, Inst, LibCtor, Program, StructlikeCtor};
+use crate::Db;
+
+/// A place where the declared or known types of a program disagree.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum TypeError {
+  /// A connection between ports whose declared types differ.
+  Connection {
+    ctor: CtorId,
+    connection: DebugOnlyId,
+    /// The position of the mismatched port among the channels that the connection selects.
+    port: usize,
+    /// The type of the port on the right side of the left end of the connection.
+    left: Type,
+    /// The type of the port on the left side of the right end of the connection.
+    right: Type,
+  },
+  /// A slice that selects channels beyond the end of the port that it slices.
+  SliceOutOfRange {
+    ctor: CtorId,
+    connection: DebugOnlyId,
+    slice: Slice,
+    /// The number of channels of the sliced port.
+    width: u64,
+  },
+  /// A connection whose ends select different numbers of channels.
+  Width {
+    ctor: CtorId,
+    connection: DebugOnlyId,
+    left: u64,
+    right: u64,
+  },
+  /// An instance that is passed the wrong number of args. Only lib ctors take params, so an
+  /// instance of any other ctor takes no args.
+  Arity {
+    ctor: CtorId,
+    inst: InstId,
+    expected: usize,
+    actual: usize,
+  },
+  /// An arg whose type differs from the type of the param that it is passed to.
+  Arg {
+    ctor: CtorId,
+    inst: InstId,
+    /// The position of the arg among the args of the instance.
+    position: usize,
+    expected: Type,
+    actual: Literal,
+  },
+  /// An iface element that is annotated with a type that differs from the type of a port that it
+  /// exposes.
+  Iface {
+    ctor: CtorId,
+    side: Side,
+    /// The position of the mismatched port among the data ports on `side` of the iface.
+    port: usize,
+    declared: Type,
+    actual: Type,
+  },
+  /// An instance of a lib ctor that has no implementation, or whose implementation cannot be
+  /// loaded.
+  Lctor {
+    ctor: CtorId,
+    inst: InstId,
+    reason: String,
+  },
+}
+
+impl Display for TypeError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      TypeError::Connection {
+        ctor,
+        connection,
+        port,
+        left,
+        right,
+      } => write!(
+        f,
+        "connection {connection} in ctor {ctor} connects {left} to {right} at port {port}"
+      ),
+      TypeError::SliceOutOfRange {
+        ctor,
+        connection,
+        slice,
+        width,
+      } => write!(
+        f,
+        "connection {connection} in ctor {ctor} selects {slice} of a port of width {width}"
+      ),
+      TypeError::Width {
+        ctor,
+        connection,
+        left,
+        right,
+      } => write!(
+        f,
+        "connection {connection} in ctor {ctor} connects {left} channels to {right} channels"
+      ),
+      TypeError::Arity {
+        ctor,
+        inst,
+        expected,
+        actual,
+      } => write!(
+        f,
+        "instance {inst} in ctor {ctor} is passed {actual} args but takes {expected}"
+      ),
+      TypeError::Arg {
+        ctor,
+        inst,
+        position,
+        expected,
+        actual,
+      } => write!(
+        f,
+        "instance {inst} in ctor {ctor} is passed {actual} at arg {position}, which is not of type {expected}"
+      ),
+      TypeError::Iface {
+        ctor,
+        side,
+        port,
+        declared,
+        actual,
+      } => write!(
+        f,
+        "iface of ctor {ctor} declares {declared} at {side} port {port}, which is of type {actual}"
+      ),
+      TypeError::Lctor { ctor, inst, reason } => {
+        write!(f, "instance {inst} in ctor {ctor} cannot be realized: {reason}")
+      }
+    }
+  }
+}
+
+/// What is known about lib ctors beyond what the program declares about them.
+pub trait LibTypes {
+  /// Returns the types of the params of `lctor`, or `None` if they are not known.
+  fn params(&self, db: &dyn Db, lctor: LibCtor) -> Option<Vec<Type>>;
+  /// Returns the types of the data ports on the given side of `lctor`, or `None` if they are not
+  /// known.
+  fn ports(&self, db: &dyn Db, lctor: LibCtor, side: Side) -> Option<Vec<Type>>;
+}
+
+/// Knows nothing about lib ctors.
+impl LibTypes for () {
+  fn params(&self, _db: &dyn Db, _lctor: LibCtor) -> Option<Vec<Type>> {
+    None
+  }
+  fn ports(&self, _db: &dyn Db, _lctor: LibCtor, _side: Side) -> Option<Vec<Type>> {
+    None
+  }
+}
+
+/// Checks that every connection in `program` connects ports of equal types and that every instance
+/// of a lib ctor is passed as many args as the lib ctor declares params. Ports whose types are not
+/// declared, such as the ports of lib ctors, match any type. The widths of connections are not
+/// checked here, since they depend on the implementations of lib ctors.
+#[salsa::tracked]
+pub fn check(db: &dyn Db, program: Program) -> Vec<TypeError> {
+  check_with(db, program, &())
+}
+
+/// Like `check`, but also checks the types of args against the param types given by `lib`, and
+/// gives the ports of lib ctors the types given by `lib`.
+pub fn check_with(db: &dyn Db, program: Program, lib: &dyn LibTypes) -> Vec<TypeError> {
+  program
+    .ctors(db)
+    .iter()
+    .filter_map(|ctor| match ctor {
+      Ctor::StructlikeCtor(sctor) => Some(check_sctor(db, *sctor, lib)),
+      _ => None,
+    })
+    .flatten()
+    .collect()
+}
+
+fn check_sctor(db: &dyn Db, sctor: StructlikeCtor, lib: &dyn LibTypes) -> Vec<TypeError> {
+  let mut ret = vec![];
+  for side in [Side::Left, Side::Right] {
+    for (port, (declared, actual)) in iface_types(db, lib, sctor, &[], side)
+      .into_iter()
+      .enumerate()
+    {
+      match (declared, actual) {
+        (Some(declared), Some(actual)) if declared != actual => ret.push(TypeError::Iface {
+          ctor: sctor.id(db),
+          side,
+          port,
+          declared,
+          actual,
+        }),
+        _ => (),
+      }
+    }
+  }
+  for inst in sctor.insts(db) {
+    match inst.ctor(db) {
+      Ctor::LibCtor(lctor) => ret.extend(check_args(db, sctor.id(db), *inst, *lctor, lib)),
+      _ if !inst.args(db).is_empty() => ret.push(TypeError::Arity {
+        ctor: sctor.id(db),
+        inst: inst.id(db),
+        expected: 0,
+        actual: inst.args(db).len(),
+      }),
+      _ => (),
+    }
+  }
+  for connection in sctor.connections(db) {
+    let (Some(left), Some(right)) = (
+      end_types(
+        db,
+        lib,
+        &connection.left(db).iref(db),
+        connection.left_slice(db),
+        Side::Right,
+      ),
+      end_types(
+        db,
+        lib,
+        &connection.right(db).iref(db),
+        connection.right_slice(db),
+        Side::Left,
+      ),
+    ) else {
+      continue;
+    };
+    for (port, (left, right)) in left.into_iter().zip(right).enumerate() {
+      match (left, right) {
+        (Some(left), Some(right)) if left != right => ret.push(TypeError::Connection {
+          ctor: sctor.id(db),
+          connection: connection.id(db),
+          port,
+          left,
+          right,
+        }),
+        _ => (),
+      }
+    }
+  }
+  ret
+}
+
+/// Checks the args passed to `inst`, an instance of `lctor`, against the params of `lctor`.
+fn check_args(
+  db: &dyn Db,
+  ctor: CtorId,
+  inst: Inst,
+  lctor: LibCtor,
+  lib: &dyn LibTypes,
+) -> Vec<TypeError> {
+  let args = inst.args(db);
+  let params = lib.params(db, lctor);
+  let expected = params.as_ref().map_or(lctor.params(db).len(), Vec::len);
+  if args.len() != expected {
+    return vec![TypeError::Arity {
+      ctor,
+      inst: inst.id(db),
+      expected,
+      actual: args.len(),
+    }];
+  }
+  args
+    .iter()
+    .zip(params.unwrap_or_default())
+    .enumerate()
+    .filter(|(_, (arg, param))| arg.ty() != *param)
+    .map(|(position, (arg, param))| TypeError::Arg {
+      ctor,
+      inst: inst.id(db),
+      position,
+      expected: param,
+      actual: arg.clone(),
+    })
+    .collect()
+}
+
+/// Returns the types of the channels that an end of a connection selects on the given side of the
+/// instance that `iref` refers to, or `None` if they are not known.
+fn end_types(
+  db: &dyn Db,
+  lib: &dyn LibTypes,
+  iref: &[Inst],
+  slice: Option<Slice>,
+  side: Side,
+) -> Option<Vec<Option<Type>>> {
+  let types = side_types(db, lib, iref, side)?;
+  // Only a reference to a whole instance selects every member of a bank.
+  let types = if iref.len() == 1 {
+    let n = types.len() * iref[0].width(db) as usize;
+    types.iter().cycle().take(n).cloned().collect()
+  } else {
+    types
+  };
+  match slice {
+    // A slice that is out of range is reported along with the widths of the connection.
+    Some(slice) => types
+      .get(slice.start as usize..slice.end as usize)
+      .map(<[_]>::to_vec),
+    None => Some(types),
+  }
+}
+
+/// Returns the types of the data ports on the given side of the instance that `iref` refers to, or
+/// `None` if the ports of that instance are not known.
+fn side_types(
+  db: &dyn Db,
+  lib: &dyn LibTypes,
+  iref: &[Inst],
+  side: Side,
+) -> Option<Vec<Option<Type>>> {
+  let (inst, part) = iref.split_first()?;
+  let sctor = match inst.ctor(db) {
+    Ctor::StructlikeCtor(sctor) => sctor,
+    Ctor::LibCtor(lctor) if part.is_empty() => {
+      return Some(lib.ports(db, *lctor, side)?.into_iter().map(Some).collect())
+    }
+    _ => return None,
+  };
+  Some(
+    iface_types(db, lib, *sctor, part, side)
+      .into_iter()
+      .map(|(declared, actual)| declared.or(actual))
+      .collect(),
+  )
+}
+
+/// Returns the declared and the actual type of each data port that the iface of `sctor` exposes on
+/// the given side of `part`. An iface element that refers to a whole bank exposes the ports of
+/// every member of the bank, and its annotation, if any, applies to each of them.
+fn iface_types(
+  db: &dyn Db,
+  lib: &dyn LibTypes,
+  sctor: StructlikeCtor,
+  part: &[Inst],
+  side: Side,
+) -> Vec<(Option<Type>, Option<Type>)> {
+  sctor
+    .iface(db)
+    .iter()
+    .filter(|node| node.0.includes(side))
+    .filter_map(|node| match &node.1 {
+      Comm::Notify => None,
+      Comm::Data(elt) => elt.iref(db).starts_with(part).then(|| {
+        // The ports of an instance that are not known count as a single port of unknown type.
+        let actual = end_types(db, lib, &elt.iref(db), None, side).unwrap_or(vec![None]);
+        actual.into_iter().map(|actual| (node.2.clone(), actual))
+      }),
+    })
+    .flatten()
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashSet;
+
+  use super::*;
+
+  #[derive(Default)]
+  #[salsa::db(crate::Jar)]
+  pub(crate) struct TestDatabase {
+    storage: salsa::Storage<Self>,
+  }
+
+  impl salsa::Database for TestDatabase {}
+
+  const PROGRAM: &str = "c 0x7 add1
+---
+---
+rtor0 0x3
+  foo 89 = 0x7
+  ---
+  L 89 : int R 89 : int
+  ---
+rtor1 0x4
+  foo 88 = 0x7
+  ---
+  L 88 : bool R 88 : (int,bool)
+  ---
+rtor2 0x5
+  a 90 = 0x3
+  b 91 = 0x4
+  c 92 = 0x3
+  ---
+  ---
+  93 90 92
+  94 90 91
+---
+0x5
+";
+
+  #[test]
+  fn test_check() {
+    let db = TestDatabase::default();
+    let (program, _) = crate::from_text(PROGRAM, &db);
+    assert_eq!(
+      check(&db, program),
+      vec![TypeError::Connection {
+        ctor: CtorId(5),
+        connection: DebugOnlyId(94),
+        port: 0,
+        left: Type::Int,
+        right: Type::Bool,
+      }]
+    );
+    let (program, _) = crate::from_text(&PROGRAM.replace("L 88 : bool", "L 88 : int"), &db);
+    assert_eq!(check(&db, program), vec![]);
+  }
+
+  const BANK_IFACE: &str = "c 0x7 add1
+---
+---
+rtor0 0x3
+  foo 89 = 0x7
+  ---
+  L 89 : int R 89 : int
+  ---
+rtor1 0x4
+  foo 88 = 0x3 [2]
+  ---
+  L 88 R 88
+  ---
+rtor2 0x5
+  a 90 = 0x4
+  b 91 = 0x3 [2]
+  ---
+  ---
+  93 90 91
+  94 91 [1:2] 90 [1:2]
+---
+0x5
+";
+
+  #[test]
+  fn test_check_bank_iface() {
+    let db = TestDatabase::default();
+    let (program, _) = crate::from_text(BANK_IFACE, &db);
+    assert_eq!(check(&db, program), vec![]);
+    let text = BANK_IFACE.replace("L 88 R 88", "L 88 : bool R 88");
+    let (program, _) = crate::from_text(&text, &db);
+    let iface = |port| TypeError::Iface {
+      ctor: CtorId(4),
+      side: Side::Left,
+      port,
+      declared: Type::Bool,
+      actual: Type::Int,
+    };
+    // The annotation applies to both members of the bank, of which connection 94 selects the
+    // second.
+    assert_eq!(
+      check(&db, program).into_iter().collect::<HashSet<_>>(),
+      HashSet::from([
+        iface(0),
+        iface(1),
+        TypeError::Connection {
+          ctor: CtorId(5),
+          connection: DebugOnlyId(94),
+          port: 0,
+          left: Type::Int,
+          right: Type::Bool,
+        },
+      ])
+    );
+  }
+
+  const ARGS: &str = "g 0x8 gain k
+---
+---
+rtor0 0x3
+  a 89 = 0x8 5
+  b 90 = 0x8
+  c 91 = 0x8 true
+  ---
+  ---
+---
+0x3
+";
+
+  /// Gives every lib ctor one param and one port on each side, all of type int.
+  struct Ints;
+
+  impl LibTypes for Ints {
+    fn params(&self, _db: &dyn Db, _lctor: LibCtor) -> Option<Vec<Type>> {
+      Some(vec![Type::Int])
+    }
+    fn ports(&self, _db: &dyn Db, _lctor: LibCtor, _side: Side) -> Option<Vec<Type>> {
+      Some(vec![Type::Int])
+    }
+  }
+
+  #[test]
+  fn test_check_args() {
+    let db = TestDatabase::default();
+    let (program, _) = crate::from_text(ARGS, &db);
+    let arity = TypeError::Arity {
+      ctor: CtorId(3),
+      inst: InstId(90),
+      expected: 1,
+      actual: 0,
+    };
+    assert_eq!(check(&db, program), vec![arity.clone()]);
+    // The instances of a ctor are not ordered.
+    assert_eq!(
+      check_with(&db, program, &Ints)
+        .into_iter()
+        .collect::<HashSet<_>>(),
+      HashSet::from([
+        arity,
+        TypeError::Arg {
+          ctor: CtorId(3),
+          inst: InstId(91),
+          position: 0,
+          expected: Type::Int,
+          actual: Literal::Bool(true),
+        }
+      ])
+    );
+  }
+
+  #[test]
+  fn test_sctor_args() {
+    let db = TestDatabase::default();
+    let text = LIB_PORTS.replace("b 91 = 0x4", "b 91 = 0x4 7 true");
+    let (program, _) = crate::from_text(&text, &db);
+    assert_eq!(
+      check(&db, program),
+      vec![TypeError::Arity {
+        ctor: CtorId(5),
+        inst: InstId(91),
+        expected: 0,
+        actual: 2,
+      }]
+    );
+  }
+
+  const LIB_PORTS: &str = "g 0x7 gain k
+---
+---
+rtor1 0x4
+  foo 88 = 0x7 2
+  ---
+  L 88 : bool R 88 : bool
+  ---
+rtor2 0x5
+  a 90 = 0x7 3
+  b 91 = 0x4
+  ---
+  ---
+  94 90 91
+---
+0x5
+";
+
+  #[test]
+  fn test_check_lib_ports() {
+    let db = TestDatabase::default();
+    let (program, _) = crate::from_text(LIB_PORTS, &db);
+    assert_eq!(check(&db, program), vec![]);
+    let iface = |side| TypeError::Iface {
+      ctor: CtorId(4),
+      side,
+      port: 0,
+      declared: Type::Bool,
+      actual: Type::Int,
+    };
+    // The iface of `rtor1` declares bool around a `gain`, whose ports `Ints` makes int.
+    assert_eq!(
+      check_with(&db, program, &Ints)
+        .into_iter()
+        .collect::<HashSet<_>>(),
+      HashSet::from([
+        iface(Side::Left),
+        iface(Side::Right),
+        TypeError::Connection {
+          ctor: CtorId(5),
+          connection: DebugOnlyId(94),
+          port: 0,
+          left: Type::Int,
+          right: Type::Bool,
+        }
+      ])
+    );
+    assert_eq!(
+      iface(Side::Left).to_string(),
+      "iface of ctor 0x4 declares bool at L port 0, which is of type int"
+    );
+  }
+
+  const SLICES: &str = "c 0x7 add1
+---
+---
+rtor0 0x3
+  foo 89 = 0x7
+  ---
+  L 89 : int R 89 : int
+  ---
+rtor1 0x4
+  foo 88 = 0x7
+  ---
+  L 88 : bool R 88 : bool
+  ---
+rtor2 0x5
+  a 90 = 0x3 [2]
+  b 91 = 0x4
+  c 92 = 0x3
+  ---
+  ---
+  93 90 [1:2] 92
+  94 90 [0:1] 91
+---
+0x5
+";
+
+  #[test]
+  fn test_check_slices() {
+    let db = TestDatabase::default();
+    let (program, _) = crate::from_text(SLICES, &db);
+    assert_eq!(
+      check(&db, program),
+      vec![TypeError::Connection {
+        ctor: CtorId(5),
+        connection: DebugOnlyId(94),
+        port: 0,
+        left: Type::Int,
+        right: Type::Bool,
+      }]
+    );
+  }
+}
@@ -5,7 +5,7 @@ use lf_types::{Comm, CtorId, IfaceNode};
 use crate::Db;
 
 impl crate::ir::Ctor {
-  fn id(&self, db: &dyn Db) -> CtorId {
+  pub fn id(&self, db: &dyn Db) -> CtorId {
     match self {
       crate::ir::Ctor::StructlikeCtor(sctor) => sctor.id(db),
       crate::ir::Ctor::BinaryCtor(bctor) => bctor.id(db),
@@ -59,7 +59,13 @@ fn unconvert_ctor(
         iface: sctor
           .iface(db)
           .iter()
-          .map(|iface| IfaceNode(iface.0, unconvert_iface_elt_e(db, &iface.1)))
+          .map(|iface| {
+            IfaceNode(
+              iface.0,
+              unconvert_iface_elt_e(db, &iface.1),
+              iface.2.clone(),
+            )
+          })
           .collect(),
         connections: sctor
           .connections(db)
@@ -75,6 +81,7 @@ fn unconvert_ctor(
     }
     crate::ir::Ctor::LibCtor(lctor) => irlf_ser::ir::Ctor::LibCtor(irlf_ser::ir::LibCtor {
       name: lctor.name(db).clone(),
+      params: lctor.params(db).clone(),
     }),
   }
 }
@@ -82,6 +89,8 @@ fn unconvert_ctor(
 fn unconvert_inst(db: &dyn Db, inst: &crate::ir::Inst) -> irlf_ser::ir::CtorCall {
   irlf_ser::ir::CtorCall {
     ctor: inst.ctor(db).id(db),
+    width: inst.width(db),
+    args: inst.args(db).clone(),
   }
 }
 
@@ -100,7 +109,10 @@ fn unconvert_connection(db: &dyn Db, c: &crate::ir::Connection) -> irlf_ser::ir:
   irlf_ser::ir::Connection {
     id: c.id(db),
     left: unconvert_instref(db, c.left(db)),
+    left_slice: c.left_slice(db),
     right: unconvert_instref(db, c.right(db)),
+    right_slice: c.right_slice(db),
+    delay: c.delay(db).clone(),
   }
 }
 
@@ -120,7 +132,8 @@ mod test {
 
   #[test]
   fn test_convert() {
-    let text = "cmxy 0x99 times2
+    let text = "g 0x98 gain k
+cmxy 0x99 times2
 ---
 a 0x1 /this/is/a/path
 b 0x2 /this/is/another/path
@@ -134,11 +147,14 @@ rtor0 0x3
 rtor1 0x4
   baz 87 = 0x2
   bar 88 = 0x3
+  amp 93 = 0x98 5
+  bank 94 = 0x99 [3]
   ---
   L 87 L 88.89 R 88 R 87
   ---
   91 88 87
-  92 87 87
+  92 87 87 after 1.0
+  95 94 [0:2] 88.89 [1:3]
 ---
 0x3
 ";
@@ -0,0 +1,544 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use get_rtor_impl::{read_wasm_path, CausalityLoop, WasmModules};
+use irlf_db::ir::SourceProgram;
+use irlf_db::typecheck::TypeError;
+use irlf_ser::index::{IdRef, Index, Occurrence};
+use irlf_ser::ir::{Ctor, Program};
+use lf_types::Level;
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, RequestId, Response};
+use lsp_types::notification::{
+  DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+  Notification as LspNotification, PublishDiagnostics,
+};
+use lsp_types::request::{GotoDefinition, HoverRequest, References, Request as LspRequest};
+use lsp_types::{
+  Diagnostic, GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+  HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf, Position,
+  PublishDiagnosticsParams, ReferenceParams, ServerCapabilities, TextDocumentPositionParams,
+  TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+
+#[derive(Default)]
+#[salsa::db(get_rtor_impl::Jar, irlf_db::Jar)]
+pub struct Database {
+  storage: salsa::Storage<Self>,
+}
+impl salsa::Database for Database {}
+
+struct Document {
+  index: Index,
+  /// The parsed program, if the text parses and passes validation.
+  program: Option<Program>,
+  source: Option<SourceProgram>,
+}
+
+#[derive(Default)]
+struct Server {
+  db: Database,
+  documents: HashMap<Url, Document>,
+}
+
+pub fn capabilities() -> ServerCapabilities {
+  ServerCapabilities {
+    text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+    definition_provider: Some(OneOf::Left(true)),
+    hover_provider: Some(HoverProviderCapability::Simple(true)),
+    references_provider: Some(OneOf::Left(true)),
+    ..ServerCapabilities::default()
+  }
+}
+
+/// Serves the IRLF language over `connection` until the client shuts the server down.
+///
+/// The lib ctors that are not built in are implemented by the modules in the directories listed in
+/// `IRLF_WASM_PATH` when the server starts.
+pub fn run(connection: &Connection) -> Result<(), Box<dyn Error + Sync + Send>> {
+  connection.initialize(serde_json::to_value(capabilities())?)?;
+  let mut server = Server::default();
+  WasmModules::new(&server.db, read_wasm_path()?);
+  for message in &connection.receiver {
+    match message {
+      Message::Request(request) => {
+        if connection.handle_shutdown(&request)? {
+          return Ok(());
+        }
+        connection
+          .sender
+          .send(Message::Response(server.request(request)))?;
+      }
+      Message::Notification(notification) => {
+        if let Some(diagnostics) = server.notification(notification) {
+          connection
+            .sender
+            .send(Message::Notification(Notification::new(
+              PublishDiagnostics::METHOD.to_string(),
+              diagnostics,
+            )))?;
+        }
+      }
+      Message::Response(_) => {}
+    }
+  }
+  Ok(())
+}
+
+fn dispatch<R: LspRequest>(
+  id: RequestId,
+  params: serde_json::Value,
+  f: impl FnOnce(R::Params) -> R::Result,
+) -> Response {
+  match serde_json::from_value(params) {
+    Ok(params) => Response::new_ok(id, f(params)),
+    Err(e) => Response::new_err(id, ErrorCode::InvalidParams as i32, e.to_string()),
+  }
+}
+
+fn lsp_range(range: irlf_ser::Range) -> lsp_types::Range {
+  let position = |(line, col): (u16, u16)| Position::new(line.into(), col.into());
+  lsp_types::Range::new(position(range.start()), position(range.end()))
+}
+
+fn describe(id: IdRef) -> String {
+  match id {
+    IdRef::Ctor(id) => format!("ctor {id}"),
+    IdRef::Inst(ctor, id) => format!("inst {id} of ctor {ctor}"),
+    IdRef::Connection(id) => format!("connection {id}"),
+  }
+}
+
+/// Reports references to undefined ids and ids that are defined more than once.
+fn validate(index: &Index) -> Vec<Diagnostic> {
+  let mut ret = vec![];
+  for occurrence in &index.occurrences {
+    let first_definition = index.definition(occurrence.id);
+    let message = match (occurrence.definition, first_definition) {
+      (false, None) => format!("undefined {}", describe(occurrence.id)),
+      (true, Some(first)) if first != occurrence => {
+        format!("duplicate definition of {}", describe(occurrence.id))
+      }
+      _ => continue,
+    };
+    ret.push(Diagnostic::new_simple(lsp_range(occurrence.range), message));
+  }
+  ret
+}
+
+impl Server {
+  fn request(&mut self, request: Request) -> Response {
+    let Request { id, method, params } = request;
+    match method.as_str() {
+      GotoDefinition::METHOD => dispatch::<GotoDefinition>(id, params, |p| self.definition(p)),
+      References::METHOD => dispatch::<References>(id, params, |p| self.references(p)),
+      HoverRequest::METHOD => dispatch::<HoverRequest>(id, params, |p| self.hover(p)),
+      _ => Response::new_err(
+        id,
+        ErrorCode::MethodNotFound as i32,
+        format!("unsupported request {method}"),
+      ),
+    }
+  }
+
+  fn notification(&mut self, notification: Notification) -> Option<PublishDiagnosticsParams> {
+    let Notification { method, params } = notification;
+    match method.as_str() {
+      DidOpenTextDocument::METHOD => {
+        let params: lsp_types::DidOpenTextDocumentParams = serde_json::from_value(params).ok()?;
+        let document = params.text_document;
+        Some(self.update(document.uri, &document.text, Some(document.version)))
+      }
+      DidChangeTextDocument::METHOD => {
+        let params: lsp_types::DidChangeTextDocumentParams = serde_json::from_value(params).ok()?;
+        let change = params.content_changes.into_iter().last()?;
+        Some(self.update(
+          params.text_document.uri,
+          &change.text,
+          Some(params.text_document.version),
+        ))
+      }
+      DidCloseTextDocument::METHOD => {
+        let params: lsp_types::DidCloseTextDocumentParams = serde_json::from_value(params).ok()?;
+        self.documents.remove(&params.text_document.uri);
+        Some(PublishDiagnosticsParams::new(
+          params.text_document.uri,
+          vec![],
+          None,
+        ))
+      }
+      _ => None,
+    }
+  }
+
+  fn update(&mut self, uri: Url, text: &str, version: Option<i32>) -> PublishDiagnosticsParams {
+    let (program, index) = irlf_ser::index::parse(text);
+    let (program, mut diagnostics) = match program {
+      Ok(program) => {
+        let diagnostics = validate(&index);
+        (diagnostics.is_empty().then_some(program), diagnostics)
+      }
+      Err((message, range)) => (
+        None,
+        vec![Diagnostic::new_simple(lsp_range(range), message)],
+      ),
+    };
+    let mut source = self.documents.remove(&uri).and_then(|it| it.source);
+    if let Some(program) = &program {
+      if let Some(source) = source {
+        source.set_source(&mut self.db).to(program.clone());
+      } else {
+        source = Some(SourceProgram::new(&self.db, program.clone()));
+      }
+      diagnostics.extend(self.type_errors(source.unwrap(), &index));
+    }
+    self.documents.insert(
+      uri.clone(),
+      Document {
+        index,
+        program,
+        source,
+      },
+    );
+    PublishDiagnosticsParams::new(uri, diagnostics, version)
+  }
+
+  fn occurrence(&self, position: &TextDocumentPositionParams) -> Option<(&Document, Occurrence)> {
+    let document = self.documents.get(&position.text_document.uri)?;
+    let line = u16::try_from(position.position.line).ok()?;
+    let col = u16::try_from(position.position.character).ok()?;
+    Some((document, *document.index.at(line, col)?))
+  }
+
+  fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+    let position = params.text_document_position_params;
+    let (document, occurrence) = self.occurrence(&position)?;
+    let definition = document.index.definition(occurrence.id)?;
+    Some(GotoDefinitionResponse::Scalar(Location::new(
+      position.text_document.uri,
+      lsp_range(definition.range),
+    )))
+  }
+
+  fn references(&self, params: ReferenceParams) -> Option<Vec<Location>> {
+    let position = params.text_document_position;
+    let (document, occurrence) = self.occurrence(&position)?;
+    Some(
+      document
+        .index
+        .references(occurrence.id)
+        .filter(|it| params.context.include_declaration || !it.definition)
+        .map(|it| Location::new(position.text_document.uri.clone(), lsp_range(it.range)))
+        .collect(),
+    )
+  }
+
+  fn hover(&self, params: HoverParams) -> Option<Hover> {
+    let (document, occurrence) = self.occurrence(&params.text_document_position_params)?;
+    let program = document.program.as_ref()?;
+    let (header, ctor) = match occurrence.id {
+      IdRef::Ctor(id) => {
+        let kind = match &program.ctors[&id] {
+          Ctor::StructlikeCtor(_) => "structlike ctor".to_string(),
+          Ctor::BinaryCtor(bctor) => format!("binary ctor `{}`", bctor.path.display()),
+          Ctor::LibCtor(lctor) => format!("lib ctor `{}`", lctor.name),
+        };
+        (format!("**{}**: {kind}", program.ctorid2sym[&id]), Some(id))
+      }
+      IdRef::Inst(owner, id) => {
+        let Some(Ctor::StructlikeCtor(sctor)) = program.ctors.get(&owner) else {
+          return None;
+        };
+        let ctor = sctor.insts.get(&id)?.ctor;
+        (
+          format!(
+            "**{}**: instance of `{}`",
+            sctor.inst2sym[&id], program.ctorid2sym[&ctor]
+          ),
+          Some(ctor),
+        )
+      }
+      IdRef::Connection(id) => (format!("connection {id}"), None),
+    };
+    let levels = ctor
+      .zip(document.source)
+      .and_then(|(ctor, source)| self.levels(source, ctor));
+    let value = match levels {
+      Some(Ok(levels)) => format!("{header}\n\nlevels: {levels:?}"),
+      Some(Err(causality_loop)) => format!("{header}\n\n{causality_loop}"),
+      None => header,
+    };
+    Some(Hover {
+      contents: HoverContents::Markup(MarkupContent {
+        kind: MarkupKind::Markdown,
+        value,
+      }),
+      range: Some(lsp_range(occurrence.range)),
+    })
+  }
+
+  /// Reports the connections that connect ports of different types, the ctors whose ifaces declare
+  /// the wrong types, and the instances that are passed args that their ctors do not accept.
+  fn type_errors(&self, source: SourceProgram, index: &Index) -> Vec<Diagnostic> {
+    let (program, _) = irlf_db::convert::convert(&self.db, source);
+    get_rtor_impl::typecheck(&self.db, program)
+      .into_iter()
+      .filter_map(|error| {
+        let id = match &error {
+          TypeError::Connection { connection, .. }
+          | TypeError::SliceOutOfRange { connection, .. }
+          | TypeError::Width { connection, .. } => IdRef::Connection(*connection),
+          TypeError::Arity { ctor, inst, .. }
+          | TypeError::Arg { ctor, inst, .. }
+          | TypeError::Lctor { ctor, inst, .. } => IdRef::Inst(*ctor, *inst),
+          TypeError::Iface { ctor, .. } => IdRef::Ctor(*ctor),
+        };
+        let occurrence = index.definition(id)?;
+        Some(Diagnostic::new_simple(
+          lsp_range(occurrence.range),
+          error.to_string(),
+        ))
+      })
+      .collect()
+  }
+
+  /// Computes the levels of the given ctor, or returns `None` if the program has no such ctor or is
+  /// not well-typed.
+  fn levels(
+    &self,
+    source: SourceProgram,
+    ctor: lf_types::CtorId,
+  ) -> Option<Result<Vec<u32>, CausalityLoop>> {
+    let db = &self.db;
+    let (program, _) = irlf_db::convert::convert(db, source);
+    if !get_rtor_impl::typecheck(db, program).is_empty() {
+      return None;
+    }
+    let ctor = program.ctors(db).iter().find(|it| it.id(db) == ctor)?;
+    Some(get_rtor_impl::levels(db, ctor).map(|levels| {
+      let mut levels: Vec<u32> = levels.into_iter().map(|Level(l)| l).collect();
+      levels.sort_unstable();
+      levels
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread::JoinHandle;
+
+  use lsp_types::{
+    request::Shutdown, DidChangeTextDocumentParams, DidOpenTextDocumentParams, InitializeParams,
+    InitializedParams, PartialResultParams, ReferenceContext, TextDocumentContentChangeEvent,
+    TextDocumentIdentifier, TextDocumentItem, VersionedTextDocumentIdentifier,
+    WorkDoneProgressParams,
+  };
+
+  use super::*;
+
+  const PROGRAM: &str = "add1 0x7 add1
+---
+---
+rtor0 0x3
+  foo 89 = 0x7
+  ---
+  L 89 R 89
+  ---
+rtor1 0x4
+  bar 88 = 0x3
+  ---
+  L 88.89
+  R 88
+  ---
+---
+0x4
+";
+
+  struct Client {
+    connection: Connection,
+    server: Option<JoinHandle<()>>,
+    next_id: i32,
+    uri: Url,
+  }
+
+  impl Client {
+    fn new() -> Self {
+      let (client, server) = Connection::memory();
+      let server = std::thread::spawn(move || run(&server).unwrap());
+      let mut ret = Client {
+        connection: client,
+        server: Some(server),
+        next_id: 0,
+        uri: Url::parse("file:///test.irlf").unwrap(),
+      };
+      ret.request::<lsp_types::request::Initialize>(InitializeParams::default());
+      ret.notify::<lsp_types::notification::Initialized>(InitializedParams {});
+      ret
+    }
+
+    fn request<R: LspRequest>(&mut self, params: R::Params) -> R::Result {
+      self.next_id += 1;
+      let request = Request::new(self.next_id.into(), R::METHOD.to_string(), params);
+      self.connection.sender.send(request.into()).unwrap();
+      match self.connection.receiver.recv().unwrap() {
+        Message::Response(response) => serde_json::from_value(response.result.unwrap()).unwrap(),
+        message => panic!("expected a response but got {message:?}"),
+      }
+    }
+
+    fn notify<N: LspNotification>(&self, params: N::Params) {
+      let notification = Notification::new(N::METHOD.to_string(), params);
+      self.connection.sender.send(notification.into()).unwrap();
+    }
+
+    fn diagnostics(&self) -> Vec<Diagnostic> {
+      match self.connection.receiver.recv().unwrap() {
+        Message::Notification(notification) => {
+          assert_eq!(notification.method, PublishDiagnostics::METHOD);
+          let params: PublishDiagnosticsParams =
+            serde_json::from_value(notification.params).unwrap();
+          params.diagnostics
+        }
+        message => panic!("expected diagnostics but got {message:?}"),
+      }
+    }
+
+    fn open(&self, text: &str) -> Vec<Diagnostic> {
+      self.notify::<DidOpenTextDocument>(DidOpenTextDocumentParams {
+        text_document: TextDocumentItem::new(self.uri.clone(), "irlf".into(), 0, text.into()),
+      });
+      self.diagnostics()
+    }
+
+    fn change(&self, text: &str) -> Vec<Diagnostic> {
+      self.notify::<DidChangeTextDocument>(DidChangeTextDocumentParams {
+        text_document: VersionedTextDocumentIdentifier::new(self.uri.clone(), 1),
+        content_changes: vec![TextDocumentContentChangeEvent {
+          range: None,
+          range_length: None,
+          text: text.into(),
+        }],
+      });
+      self.diagnostics()
+    }
+
+    fn position(&self, line: u32, character: u32) -> TextDocumentPositionParams {
+      TextDocumentPositionParams::new(
+        TextDocumentIdentifier::new(self.uri.clone()),
+        Position::new(line, character),
+      )
+    }
+  }
+
+  impl Drop for Client {
+    fn drop(&mut self) {
+      self.request::<Shutdown>(());
+      self.notify::<lsp_types::notification::Exit>(());
+      self.server.take().unwrap().join().unwrap();
+    }
+  }
+
+  #[test]
+  fn test_diagnostics() {
+    let client = Client::new();
+    assert!(client.open(PROGRAM).is_empty());
+    let diagnostics = client.change(&PROGRAM.replace("bar 88 = 0x3", "bar 88 = 0x5"));
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "undefined ctor 0x5");
+    assert_eq!(diagnostics[0].range.start, Position::new(9, 11));
+    let typed = PROGRAM
+      .replace("L 89 R 89", "L 89 : bool R 89 : int")
+      .replace("  R 88\n  ---\n", "  R 88\n  ---\n  90 88 88\n");
+    let mut diagnostics = client.change(&typed);
+    diagnostics.sort_by_key(|diagnostic| diagnostic.range.start);
+    assert_eq!(diagnostics.len(), 2);
+    // `add1` takes an int, so the iface that declares bool around it is wrong too.
+    assert_eq!(
+      diagnostics[0].message,
+      "iface of ctor 0x3 declares bool at L port 0, which is of type int"
+    );
+    assert_eq!(
+      diagnostics[1].message,
+      "connection 90 in ctor 0x4 connects int to bool at port 0"
+    );
+    assert_eq!(diagnostics[1].range.start, Position::new(14, 2));
+    let diagnostics = client.change("add1 0x7 add1\n---\n---\n---\nmain");
+    assert_eq!(diagnostics[0].message, "expected numeric id");
+  }
+
+  #[test]
+  fn test_definition_and_references() {
+    let mut client = Client::new();
+    client.open(PROGRAM);
+    let definition = client.request::<GotoDefinition>(GotoDefinitionParams {
+      text_document_position_params: client.position(11, 8),
+      work_done_progress_params: WorkDoneProgressParams::default(),
+      partial_result_params: PartialResultParams::default(),
+    });
+    let Some(GotoDefinitionResponse::Scalar(location)) = definition else {
+      panic!("expected a single location but got {definition:?}");
+    };
+    assert_eq!(location.range.start, Position::new(4, 6));
+    let references = client
+      .request::<References>(ReferenceParams {
+        text_document_position: client.position(4, 6),
+        work_done_progress_params: WorkDoneProgressParams::default(),
+        partial_result_params: PartialResultParams::default(),
+        context: ReferenceContext {
+          include_declaration: false,
+        },
+      })
+      .unwrap();
+    let mut starts: Vec<_> = references.iter().map(|it| it.range.start).collect();
+    starts.sort_by_key(|it| (it.line, it.character));
+    assert_eq!(
+      starts,
+      vec![
+        Position::new(6, 4),
+        Position::new(6, 9),
+        Position::new(11, 7)
+      ]
+    );
+  }
+
+  #[test]
+  fn test_hover() {
+    let mut client = Client::new();
+    client.open(PROGRAM);
+    let hover = client
+      .request::<HoverRequest>(HoverParams {
+        text_document_position_params: client.position(9, 6),
+        work_done_progress_params: WorkDoneProgressParams::default(),
+      })
+      .unwrap();
+    let HoverContents::Markup(MarkupContent { value, .. }) = hover.contents else {
+      panic!("expected markup");
+    };
+    assert_eq!(value, "**bar**: instance of `rtor0`\n\nlevels: [0]");
+  }
+
+  #[test]
+  fn test_hover_repeated_inst_id() {
+    let mut client = Client::new();
+    // Both ctors declare an instance 89, and `L 89.89` refers to one of each.
+    client.open(
+      &PROGRAM
+        .replace("bar 88", "bar 89")
+        .replace("L 88.89", "L 89.89")
+        .replace("R 88", "R 89"),
+    );
+    let mut hover = |line, character| {
+      let hover = client
+        .request::<HoverRequest>(HoverParams {
+          text_document_position_params: client.position(line, character),
+          work_done_progress_params: WorkDoneProgressParams::default(),
+        })
+        .unwrap();
+      let HoverContents::Markup(MarkupContent { value, .. }) = hover.contents else {
+        panic!("expected markup");
+      };
+      value
+    };
+    assert_eq!(hover(11, 4), "**bar**: instance of `rtor0`\n\nlevels: [0]");
+    assert_eq!(hover(11, 7), "**foo**: instance of `add1`\n\nlevels: [0]");
+  }
+}
@@ -0,0 +1,39 @@
+//! Pretty-prints a program in the IRLF text format.
+//!
+//! Usage: `irlf-fmt [--normalize] <path>`
+
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+  let mut normalize = false;
+  let mut path = None;
+  for arg in std::env::args().skip(1) {
+    match arg.as_str() {
+      "--normalize" => normalize = true,
+      _ => path = Some(arg),
+    }
+  }
+  let Some(path) = path else {
+    eprintln!("usage: irlf-fmt [--normalize] <path>");
+    return ExitCode::FAILURE;
+  };
+  let text = match std::fs::read_to_string(&path) {
+    Ok(text) => text,
+    Err(e) => {
+      eprintln!("{path}: {e}");
+      return ExitCode::FAILURE;
+    }
+  };
+  let mut program = match irlf_ser::unpretty::unpretty(&text) {
+    Ok(program) => program,
+    Err((message, range)) => {
+      eprintln!("{path}: {message} at {range:?}");
+      return ExitCode::FAILURE;
+    }
+  };
+  if normalize {
+    irlf_ser::canon::normalize(&mut program);
+  }
+  print!("{program}");
+  ExitCode::SUCCESS
+}
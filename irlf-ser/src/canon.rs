@@ -0,0 +1,634 @@
+use std::collections::{hash_map::DefaultHasher, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+
+use lf_types::{Comm, CtorId, DebugOnlyId, DeltaT, InstId, Literal, SideMatch, Slice, Type};
+
+use crate::ir::{Ctor, InstRef, Program, StructlikeCtor, Sym};
+
+/// The structure of a ctor with all of its ids erased.
+///
+/// Instances are replaced by labels that depend only on where they are first used, and the ctors
+/// that they call are replaced by the structural hashes of those ctors.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum Shape {
+  Lib(String, Vec<Sym>),
+  Binary(PathBuf),
+  Structlike {
+    insts: Vec<(u64, u64, Vec<Literal>)>,
+    iface: Vec<(SideMatch, Comm<Vec<usize>>, Option<Type>)>,
+    connections: Vec<(LabeledEnd, LabeledEnd, Option<DeltaT>)>,
+  },
+  /// A reference to the ctor that is `.0` levels up the stack of ctors currently being hashed.
+  Rec(usize),
+}
+
+/// An end of a connection with its instances replaced by labels.
+type LabeledEnd = (Vec<usize>, Option<Slice>);
+
+/// Computes structural hashes of the ctors of a program modulo renaming of `CtorId`s and
+/// `InstId`s.
+pub struct Canonicalizer<'a> {
+  program: &'a Program,
+  hashes: HashMap<CtorId, u64>,
+  labels: HashMap<CtorId, HashMap<InstId, usize>>,
+  stack: Vec<CtorId>,
+}
+
+impl<'a> Canonicalizer<'a> {
+  #[must_use]
+  pub fn new(program: &'a Program) -> Self {
+    Canonicalizer {
+      program,
+      hashes: HashMap::new(),
+      labels: HashMap::new(),
+      stack: vec![],
+    }
+  }
+
+  /// Returns a hash of the given ctor that is invariant under renaming of ids.
+  pub fn structural_hash(&mut self, ctor: CtorId) -> u64 {
+    self.hash_ctor(ctor).0
+  }
+
+  /// Returns whether `a` and `b` are the same ctor up to renaming of ids.
+  ///
+  /// Shapes refer to the ctors that they call only by their structural hashes, which may collide,
+  /// so the ctors called by corresponding instances of `a` and `b` are compared recursively.
+  pub fn are_isomorphic(&mut self, a: CtorId, b: CtorId) -> bool {
+    self.isomorphic(a, b, &mut HashSet::new())
+  }
+
+  /// Like `are_isomorphic`, but takes the pairs in `assumed` to be isomorphic, which lets ctors
+  /// that call themselves be compared.
+  fn isomorphic(&mut self, a: CtorId, b: CtorId, assumed: &mut HashSet<(CtorId, CtorId)>) -> bool {
+    if a == b || !assumed.insert((a, b)) {
+      return true;
+    }
+    if self.shape(a).0 != self.shape(b).0 {
+      return false;
+    }
+    let callees = self.callees_by_label(a).into_iter().zip(self.callees_by_label(b));
+    callees
+      .collect::<Vec<_>>()
+      .into_iter()
+      .all(|(a, b)| self.isomorphic(a, b, assumed))
+  }
+
+  /// Returns the ctors called by the instances of `ctor` in order of their labels.
+  fn callees_by_label(&mut self, ctor: CtorId) -> Vec<CtorId> {
+    let Ctor::StructlikeCtor(sctor) = &self.program.ctors[&ctor] else {
+      return vec![];
+    };
+    let mut by_label: Vec<(usize, InstId)> =
+      self.labels(ctor).iter().map(|(iid, l)| (*l, *iid)).collect();
+    by_label.sort();
+    by_label.into_iter().map(|(_, iid)| sctor.insts[&iid].ctor).collect()
+  }
+
+  /// Returns the label of each instance of the given ctor. Isomorphic ctors assign the same labels
+  /// to corresponding instances.
+  pub fn labels(&mut self, ctor: CtorId) -> &HashMap<InstId, usize> {
+    if !self.labels.contains_key(&ctor) {
+      let labels = match &self.program.ctors[&ctor] {
+        Ctor::StructlikeCtor(sctor) => self.compute_labels(sctor),
+        _ => HashMap::new(),
+      };
+      self.labels.insert(ctor, labels);
+    }
+    &self.labels[&ctor]
+  }
+
+  fn hash_ctor(&mut self, ctor: CtorId) -> (u64, usize) {
+    if let Some(hash) = self.hashes.get(&ctor) {
+      return (*hash, usize::MAX);
+    }
+    if let Some(depth) = self.stack.iter().position(|it| *it == ctor) {
+      let mut hasher = DefaultHasher::new();
+      Shape::Rec(self.stack.len() - depth).hash(&mut hasher);
+      return (hasher.finish(), depth);
+    }
+    let (shape, lowest) = self.shape(ctor);
+    let mut hasher = DefaultHasher::new();
+    shape.hash(&mut hasher);
+    let hash = hasher.finish();
+    // The hash of a ctor that is part of a cycle depends on where the cycle was entered.
+    if lowest >= self.stack.len() {
+      self.hashes.insert(ctor, hash);
+    }
+    (hash, lowest)
+  }
+
+  /// Returns the shape of `ctor` and the lowest position in the stack that the shape refers to.
+  fn shape(&mut self, ctor: CtorId) -> (Shape, usize) {
+    let program = self.program;
+    let sctor = match &program.ctors[&ctor] {
+      Ctor::LibCtor(lctor) => {
+        return (
+          Shape::Lib(lctor.name.clone(), lctor.params.clone()),
+          usize::MAX,
+        )
+      }
+      Ctor::BinaryCtor(bctor) => return (Shape::Binary(bctor.path.clone()), usize::MAX),
+      Ctor::StructlikeCtor(sctor) => sctor,
+    };
+    let labels = self.labels(ctor).clone();
+    let mut by_label: Vec<(usize, InstId)> = labels.iter().map(|(iid, l)| (*l, *iid)).collect();
+    by_label.sort();
+    self.stack.push(ctor);
+    let mut lowest = usize::MAX;
+    let mut insts = vec![];
+    for (_, iid) in by_label {
+      let call = &sctor.insts[&iid];
+      let (hash, low) = self.hash_ctor(call.ctor);
+      lowest = lowest.min(low);
+      insts.push((hash, call.width, call.args.clone()));
+    }
+    self.stack.pop();
+    let mut iface = vec![];
+    for node in &sctor.iface {
+      let elt = match &node.1 {
+        Comm::Notify => Comm::Notify,
+        Comm::Data(iref) => Comm::Data(self.label_iref(ctor, iref)),
+      };
+      iface.push((node.0, elt, node.2.clone()));
+    }
+    let connections = sctor
+      .connections
+      .iter()
+      .map(|c| {
+        (
+          (self.label_iref(ctor, &c.left), c.left_slice),
+          (self.label_iref(ctor, &c.right), c.right_slice),
+          c.delay.clone(),
+        )
+      })
+      .collect();
+    (
+      Shape::Structlike {
+        insts,
+        iface,
+        connections,
+      },
+      lowest,
+    )
+  }
+
+  fn label_iref(&mut self, ctor: CtorId, iref: &InstRef) -> Vec<usize> {
+    let mut ret = vec![];
+    let mut owner = ctor;
+    for iid in &iref.0 {
+      ret.push(self.labels(owner)[iid]);
+      if let Ctor::StructlikeCtor(sctor) = &self.program.ctors[&owner] {
+        owner = sctor.insts[iid].ctor;
+      }
+    }
+    ret
+  }
+
+  /// Labels instances in order of first use by the iface and then by the connections. Instances
+  /// that are used by neither are ordered by a shallow fingerprint of the ctor that they call, so
+  /// isomorphisms that only permute such instances among ctors with equal fingerprints may go
+  /// undetected.
+  fn compute_labels(&self, sctor: &StructlikeCtor) -> HashMap<InstId, usize> {
+    let mut order = vec![];
+    let mut seen = HashSet::new();
+    let mut visit = |iref: &InstRef| {
+      if let Some(first) = iref.0.first() {
+        if seen.insert(*first) {
+          order.push(*first);
+        }
+      }
+    };
+    for node in &sctor.iface {
+      if let Comm::Data(iref) = &node.1 {
+        visit(iref);
+      }
+    }
+    for connection in &sctor.connections {
+      visit(&connection.left);
+      visit(&connection.right);
+    }
+    let mut unused: Vec<(String, InstId)> = sctor
+      .insts
+      .iter()
+      .filter(|(iid, _)| !seen.contains(iid))
+      .map(|(iid, call)| (self.fingerprint(call.ctor), *iid))
+      .collect();
+    unused.sort();
+    order.extend(unused.into_iter().map(|(_, iid)| iid));
+    order.into_iter().enumerate().map(|(l, iid)| (iid, l)).collect()
+  }
+
+  fn fingerprint(&self, ctor: CtorId) -> String {
+    match &self.program.ctors[&ctor] {
+      Ctor::LibCtor(lctor) => format!("l{}", lctor.name),
+      Ctor::BinaryCtor(bctor) => format!("b{}", bctor.path.display()),
+      Ctor::StructlikeCtor(sctor) => format!(
+        "s{} {} {}",
+        sctor.insts.len(),
+        sctor.iface.len(),
+        sctor.connections.len()
+      ),
+    }
+  }
+}
+
+/// Returns a hash of the given ctor of `program` that is invariant under renaming of ids.
+#[must_use]
+pub fn structural_hash(program: &Program, ctor: CtorId) -> u64 {
+  Canonicalizer::new(program).structural_hash(ctor)
+}
+
+/// Returns whether the ctors `a` and `b` of `program` are the same up to renaming of ids.
+#[must_use]
+pub fn are_isomorphic(program: &Program, a: CtorId, b: CtorId) -> bool {
+  Canonicalizer::new(program).are_isomorphic(a, b)
+}
+
+/// Merges isomorphic ctors of `program` into one representative each and rewrites all `CtorCall`s
+/// and `InstRef`s accordingly. The representative of a set of isomorphic ctors is the one with the
+/// smallest id.
+///
+/// Returns the representative of each ctor that was removed.
+pub fn hash_cons(program: &mut Program) -> HashMap<CtorId, CtorId> {
+  let mut canon = Canonicalizer::new(program);
+  let mut ids: Vec<CtorId> = program.ctors.keys().copied().collect();
+  ids.sort();
+  let mut representatives: HashMap<u64, Vec<CtorId>> = HashMap::new();
+  let mut replaced = HashMap::new();
+  for id in ids {
+    let candidates = representatives.entry(canon.structural_hash(id)).or_default();
+    if let Some(rep) = candidates
+      .clone()
+      .into_iter()
+      .find(|rep| canon.are_isomorphic(*rep, id))
+    {
+      replaced.insert(id, rep);
+    } else {
+      candidates.push(id);
+    }
+  }
+  // Instance ids are only unique within the ctor that declares them.
+  let mut inst_renaming: HashMap<(CtorId, InstId), InstId> = HashMap::new();
+  for (old, rep) in &replaced {
+    let rep_labels: HashMap<usize, InstId> =
+      canon.labels(*rep).iter().map(|(iid, l)| (*l, *iid)).collect();
+    for (iid, l) in canon.labels(*old).clone() {
+      inst_renaming.insert((*old, iid), rep_labels[&l]);
+    }
+  }
+  let callees = callees(program);
+  for old in replaced.keys() {
+    program.ctors.remove(old);
+    program.ctorid2sym.remove(old);
+  }
+  // The ids in an `InstRef` after the first one belong to the ctor of the instance before them.
+  let rename = |owner: CtorId, iref: &mut InstRef| {
+    let mut owner = owner;
+    for iid in &mut iref.0 {
+      let key = (owner, *iid);
+      if let Some(new) = inst_renaming.get(&key) {
+        *iid = *new;
+      }
+      match callees.get(&key) {
+        Some(callee) => owner = *callee,
+        None => break,
+      }
+    }
+  };
+  for (cid, ctor) in &mut program.ctors {
+    if let Ctor::StructlikeCtor(sctor) = ctor {
+      for call in sctor.insts.values_mut() {
+        if let Some(rep) = replaced.get(&call.ctor) {
+          call.ctor = *rep;
+        }
+      }
+      for node in &mut sctor.iface {
+        if let Comm::Data(iref) = &mut node.1 {
+          rename(*cid, iref);
+        }
+      }
+      for connection in &mut sctor.connections {
+        rename(*cid, &mut connection.left);
+        rename(*cid, &mut connection.right);
+      }
+    }
+  }
+  if let Some(rep) = replaced.get(&program.main) {
+    program.main = *rep;
+  }
+  replaced
+}
+
+/// Maps each instance of each structlike ctor of `program` to the ctor that it calls.
+fn callees(program: &Program) -> HashMap<(CtorId, InstId), CtorId> {
+  let mut ret = HashMap::new();
+  for (cid, ctor) in &program.ctors {
+    if let Ctor::StructlikeCtor(sctor) = ctor {
+      for (iid, call) in &sctor.insts {
+        ret.insert((*cid, *iid), call.ctor);
+      }
+    }
+  }
+  ret
+}
+
+/// Renumbers the `CtorId`s, `InstId`s and connection `DebugOnlyId`s of `program` so that they
+/// depend only on its structure and symbols. Ctors are numbered in order of their symbols, and
+/// instances are numbered in order of the ctors that declare them and then of their own symbols.
+pub fn normalize(program: &mut Program) {
+  let mut canon = Canonicalizer::new(program);
+  let mut ctors: Vec<(&Sym, u64, CtorId)> = program
+    .ctors
+    .keys()
+    .map(|cid| (&program.ctorid2sym[cid], canon.structural_hash(*cid), *cid))
+    .collect();
+  ctors.sort();
+  let mut ctor_renaming = HashMap::new();
+  let mut inst_renaming = HashMap::new();
+  for (sym, _, cid) in ctors {
+    ctor_renaming.insert(cid, (CtorId(ctor_renaming.len() as u64), sym.clone()));
+    if let Ctor::StructlikeCtor(sctor) = &program.ctors[&cid] {
+      let labels = canon.labels(cid);
+      let mut insts: Vec<(&Sym, usize, InstId)> = sctor
+        .insts
+        .keys()
+        .map(|iid| (&sctor.inst2sym[iid], labels[iid], *iid))
+        .collect();
+      insts.sort();
+      for (_, _, iid) in insts {
+        inst_renaming.insert((cid, iid), InstId(inst_renaming.len() as u64));
+      }
+    }
+  }
+  let callees = callees(program);
+  let rename = |owner: CtorId, iref: &mut InstRef| {
+    let mut owner = owner;
+    for iid in &mut iref.0 {
+      let key = (owner, *iid);
+      *iid = inst_renaming[&key];
+      match callees.get(&key) {
+        Some(callee) => owner = *callee,
+        None => break,
+      }
+    }
+  };
+  let mut next_connection_id = 0;
+  program.ctorid2sym = ctor_renaming.values().cloned().collect();
+  program.main = ctor_renaming[&program.main].0;
+  let mut ctors: Vec<(CtorId, CtorId, Ctor)> = program
+    .ctors
+    .drain()
+    .map(|(old, ctor)| (ctor_renaming[&old].0, old, ctor))
+    .collect();
+  ctors.sort_by_key(|(cid, _, _)| *cid);
+  for (cid, old, mut ctor) in ctors {
+    if let Ctor::StructlikeCtor(sctor) = &mut ctor {
+      sctor.inst2sym = sctor
+        .inst2sym
+        .drain()
+        .map(|(iid, sym)| (inst_renaming[&(old, iid)], sym))
+        .collect();
+      sctor.insts = sctor
+        .insts
+        .drain()
+        .map(|(iid, mut call)| {
+          call.ctor = ctor_renaming[&call.ctor].0;
+          (inst_renaming[&(old, iid)], call)
+        })
+        .collect();
+      for node in &mut sctor.iface {
+        if let Comm::Data(iref) = &mut node.1 {
+          rename(old, iref);
+        }
+      }
+      for connection in &mut sctor.connections {
+        connection.id = DebugOnlyId(next_connection_id);
+        next_connection_id += 1;
+        rename(old, &mut connection.left);
+        rename(old, &mut connection.right);
+      }
+    }
+    program.ctors.insert(cid, ctor);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::unpretty::unpretty;
+
+  const DUPLICATES: &str = "add1 0x1 add1
+mul2 0x2 mul2
+---
+---
+a 0x3
+  x 10 = 0x1
+  y 11 = 0x2
+  ---
+  L 10 R 11
+  ---
+  100 10 11
+b 0x4
+  p 20 = 0x2
+  q 21 = 0x1
+  ---
+  L 21 R 20
+  ---
+  101 21 20
+c 0x5
+  r 30 = 0x1
+  s 31 = 0x2
+  ---
+  L 31 R 30
+  ---
+  102 31 30
+top 0x6
+  u 40 = 0x3
+  v 41 = 0x4
+  ---
+  L 40.10 R 41.20
+  ---
+  103 40 41
+---
+0x6
+";
+
+  #[test]
+  fn test_isomorphic() {
+    let program = unpretty(DUPLICATES).unwrap();
+    let mut canon = Canonicalizer::new(&program);
+    assert!(canon.are_isomorphic(CtorId(3), CtorId(4)));
+    assert!(!canon.are_isomorphic(CtorId(3), CtorId(5)));
+    assert_eq!(
+      canon.structural_hash(CtorId(3)),
+      canon.structural_hash(CtorId(4))
+    );
+    assert!(!are_isomorphic(&program, CtorId(1), CtorId(2)));
+    let delayed = unpretty(&DUPLICATES.replace("101 21 20", "101 21 20 after 1")).unwrap();
+    assert!(!are_isomorphic(&delayed, CtorId(3), CtorId(4)));
+  }
+
+  #[test]
+  fn test_hash_collision() {
+    let program = unpretty(DUPLICATES).unwrap();
+    let mut canon = Canonicalizer::new(&program);
+    // Let `mul2` collide with `add1`, so that `c` has the shape of `a` with its instances swapped.
+    let add1 = canon.structural_hash(CtorId(1));
+    canon.hashes.insert(CtorId(2), add1);
+    assert_eq!(canon.shape(CtorId(3)).0, canon.shape(CtorId(5)).0);
+    assert!(!canon.are_isomorphic(CtorId(3), CtorId(5)));
+    assert!(canon.are_isomorphic(CtorId(3), CtorId(4)));
+  }
+
+  #[test]
+  fn test_hash_cons() {
+    let mut program = unpretty(DUPLICATES).unwrap();
+    let replaced = hash_cons(&mut program);
+    assert_eq!(replaced, HashMap::from([(CtorId(4), CtorId(3))]));
+    pretty_assertions::assert_eq!(
+      program.to_string(),
+      "add1 0x1 add1
+mul2 0x2 mul2
+---
+---
+a 0x3
+  x 10 = 0x1
+  y 11 = 0x2
+  ---
+  L 10 R 11
+  ---
+  100 10 11
+c 0x5
+  r 30 = 0x1
+  s 31 = 0x2
+  ---
+  L 31 R 30
+  ---
+  102 31 30
+top 0x6
+  u 40 = 0x3
+  v 41 = 0x3
+  ---
+  L 40.10 R 41.11
+  ---
+  103 40 41
+---
+0x6
+"
+    );
+  }
+
+  #[test]
+  fn test_hash_cons_repeated_ids() {
+    // `b` declares the same instance ids as `a`, but labels them the other way around.
+    let mut program = unpretty(
+      &DUPLICATES
+        .replace("p 20", "p 10")
+        .replace("q 21", "q 11")
+        .replace("L 21 R 20", "L 11 R 10")
+        .replace("101 21 20", "101 11 10")
+        .replace("L 40.10 R 41.20", "L 40.10 R 41.10"),
+    )
+    .unwrap();
+    hash_cons(&mut program);
+    let Ctor::StructlikeCtor(top) = &program.ctors[&CtorId(6)] else {
+      unreachable!()
+    };
+    assert_eq!(
+      top.iface[0].1,
+      Comm::Data(InstRef(vec![InstId(40), InstId(10)]))
+    );
+    assert_eq!(
+      top.iface[1].1,
+      Comm::Data(InstRef(vec![InstId(41), InstId(11)]))
+    );
+  }
+
+  #[test]
+  fn test_recursive() {
+    let program = unpretty(
+      "---
+---
+a 0x1
+  x 10 = 0x2
+  ---
+  L 10
+  ---
+b 0x2
+  y 20 = 0x1
+  ---
+  L 20
+  ---
+c 0x3
+  z 30 = 0x3
+  ---
+  L 30
+  ---
+---
+0x1
+",
+    )
+    .unwrap();
+    let mut canon = Canonicalizer::new(&program);
+    assert!(canon.are_isomorphic(CtorId(1), CtorId(2)));
+    assert!(!canon.are_isomorphic(CtorId(1), CtorId(3)));
+  }
+
+  #[test]
+  fn test_normalize() {
+    let mut program = unpretty(DUPLICATES).unwrap();
+    normalize(&mut program);
+    let normalized = program.to_string();
+    pretty_assertions::assert_eq!(
+      normalized,
+      "add1 0x1 add1
+mul2 0x4 mul2
+---
+---
+a 0x0
+  x 0 = 0x1
+  y 1 = 0x4
+  ---
+  L 0 R 1
+  ---
+  0 0 1
+b 0x2
+  p 2 = 0x4
+  q 3 = 0x1
+  ---
+  L 3 R 2
+  ---
+  1 3 2
+c 0x3
+  r 4 = 0x1
+  s 5 = 0x4
+  ---
+  L 5 R 4
+  ---
+  2 5 4
+top 0x5
+  u 6 = 0x0
+  v 7 = 0x2
+  ---
+  L 6.0 R 7.2
+  ---
+  3 6 7
+---
+0x5
+"
+    );
+    let mut renumbered = unpretty(
+      &DUPLICATES
+        .replace("0x6", "0x60")
+        .replace("x 10", "x 12")
+        .replace("L 10", "L 12")
+        .replace("100 10", "999 12")
+        .replace("40.10", "40.12"),
+    )
+    .unwrap();
+    normalize(&mut renumbered);
+    pretty_assertions::assert_eq!(normalized, renumbered.to_string());
+  }
+}
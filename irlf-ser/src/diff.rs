@@ -0,0 +1,569 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+use lf_types::{Comm, CtorId, DeltaT, IfaceNode, InstId, Slice};
+
+use crate::canon::Canonicalizer;
+use crate::ir::{Connection, Ctor, InstRef, Program, Sym};
+use crate::pretty::pretty_delta;
+
+/// A reference to a (possibly nested) instance by the symbols of the instances along its path.
+pub type SymRef = Vec<Sym>;
+/// An end of a connection: The instance that it refers to and the channels of it that it selects.
+pub type SymEnd = (SymRef, Option<Slice>);
+/// A connection between two ends, with its delay if it has one.
+pub type SymConnection = (SymEnd, SymEnd, Option<DeltaT>);
+
+/// The differences between two versions of a program.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ProgramDiff {
+  /// Symbols of the ctors that exist only in the new program.
+  pub added_ctors: Vec<Sym>,
+  /// Symbols of the ctors that exist only in the old program.
+  pub removed_ctors: Vec<Sym>,
+  pub changed_ctors: Vec<CtorDiff>,
+  /// The old and new symbols of `main` if `main` is not the same ctor in both programs.
+  pub main: Option<(Sym, Sym)>,
+}
+
+/// The differences between two versions of a ctor.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CtorDiff {
+  pub old_sym: Sym,
+  pub new_sym: Sym,
+  /// Whether the kind of the ctor, or the name or path of a lib or binary ctor, changed.
+  pub definition_changed: bool,
+  pub added_insts: Vec<Sym>,
+  pub removed_insts: Vec<Sym>,
+  /// Symbols in the new program of the instances that call a different ctor, pass different
+  /// arguments, or have a different bank width than before.
+  pub changed_insts: Vec<Sym>,
+  /// Old and new symbols of the instances that were matched by structure.
+  pub renamed_insts: Vec<(Sym, Sym)>,
+  pub added_iface: Vec<IfaceNode<SymRef>>,
+  pub removed_iface: Vec<IfaceNode<SymRef>>,
+  pub added_connections: Vec<SymConnection>,
+  pub removed_connections: Vec<SymConnection>,
+}
+
+impl CtorDiff {
+  #[must_use]
+  pub fn is_empty(&self) -> bool {
+    self.old_sym == self.new_sym
+      && !self.definition_changed
+      && self.added_insts.is_empty()
+      && self.removed_insts.is_empty()
+      && self.changed_insts.is_empty()
+      && self.renamed_insts.is_empty()
+      && self.added_iface.is_empty()
+      && self.removed_iface.is_empty()
+      && self.added_connections.is_empty()
+      && self.removed_connections.is_empty()
+  }
+}
+
+impl ProgramDiff {
+  #[must_use]
+  pub fn is_empty(&self) -> bool {
+    self.added_ctors.is_empty()
+      && self.removed_ctors.is_empty()
+      && self.changed_ctors.is_empty()
+      && self.main.is_none()
+  }
+}
+
+/// Computes the differences between `old` and `new`. Ctors and instances are matched by symbol,
+/// and those that cannot be matched by symbol are matched by structure.
+#[must_use]
+pub fn diff(old: &Program, new: &Program) -> ProgramDiff {
+  let ctors = match_ctors(old, new);
+  let insts = match_insts(old, new, &ctors);
+  let mut ret = ProgramDiff::default();
+  for (cid, sym) in &new.ctorid2sym {
+    if !ctors.values().any(|it| it == cid) {
+      ret.added_ctors.push(sym.clone());
+    }
+  }
+  for (cid, sym) in &old.ctorid2sym {
+    if !ctors.contains_key(cid) {
+      ret.removed_ctors.push(sym.clone());
+    }
+  }
+  for (old_id, new_id) in &ctors {
+    let cdiff = diff_ctor(old, new, &ctors, &insts, *old_id, *new_id);
+    if !cdiff.is_empty() {
+      ret.changed_ctors.push(cdiff);
+    }
+  }
+  if ctors.get(&old.main) != Some(&new.main) {
+    ret.main = Some((
+      old.ctorid2sym[&old.main].clone(),
+      new.ctorid2sym[&new.main].clone(),
+    ));
+  }
+  ret.added_ctors.sort();
+  ret.removed_ctors.sort();
+  ret.changed_ctors.sort_by(|a, b| a.new_sym.cmp(&b.new_sym));
+  ret
+}
+
+fn match_ctors(old: &Program, new: &Program) -> HashMap<CtorId, CtorId> {
+  let new_by_sym: HashMap<&Sym, CtorId> = new
+    .ctorid2sym
+    .iter()
+    .map(|(cid, sym)| (sym, *cid))
+    .collect();
+  let mut ret: HashMap<CtorId, CtorId> = old
+    .ctorid2sym
+    .iter()
+    .filter_map(|(cid, sym)| Some((*cid, *new_by_sym.get(sym)?)))
+    .collect();
+  let matched_new: HashSet<CtorId> = ret.values().copied().collect();
+  let mut old_canon = Canonicalizer::new(old);
+  let mut new_canon = Canonicalizer::new(new);
+  let mut unmatched_new: HashMap<u64, Vec<CtorId>> = HashMap::new();
+  for cid in sorted(new.ctors.keys().filter(|cid| !matched_new.contains(cid))) {
+    unmatched_new
+      .entry(new_canon.structural_hash(cid))
+      .or_default()
+      .push(cid);
+  }
+  for cid in sorted(old.ctors.keys().filter(|cid| !ret.contains_key(cid))) {
+    if let Some(candidates) = unmatched_new.get_mut(&old_canon.structural_hash(cid)) {
+      if !candidates.is_empty() {
+        ret.insert(cid, candidates.remove(0));
+      }
+    }
+  }
+  ret
+}
+
+/// Matches the instances of matched structlike ctors by symbol, or otherwise by their labels in
+/// the canonical labelings of their ctors.
+fn match_insts(
+  old: &Program,
+  new: &Program,
+  ctors: &HashMap<CtorId, CtorId>,
+) -> HashMap<InstId, InstId> {
+  let mut old_canon = Canonicalizer::new(old);
+  let mut new_canon = Canonicalizer::new(new);
+  let mut ret = HashMap::new();
+  for (old_id, new_id) in ctors {
+    let (Ctor::StructlikeCtor(old_sctor), Ctor::StructlikeCtor(new_sctor)) =
+      (&old.ctors[old_id], &new.ctors[new_id])
+    else {
+      continue;
+    };
+    let new_by_sym: HashMap<&Sym, InstId> = new_sctor
+      .inst2sym
+      .iter()
+      .map(|(iid, sym)| (sym, *iid))
+      .collect();
+    let mut matched_new = HashSet::new();
+    let mut unmatched_old = vec![];
+    for (old_inst, sym) in &old_sctor.inst2sym {
+      if let Some(new_inst) = new_by_sym.get(sym) {
+        ret.insert(*old_inst, *new_inst);
+        matched_new.insert(*new_inst);
+      } else {
+        unmatched_old.push(*old_inst);
+      }
+    }
+    let new_by_label: HashMap<usize, InstId> = new_canon
+      .labels(*new_id)
+      .iter()
+      .filter(|(iid, _)| !matched_new.contains(iid))
+      .map(|(iid, l)| (*l, *iid))
+      .collect();
+    let old_labels = old_canon.labels(*old_id);
+    for old_inst in unmatched_old {
+      if let Some(new_inst) = new_by_label.get(&old_labels[&old_inst]) {
+        if ctors.get(&old_sctor.insts[&old_inst].ctor) == Some(&new_sctor.insts[new_inst].ctor) {
+          ret.insert(old_inst, *new_inst);
+        }
+      }
+    }
+  }
+  ret
+}
+
+fn diff_ctor(
+  old: &Program,
+  new: &Program,
+  ctors: &HashMap<CtorId, CtorId>,
+  insts: &HashMap<InstId, InstId>,
+  old_id: CtorId,
+  new_id: CtorId,
+) -> CtorDiff {
+  let mut ret = CtorDiff {
+    old_sym: old.ctorid2sym[&old_id].clone(),
+    new_sym: new.ctorid2sym[&new_id].clone(),
+    ..CtorDiff::default()
+  };
+  let (old_sctor, new_sctor) = match (&old.ctors[&old_id], &new.ctors[&new_id]) {
+    (Ctor::StructlikeCtor(old_sctor), Ctor::StructlikeCtor(new_sctor)) => (old_sctor, new_sctor),
+    (Ctor::LibCtor(a), Ctor::LibCtor(b)) => {
+      ret.definition_changed = a.name != b.name || a.params != b.params;
+      return ret;
+    }
+    (Ctor::BinaryCtor(a), Ctor::BinaryCtor(b)) => {
+      ret.definition_changed = a.path != b.path;
+      return ret;
+    }
+    _ => {
+      ret.definition_changed = true;
+      return ret;
+    }
+  };
+  for (old_inst, sym) in &old_sctor.inst2sym {
+    match insts.get(old_inst) {
+      None => ret.removed_insts.push(sym.clone()),
+      Some(new_inst) => {
+        if *sym != new_sctor.inst2sym[new_inst] {
+          ret
+            .renamed_insts
+            .push((sym.clone(), new_sctor.inst2sym[new_inst].clone()));
+        }
+        let (old_call, new_call) = (&old_sctor.insts[old_inst], &new_sctor.insts[new_inst]);
+        if ctors.get(&old_call.ctor) != Some(&new_call.ctor)
+          || old_call.args != new_call.args
+          || old_call.width != new_call.width
+        {
+          ret.changed_insts.push(new_sctor.inst2sym[new_inst].clone());
+        }
+      }
+    }
+  }
+  for (new_inst, sym) in &new_sctor.inst2sym {
+    if !insts.values().any(|it| it == new_inst) {
+      ret.added_insts.push(sym.clone());
+    }
+  }
+  ret.added_insts.sort();
+  ret.removed_insts.sort();
+  ret.changed_insts.sort();
+  ret.renamed_insts.sort();
+  let translate = |iref: &InstRef| -> Option<Vec<InstId>> {
+    iref.0.iter().map(|iid| insts.get(iid).copied()).collect()
+  };
+  let old_iface: Vec<IfaceNode<Option<Vec<InstId>>>> = old_sctor
+    .iface
+    .iter()
+    .map(|node| IfaceNode(node.0, node.1.map(translate), node.2.clone()))
+    .collect();
+  let new_iface: Vec<IfaceNode<Option<Vec<InstId>>>> = new_sctor
+    .iface
+    .iter()
+    .map(|node| {
+      IfaceNode(
+        node.0,
+        node.1.map(|iref| Some(iref.0.clone())),
+        node.2.clone(),
+      )
+    })
+    .collect();
+  let (removed, added) = unmatched_by_lcs(&old_iface, &new_iface);
+  ret.removed_iface = removed
+    .into_iter()
+    .map(|i| sym_iface_node(old, old_id, &old_sctor.iface[i]))
+    .collect();
+  ret.added_iface = added
+    .into_iter()
+    .map(|i| sym_iface_node(new, new_id, &new_sctor.iface[i]))
+    .collect();
+  let own = |iref: &InstRef| Some(iref.0.clone());
+  let new_connections: HashSet<ConnectionKey> = new_sctor
+    .connections
+    .iter()
+    .filter_map(|c| connection_key(c, own))
+    .collect();
+  let mut old_connections = HashSet::new();
+  for c in &old_sctor.connections {
+    match connection_key(c, translate).filter(|it| new_connections.contains(it)) {
+      Some(key) => {
+        old_connections.insert(key);
+      }
+      None => ret.removed_connections.push(sym_connection(old, old_id, c)),
+    }
+  }
+  for c in &new_sctor.connections {
+    if !connection_key(c, own).is_some_and(|key| old_connections.contains(&key)) {
+      ret.added_connections.push(sym_connection(new, new_id, c));
+    }
+  }
+  ret
+}
+
+/// Returns the indices of the elements of `old` and of `new` that are not part of a longest common
+/// subsequence of `old` and `new`. Elements that are `None` never match.
+fn unmatched_by_lcs<T: PartialEq>(
+  old: &[IfaceNode<Option<T>>],
+  new: &[IfaceNode<Option<T>>],
+) -> (Vec<usize>, Vec<usize>) {
+  let matches = |a: &IfaceNode<Option<T>>, b: &IfaceNode<Option<T>>| {
+    a.0 == b.0
+      && a.2 == b.2
+      && match (&a.1, &b.1) {
+        (Comm::Notify, Comm::Notify) => true,
+        (Comm::Data(Some(a)), Comm::Data(Some(b))) => a == b,
+        _ => false,
+      }
+  };
+  let mut lengths = vec![vec![0; new.len() + 1]; old.len() + 1];
+  for i in (0..old.len()).rev() {
+    for j in (0..new.len()).rev() {
+      lengths[i][j] = if matches(&old[i], &new[j]) {
+        lengths[i + 1][j + 1] + 1
+      } else {
+        lengths[i + 1][j].max(lengths[i][j + 1])
+      };
+    }
+  }
+  let (mut removed, mut added) = (vec![], vec![]);
+  let (mut i, mut j) = (0, 0);
+  while i < old.len() && j < new.len() {
+    if matches(&old[i], &new[j]) {
+      i += 1;
+      j += 1;
+    } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+      removed.push(i);
+      i += 1;
+    } else {
+      added.push(j);
+      j += 1;
+    }
+  }
+  removed.extend(i..old.len());
+  added.extend(j..new.len());
+  (removed, added)
+}
+
+fn sym_ref(program: &Program, ctor: CtorId, iref: &InstRef) -> SymRef {
+  let mut ret = vec![];
+  let mut owner = ctor;
+  for iid in &iref.0 {
+    let Ctor::StructlikeCtor(sctor) = &program.ctors[&owner] else {
+      unreachable!("refs should only pass through structlike ctors")
+    };
+    ret.push(sctor.inst2sym[iid].clone());
+    owner = sctor.insts[iid].ctor;
+  }
+  ret
+}
+
+/// A connection in terms of the ids of a single program.
+type ConnectionKey = (
+  (Vec<InstId>, Option<Slice>),
+  (Vec<InstId>, Option<Slice>),
+  Option<DeltaT>,
+);
+
+fn connection_key(
+  c: &Connection,
+  translate: impl Fn(&InstRef) -> Option<Vec<InstId>>,
+) -> Option<ConnectionKey> {
+  Some((
+    (translate(&c.left)?, c.left_slice),
+    (translate(&c.right)?, c.right_slice),
+    c.delay.clone(),
+  ))
+}
+
+fn sym_connection(program: &Program, ctor: CtorId, c: &Connection) -> SymConnection {
+  (
+    (sym_ref(program, ctor, &c.left), c.left_slice),
+    (sym_ref(program, ctor, &c.right), c.right_slice),
+    c.delay.clone(),
+  )
+}
+
+fn sym_iface_node(program: &Program, ctor: CtorId, node: &IfaceNode<InstRef>) -> IfaceNode<SymRef> {
+  IfaceNode(
+    node.0,
+    node.1.map(|iref| sym_ref(program, ctor, iref)),
+    node.2.clone(),
+  )
+}
+
+fn sorted<'a>(ids: impl Iterator<Item = &'a CtorId>) -> Vec<CtorId> {
+  let mut ret: Vec<CtorId> = ids.copied().collect();
+  ret.sort();
+  ret
+}
+
+struct DisplaySymRef<'a>(&'a SymRef);
+
+impl Display for DisplaySymRef<'_> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.0.join("."))
+  }
+}
+
+fn write_connection(
+  f: &mut std::fmt::Formatter<'_>,
+  prefix: &str,
+  (left, right, delay): &SymConnection,
+) -> std::fmt::Result {
+  write!(f, "  {prefix} connection")?;
+  for (iref, slice) in [left, right] {
+    write!(f, " {}", DisplaySymRef(iref))?;
+    if let Some(slice) = slice {
+      write!(f, " {slice}")?;
+    }
+  }
+  if let Some(delay) = delay {
+    write!(f, " after {}", pretty_delta(delay))?;
+  }
+  writeln!(f)
+}
+
+fn write_iface_node(
+  f: &mut std::fmt::Formatter<'_>,
+  prefix: &str,
+  node: &IfaceNode<SymRef>,
+) -> std::fmt::Result {
+  match &node.1 {
+    Comm::Notify => write!(f, "  {prefix} iface {} -", node.0)?,
+    Comm::Data(iref) => write!(f, "  {prefix} iface {} {}", node.0, DisplaySymRef(iref))?,
+  }
+  if let Some(ty) = &node.2 {
+    write!(f, " : {ty}")?;
+  }
+  writeln!(f)
+}
+
+impl Display for CtorDiff {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if self.old_sym == self.new_sym {
+      writeln!(f, "~ ctor {}", self.new_sym)?;
+    } else {
+      writeln!(f, "~ ctor {} (was {})", self.new_sym, self.old_sym)?;
+    }
+    if self.definition_changed {
+      writeln!(f, "  ~ definition")?;
+    }
+    for sym in &self.added_insts {
+      writeln!(f, "  + inst {sym}")?;
+    }
+    for sym in &self.removed_insts {
+      writeln!(f, "  - inst {sym}")?;
+    }
+    for sym in &self.changed_insts {
+      writeln!(f, "  ~ inst {sym}")?;
+    }
+    for (old, new) in &self.renamed_insts {
+      writeln!(f, "  ~ inst {new} (was {old})")?;
+    }
+    for node in &self.added_iface {
+      write_iface_node(f, "+", node)?;
+    }
+    for node in &self.removed_iface {
+      write_iface_node(f, "-", node)?;
+    }
+    for c in &self.added_connections {
+      write_connection(f, "+", c)?;
+    }
+    for c in &self.removed_connections {
+      write_connection(f, "-", c)?;
+    }
+    Ok(())
+  }
+}
+
+impl Display for ProgramDiff {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for sym in &self.added_ctors {
+      writeln!(f, "+ ctor {sym}")?;
+    }
+    for sym in &self.removed_ctors {
+      writeln!(f, "- ctor {sym}")?;
+    }
+    for cdiff in &self.changed_ctors {
+      write!(f, "{cdiff}")?;
+    }
+    if let Some((old, new)) = &self.main {
+      writeln!(f, "~ main {new} (was {old})")?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::unpretty::unpretty;
+
+  const OLD: &str = "add1 0x1 add1
+mul2 0x2 mul2
+---
+---
+a 0x3
+  x 10 = 0x1
+  y 11 = 0x2
+  ---
+  L 10 R 11
+  ---
+  100 10 11
+top 0x4
+  u 40 = 0x3
+  ---
+  L 40.10 R 40
+  ---
+---
+0x4
+";
+
+  #[test]
+  fn test_identical() {
+    let old = unpretty(OLD).unwrap();
+    let mut new = unpretty(OLD).unwrap();
+    crate::canon::normalize(&mut new);
+    assert!(diff(&old, &new).is_empty());
+  }
+
+  #[test]
+  fn test_diff() {
+    let old = unpretty(OLD).unwrap();
+    let new = unpretty(
+      "add1 0x1 add1
+double 0x2 mul2
+sum 0x5 sum
+---
+---
+a 0x3
+  x 10 = 0x1
+  z 12 = 0x5 [2]
+  ---
+  L 10 R 12
+  L -
+  ---
+  100 10 12 [1:2] after 1
+top 0x4
+  w 40 = 0x3
+  ---
+  L 40.10 R 40
+  ---
+---
+0x4
+",
+    )
+    .unwrap();
+    let diff = diff(&old, &new);
+    pretty_assertions::assert_eq!(
+      diff.to_string(),
+      "+ ctor sum
+~ ctor a
+  + inst z
+  - inst y
+  + iface R z
+  + iface L -
+  - iface R y
+  + connection x z [1:2] after 1
+  - connection x y
+~ ctor double (was mul2)
+~ ctor top
+  ~ inst w (was u)
+"
+    );
+  }
+}
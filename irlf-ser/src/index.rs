@@ -0,0 +1,222 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use lf_types::{CtorId, DebugOnlyId, InstId};
+
+use crate::ir::Program;
+use crate::lex::Range;
+use crate::unpretty::unpretty_indexed;
+
+/// An id that can be defined and referred to in the text of a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IdRef {
+  Ctor(CtorId),
+  /// An instance, along with the ctor that declares it. Instance ids are only unique within the
+  /// ctor that declares them.
+  Inst(CtorId, InstId),
+  Connection(DebugOnlyId),
+}
+
+impl From<CtorId> for IdRef {
+  fn from(id: CtorId) -> Self {
+    IdRef::Ctor(id)
+  }
+}
+
+impl From<DebugOnlyId> for IdRef {
+  fn from(id: DebugOnlyId) -> Self {
+    IdRef::Connection(id)
+  }
+}
+
+/// A place in the text of a program where an id appears.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Occurrence {
+  pub id: IdRef,
+  pub range: Range,
+  /// Whether this is the place where `id` is defined.
+  pub definition: bool,
+}
+
+/// What the parser records about the ids that it parses. Which ctor declares an instance is only
+/// known once the instances before it in an `InstRef` are resolved, which may take ctors that are
+/// defined further on in the text.
+#[derive(Debug)]
+pub(crate) enum Recorded {
+  /// An id whose occurrence is known as parsed. Definitions of ctors also start the ctor that the
+  /// instances recorded after them belong to.
+  Id(Occurrence),
+  /// An occurrence of the last instance of `path`, which starts with an instance of the current
+  /// ctor, as in an `InstRef`.
+  Inst {
+    path: Vec<InstId>,
+    range: Range,
+    definition: bool,
+  },
+  /// The instance `inst` of the current ctor calls `ctor`.
+  Call { inst: InstId, ctor: CtorId },
+}
+
+/// Resolves the instances in `recorded` to the ctors that declare them. Instances behind a segment
+/// whose ctor is unknown, such as one that calls an undefined ctor, are dropped, since the
+/// reference to that ctor is reported instead.
+fn resolve(recorded: &[Recorded]) -> Vec<Occurrence> {
+  let owners = || {
+    let mut owner = None;
+    recorded.iter().map(move |recorded| {
+      if let Recorded::Id(Occurrence {
+        id: IdRef::Ctor(ctor),
+        definition: true,
+        ..
+      }) = recorded
+      {
+        owner = Some(*ctor);
+      }
+      (owner, recorded)
+    })
+  };
+  let defined: HashSet<CtorId> = owners().filter_map(|(owner, _)| owner).collect();
+  let callees: HashMap<(CtorId, InstId), CtorId> = owners()
+    .filter_map(|(owner, recorded)| match recorded {
+      Recorded::Call { inst, ctor } => Some(((owner?, *inst), *ctor)),
+      _ => None,
+    })
+    .collect();
+  owners()
+    .filter_map(|(owner, recorded)| match recorded {
+      Recorded::Id(occurrence) => Some(*occurrence),
+      Recorded::Inst {
+        path,
+        range,
+        definition,
+      } => {
+        let (last, before) = path.split_last()?;
+        let mut owner = owner?;
+        for inst in before {
+          owner = *callees.get(&(owner, *inst))?;
+          if !defined.contains(&owner) {
+            return None;
+          }
+        }
+        Some(Occurrence {
+          id: IdRef::Inst(owner, *last),
+          range: *range,
+          definition: *definition,
+        })
+      }
+      Recorded::Call { .. } => None,
+    })
+    .collect()
+}
+
+/// The locations of the ids in the text of a program.
+#[derive(Debug, Default)]
+pub struct Index {
+  pub occurrences: Vec<Occurrence>,
+}
+
+impl Index {
+  /// Returns the occurrence at the given zero-based line and column, if any.
+  #[must_use]
+  pub fn at(&self, line: u16, col: u16) -> Option<&Occurrence> {
+    self
+      .occurrences
+      .iter()
+      .find(|occurrence| occurrence.range.touches(line, col))
+  }
+  /// Returns the first definition of `id`, if any.
+  #[must_use]
+  pub fn definition(&self, id: IdRef) -> Option<&Occurrence> {
+    self.references(id).find(|occurrence| occurrence.definition)
+  }
+  /// Returns all occurrences of `id`, including definitions.
+  pub fn references(&self, id: IdRef) -> impl Iterator<Item = &Occurrence> {
+    self
+      .occurrences
+      .iter()
+      .filter(move |occurrence| occurrence.id == id)
+  }
+}
+
+/// Indexes the ids in `text`. Indexing is best-effort: It stops where the text stops parsing, so
+/// that a partially edited program is still indexed up to the point of the edit.
+#[must_use]
+pub fn index(text: &str) -> Index {
+  parse(text).1
+}
+
+/// Parses `text` and indexes its ids in the same pass (see `index`).
+pub fn parse(text: &str) -> (Result<Program, (String, Range)>, Index) {
+  let recorded = RefCell::new(vec![]);
+  let program = unpretty_indexed(text, &recorded);
+  let index = Index {
+    occurrences: resolve(&recorded.into_inner()),
+  };
+  (program, index)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const PROGRAM: &str = "c 0x7 add1
+---
+---
+rtor0 0x3
+  foo 89 = 0x7
+  ---
+  L 89
+  ---
+  90 89 [0:1] 89 after 1
+rtor1 0x4
+  bar 88 = 0x3
+  ---
+  R 88.89
+  ---
+---
+0x4
+";
+
+  #[test]
+  fn test_index() {
+    let index = index(PROGRAM);
+    let inst = index.at(12, 8).unwrap();
+    assert_eq!(inst.id, IdRef::Inst(CtorId(3), InstId(89)));
+    assert!(!inst.definition);
+    assert_eq!(index.definition(inst.id).unwrap().range.start(), (4, 6));
+    assert_eq!(index.references(inst.id).count(), 5);
+    let main = index.at(15, 0).unwrap();
+    assert_eq!(main.id, IdRef::Ctor(CtorId(4)));
+    assert_eq!(index.definition(main.id).unwrap().range.start(), (9, 6));
+    let connection = index.at(8, 3).unwrap();
+    assert_eq!(connection.id, IdRef::Connection(DebugOnlyId(90)));
+    assert!(connection.definition);
+  }
+
+  #[test]
+  fn test_repeated_inst_ids() {
+    // `rtor1` declares an instance 89 of its own, which is not the 89 of `rtor0`.
+    let text = PROGRAM
+      .replace("  bar 88 = 0x3\n", "  bar 88 = 0x3\n  baz 89 = 0x7\n")
+      .replace("R 88.89\n", "R 88.89\n  L 89\n");
+    let index = index(&text);
+    let outer = IdRef::Inst(CtorId(4), InstId(89));
+    let inner = IdRef::Inst(CtorId(3), InstId(89));
+    assert_eq!(index.at(13, 8).unwrap().id, inner);
+    assert_eq!(index.at(14, 4).unwrap().id, outer);
+    assert_eq!(index.definition(outer).unwrap().range.start(), (11, 6));
+    assert_eq!(index.definition(inner).unwrap().range.start(), (4, 6));
+    assert_eq!(index.references(outer).count(), 2);
+    assert_eq!(index.references(inner).count(), 5);
+  }
+
+  #[test]
+  fn test_partial() {
+    let index = index(&PROGRAM.replace("bar 88 = 0x3", "bar 88 ="));
+    assert!(index
+      .definition(IdRef::Inst(CtorId(4), InstId(88)))
+      .is_some());
+    assert!(index.definition(IdRef::Ctor(CtorId(7))).is_some());
+    assert!(index.at(15, 0).is_none());
+  }
+}
@@ -1,43 +1,53 @@
 use std::{collections::HashMap, path::PathBuf};
 
-use lf_types::{CtorId, DebugOnlyId, Iface, InstId};
+use lf_types::{CtorId, DebugOnlyId, DeltaT, Iface, InstId, Literal, Slice};
 use serde::{Deserialize, Serialize};
 pub type IfaceElt = InstRef;
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct CtorCall {
   pub ctor: CtorId,
+  /// The number of instances in the bank that this call creates.
+  pub width: u64,
+  pub args: Vec<Literal>,
 }
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
 pub struct InstRef(pub Vec<InstId>);
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct Connection {
   pub id: DebugOnlyId,
   pub left: InstRef,
+  pub left_slice: Option<Slice>,
   pub right: InstRef,
+  pub right_slice: Option<Slice>,
+  /// How long after being provided by the right end a value reaches the left end, outermost
+  /// component first. Connections without a delay deliver values at the same tag.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub delay: Option<DeltaT>,
 }
 pub type Sym = String;
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct StructlikeCtor {
   pub inst2sym: HashMap<InstId, Sym>,
   pub insts: HashMap<InstId, CtorCall>,
   pub iface: Iface<IfaceElt>,
   pub connections: Vec<Connection>,
 }
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct BinaryCtor {
   pub path: PathBuf,
 }
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct LibCtor {
   pub name: String,
+  pub params: Vec<Sym>,
 }
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub enum Ctor {
   StructlikeCtor(StructlikeCtor),
   BinaryCtor(BinaryCtor),
   LibCtor(LibCtor),
 }
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct Program {
   pub ctorid2sym: HashMap<CtorId, Sym>,
   pub ctors: HashMap<CtorId, Ctor>,
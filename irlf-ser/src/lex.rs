@@ -1,10 +1,16 @@
+use std::cell::RefCell;
+
 use serde::{Deserialize, Serialize};
 
+use crate::index::Recorded;
+
 #[derive(Clone, Copy, Debug)]
 pub struct TokenStream<'a> {
   source: &'a str,
   line: u16,
   col: u16,
+  /// Where to record the ids that are parsed from this stream, if anywhere.
+  index: Option<&'a RefCell<Vec<Recorded>>>,
 }
 
 fn indentation(s: &str) -> usize {
@@ -21,6 +27,19 @@ impl<'a> TokenStream<'a> {
       source: s,
       line: 0,
       col: 0,
+      index: None,
+    }
+  }
+  /// Like `new`, but records the ids that are parsed from the stream in `index`.
+  pub fn indexed(s: &'a str, index: &'a RefCell<Vec<Recorded>>) -> TokenStream<'a> {
+    TokenStream {
+      index: Some(index),
+      ..TokenStream::new(s)
+    }
+  }
+  pub fn record(&self, recorded: Recorded) {
+    if let Some(index) = self.index {
+      index.borrow_mut().push(recorded);
     }
   }
   /// Precondition: other is a suffix of self.
@@ -206,6 +225,24 @@ pub struct Range {
   line1: u16,
   col1: u16,
 }
+impl Range {
+  /// Returns the zero-based line and column at which `self` starts.
+  #[must_use]
+  pub fn start(&self) -> (u16, u16) {
+    (self.line0, self.col0)
+  }
+  /// Returns the zero-based line and column right after the end of `self`.
+  #[must_use]
+  pub fn end(&self) -> (u16, u16) {
+    (self.line1, self.col1)
+  }
+  /// Returns whether the given position is in `self` or right after its end.
+  #[must_use]
+  pub fn touches(&self, line: u16, col: u16) -> bool {
+    self.start() <= (line, col) && (line, col) <= self.end()
+  }
+}
+
 /// A token and the range from which it came.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Token<'a> {
@@ -1,7 +1,12 @@
 #![warn(clippy::pedantic)]
 #![allow(clippy::cast_possible_truncation)]
+pub mod canon;
+pub mod diff;
+pub mod index;
 pub mod ir;
 mod lex;
 pub mod pretty;
 pub mod unpretty;
 pub mod visitor;
+
+pub use lex::Range;
@@ -5,7 +5,14 @@ use std::{collections::HashMap, fmt::Display};
 
 impl Display for CtorCall {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "{}", self.ctor)
+    write!(f, "{}", self.ctor)?;
+    if self.width != 1 {
+      write!(f, " [{}]", self.width)?;
+    }
+    for arg in &self.args {
+      write!(f, " {arg}")?;
+    }
+    Ok(())
   }
 }
 
@@ -25,10 +32,30 @@ impl Display for InstRef {
 
 impl Display for Connection {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "{} {} {}", self.id, self.left, self.right)
+    write!(f, "{} {}", self.id, self.left)?;
+    if let Some(slice) = self.left_slice {
+      write!(f, " {slice}")?;
+    }
+    write!(f, " {}", self.right)?;
+    if let Some(slice) = self.right_slice {
+      write!(f, " {slice}")?;
+    }
+    if let Some(delay) = &self.delay {
+      write!(f, " after {}", pretty_delta(delay))?;
+    }
+    Ok(())
   }
 }
 
+/// Prints a hierarchical duration as dot-separated components, outermost first.
+pub(crate) fn pretty_delta(delta: &[u64]) -> String {
+  delta
+    .iter()
+    .map(ToString::to_string)
+    .collect::<Vec<_>>()
+    .join(".")
+}
+
 fn sortedkeys<K: Ord, V>(hm: &HashMap<K, V>) -> Vec<&K> {
   let mut sorted: Vec<&K> = hm.keys().collect();
   sorted.sort();
@@ -69,7 +96,11 @@ impl Display for BinaryCtor {
 
 impl Display for LibCtor {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "{}", self.name)
+    write!(f, "{}", self.name)?;
+    for param in &self.params {
+      write!(f, " {param}")?;
+    }
+    Ok(())
   }
 }
 
@@ -1,11 +1,15 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::path::PathBuf;
 
+use crate::index::{IdRef, Occurrence, Recorded};
 use crate::ir::{
   BinaryCtor, Connection, Ctor, CtorCall, IfaceElt, InstRef, LibCtor, Program, StructlikeCtor, Sym,
 };
 use crate::lex::{Range, Token, TokenStream};
-use lf_types::{Comm, CtorId, DebugOnlyId, IfaceNode, InstId, Side, SideMatch};
+use lf_types::{
+  Comm, CtorId, DebugOnlyId, DeltaT, IfaceNode, InstId, Literal, Side, SideMatch, Slice, Type,
+};
 
 /// Extracts a program from its pretty-printed format.
 ///
@@ -16,6 +20,19 @@ pub fn unpretty(s: &str) -> Result<Program, (String, Range)> {
   Program::unpretty(&mut toks)
 }
 
+/// Like `unpretty`, but records in `index` where each id is defined or referred to, up to the
+/// point at which parsing fails if it does.
+///
+/// # Errors
+/// Returns an error result if the program does not parse.
+pub(crate) fn unpretty_indexed(
+  s: &str,
+  index: &RefCell<Vec<Recorded>>,
+) -> Result<Program, (String, Range)> {
+  let mut toks = TokenStream::indexed(s, index);
+  Program::unpretty(&mut toks)
+}
+
 trait Unpretty<'a>: Sized {
   fn unpretty(toks: &mut TokenStream<'a>) -> Result<Self, (String, Range)>;
 }
@@ -25,28 +42,52 @@ fn parse_id<Id, F: Fn(u64) -> Id>(
   ctor: F,
   description: &str,
 ) -> Result<Id, (String, Range)> {
+  Ok(parse_id_at(toks, ctor, description)?.0)
+}
+
+/// Like `parse_id`, but also returns the range of the id.
+fn parse_id_at<Id, F: Fn(u64) -> Id>(
+  toks: &mut TokenStream,
+  ctor: F,
+  description: &str,
+) -> Result<(Id, Range), (String, Range)> {
   let tok = toks.token(Some(description))?;
-  let parsed = if tok.s.starts_with("0x") {
-    u64::from_str_radix(&tok.s[2..], 16)
-  } else {
-    tok.s.parse::<u64>()
-  };
-  if let Ok(id) = parsed {
-    Ok(ctor(id))
+  if let Some(id) = parse_numeric(tok.s) {
+    Ok((ctor(id), tok.r))
   } else {
     Err(("expected numeric id".to_string(), tok.r))
   }
 }
 
-impl<'a> Unpretty<'a> for CtorId {
-  fn unpretty(toks: &mut TokenStream<'a>) -> Result<Self, (String, Range)> {
-    parse_id(toks, CtorId, "ctor id")
+/// Parses an id and records where it occurs in the index of `toks`.
+fn indexed_id<Id: Copy + Into<IdRef>, F: Fn(u64) -> Id>(
+  toks: &mut TokenStream,
+  ctor: F,
+  description: &str,
+  definition: bool,
+) -> Result<Id, (String, Range)> {
+  let (id, range) = parse_id_at(toks, ctor, description)?;
+  toks.record(Recorded::Id(Occurrence {
+    id: id.into(),
+    range,
+    definition,
+  }));
+  Ok(id)
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal number.
+fn parse_numeric(s: &str) -> Option<u64> {
+  if let Some(hex) = s.strip_prefix("0x") {
+    u64::from_str_radix(hex, 16).ok()
+  } else {
+    s.parse::<u64>().ok()
   }
 }
 
-impl<'a> Unpretty<'a> for InstId {
+/// Parses a reference to a ctor. Definitions of ctors are parsed by `unpretty_ctortyp`.
+impl<'a> Unpretty<'a> for CtorId {
   fn unpretty(toks: &mut TokenStream<'a>) -> Result<Self, (String, Range)> {
-    parse_id(toks, InstId, "inst id")
+    indexed_id(toks, CtorId, "ctor id", false)
   }
 }
 
@@ -71,7 +112,14 @@ impl<'a> Unpretty<'a> for IfaceNode<IfaceElt> {
       *toks = bak;
       Comm::Data(InstRef::unpretty(toks)?)
     };
-    Ok(IfaceNode(side, elt))
+    let bak = *toks;
+    let ty = if let Ok(Token { s: ":", .. }) = toks.token(None) {
+      Some(Type::unpretty(toks)?)
+    } else {
+      *toks = bak;
+      None
+    };
+    Ok(IfaceNode(side, elt, ty))
   }
 }
 
@@ -87,21 +135,72 @@ impl<'a> Unpretty<'a> for SideMatch {
   }
 }
 
+impl<'a> Unpretty<'a> for Type {
+  fn unpretty(toks: &mut TokenStream<'a>) -> Result<Self, (String, Range)> {
+    let tok = toks.token(Some("type"))?;
+    tok.s.parse().map_err(|e| (e, tok.r))
+  }
+}
+
+impl<'a> Unpretty<'a> for Literal {
+  fn unpretty(toks: &mut TokenStream<'a>) -> Result<Self, (String, Range)> {
+    let tok = toks.token(Some("literal"))?;
+    match tok.s {
+      "true" => Ok(Literal::Bool(true)),
+      "false" => Ok(Literal::Bool(false)),
+      s => parse_numeric(s)
+        .map(Literal::U64)
+        .ok_or(("expected a literal".to_string(), tok.r)),
+    }
+  }
+}
+
 impl<'a> Unpretty<'a> for CtorCall {
   fn unpretty(toks: &mut TokenStream<'a>) -> Result<Self, (String, Range)> {
-    Ok(CtorCall {
-      ctor: CtorId::unpretty(toks)?,
-    })
+    let ctor = CtorId::unpretty(toks)?;
+    let bak = *toks;
+    let width = match toks.token(None) {
+      Ok(Token { s, r }) if s.starts_with('[') => s
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .and_then(parse_numeric)
+        .ok_or(("expected a bank width such as [4]".to_string(), r))?,
+      _ => {
+        *toks = bak;
+        1
+      }
+    };
+    let mut args = Vec::new();
+    while !{
+      toks.skip_whitespace();
+      toks.is_empty()
+    } {
+      args.push(Literal::unpretty(toks)?);
+    }
+    Ok(CtorCall { ctor, width, args })
   }
 }
 
-impl<'a> Unpretty<'a> for InstRef {
+/// Parses a segment of an `InstRef` and records where it occurs in the index of `toks`, along with
+/// the segments before it.
+fn indexed_segment(toks: &mut TokenStream, insts: &mut Vec<InstId>) -> Result<(), (String, Range)> {
+  let (id, range) = parse_id_at(toks, InstId, "inst id")?;
+  insts.push(id);
+  toks.record(Recorded::Inst {
+    path: insts.clone(),
+    range,
+    definition: false,
+  });
+  Ok(())
+}
+
+impl Unpretty<'_> for InstRef {
   fn unpretty(toks: &mut TokenStream) -> Result<InstRef, (String, Range)> {
     let mut insts = Vec::new();
-    insts.push(InstId::unpretty(toks)?);
+    indexed_segment(toks, &mut insts)?;
     let mut backup = *toks;
     while let Ok(Token { s: ".", .. }) = toks.token(None) {
-      insts.push(InstId::unpretty(toks)?);
+      indexed_segment(toks, &mut insts)?;
       backup = *toks;
     }
     *toks = backup;
@@ -109,12 +208,72 @@ impl<'a> Unpretty<'a> for InstRef {
   }
 }
 
+impl<'a> Unpretty<'a> for Option<Slice> {
+  fn unpretty(toks: &mut TokenStream<'a>) -> Result<Self, (String, Range)> {
+    let bak = *toks;
+    match toks.token(None) {
+      Ok(Token { s, r }) if s.starts_with('[') => {
+        let slice = s
+          .strip_prefix('[')
+          .and_then(|s| s.strip_suffix(']'))
+          .and_then(|s| s.split_once(':'))
+          .and_then(|(start, end)| Some((parse_numeric(start)?, parse_numeric(end)?)))
+          .filter(|(start, end)| start <= end)
+          .map(|(start, end)| Slice { start, end });
+        slice
+          .map(Some)
+          .ok_or(("expected a slice such as [0:4]".to_string(), r))
+      }
+      _ => {
+        *toks = bak;
+        Ok(None)
+      }
+    }
+  }
+}
+
+impl<'a> Unpretty<'a> for DeltaT {
+  fn unpretty(toks: &mut TokenStream<'a>) -> Result<Self, (String, Range)> {
+    let mut delta = vec![parse_id(toks, |n| n, "delay")?];
+    let mut backup = *toks;
+    while let Ok(Token { s: ".", .. }) = toks.token(None) {
+      delta.push(parse_id(toks, |n| n, "delay component")?);
+      backup = *toks;
+    }
+    *toks = backup;
+    Ok(delta)
+  }
+}
+
 impl<'a> Unpretty<'a> for Connection {
   fn unpretty(toks: &mut TokenStream<'a>) -> Result<Self, (String, Range)> {
-    let id = parse_id(toks, DebugOnlyId, "connection id")?;
+    let id = indexed_id(toks, DebugOnlyId, "connection id", true)?;
     let left = InstRef::unpretty(toks)?;
+    let left_slice = Option::<Slice>::unpretty(toks)?;
     let right = InstRef::unpretty(toks)?;
-    Ok(Connection { id, left, right })
+    let right_slice = Option::<Slice>::unpretty(toks)?;
+    let bak = *toks;
+    let delay = if let Ok(Token { s: "after", r }) = toks.token(None) {
+      let delay = DeltaT::unpretty(toks)?;
+      if delay.iter().all(|&d| d == 0) {
+        return Err((
+          "expected a nonzero delay; connections without one deliver at the same tag".to_string(),
+          r,
+        ));
+      }
+      Some(delay)
+    } else {
+      *toks = bak;
+      None
+    };
+    Ok(Connection {
+      id,
+      left,
+      left_slice,
+      right,
+      right_slice,
+      delay,
+    })
   }
 }
 
@@ -129,12 +288,22 @@ impl<'a> Unpretty<'a> for StructlikeCtor {
     let mut connections_section = toks.section();
     for mut line in instantiations_section.lines() {
       let sym = line.token(Some("inst name"))?.s.to_string();
-      let id = InstId::unpretty(&mut line)?;
+      let (id, range) = parse_id_at(&mut line, InstId, "inst id")?;
+      line.record(Recorded::Inst {
+        path: vec![id],
+        range,
+        definition: true,
+      });
       inst2sym.insert(id, sym);
       let Token { r, s: equals } = &line.token(Some("equals sign"))?;
       match *equals {
         "=" => {
-          insts.insert(id, CtorCall::unpretty(&mut line)?);
+          let call = CtorCall::unpretty(&mut line)?;
+          line.record(Recorded::Call {
+            inst: id,
+            ctor: call.ctor,
+          });
+          insts.insert(id, call);
         }
         _ => {
           return Err(("expected =".to_string(), *r));
@@ -169,8 +338,13 @@ impl<'a> Unpretty<'a> for BinaryCtor {
 impl<'a> Unpretty<'a> for LibCtor {
   fn unpretty(toks: &mut TokenStream<'a>) -> Result<Self, (String, Range)> {
     let name = toks.token(Some("lctor name"))?;
+    let mut params = Vec::new();
+    while let Ok(param) = toks.token(None) {
+      params.push(param.s.to_string());
+    }
     Ok(LibCtor {
       name: name.s.to_string(),
+      params,
     })
   }
 }
@@ -188,7 +362,7 @@ where
   for mut block in section.blocks() {
     let mut header = block.line()?;
     let sym = header.token(Some("ctor name"))?.s;
-    let cid = CtorId::unpretty(&mut header)?;
+    let cid = indexed_id(&mut header, CtorId, "ctor id", true)?;
     let ctor = CtorTyp::unpretty(if big { &mut block } else { &mut header })?;
     ctor2sym.insert(cid, sym.to_string());
     ctors.insert(cid, ctor_ctor(ctor));
@@ -247,15 +421,44 @@ mod tests {
     assert_eq!(CtorId::unpretty(&mut toks), Ok(CtorId(0x999ab)));
   }
 
+  #[test]
+  fn test_type() {
+    round_trip::<Type>("(int,(bool,()),celsius)");
+    assert_eq!("float".parse(), Ok(Type::Float));
+    assert!("(int,".parse::<Type>().is_err());
+    assert!("(int)x".parse::<Type>().is_err());
+  }
+
   #[test]
   fn test_ctorcall() {
     let mut toks = TokenStream::new("0x999ab");
     assert_eq!(
       CtorCall::unpretty(&mut toks),
       Ok(CtorCall {
-        ctor: CtorId(0x999ab)
+        ctor: CtorId(0x999ab),
+        width: 1,
+        args: vec![]
+      })
+    );
+    let mut toks = TokenStream::new("0x5 3 true");
+    assert_eq!(
+      CtorCall::unpretty(&mut toks),
+      Ok(CtorCall {
+        ctor: CtorId(5),
+        width: 1,
+        args: vec![Literal::U64(3), Literal::Bool(true)]
+      })
+    );
+    let mut toks = TokenStream::new("0x5 [4] 3");
+    assert_eq!(
+      CtorCall::unpretty(&mut toks),
+      Ok(CtorCall {
+        ctor: CtorId(5),
+        width: 4,
+        args: vec![Literal::U64(3)]
       })
     );
+    assert!(CtorCall::unpretty(&mut TokenStream::new("0x5 [x]")).is_err());
   }
 
   #[test]
@@ -270,6 +473,14 @@ mod tests {
   #[test]
   fn test_connection() {
     round_trip::<Connection>("99 1.2.3.2 3");
+    round_trip::<Connection>("91 87 [0:4] 88.89 [4:8]");
+    round_trip::<Connection>("91 87 88.89 [4:8]");
+    round_trip::<Connection>("92 87 88 after 1");
+    round_trip::<Connection>("93 87 [0:4] 88 [4:8] after 0.2.1");
+    assert!(Connection::unpretty(&mut TokenStream::new("94 87 88 after")).is_err());
+    assert!(Connection::unpretty(&mut TokenStream::new("94 87 88 after 0")).is_err());
+    assert!(Connection::unpretty(&mut TokenStream::new("94 87 88 after 0.0")).is_err());
+    assert!(Connection::unpretty(&mut TokenStream::new("91 87 [4:0] 88")).is_err());
   }
 
   #[test]
@@ -281,7 +492,7 @@ mod tests {
   R 1 R 6
   ---
   10 1 6
-  11 6 1
+  11 6 1 after 1
 ",
     );
   }
@@ -290,6 +501,7 @@ mod tests {
   fn test_program() {
     round_trip::<Program>(
       "c 0x7 add1
+g 0x8 gain k
 ---
 a 0x1 /this/is/a/path
 b 0x2 /this/is/another/path
@@ -297,17 +509,20 @@ b 0x2 /this/is/another/path
 rtor0 0x3
   foo 89 = 0x4
   ---
-  L 89
+  L 89 : celsius
   ---
   90 89 89
 rtor1 0x4
   baz 87 = 0x3
   bar 88 = 0x4
+  amp 93 = 0x8 3
+  bank 94 = 0x8 [4] 2
   ---
-  L 87 R 88.89
+  L 87 : (int,(bool,float)) R 88.89 : int
   ---
   91 88 87
   92 87 87
+  95 94 [1:3] 93
 ---
 0x3
 ",
@@ -1,3 +1,4 @@
+use std::any::Any;
 use std::fmt::Display;
 use std::hash::Hash;
 
@@ -46,12 +47,177 @@ pub enum Comm<IfaceElt> {
   Notify,
   Data(IfaceElt),
 }
+/// An element of an iface, optionally annotated with the type of the values that pass through it.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
-pub struct IfaceNode<IfaceElt>(pub SideMatch, pub Comm<IfaceElt>);
+pub struct IfaceNode<IfaceElt>(pub SideMatch, pub Comm<IfaceElt>, pub Option<Type>);
 pub type Iface<IfaceElt> = Vec<IfaceNode<IfaceElt>>;
 
+/// The type of the values that pass through a port.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone, PartialOrd, Ord)]
+pub enum Type {
+  Int,
+  Float,
+  Bool,
+  Tuple(Vec<Type>),
+  /// An opaque type that is only equal to other types with the same name.
+  Named(String),
+}
+
+/// A half-open range `start..end` of the channels of a port.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub struct Slice {
+  pub start: u64,
+  pub end: u64,
+}
+
+impl Slice {
+  pub fn width(&self) -> u64 {
+    self.end.saturating_sub(self.start)
+  }
+}
+
+/// A constant that is passed to a ctor when it is instantiated.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Literal {
+  U64(u64),
+  Bool(bool),
+}
+
+impl Literal {
+  /// Returns the value of `self` in the form in which it is passed to `realize`.
+  pub fn as_any(&self) -> &dyn Any {
+    match self {
+      Literal::U64(x) => x,
+      Literal::Bool(b) => b,
+    }
+  }
+
+  /// Returns the type of the ports that could carry `self`.
+  pub fn ty(&self) -> Type {
+    match self {
+      Literal::U64(_) => Type::Int,
+      Literal::Bool(_) => Type::Bool,
+    }
+  }
+}
+
+/// A value that passes through a port, in a form that can be kept after it has been passed.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum Value {
+  Int(u64),
+  Float(f64),
+  Bool(bool),
+  Tuple(Vec<Value>),
+}
+
+impl Value {
+  /// Returns the type of the ports that could carry `self`.
+  pub fn ty(&self) -> Type {
+    match self {
+      Value::Int(_) => Type::Int,
+      Value::Float(_) => Type::Float,
+      Value::Bool(_) => Type::Bool,
+      Value::Tuple(elts) => Type::Tuple(elts.iter().map(Value::ty).collect()),
+    }
+  }
+}
+
+/// A duration in hierarchical time, outermost component first.
 pub type DeltaT = Vec<u64>;
-pub type Net = DeltaT;
+
+/// A point in hierarchical time: the number of steps taken at each nesting depth, outermost first.
+///
+/// Tags are ordered lexicographically, so a tag precedes every tag nested within it.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone, PartialOrd, Ord)]
+pub struct Tag(pub Vec<u64>);
+
+/// One of the moves by which time advances.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TagStep {
+  /// Steps forward by the given number of steps at the current nesting depth.
+  Forward(u64),
+  /// Enters the time nested within the current tag.
+  Down,
+  /// Leaves the current nesting depth.
+  Up,
+}
+
+impl Default for Tag {
+  /// The start of time at the outermost nesting depth.
+  fn default() -> Self {
+    Tag(vec![0])
+  }
+}
+
+impl Tag {
+  /// The number of nesting levels that `self` is inside of.
+  pub fn depth(&self) -> usize {
+    self.0.len().saturating_sub(1)
+  }
+
+  /// Returns the tag that is `delta` after `self`, where the outermost component of `delta` is
+  /// counted at nesting depth `depth`. Time nested within the first nonzero component of `delta`
+  /// restarts from the remaining components of `delta`.
+  pub fn add_at(&self, depth: usize, delta: &[u64]) -> Tag {
+    let Some(k) = delta.iter().position(|&d| d != 0) else {
+      return self.clone();
+    };
+    let i = depth + k;
+    let mut ret: Vec<u64> = self.0.iter().take(i).copied().collect();
+    ret.resize(i, 0);
+    ret.push(self.0.get(i).copied().unwrap_or(0) + delta[k]);
+    ret.extend(&delta[k + 1..]);
+    Tag(ret)
+  }
+
+  /// Returns the tag `distance` steps after `self` at its own nesting depth.
+  pub fn step_forward(&self, distance: u64) -> Tag {
+    self.add_at(self.depth(), &[distance])
+  }
+
+  /// Returns the first tag nested within `self`.
+  pub fn step_down(&self) -> Tag {
+    let mut ret = self.clone();
+    ret.0.push(0);
+    ret
+  }
+
+  /// Returns the steps that take time from `self` directly to `to`, skipping the tags in between.
+  ///
+  /// # Panics
+  /// Panics if `to` precedes `self`.
+  pub fn steps_to(&self, to: &Tag) -> Vec<TagStep> {
+    assert!(self <= to, "cannot step from {self} back to {to}");
+    let common = self.0.iter().zip(&to.0).take_while(|(a, b)| a == b).count();
+    let mut ret = vec![];
+    let mut len = self.0.len();
+    while len > common + 1 {
+      ret.push(TagStep::Up);
+      len -= 1;
+    }
+    if len == common + 1 {
+      ret.push(TagStep::Forward(to.0[common] - self.0[common]));
+    }
+    while len < to.0.len() {
+      ret.push(TagStep::Down);
+      if to.0[len] > 0 {
+        ret.push(TagStep::Forward(to.0[len]));
+      }
+      len += 1;
+    }
+    ret
+  }
+
+  /// Returns the tag that `self` is nested within, or `None` if `self` is outermost.
+  pub fn step_up(&self) -> Option<Tag> {
+    if self.0.len() <= 1 {
+      return None;
+    }
+    let mut ret = self.clone();
+    ret.0.pop();
+    Some(ret)
+  }
+}
 
 #[derive(Serialize, Deserialize, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub struct CtorId(pub u64);
@@ -69,6 +235,19 @@ impl Display for Side {
   }
 }
 
+impl std::str::FromStr for Side {
+  type Err = String;
+
+  /// Parses a side in the format produced by its `Display` impl.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "L" => Ok(Side::Left),
+      "R" => Ok(Side::Right),
+      s => Err(format!("expected L or R at \"{s}\"")),
+    }
+  }
+}
+
 impl Display for SideMatch {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
@@ -80,7 +259,75 @@ impl Display for SideMatch {
 
 impl<IfaceElt: Display> Display for IfaceNode<IfaceElt> {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "{} {}", self.0, self.1)
+    write!(f, "{} {}", self.0, self.1)?;
+    if let Some(ty) = &self.2 {
+      write!(f, " : {ty}")?;
+    }
+    Ok(())
+  }
+}
+
+impl Display for Type {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Type::Int => write!(f, "int"),
+      Type::Float => write!(f, "float"),
+      Type::Bool => write!(f, "bool"),
+      Type::Tuple(elts) => {
+        write!(f, "(")?;
+        for (i, elt) in elts.iter().enumerate() {
+          if i > 0 {
+            write!(f, ",")?;
+          }
+          elt.fmt(f)?;
+        }
+        write!(f, ")")
+      }
+      Type::Named(name) => write!(f, "{name}"),
+    }
+  }
+}
+
+impl std::str::FromStr for Type {
+  type Err = String;
+
+  /// Parses a type in the format produced by its `Display` impl.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    fn parse(s: &str) -> Result<(Type, &str), String> {
+      if let Some(mut rest) = s.strip_prefix('(') {
+        let mut elts = vec![];
+        if let Some(rest) = rest.strip_prefix(')') {
+          return Ok((Type::Tuple(elts), rest));
+        }
+        loop {
+          let (elt, tail) = parse(rest)?;
+          elts.push(elt);
+          if let Some(tail) = tail.strip_prefix(',') {
+            rest = tail;
+          } else if let Some(tail) = tail.strip_prefix(')') {
+            return Ok((Type::Tuple(elts), tail));
+          } else {
+            return Err(format!("expected , or ) in type at \"{tail}\""));
+          }
+        }
+      }
+      let end = s
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(s.len());
+      let (name, rest) = s.split_at(end);
+      let ty = match name {
+        "" => return Err(format!("expected a type at \"{s}\"")),
+        "int" => Type::Int,
+        "float" => Type::Float,
+        "bool" => Type::Bool,
+        name => Type::Named(name.to_string()),
+      };
+      Ok((ty, rest))
+    }
+    match parse(s)? {
+      (ty, "") => Ok(ty),
+      (_, rest) => Err(format!("unexpected \"{rest}\" after type")),
+    }
   }
 }
 
@@ -108,6 +355,102 @@ impl<IfaceElt: Display> Display for Comm<IfaceElt> {
   }
 }
 
+impl Display for Slice {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "[{}:{}]", self.start, self.end)
+  }
+}
+
+impl Display for Literal {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Literal::U64(x) => write!(f, "{x}"),
+      Literal::Bool(b) => write!(f, "{b}"),
+    }
+  }
+}
+
+impl std::str::FromStr for Literal {
+  type Err = String;
+
+  /// Parses a literal in the format produced by its `Display` impl.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "true" => Ok(Literal::Bool(true)),
+      "false" => Ok(Literal::Bool(false)),
+      s => s
+        .parse()
+        .map(Literal::U64)
+        .map_err(|_| format!("expected a literal at \"{s}\"")),
+    }
+  }
+}
+
+impl Display for Value {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Value::Int(x) => write!(f, "{x}"),
+      // The debug format of a float always has a point or an exponent, which tells it apart from an
+      // int.
+      Value::Float(x) => write!(f, "{x:?}"),
+      Value::Bool(b) => write!(f, "{b}"),
+      Value::Tuple(elts) => {
+        write!(f, "(")?;
+        for (i, elt) in elts.iter().enumerate() {
+          if i > 0 {
+            write!(f, ",")?;
+          }
+          elt.fmt(f)?;
+        }
+        write!(f, ")")
+      }
+    }
+  }
+}
+
+impl std::str::FromStr for Value {
+  type Err = String;
+
+  /// Parses a value in the format produced by its `Display` impl.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    fn parse(s: &str) -> Result<(Value, &str), String> {
+      if let Some(mut rest) = s.strip_prefix('(') {
+        let mut elts = vec![];
+        if let Some(rest) = rest.strip_prefix(')') {
+          return Ok((Value::Tuple(elts), rest));
+        }
+        loop {
+          let (elt, tail) = parse(rest)?;
+          elts.push(elt);
+          if let Some(tail) = tail.strip_prefix(',') {
+            rest = tail;
+          } else if let Some(tail) = tail.strip_prefix(')') {
+            return Ok((Value::Tuple(elts), tail));
+          } else {
+            return Err(format!("expected , or ) in value at \"{tail}\""));
+          }
+        }
+      }
+      let end = s.find([',', ')']).unwrap_or(s.len());
+      let (word, rest) = s.split_at(end);
+      let value = if let Ok(x) = word.parse() {
+        Value::Int(x)
+      } else if let Ok(b) = word.parse() {
+        Value::Bool(b)
+      } else if let Ok(x) = word.parse() {
+        Value::Float(x)
+      } else {
+        return Err(format!("expected a value at \"{s}\""));
+      };
+      Ok((value, rest))
+    }
+    match parse(s)? {
+      (value, "") => Ok(value),
+      (_, rest) => Err(format!("unexpected \"{rest}\" after value")),
+    }
+  }
+}
+
 impl Display for CtorId {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     write!(f, "0x{:x}", self.0)
@@ -120,8 +463,152 @@ impl Display for InstId {
   }
 }
 
+impl Display for Tag {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for (i, step) in self.0.iter().enumerate() {
+      if i > 0 {
+        write!(f, ".")?;
+      }
+      write!(f, "{step}")?;
+    }
+    Ok(())
+  }
+}
+
+impl std::str::FromStr for Tag {
+  type Err = String;
+
+  /// Parses a tag in the format produced by its `Display` impl.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    s.split('.')
+      .map(|step| {
+        step
+          .parse()
+          .map_err(|_| format!("expected a number in tag at \"{step}\""))
+      })
+      .collect::<Result<_, _>>()
+      .map(Tag)
+  }
+}
+
 impl Display for DebugOnlyId {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     write!(f, "{}", self.0)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_tag_order() {
+    let mut tags = [
+      Tag(vec![1, 0]),
+      Tag(vec![0, 5]),
+      Tag(vec![1]),
+      Tag(vec![0, 5, 2]),
+      Tag(vec![2]),
+    ];
+    tags.sort();
+    let shown: Vec<_> = tags.iter().map(ToString::to_string).collect();
+    assert_eq!(shown, ["0.5", "0.5.2", "1", "1.0", "2"]);
+  }
+
+  #[test]
+  fn test_tag_arithmetic() {
+    let tag = Tag(vec![3, 4, 5]);
+    assert_eq!(tag.add_at(0, &[1]), Tag(vec![4]));
+    assert_eq!(tag.add_at(1, &[2, 7]), Tag(vec![3, 6, 7]));
+    assert_eq!(tag.add_at(0, &[0, 1]), Tag(vec![3, 5]));
+    assert_eq!(tag.add_at(2, &[0, 0]), tag);
+    assert_eq!(tag.add_at(4, &[1]), Tag(vec![3, 4, 5, 0, 1]));
+    assert_eq!(tag.step_forward(2), Tag(vec![3, 4, 7]));
+    assert_eq!(tag.step_down(), Tag(vec![3, 4, 5, 0]));
+    assert_eq!(tag.step_down().step_up(), Some(tag.clone()));
+    assert_eq!(Tag(vec![3]).step_up(), None);
+    assert!(tag < tag.step_down());
+    assert!(tag.step_down() < tag.step_forward(1));
+    assert_eq!(Tag::default().step_forward(1).to_string(), "1");
+  }
+
+  #[test]
+  fn test_tag_from_str() {
+    for tag in [Tag(vec![0]), Tag(vec![3, 0, 12])] {
+      assert_eq!(tag.to_string().parse::<Tag>(), Ok(tag));
+    }
+    assert!("1..2".parse::<Tag>().is_err());
+    assert!("".parse::<Tag>().is_err());
+  }
+
+  #[test]
+  fn test_side_and_literal_from_str() {
+    for side in [Side::Left, Side::Right] {
+      assert_eq!(side.to_string().parse::<Side>(), Ok(side));
+    }
+    assert!("A".parse::<Side>().is_err());
+    for literal in [Literal::U64(7), Literal::Bool(false)] {
+      assert_eq!(literal.to_string().parse::<Literal>(), Ok(literal));
+    }
+    assert!("7.0".parse::<Literal>().is_err());
+  }
+
+  #[test]
+  fn test_value_from_str() {
+    for value in [
+      Value::Int(7),
+      Value::Float(7.0),
+      Value::Float(-1.5e-9),
+      Value::Bool(true),
+      Value::Tuple(vec![]),
+      Value::Tuple(vec![
+        Value::Int(1),
+        Value::Tuple(vec![Value::Float(0.5), Value::Bool(false)]),
+      ]),
+    ] {
+      assert_eq!(value.to_string().parse::<Value>(), Ok(value));
+    }
+    assert!("(1,".parse::<Value>().is_err());
+    assert!("(1)2".parse::<Value>().is_err());
+    assert!("x".parse::<Value>().is_err());
+  }
+
+  #[test]
+  fn test_steps_to() {
+    let check = |from: &[u64], to: &[u64], expected: &[TagStep]| {
+      let (from, to) = (Tag(from.to_vec()), Tag(to.to_vec()));
+      let steps = from.steps_to(&to);
+      assert_eq!(steps, expected);
+      let reached = steps.iter().fold(from, |tag, step| match step {
+        TagStep::Forward(distance) => tag.step_forward(*distance),
+        TagStep::Down => tag.step_down(),
+        TagStep::Up => tag.step_up().unwrap(),
+      });
+      assert_eq!(reached, to);
+    };
+    check(&[3, 4, 5], &[3, 6], &[TagStep::Up, TagStep::Forward(2)]);
+    check(
+      &[3],
+      &[3, 0, 2],
+      &[TagStep::Down, TagStep::Down, TagStep::Forward(2)],
+    );
+    check(
+      &[1, 2],
+      &[4, 0, 1],
+      &[
+        TagStep::Up,
+        TagStep::Forward(3),
+        TagStep::Down,
+        TagStep::Down,
+        TagStep::Forward(1),
+      ],
+    );
+    check(&[2, 7], &[2, 7], &[]);
+  }
+
+  #[test]
+  #[should_panic(expected = "cannot step from 2 back to 1.5")]
+  fn test_steps_back() {
+    Tag(vec![2]).steps_to(&Tag(vec![1, 5]));
+  }
+}
@@ -5,4 +5,5 @@ cd lf-types && cargo test && cd .. \
   && cd irlf-ser && cargo test && cd .. \
   && cd irlf-db && cargo test && cd .. \
   && cd get-rtor-impl && cargo test && cd .. \
+  && cd irlf-lsp && cargo test && cd .. \
   && printf "${CYAN}**************** ALL TESTS PASSED ****************${NO_COLOR}\n"